@@ -0,0 +1,162 @@
+//! Benchmarks of the hashing, border detection and matching kernels, over
+//! images and hashes generated from fixed seeds.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fromthumb::borders::{detect_inner_image_bounds, BorderOptions, BorderScan};
+use fromthumb::matching::{FullsizeSearch, Matcher, PathPhash, DEFAULT_INDEX_CHUNKS};
+use fromthumb::phash::{HashConfig, Hashers};
+use image::{DynamicImage, Rgb, RgbImage};
+use img_hash::ImageHash;
+
+/// Thumbnails searched for in each run of the matching benchmarks.
+const QUERIES: usize = 100;
+
+/// Xorshift generator, so that the inputs are the same on every run.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn byte(&mut self) -> u8 {
+        (self.next() >> 56) as u8
+    }
+}
+
+/// Generates a scan of `width` by `height` pixels: smooth gradients with some
+/// noise, inside a white border an eighth of the width on each side.
+fn scan(width: u32, height: u32, seed: u64) -> DynamicImage {
+    let mut random = Random(seed);
+    let (left, top) = (width / 8, height / 8);
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        if x < left || x >= width - left || y < top || y >= height - top {
+            return Rgb([255, 255, 255]);
+        }
+        let noise = random.byte() / 8;
+        Rgb([
+            (x * 255 / width) as u8 / 2 + noise,
+            (y * 255 / height) as u8 / 2 + noise,
+            ((x + y) * 127 / (width + height)) as u8 + noise,
+        ])
+    });
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Hashes `count` images of noise with the default config, giving hashes
+/// spread evenly over the space, along with the images.
+fn random_phashes(count: usize, seed: u64) -> (Vec<PathPhash>, Vec<RgbImage>) {
+    let hashers = Hashers::new(HashConfig::default());
+    let mut random = Random(seed);
+    let mut images = Vec::with_capacity(count);
+    let mut phashes = Vec::with_capacity(count);
+    for index in 0..count {
+        let image = RgbImage::from_fn(9, 8, |_, _| {
+            Rgb([random.byte(), random.byte(), random.byte()])
+        });
+        let phash = hashers
+            .fine
+            .hash_image(&DynamicImage::ImageRgb8(image.clone()));
+        phashes.push(path_phash(format!("fullsize/{}.png", index), phash));
+        images.push(image);
+    }
+    (phashes, images)
+}
+
+fn path_phash(path: String, phash: ImageHash) -> PathPhash {
+    let path = PathBuf::from(path);
+    PathPhash {
+        file_name: path.file_name().map(OsString::from).unwrap_or_default(),
+        relative: path.clone(),
+        path,
+        phash,
+        coarse: None,
+        dimensions: None,
+        transformed: Vec::new(),
+        histogram: None,
+        provenance: None,
+    }
+}
+
+/// Hashes thumbnails of the first `QUERIES` of `images`, each with a pixel
+/// changed so that they are near, but not equal to, their fullsize images.
+fn thumbnails(images: &[RgbImage]) -> Vec<PathPhash> {
+    let hashers = Hashers::new(HashConfig::default());
+    images
+        .iter()
+        .take(QUERIES)
+        .enumerate()
+        .map(|(index, image)| {
+            let mut image = image.clone();
+            let pixel = image.get_pixel_mut((index % 9) as u32, (index % 8) as u32);
+            pixel.0 = [255 - pixel.0[0], 255 - pixel.0[1], 255 - pixel.0[2]];
+            let phash = hashers.fine.hash_image(&DynamicImage::ImageRgb8(image));
+            path_phash(format!("thumbnail/{}.png", index), phash)
+        })
+        .collect()
+}
+
+fn hashing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash");
+    let hashers = Hashers::new(HashConfig::default());
+    for size in [128, 512, 2048].iter() {
+        let image = scan(*size, *size, 1);
+        group.throughput(Throughput::Elements(u64::from(size * size)));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &image, |b, image| {
+            b.iter(|| hashers.fine.hash_image(image))
+        });
+    }
+    group.finish();
+}
+
+fn borders(c: &mut Criterion) {
+    let mut group = c.benchmark_group("detect_inner_image_bounds");
+    for (width, height) in [(1000, 1400), (3000, 4000)].iter() {
+        let image = scan(*width, *height, 2);
+        for scan in [BorderScan::Sparse, BorderScan::Dense, BorderScan::Full].iter() {
+            let border = BorderOptions {
+                scan: *scan,
+                ..BorderOptions::default()
+            };
+            group.bench_with_input(
+                BenchmarkId::new(scan.name(), format!("{}x{}", width, height)),
+                &image,
+                |b, image| b.iter(|| detect_inner_image_bounds(image, &border)),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn matching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("best_match");
+    group.throughput(Throughput::Elements(QUERIES as u64));
+    for count in [10_000, 100_000].iter() {
+        let (fullsize, images) = random_phashes(*count, 3);
+        let thumbs = thumbnails(&images);
+        for matcher in [Matcher::BruteForce, Matcher::BkTree, Matcher::MultiIndex].iter() {
+            let search = FullsizeSearch::new(&fullsize, *matcher, DEFAULT_INDEX_CHUNKS);
+            group.bench_with_input(
+                BenchmarkId::new(matcher.name(), count),
+                &thumbs,
+                |b, thumbs| {
+                    b.iter(|| {
+                        for thumb in thumbs.iter() {
+                            search.candidates(thumb, 1, None, |_| true, &mut |_, _| ());
+                        }
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hashing, borders, matching);
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+//! Finds the fullsize images thumbnails were made from, and pulls the
+//! thumbnails out of scanned pages of them.
+
+use std::ffi::OsString;
+
+use anyhow::Result;
+use structopt::StructOpt;
+
+#[cfg(feature = "decollage")]
+use fromthumb::cli::decollage;
+use fromthumb::cli::{self, find};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "fromthumb",
+    about = "Find fullsize images from their thumbnails."
+)]
+// Parsed once, so the size of `find`'s options doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Find matching images from a large set.
+    Find(find::FindOpt),
+    /// Pull sub images out of input images with a white background.
+    #[cfg(feature = "decollage")]
+    Decollage(decollage::DecollageOpt),
+    /// Print the perceptual hash of each image, as `find` hashes it.
+    Hash(find::HashOpt),
+    /// Print the hash distance between a thumbnail and a fullsize image, as
+    /// `find` hashes them.
+    Dist(find::DistOpt),
+    /// Check the copies in an output directory against its recorded matches.
+    Verify(find::VerifyOpt),
+}
+
+fn main() -> Result<()> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let command = args
+        .get(1)
+        .and_then(|command| command.to_str())
+        .unwrap_or_default()
+        .to_owned();
+    match cli::parse(args, 2, &command)? {
+        Command::Find(opt) => find::run(opt),
+        #[cfg(feature = "decollage")]
+        Command::Decollage(opt) => decollage::run(opt),
+        Command::Hash(opt) => find::hash_command(opt),
+        Command::Dist(opt) => find::dist_command(opt),
+        Command::Verify(opt) => find::verify_command(opt),
+    }
+}
@@ -0,0 +1,968 @@
+//! Finding and removing the borders around images, such as the margin left
+//! around a photo when it was scanned.
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::str::FromStr;
+
+use image::{DynamicImage, GenericImageView};
+use log::warn;
+
+use crate::{Error, Result};
+
+/// Default `BorderOptions::threshold`.
+pub const WHITE_THRESHOLD: u8 = 230;
+/// Number of scan lines across each axis of a `Sparse` scan, at its
+/// quarters.
+pub const SCAN_LINES: usize = 3;
+/// Distance between scan lines of a `Dense` scan.
+const DENSE_SCAN_STEP: u32 = 8;
+/// Most frames of different colors cropped from an image with an `Auto`
+/// border color.
+const MAX_BORDER_FRAMES: usize = 3;
+/// Default `BorderOptions::min_confirmations`.
+pub const MIN_CONFIRMATIONS: usize = 2;
+/// Default `BorderOptions::max_crop_fraction`.
+pub const MAX_CROP_FRACTION: f64 = 0.6;
+
+/// Color of the borders removed by cleanup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BorderColor {
+    White,
+    Black,
+    /// The most common color among the corners of each image.
+    Auto,
+}
+
+impl BorderColor {
+    pub fn name(self) -> &'static str {
+        match self {
+            BorderColor::White => "white",
+            BorderColor::Black => "black",
+            BorderColor::Auto => "auto",
+        }
+    }
+}
+
+impl FromStr for BorderColor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "white" => Ok(BorderColor::White),
+            "black" => Ok(BorderColor::Black),
+            "auto" => Ok(BorderColor::Auto),
+            _ => Err(Error::Unknown {
+                kind: "border color",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Which lines of an image are scanned for borders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BorderScan {
+    /// Three lines, at the quarters of each axis.
+    Sparse,
+    /// Every `DENSE_SCAN_STEP`th line.
+    Dense,
+    /// Every line.
+    Full,
+}
+
+impl BorderScan {
+    pub fn name(self) -> &'static str {
+        match self {
+            BorderScan::Sparse => "sparse",
+            BorderScan::Dense => "dense",
+            BorderScan::Full => "full",
+        }
+    }
+
+    /// Positions of the lines scanned across an axis of `length` pixels.
+    pub fn lines(self, length: u32) -> Vec<u32> {
+        match self {
+            BorderScan::Sparse => (1..=SCAN_LINES as u32)
+                .map(|line| length / (SCAN_LINES as u32 + 1) * line)
+                .collect(),
+            BorderScan::Dense => (DENSE_SCAN_STEP / 2..length)
+                .step_by(DENSE_SCAN_STEP as usize)
+                .collect(),
+            BorderScan::Full => (0..length).collect(),
+        }
+    }
+}
+
+impl FromStr for BorderScan {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sparse" => Ok(BorderScan::Sparse),
+            "dense" => Ok(BorderScan::Dense),
+            "full" => Ok(BorderScan::Full),
+            _ => Err(Error::Unknown {
+                kind: "border scan",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// How border pixels are recognised by cleanup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BorderOptions {
+    pub color: BorderColor,
+    /// Pixels are border if every channel is within `255 - threshold` of the
+    /// border color. For white borders, this is every channel being above the
+    /// threshold.
+    pub threshold: u8,
+    /// Scan lines that must agree on each edge of the inner image.
+    pub min_confirmations: usize,
+    /// Largest fraction of an image's area that cleanup may crop away.
+    pub max_crop_fraction: f64,
+    pub scan: BorderScan,
+}
+
+impl Default for BorderOptions {
+    fn default() -> Self {
+        BorderOptions {
+            color: BorderColor::White,
+            threshold: WHITE_THRESHOLD,
+            min_confirmations: MIN_CONFIRMATIONS,
+            max_crop_fraction: MAX_CROP_FRACTION,
+            scan: BorderScan::Sparse,
+        }
+    }
+}
+
+impl BorderOptions {
+    /// Suffix of the cache subdirectory for images cleaned up with these
+    /// options, empty for the defaults.
+    pub fn cache_suffix(&self) -> String {
+        let default = BorderOptions::default();
+        let mut suffix = String::new();
+        if self.color != default.color || self.threshold != default.threshold {
+            suffix.push_str(&format!("-{}{}", self.color.name(), self.threshold));
+        }
+        if self.min_confirmations != default.min_confirmations
+            || self.max_crop_fraction != default.max_crop_fraction
+        {
+            suffix.push_str(&format!(
+                "-confirm{}-maxcrop{}",
+                self.min_confirmations, self.max_crop_fraction
+            ));
+        }
+        if self.scan != default.scan {
+            suffix.push_str(&format!("-{}", self.scan.name()));
+        }
+        suffix
+    }
+
+    /// Description of these options recorded in cache indexes.
+    pub fn config_name(&self) -> String {
+        let mut name = format!(
+            "{}{}-confirm{}-maxcrop{}-{}",
+            self.color.name(),
+            self.threshold,
+            self.min_confirmations,
+            self.max_crop_fraction,
+            self.scan.name()
+        );
+        if self.color == BorderColor::Auto {
+            name.push_str(&format!("-frames{}", MAX_BORDER_FRAMES));
+        }
+        name
+    }
+
+    fn tolerance(&self) -> u8 {
+        255 - self.threshold
+    }
+
+    /// Returns the border color to look for in `image`.
+    fn reference(&self, image: &DynamicImage) -> [u8; 3] {
+        match self.color {
+            BorderColor::White => [255, 255, 255],
+            BorderColor::Black => [0, 0, 0],
+            BorderColor::Auto => self.corner_color(image).0,
+        }
+    }
+
+    /// Returns the most common color among the corners of `image`, and how
+    /// many of the four corners share it.
+    fn corner_color(&self, image: &DynamicImage) -> ([u8; 3], usize) {
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return ([255, 255, 255], 0);
+        }
+        let corners: Vec<[u8; 3]> = [
+            (0, 0),
+            (width - 1, 0),
+            (0, height - 1),
+            (width - 1, height - 1),
+        ]
+        .iter()
+        .map(|(x, y)| {
+            let data = image.get_pixel(*x, *y).0;
+            [data[0], data[1], data[2]]
+        })
+        .collect();
+        // Take the corner agreeing with the most others, preferring earlier
+        // corners on a tie.
+        let mut best = corners[0];
+        let mut best_count = 0;
+        for corner in corners.iter() {
+            let count = corners
+                .iter()
+                .filter(|other| is_border_pixel(**other, *corner, self.tolerance()))
+                .count();
+            if count > best_count {
+                best = *corner;
+                best_count = count;
+            }
+        }
+        (best, best_count)
+    }
+
+    /// Whether `image` looks framed by another border, with all four of its
+    /// corners the same color.
+    fn is_framed(&self, image: &DynamicImage) -> bool {
+        self.corner_color(image).1 == 4
+    }
+}
+
+/// Returns whether every channel of `pixel` is within `tolerance` of
+/// `reference`.
+fn is_border_pixel(pixel: [u8; 3], reference: [u8; 3], tolerance: u8) -> bool {
+    pixel
+        .iter()
+        .zip(reference.iter())
+        .all(|(channel, reference)| {
+            (i16::from(*channel) - i16::from(*reference)).abs() < i16::from(tolerance)
+        })
+}
+
+/// Returns the bounds of content furthest out that at least `confirmations`
+/// scan lines agree on, from where each line's content `starts` and `ends`.
+/// A speck of dust met by a single scan line then can't keep the border.
+fn confirmed_bounds(
+    mut starts: Vec<u32>,
+    mut ends: Vec<u32>,
+    confirmations: usize,
+) -> Option<(u32, u32)> {
+    if confirmations == 0 || starts.len() < confirmations {
+        return None;
+    }
+    starts.sort_unstable();
+    ends.sort_unstable_by(|a, b| b.cmp(a));
+    Some((starts[confirmations - 1], ends[confirmations - 1]))
+}
+
+/// Returns a (x, y, width, height) indicating the inner image, or `None` if
+/// too few scan lines find anything but border, as in a blank scan.
+pub fn detect_inner_image_bounds(
+    image: &DynamicImage,
+    border: &BorderOptions,
+) -> Option<(u32, u32, u32, u32)> {
+    let reference = border.reference(image);
+    let tolerance = border.tolerance();
+    // Pixels are read straight from the buffer, as dense scans read most of
+    // the image.
+    let buffer = match image.as_rgb8() {
+        Some(buffer) => Cow::Borrowed(buffer),
+        None => Cow::Owned(image.to_rgb()),
+    };
+    let (width, height) = buffer.dimensions();
+    let raw: &[u8] = &buffer;
+    let is_border = |x: u32, y: u32| {
+        let offset = (y as usize * width as usize + x as usize) * 3;
+        is_border_pixel(
+            [raw[offset], raw[offset + 1], raw[offset + 2]],
+            reference,
+            tolerance,
+        )
+    };
+    let width_checks = border.scan.lines(width);
+    let height_checks = border.scan.lines(height);
+
+    // Each scan line is followed in from both edges until it meets content,
+    // so content on only one side of the image is not cut off at the middle.
+    let mut starts_x = Vec::with_capacity(height_checks.len());
+    let mut ends_x = Vec::with_capacity(height_checks.len());
+    for height_check in height_checks.iter() {
+        let is_content = |x_check: &u32| !is_border(*x_check, *height_check);
+        if let Some(start) = (0..width).find(is_content) {
+            starts_x.push(start);
+            ends_x.push((0..width).rev().find(is_content).unwrap_or(start));
+        }
+    }
+
+    let mut starts_y = Vec::with_capacity(width_checks.len());
+    let mut ends_y = Vec::with_capacity(width_checks.len());
+    for width_check in width_checks.iter() {
+        let is_content = |y_check: &u32| !is_border(*width_check, *y_check);
+        if let Some(start) = (0..height).find(is_content) {
+            starts_y.push(start);
+            ends_y.push((0..height).rev().find(is_content).unwrap_or(start));
+        }
+    }
+
+    let (min_x, max_x) = confirmed_bounds(starts_x, ends_x, border.min_confirmations)?;
+    let (min_y, max_y) = confirmed_bounds(starts_y, ends_y, border.min_confirmations)?;
+    // The content is too thin to crop to.
+    if max_x <= min_x || max_y <= min_y {
+        return None;
+    }
+    // The bounds are of the first and last content pixels, both included.
+    Some((min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+/// Finds the bounds to crop the border from an image to, as `(x, y, width,
+/// height)`. Images with no inner image, or that would lose more than
+/// `max_crop_fraction` of their area, are not cropped, with a warning.
+///
+/// With an `auto` border color, frames inside the border in another color,
+/// such as a black mat inside a white mount, are cropped in turn, up to
+/// `MAX_BORDER_FRAMES` in all.
+pub fn border_crop(
+    image: &DynamicImage,
+    border: &BorderOptions,
+    path: &Path,
+) -> Option<(u32, u32, u32, u32)> {
+    let mut bounds = match detect_inner_image_bounds(image, border) {
+        Some(bounds) => bounds,
+        None => {
+            warn!(
+                "Not removing borders from {}, as no inner image was found",
+                path.to_string_lossy()
+            );
+            return None;
+        }
+    };
+    if border.color == BorderColor::Auto {
+        for _ in 1..MAX_BORDER_FRAMES {
+            let (x, y, width, height) = bounds;
+            let inner = image.crop_imm(x, y, width, height);
+            if !border.is_framed(&inner) {
+                break;
+            }
+            match detect_inner_image_bounds(&inner, border) {
+                Some((inner_x, inner_y, inner_width, inner_height)) => {
+                    bounds = (x + inner_x, y + inner_y, inner_width, inner_height)
+                }
+                None => break,
+            }
+        }
+    }
+
+    let (_, _, width, height) = bounds;
+    let (image_width, image_height) = image.dimensions();
+    let area = f64::from(image_width) * f64::from(image_height);
+    let cropped = 1.0 - f64::from(width) * f64::from(height) / area;
+    if cropped > border.max_crop_fraction {
+        warn!(
+            "Not removing borders from {}, as it would crop away {:.0}% of it",
+            path.to_string_lossy(),
+            cropped * 100.0
+        );
+        return None;
+    }
+    Some(bounds)
+}
+
+/// Crops the border from an image, to the bounds found by `border_crop`.
+/// Images it does not crop are returned as they are.
+pub fn remove_borders(image: DynamicImage, border: &BorderOptions, path: &Path) -> DynamicImage {
+    match border_crop(&image, border, path) {
+        Some((x, y, width, height)) => image.crop_imm(x, y, width, height),
+        None => image,
+    }
+}
+
+/// Returns the fraction of pixels of an image that are not border.
+pub fn content_fraction(image: &DynamicImage, border: &BorderOptions) -> f64 {
+    let reference = border.reference(image);
+    let tolerance = border.tolerance();
+    let (width, height) = image.dimensions();
+    let content = image
+        .pixels()
+        .filter(|(_, _, pixel)| {
+            let data = pixel.0;
+            !is_border_pixel([data[0], data[1], data[2]], reference, tolerance)
+        })
+        .count();
+    content as f64 / (f64::from(width) * f64::from(height)).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Pixel, Rgb, RgbImage};
+
+    use super::*;
+
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const CONTENT: Rgb<u8> = Rgb([40, 90, 160]);
+
+    /// A white canvas of `width` by `height` with `content`, as `(x, y,
+    /// width, height)`, filled in.
+    fn canvas(width: u32, height: u32, content: (u32, u32, u32, u32)) -> RgbImage {
+        let (x, y, content_width, content_height) = content;
+        RgbImage::from_fn(width, height, |px, py| {
+            if px >= x && px < x + content_width && py >= y && py < y + content_height {
+                CONTENT
+            } else {
+                WHITE
+            }
+        })
+    }
+
+    fn bounds(image: RgbImage) -> Option<(u32, u32, u32, u32)> {
+        detect_inner_image_bounds(&DynamicImage::ImageRgb8(image), &BorderOptions::default())
+    }
+
+    #[test]
+    fn all_white_image_is_not_cropped() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(100, 80, WHITE));
+        let border = BorderOptions::default();
+        assert_eq!(detect_inner_image_bounds(&image, &border), None);
+        assert_eq!(border_crop(&image, &border, Path::new("white.png")), None);
+        let cleaned = remove_borders(image, &border, Path::new("white.png"));
+        assert_eq!(cleaned.dimensions(), (100, 80));
+        assert_eq!(content_fraction(&cleaned, &border), 0.0);
+    }
+
+    #[test]
+    fn half_blank_image_is_cropped_to_its_content() {
+        assert_eq!(
+            bounds(canvas(100, 80, (0, 0, 55, 80))),
+            Some((0, 0, 55, 80))
+        );
+        assert_eq!(
+            bounds(canvas(100, 80, (45, 0, 55, 80))),
+            Some((45, 0, 55, 80))
+        );
+    }
+
+    #[test]
+    fn bordered_image_is_cropped_to_its_content() {
+        let content = (10, 8, 70, 60);
+        let image = DynamicImage::ImageRgb8(canvas(100, 80, content));
+        let border = BorderOptions::default();
+        assert_eq!(
+            border_crop(&image, &border, Path::new("bordered.png")),
+            Some(content)
+        );
+        let cleaned = remove_borders(image, &border, Path::new("bordered.png"));
+        assert_eq!(cleaned.dimensions(), (70, 60));
+        assert_eq!(content_fraction(&cleaned, &border), 1.0);
+    }
+
+    #[test]
+    fn speck_on_a_blank_image_is_not_an_inner_image() {
+        // The speck is on a scan line of each axis.
+        let mut image = RgbImage::from_pixel(100, 80, WHITE);
+        image.put_pixel(50, 40, Rgb([0, 0, 0]));
+        let image = DynamicImage::ImageRgb8(image);
+        let border = BorderOptions::default();
+        assert_eq!(detect_inner_image_bounds(&image, &border), None);
+        assert_eq!(border_crop(&image, &border, Path::new("speck.png")), None);
+        let fraction = content_fraction(&image, &border);
+        assert!(fraction > 0.0 && fraction < 0.01, "{}", fraction);
+    }
+
+    #[test]
+    fn isolated_specks_in_the_border_are_not_confirmed() {
+        let content = (20, 16, 60, 48);
+        let mut image = canvas(100, 80, content);
+        // Specks on scan lines near each edge, each met by only one line.
+        image.put_pixel(3, 40, Rgb([0, 0, 0]));
+        image.put_pixel(96, 40, Rgb([0, 0, 0]));
+        image.put_pixel(50, 2, Rgb([0, 0, 0]));
+        let image = DynamicImage::ImageRgb8(image);
+        assert_eq!(
+            detect_inner_image_bounds(&image, &BorderOptions::default()),
+            Some(content)
+        );
+        // Trusting every line lets the specks keep the border.
+        let trusting = BorderOptions {
+            min_confirmations: 1,
+            ..BorderOptions::default()
+        };
+        assert_eq!(
+            detect_inner_image_bounds(&image, &trusting),
+            Some((3, 2, 94, 62))
+        );
+    }
+
+    #[test]
+    fn confirmed_bounds_take_the_furthest_edge_enough_lines_agree_on() {
+        assert_eq!(
+            confirmed_bounds(vec![20, 3, 21], vec![80, 97, 79], 2),
+            Some((20, 80))
+        );
+        assert_eq!(
+            confirmed_bounds(vec![20, 3, 21], vec![80, 97, 79], 1),
+            Some((3, 97))
+        );
+        assert_eq!(confirmed_bounds(vec![20], vec![80], 2), None);
+        assert_eq!(confirmed_bounds(vec![20], vec![80], 0), None);
+    }
+
+    #[test]
+    fn crops_removing_too_much_of_the_image_are_refused() {
+        let content = (20, 20, 40, 40);
+        let image = DynamicImage::ImageRgb8(canvas(100, 100, content));
+        let border = BorderOptions::default();
+        assert_eq!(detect_inner_image_bounds(&image, &border), Some(content));
+        assert_eq!(border_crop(&image, &border, Path::new("small.png")), None);
+        let lenient = BorderOptions {
+            max_crop_fraction: 0.9,
+            ..BorderOptions::default()
+        };
+        assert_eq!(
+            border_crop(&image, &lenient, Path::new("small.png")),
+            Some(content)
+        );
+    }
+
+    #[test]
+    fn sparse_scan_lines_are_at_the_quarters() {
+        assert_eq!(BorderScan::Sparse.lines(100), vec![25, 50, 75]);
+        assert_eq!(BorderScan::Sparse.lines(103), vec![25, 50, 75]);
+        assert_eq!(BorderScan::Dense.lines(30), vec![4, 12, 20, 28]);
+        assert_eq!(BorderScan::Full.lines(3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn every_scan_agrees_on_content_across_the_quarters() {
+        let content = (10, 8, 70, 60);
+        let image = DynamicImage::ImageRgb8(canvas(100, 80, content));
+        for scan in [BorderScan::Sparse, BorderScan::Dense, BorderScan::Full].iter() {
+            let border = BorderOptions {
+                scan: *scan,
+                ..BorderOptions::default()
+            };
+            assert_eq!(
+                detect_inner_image_bounds(&image, &border),
+                Some(content),
+                "{}",
+                scan.name()
+            );
+        }
+    }
+
+    #[test]
+    fn only_dense_scans_find_a_small_image_in_a_corner() {
+        let content = (10, 10, 60, 60);
+        let image = DynamicImage::ImageRgb8(canvas(400, 400, content));
+        let scan = |scan: BorderScan| {
+            detect_inner_image_bounds(
+                &image,
+                &BorderOptions {
+                    scan,
+                    ..BorderOptions::default()
+                },
+            )
+        };
+        assert_eq!(scan(BorderScan::Sparse), None);
+        assert_eq!(scan(BorderScan::Dense), Some(content));
+        assert_eq!(scan(BorderScan::Full), Some(content));
+    }
+
+    /// Xorshift generator, so that the random fixtures are the same on every
+    /// run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// Returns a number in `range`.
+        fn between(&mut self, range: std::ops::Range<u32>) -> u32 {
+            range.start + (self.next() % u64::from(range.end - range.start)) as u32
+        }
+    }
+
+    #[test]
+    fn borders_of_any_thickness_are_removed() {
+        for thickness in [0, 1, 2, 7, 15, 20].iter() {
+            let content = (
+                *thickness,
+                *thickness,
+                120 - 2 * thickness,
+                90 - 2 * thickness,
+            );
+            assert_eq!(
+                bounds(canvas(120, 90, content)),
+                Some(content),
+                "{}",
+                thickness
+            );
+        }
+        // Borders of different thickness on each side.
+        let content = (3, 17, 100, 40);
+        assert_eq!(bounds(canvas(120, 90, content)), Some(content));
+    }
+
+    #[test]
+    fn content_touching_an_edge_keeps_that_edge() {
+        for content in [
+            (0, 10, 70, 60),
+            (30, 10, 70, 60),
+            (10, 0, 70, 60),
+            (10, 20, 70, 60),
+            (0, 0, 100, 80),
+        ]
+        .iter()
+        {
+            assert_eq!(bounds(canvas(100, 80, *content)), Some(*content));
+        }
+    }
+
+    #[test]
+    fn content_between_the_scan_lines_is_only_found_by_denser_scans() {
+        // Between the quarter lines at 25 and 50 on both axes.
+        let content = (28, 30, 18, 16);
+        let image = DynamicImage::ImageRgb8(canvas(100, 100, content));
+        for (scan, expected) in [
+            (BorderScan::Sparse, None),
+            (BorderScan::Dense, Some(content)),
+            (BorderScan::Full, Some(content)),
+        ]
+        .iter()
+        {
+            let border = BorderOptions {
+                scan: *scan,
+                ..BorderOptions::default()
+            };
+            assert_eq!(detect_inner_image_bounds(&image, &border), *expected);
+        }
+    }
+
+    #[test]
+    fn faint_noise_in_the_border_is_ignored() {
+        let content = (14, 9, 80, 61);
+        let mut random = Random(7);
+        let mut image = canvas(110, 80, content);
+        for pixel in image.pixels_mut() {
+            if *pixel == WHITE {
+                let faint = random.between(WHITE_THRESHOLD as u32 + 1..256) as u8;
+                *pixel = Rgb([faint, faint, 255 - random.between(0..20) as u8]);
+            }
+        }
+        for scan in [BorderScan::Sparse, BorderScan::Dense, BorderScan::Full].iter() {
+            let border = BorderOptions {
+                scan: *scan,
+                ..BorderOptions::default()
+            };
+            assert_eq!(
+                detect_inner_image_bounds(&DynamicImage::ImageRgb8(image.clone()), &border),
+                Some(content),
+                "{}",
+                scan.name()
+            );
+        }
+    }
+
+    #[test]
+    fn random_rectangles_are_found_exactly_when_enough_lines_cross_them() {
+        let mut random = Random(11);
+        for _ in 0..300 {
+            let (width, height) = (random.between(20..200), random.between(20..200));
+            let (content_width, content_height) =
+                (random.between(1..width + 1), random.between(1..height + 1));
+            let content = (
+                random.between(0..width - content_width + 1),
+                random.between(0..height - content_height + 1),
+                content_width,
+                content_height,
+            );
+            let image = DynamicImage::ImageRgb8(canvas(width, height, content));
+            for scan in [BorderScan::Sparse, BorderScan::Dense, BorderScan::Full].iter() {
+                let border = BorderOptions {
+                    scan: *scan,
+                    ..BorderOptions::default()
+                };
+                // Lines crossing the content see both of its edges.
+                let crossing = |length: u32, start: u32, size: u32| {
+                    scan.lines(length)
+                        .iter()
+                        .filter(|line| (start..start + size).contains(line))
+                        .count()
+                };
+                let (x, y, _, _) = content;
+                let found = crossing(height, y, content_height) >= border.min_confirmations
+                    && crossing(width, x, content_width) >= border.min_confirmations
+                    && content_width > 1
+                    && content_height > 1;
+                assert_eq!(
+                    detect_inner_image_bounds(&image, &border),
+                    if found { Some(content) } else { None },
+                    "{} scan of {:?} in {}x{}",
+                    scan.name(),
+                    content,
+                    width,
+                    height
+                );
+            }
+        }
+    }
+
+    /// Finds the bounds as `detect_inner_image_bounds` documents them, a pixel
+    /// at a time: the furthest content that `min_confirmations` of the scan
+    /// lines reach on each edge.
+    fn reference_bounds(
+        image: &DynamicImage,
+        border: &BorderOptions,
+    ) -> Option<(u32, u32, u32, u32)> {
+        let reference = border.reference(image);
+        let (width, height) = image.dimensions();
+        let is_content = |x: u32, y: u32| {
+            let pixel = image.get_pixel(x, y).to_rgb().0;
+            !(0..3).all(|channel| {
+                (i16::from(pixel[channel]) - i16::from(reference[channel])).abs()
+                    < i16::from(border.tolerance())
+            })
+        };
+        // The `confirmations`th furthest out of the edges the lines meet.
+        let edge = |mut edges: Vec<u32>, last: bool| {
+            edges.sort_unstable();
+            if last {
+                edges.reverse();
+            }
+            edges.get(border.min_confirmations.checked_sub(1)?).copied()
+        };
+        let rows: Vec<Vec<u32>> = border
+            .scan
+            .lines(height)
+            .into_iter()
+            .map(|y| (0..width).filter(|x| is_content(*x, y)).collect())
+            .collect();
+        let columns: Vec<Vec<u32>> = border
+            .scan
+            .lines(width)
+            .into_iter()
+            .map(|x| (0..height).filter(|y| is_content(x, *y)).collect())
+            .collect();
+        let met = |lines: &[Vec<u32>], last: bool| {
+            let edges = lines
+                .iter()
+                .filter_map(|line| if last { line.last() } else { line.first() })
+                .copied()
+                .collect();
+            edge(edges, last)
+        };
+        let (min_x, max_x) = (met(&rows, false)?, met(&rows, true)?);
+        let (min_y, max_y) = (met(&columns, false)?, met(&columns, true)?);
+        if max_x <= min_x || max_y <= min_y {
+            return None;
+        }
+        Some((min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+    }
+
+    /// A scan of noisy gradients at `content` in a border of pale noise, with
+    /// specks of dust scattered through the border.
+    fn noisy_scan(
+        width: u32,
+        height: u32,
+        content: (u32, u32, u32, u32),
+        random: &mut Random,
+    ) -> RgbImage {
+        let (x, y, content_width, content_height) = content;
+        RgbImage::from_fn(width, height, |px, py| {
+            let noise = random.between(0..40) as u8;
+            if px >= x && px < x + content_width && py >= y && py < y + content_height {
+                Rgb([
+                    (px * 127 / width) as u8 + noise,
+                    (py * 127 / height) as u8 + noise,
+                    90 + noise,
+                ])
+            } else if random.between(0..400) == 0 {
+                Rgb([noise, noise, noise])
+            } else {
+                let pale = 255 - random.between(0..(255 - u32::from(WHITE_THRESHOLD))) as u8;
+                Rgb([pale, pale, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn every_scan_finds_the_bounds_of_a_pixel_by_pixel_reference() {
+        let mut random = Random(13);
+        for _ in 0..40 {
+            let (width, height) = (random.between(40..240), random.between(40..240));
+            let content = (
+                random.between(0..width / 3),
+                random.between(0..height / 3),
+                random.between(width / 3..2 * width / 3),
+                random.between(height / 3..2 * height / 3),
+            );
+            let image = noisy_scan(width, height, content, &mut random);
+            // Read through the buffer directly, and converted from another
+            // layout.
+            let images = [
+                DynamicImage::ImageRgb8(image.clone()),
+                DynamicImage::ImageRgba8(DynamicImage::ImageRgb8(image).to_rgba()),
+            ];
+            for image in images.iter() {
+                for scan in [BorderScan::Sparse, BorderScan::Dense, BorderScan::Full].iter() {
+                    for min_confirmations in [1, 2, 3].iter() {
+                        let border = BorderOptions {
+                            scan: *scan,
+                            min_confirmations: *min_confirmations,
+                            ..BorderOptions::default()
+                        };
+                        assert_eq!(
+                            detect_inner_image_bounds(image, &border),
+                            reference_bounds(image, &border),
+                            "{} scan confirmed by {} of {:?} in {}x{}",
+                            scan.name(),
+                            min_confirmations,
+                            content,
+                            width,
+                            height
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Bounds of part of an image, as `(x, y, width, height)`.
+    type Bounds = (u32, u32, u32, u32);
+
+    /// An image of `width` by `height` in `background`, with each of `fills`,
+    /// a color and its bounds, painted over it in turn.
+    fn painted(
+        width: u32,
+        height: u32,
+        background: Rgb<u8>,
+        fills: &[(Rgb<u8>, Bounds)],
+    ) -> DynamicImage {
+        let mut image = RgbImage::from_pixel(width, height, background);
+        for (color, (x, y, fill_width, fill_height)) in fills.iter() {
+            for py in *y..y + fill_height {
+                for px in *x..x + fill_width {
+                    image.put_pixel(px, py, *color);
+                }
+            }
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+
+    fn colored(color: BorderColor) -> BorderOptions {
+        BorderOptions {
+            color,
+            ..BorderOptions::default()
+        }
+    }
+
+    #[test]
+    fn black_borders_are_only_removed_as_black() {
+        let content = (10, 8, 80, 64);
+        let image = painted(100, 80, Rgb([0, 0, 0]), &[(CONTENT, content)]);
+        let black = colored(BorderColor::Black);
+        assert_eq!(detect_inner_image_bounds(&image, &black), Some(content));
+        assert_eq!(
+            border_crop(&image, &black, Path::new("letterbox.png")),
+            Some(content)
+        );
+        // Near black is black too, within the threshold.
+        let image = painted(100, 80, Rgb([20, 12, 24]), &[(CONTENT, content)]);
+        assert_eq!(detect_inner_image_bounds(&image, &black), Some(content));
+        // But as content to white borders.
+        assert_eq!(
+            detect_inner_image_bounds(&image, &BorderOptions::default()),
+            Some((0, 0, 100, 80))
+        );
+        assert_eq!(content_fraction(&image, &black), 5120.0 / 8000.0);
+    }
+
+    #[test]
+    fn auto_borders_are_the_color_of_the_corners() {
+        let content = (9, 21, 70, 40);
+        for background in [Rgb([240, 228, 196]), Rgb([128, 128, 128]), Rgb([0, 0, 0])].iter() {
+            let image = painted(100, 80, *background, &[(CONTENT, content)]);
+            let auto = colored(BorderColor::Auto);
+            assert_eq!(
+                detect_inner_image_bounds(&image, &auto),
+                Some(content),
+                "{:?}",
+                background
+            );
+            assert_eq!(content_fraction(&image, &auto), 2800.0 / 8000.0);
+        }
+        // A corner covered by content is outvoted by the other three.
+        let image = painted(
+            100,
+            80,
+            Rgb([128, 128, 128]),
+            &[(CONTENT, content), (WHITE, (0, 0, 4, 4))],
+        );
+        assert_eq!(
+            detect_inner_image_bounds(&image, &colored(BorderColor::Auto)),
+            Some(content)
+        );
+        // Only pixels within the threshold of the border color are border.
+        let tight = BorderOptions {
+            threshold: 250,
+            ..colored(BorderColor::Auto)
+        };
+        let image = painted(
+            100,
+            80,
+            Rgb([128, 128, 128]),
+            &[(Rgb([118, 128, 128]), (5, 5, 90, 70)), (CONTENT, content)],
+        );
+        assert_eq!(
+            detect_inner_image_bounds(&image, &tight),
+            Some((5, 5, 90, 70))
+        );
+        assert_eq!(
+            detect_inner_image_bounds(&image, &colored(BorderColor::Auto)),
+            Some(content)
+        );
+    }
+
+    #[test]
+    fn frames_inside_an_auto_border_are_removed_in_turn() {
+        // A black mat inside a white mount.
+        let mat = (8, 6, 104, 78);
+        let content = (20, 16, 80, 58);
+        let image = painted(120, 90, WHITE, &[(Rgb([0, 0, 0]), mat), (CONTENT, content)]);
+        assert_eq!(
+            border_crop(&image, &colored(BorderColor::Auto), Path::new("mat.png")),
+            Some(content)
+        );
+        // Fixed colors only remove their own frame.
+        assert_eq!(
+            border_crop(&image, &BorderOptions::default(), Path::new("mat.png")),
+            Some(mat)
+        );
+        assert_eq!(
+            border_crop(&image, &colored(BorderColor::Black), Path::new("mat.png")),
+            Some((0, 0, 120, 90))
+        );
+        let cleaned = remove_borders(image, &colored(BorderColor::Auto), Path::new("mat.png"));
+        assert_eq!(cleaned.dimensions(), (80, 58));
+    }
+
+    #[test]
+    fn border_colors_are_named_and_cached_apart() {
+        for color in [BorderColor::White, BorderColor::Black, BorderColor::Auto].iter() {
+            assert_eq!(color.name().parse::<BorderColor>().unwrap(), *color);
+        }
+        assert!("grey".parse::<BorderColor>().is_err());
+        assert_eq!(BorderOptions::default().cache_suffix(), "");
+        assert_eq!(colored(BorderColor::Black).cache_suffix(), "-black230");
+        assert_eq!(colored(BorderColor::Auto).cache_suffix(), "-auto230");
+        assert!(colored(BorderColor::Auto)
+            .config_name()
+            .ends_with("-frames3"));
+    }
+}
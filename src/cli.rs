@@ -0,0 +1,755 @@
+//! The commands of the binaries, with the options shared by every command
+//! and the logging and thread pool they set up.
+
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use structopt::clap::{App, AppSettings, ArgMatches, ArgSettings};
+use structopt::StructOpt;
+
+#[cfg(feature = "decollage")]
+pub mod decollage;
+pub mod find;
+// Built for their tests without OpenCV, as they don't use it.
+#[cfg(any(feature = "decollage", test))]
+#[cfg_attr(not(feature = "decollage"), allow(dead_code))]
+mod geometry;
+#[cfg(any(feature = "decollage", test))]
+#[cfg_attr(not(feature = "decollage"), allow(dead_code))]
+mod template;
+
+/// Configuration file read from the working directory, without `--config`.
+pub const CONFIG_FILE_NAME: &str = "fromthumb.toml";
+/// Tables of the configuration file holding the options of each command.
+const CONFIG_COMMANDS: &[&str] = &["find", "decollage", "hash", "dist", "verify"];
+
+/// Format of the log lines written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Lines of text for reading.
+    #[default]
+    Text,
+    /// One JSON object per line, with the time, level and event type, and the
+    /// fields of the event.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Whether events are logged as JSON, for `--log-format json`.
+pub static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Target of the log records of events, whose JSON the log format extends
+/// rather than quoting.
+pub const EVENT_TARGET: &str = concat!(module_path!(), "::event");
+
+// Options taken by every command, which behave the same in each. Not a doc
+// comment, as structopt would take it for the about of each command.
+#[derive(Debug, StructOpt)]
+pub struct GlobalArgs {
+    /// Only log warnings and errors.
+    #[structopt(short = "q", long = "quiet", conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log debugging details too, such as each image hashed behind a progress
+    /// bar.
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+
+    /// Format of the log on stderr: `text`, or `json` for one object per
+    /// event with its type and fields, such as the files, distance or
+    /// duration. `RUST_LOG` still overrides the level.
+    #[structopt(long = "log-format", default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Number of threads hashing and matching images. Defaults to the number
+    /// of logical cores.
+    #[structopt(long = "threads")]
+    pub threads: Option<usize>,
+
+    /// TOML file of default options, such as `max-distance = 4`, for every
+    /// command taking them at the top level and for one command in its
+    /// table, such as `[find]`. Options given here override the file. Defaults to
+    /// `fromthumb.toml` in the working directory, if there is one.
+    #[structopt(long = "config", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Print the options the command would run with, after reading the
+    /// configuration file, as the TOML of a configuration file, and exit.
+    #[structopt(long = "print-config")]
+    pub print_config: bool,
+}
+
+impl GlobalArgs {
+    /// Sets up logging at `level`, unless `--quiet` or `--verbose` choose
+    /// another.
+    pub fn init_logger(&self, level: log::LevelFilter) {
+        let level = if self.quiet {
+            log::LevelFilter::Warn
+        } else if self.verbose {
+            log::LevelFilter::Debug
+        } else {
+            level
+        };
+        init_logger(level, self.log_format);
+    }
+
+    /// Builds the global thread pool of `--threads` threads, or else of
+    /// `threads`, returning its size.
+    pub fn init_threads(&self, threads: Option<usize>) -> Result<usize> {
+        let threads = match self.threads.or(threads) {
+            Some(threads) => threads,
+            None => std::thread::available_parallelism()?.get(),
+        };
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+        Ok(threads)
+    }
+}
+
+/// Sets up logging at the given level, which `RUST_LOG` overrides.
+fn init_logger(level: log::LevelFilter, format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(level.to_string()),
+    );
+    if format == LogFormat::Json {
+        JSON_LOGS.store(true, Ordering::Relaxed);
+        builder.format(|buf, record| {
+            let time = buf.timestamp();
+            let level = record.level().to_string().to_lowercase();
+            if record.target() == EVENT_TARGET {
+                // The message is the object of the event, which gains the
+                // time and level.
+                let event = record.args().to_string();
+                writeln!(
+                    buf,
+                    "{{\"time\":\"{}\",\"level\":\"{}\",{}",
+                    time,
+                    level,
+                    event.strip_prefix('{').unwrap_or(&event)
+                )
+            } else {
+                let line = serde_json::json!({
+                    "time": time.to_string(),
+                    "level": level,
+                    "event": "message",
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            }
+        });
+    }
+    builder.init();
+}
+
+/// An option of a command, by the names it is given under in the arguments
+/// and the configuration file.
+#[derive(Debug, Clone)]
+struct CommandOption {
+    /// Name of the option in the matches of the command.
+    id: String,
+    long: String,
+    /// Other long names of the option.
+    aliases: Vec<String>,
+    short: Option<char>,
+    takes_value: bool,
+    /// Whether the option takes a list of values.
+    multiple: bool,
+    /// Names of the options it can't be given with.
+    conflicts: Vec<String>,
+}
+
+impl CommandOption {
+    /// Whether the option is named `name`, by its long name or an alias.
+    fn is_named(&self, name: &str) -> bool {
+        self.long == name || self.aliases.iter().any(|alias| alias == name)
+    }
+}
+
+/// Returns the options of the command `app` defines, leaving out hidden
+/// ones. Clap has no public way to list the arguments of an app, so they are
+/// read from its parser.
+fn command_options(app: &App) -> Vec<CommandOption> {
+    let flags = app.p.flags.iter().map(|flag| (&flag.b, &flag.s, false));
+    let opts = app.p.opts.iter().map(|opt| (&opt.b, &opt.s, true));
+    flags
+        .chain(opts)
+        .filter(|(base, _, _)| !base.is_set(ArgSettings::Hidden))
+        .filter_map(|(base, switched, takes_value)| {
+            Some(CommandOption {
+                id: base.name.to_owned(),
+                long: switched.long?.to_owned(),
+                aliases: switched
+                    .aliases
+                    .iter()
+                    .flatten()
+                    .map(|(alias, _)| (*alias).to_owned())
+                    .collect(),
+                short: switched.short,
+                takes_value,
+                multiple: base.is_set(ArgSettings::Multiple),
+                conflicts: base
+                    .blacklist
+                    .iter()
+                    .flatten()
+                    .map(|name| (*name).to_owned())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Returns the app of `command`, the subcommand of `app` of that name, or
+/// `app` itself for the binaries of a single command.
+fn command_app<'a, 'b>(app: &'a App<'a, 'b>, command: &str) -> &'a App<'a, 'b> {
+    app.p
+        .subcommands
+        .iter()
+        .find(|subcommand| subcommand.p.meta.name == command)
+        .unwrap_or(app)
+}
+
+/// Returns the options of each command with a table in the configuration
+/// file, other than `command`, for telling options of another command at the
+/// top level of the file from unknown ones.
+fn other_command_options(command: &str) -> Vec<CommandOption> {
+    #[cfg_attr(not(feature = "decollage"), allow(unused_mut, clippy::useless_vec))]
+    let mut apps = vec![
+        ("find", find::FindOpt::clap()),
+        ("hash", find::HashOpt::clap()),
+        ("dist", find::DistOpt::clap()),
+        ("verify", find::VerifyOpt::clap()),
+    ];
+    #[cfg(feature = "decollage")]
+    apps.push(("decollage", decollage::DecollageOpt::clap()));
+    apps.iter()
+        .filter(|(name, _)| *name != command)
+        .flat_map(|(_, app)| command_options(app))
+        .collect()
+}
+
+/// The configuration file, as options for every command that takes them at
+/// the top level, and options for one command in its table.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    options: toml::value::Table,
+    #[serde(default, skip_serializing_if = "toml::value::Table::is_empty")]
+    find: toml::value::Table,
+    #[serde(default, skip_serializing_if = "toml::value::Table::is_empty")]
+    decollage: toml::value::Table,
+    #[serde(default, skip_serializing_if = "toml::value::Table::is_empty")]
+    hash: toml::value::Table,
+    #[serde(default, skip_serializing_if = "toml::value::Table::is_empty")]
+    dist: toml::value::Table,
+    #[serde(default, skip_serializing_if = "toml::value::Table::is_empty")]
+    verify: toml::value::Table,
+}
+
+impl ConfigFile {
+    /// Returns the table of the options of `command`.
+    fn command_mut(&mut self, command: &str) -> Option<&mut toml::value::Table> {
+        match command {
+            "find" => Some(&mut self.find),
+            "decollage" => Some(&mut self.decollage),
+            "hash" => Some(&mut self.hash),
+            "dist" => Some(&mut self.dist),
+            "verify" => Some(&mut self.verify),
+            _ => None,
+        }
+    }
+}
+
+/// Options read from a configuration file, as arguments of a command.
+#[derive(Debug)]
+struct Config {
+    path: PathBuf,
+    options: Vec<ConfigOption>,
+}
+
+/// An option read from a configuration file.
+#[derive(Debug)]
+struct ConfigOption {
+    option: CommandOption,
+    /// Arguments giving the option, such as `--quiet` or `--max-distance=4`.
+    args: Vec<OsString>,
+}
+
+impl Config {
+    /// Reads the options of `command`, which takes `options`, from the
+    /// configuration file at `path`. Options at the top level that only
+    /// other commands take are left out, and any other unknown option is an
+    /// error.
+    fn read(path: &Path, command: &str, options: &[CommandOption]) -> Result<Self> {
+        let text = read_to_string(path).map_err(|error| {
+            anyhow!(
+                "Failed to read config {}: {}",
+                path.to_string_lossy(),
+                error
+            )
+        })?;
+        let mut file: ConfigFile = toml::from_str(&text).map_err(|error| {
+            anyhow!(
+                "Failed to parse config {}: {}",
+                path.to_string_lossy(),
+                error
+            )
+        })?;
+        let unknown = |key: &str| {
+            anyhow!(
+                "Unknown option in config {}: {}",
+                path.to_string_lossy(),
+                key
+            )
+        };
+        let mut other_options = None;
+        let mut top_level = Vec::new();
+        for (key, value) in file.options.iter() {
+            if let toml::Value::Table(_) = value {
+                return Err(anyhow!(
+                    "Unknown command in config {}: {}",
+                    path.to_string_lossy(),
+                    key
+                ));
+            }
+            match options.iter().find(|option| option.is_named(key)) {
+                Some(option) => top_level.push(ConfigOption {
+                    option: option.clone(),
+                    args: option_args(path, key, value)?,
+                }),
+                None => {
+                    let other_options =
+                        other_options.get_or_insert_with(|| other_command_options(command));
+                    if !other_options.iter().any(|option| option.is_named(key)) {
+                        return Err(unknown(key));
+                    }
+                }
+            }
+        }
+        let mut command_options = Vec::new();
+        for (key, value) in file.command_mut(command).into_iter().flatten() {
+            let option = options
+                .iter()
+                .find(|option| option.is_named(key))
+                .ok_or_else(|| unknown(key))?;
+            command_options.push(ConfigOption {
+                option: option.clone(),
+                args: option_args(path, key, value)?,
+            });
+        }
+        // The options of the command replace those of every command.
+        top_level.retain(|top| {
+            !command_options
+                .iter()
+                .any(|command_option| command_option.option.id == top.option.id)
+        });
+        top_level.extend(command_options);
+        Ok(Config {
+            path: path.to_path_buf(),
+            options: top_level,
+        })
+    }
+}
+
+/// Returns the arguments giving `value` to the option `name`.
+fn option_args(path: &Path, name: &str, value: &toml::Value) -> Result<Vec<OsString>> {
+    let values = match value {
+        toml::Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+    let mut args = Vec::with_capacity(values.len());
+    for value in values {
+        let value = match value {
+            toml::Value::Boolean(true) => {
+                args.push(format!("--{}", name).into());
+                continue;
+            }
+            // A flag can only be set, and is unset with `--no-<name>`.
+            toml::Value::Boolean(false) => {
+                return Err(anyhow!(
+                    "Invalid value in config {} for {}: a flag can only be set to true, or unset with --no-{} in the arguments",
+                    path.to_string_lossy(),
+                    name,
+                    name
+                ))
+            }
+            toml::Value::String(value) => value.clone(),
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            toml::Value::Datetime(value) => value.to_string(),
+            toml::Value::Array(_) | toml::Value::Table(_) => {
+                return Err(anyhow!(
+                    "Invalid value in config {} for {}: expected a value or a list of values",
+                    path.to_string_lossy(),
+                    name
+                ))
+            }
+        };
+        args.push(format!("--{}={}", name, value).into());
+    }
+    Ok(args)
+}
+
+/// Returns the value of `--config` in `args`.
+fn config_arg(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Whether `option` of a command taking `options` is given in `args`, by
+/// its long name, an alias or its short name, alone or among other short
+/// flags, as in `-qv`.
+fn has_option(args: &[OsString], option: &CommandOption, options: &[CommandOption]) -> bool {
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| {
+            if let Some(long) = arg.strip_prefix("--") {
+                return option.is_named(long.split('=').next().unwrap_or(long));
+            }
+            let shorts = match arg.strip_prefix('-') {
+                Some(shorts) => shorts,
+                None => return false,
+            };
+            for short in shorts.chars() {
+                if Some(short) == option.short {
+                    return true;
+                }
+                // The rest of the argument is the value of the option.
+                let takes_value = options
+                    .iter()
+                    .any(|other| other.short == Some(short) && other.takes_value);
+                if takes_value {
+                    break;
+                }
+            }
+            false
+        })
+}
+
+/// Returns the options `matches` were parsed with, from any of `options`
+/// given or with a default, as the table of a configuration file. The
+/// options for the configuration file itself are left out.
+fn effective_options(options: &[CommandOption], matches: &ArgMatches) -> toml::value::Table {
+    let mut table = toml::value::Table::new();
+    for option in options.iter() {
+        if option.long == "config" || option.long == "print-config" {
+            continue;
+        }
+        if !option.takes_value {
+            if matches.is_present(&option.id) {
+                table.insert(option.long.clone(), toml::Value::Boolean(true));
+            }
+            continue;
+        }
+        let values: Vec<toml::Value> = match matches.values_of_os(&option.id) {
+            Some(values) => values
+                .map(|value| config_value(&value.to_string_lossy()))
+                .collect(),
+            None => continue,
+        };
+        let value = match (option.multiple, values.len()) {
+            (false, 1) => values.into_iter().next().unwrap(),
+            _ => toml::Value::Array(values),
+        };
+        table.insert(option.long.clone(), value);
+    }
+    table
+}
+
+/// Returns `value` of an option as written in a configuration file: as a
+/// number if it is one, and otherwise as a string.
+fn config_value(value: &str) -> toml::Value {
+    if let Some(integer) = value.parse::<i64>().ok().filter(|n| n.to_string() == value) {
+        return toml::Value::Integer(integer);
+    }
+    if let Some(float) = value.parse::<f64>().ok().filter(|n| n.to_string() == value) {
+        return toml::Value::Float(float);
+    }
+    toml::Value::String(value.to_owned())
+}
+
+/// Returns the configuration file giving `options` to `command`, for
+/// `--print-config`, noting the file they were read with.
+fn config_text(
+    config: Option<&Config>,
+    command: &str,
+    options: toml::value::Table,
+) -> Result<String> {
+    let mut text = match config {
+        Some(config) => format!("# config: {}\n", config.path.to_string_lossy()),
+        None => "# config: none\n".to_owned(),
+    };
+    let mut file = ConfigFile::default();
+    match file.command_mut(command) {
+        Some(table) => *table = options,
+        None => file.options = options,
+    }
+    text.push_str(&toml::to_string(&file)?);
+    Ok(text)
+}
+
+/// Parses the arguments of the program, giving the options of `command` in
+/// the configuration file first, where the command's own arguments start at
+/// `start`. Options given after override those earlier, so the arguments
+/// override the configuration file. Options in the file are left out when
+/// given in the arguments, so that lists of values are replaced rather than
+/// added to, and flags set in the file are unset by `--no-<name>` in the
+/// arguments. With `--print-config`, the options are printed and the
+/// program exits, as it does for `--help`.
+pub fn parse<T: StructOpt>(args: Vec<OsString>, start: usize, command: &str) -> Result<T> {
+    let start = start.min(args.len());
+    let app = T::clap().global_setting(AppSettings::AllArgsOverrideSelf);
+    let options = command_options(command_app(&app, command));
+    let path = match config_arg(&args[start..]) {
+        _ if !CONFIG_COMMANDS.contains(&command) => None,
+        Some(path) => Some(path),
+        None => Some(PathBuf::from(CONFIG_FILE_NAME)).filter(|path| path.is_file()),
+    };
+    let config = match path {
+        Some(path) => Some(Config::read(&path, command, &options)?),
+        None => None,
+    };
+    let mut config_args = Vec::new();
+    // Arguments unsetting flags set in the file, which are left out of both.
+    let mut unset = Vec::new();
+    if let Some(config) = &config {
+        // Recorded for `--print-config`, when the file was found rather than
+        // given.
+        let mut arg = OsString::from("--config=");
+        arg.push(&config.path);
+        config_args.push(arg);
+        for config_option in config.options.iter() {
+            let option = &config_option.option;
+            let unset_flag = format!("--no-{}", option.long);
+            if !option.takes_value && args[start..].iter().any(|arg| *arg == *unset_flag) {
+                unset.push(OsString::from(unset_flag));
+                continue;
+            }
+            // Options given in the arguments, or conflicting with those that
+            // are, such as `quiet` with `-v`, are overridden.
+            let overridden = options.iter().any(|given| {
+                (given.id == option.id
+                    || given.conflicts.contains(&option.id)
+                    || option.conflicts.contains(&given.id))
+                    && has_option(&args[start..], given, &options)
+            });
+            if overridden {
+                continue;
+            }
+            config_args.extend(config_option.args.iter().cloned());
+        }
+    }
+    let mut positional = false;
+    let command_args = args[start..].iter().filter(|arg| {
+        positional |= *arg == "--";
+        positional || !unset.contains(arg)
+    });
+    let args: Vec<OsString> = args[..start]
+        .iter()
+        .chain(config_args.iter())
+        .chain(command_args)
+        .cloned()
+        .collect();
+    let matches = app
+        .get_matches_from_safe(args)
+        .unwrap_or_else(|error| error.exit());
+    let command_matches = matches.subcommand_matches(command).unwrap_or(&matches);
+    if command_matches.is_present("print-config") {
+        let options = effective_options(&options, command_matches);
+        print!("{}", config_text(config.as_ref(), command, options)?);
+        std::process::exit(0);
+    }
+    Ok(T::from_clap(&matches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, StructOpt)]
+    struct Options {
+        #[structopt(long = "config", parse(from_os_str))]
+        _config: Option<PathBuf>,
+        #[structopt(long = "print-config")]
+        _print_config: bool,
+        #[structopt(short = "d", long = "max-distance")]
+        max_distance: Option<u32>,
+        #[structopt(long = "fullsize", parse(from_os_str))]
+        fullsize: Vec<PathBuf>,
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        #[structopt(short = "q", long = "quiet", conflicts_with = "verbose")]
+        quiet: bool,
+        #[structopt(short = "v", long = "verbose")]
+        verbose: bool,
+        rest: Vec<String>,
+    }
+
+    /// The options of a command without `max-distance`.
+    #[derive(Debug, StructOpt)]
+    struct HashOptions {
+        #[structopt(long = "config", parse(from_os_str))]
+        _config: Option<PathBuf>,
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    }
+
+    /// Parses `args` of `command` with the configuration file `config`.
+    fn parse_command<T: StructOpt>(command: &str, config: &str, args: &[&str]) -> Result<T> {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(CONFIG_FILE_NAME);
+        std::fs::write(&path, config).unwrap();
+        let mut command_args = vec![command.into(), "--config".into(), path.into_os_string()];
+        command_args.extend(args.iter().map(OsString::from));
+        parse(command_args, 1, command)
+    }
+
+    /// Parses `args` of the `find` command with the configuration file
+    /// `config`.
+    fn parse_with(config: &str, args: &[&str]) -> Result<Options> {
+        parse_command("find", config, args)
+    }
+
+    #[test]
+    fn arguments_override_the_command_table_over_the_top_level() {
+        let config = "max-distance = 1\n[find]\nmax-distance = 2\n[hash]\nmax-distance = 3\n";
+        assert_eq!(parse_with(config, &[]).unwrap().max_distance, Some(2));
+        let options = parse_with(config, &["--max-distance", "4"]).unwrap();
+        assert_eq!(options.max_distance, Some(4));
+        let options = parse_with("max-distance = 1\n[hash]\nmax-distance = 3\n", &[]).unwrap();
+        assert_eq!(options.max_distance, Some(1));
+    }
+
+    #[test]
+    fn lists_in_the_arguments_replace_those_in_the_file() {
+        let config = "fullsize = [\"a\", \"b\"]\n";
+        let options = parse_with(config, &[]).unwrap();
+        assert_eq!(
+            options.fullsize,
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        let options = parse_with(config, &["--fullsize", "c"]).unwrap();
+        assert_eq!(options.fullsize, vec![PathBuf::from("c")]);
+        let config = "fullsize = [\"a\", \"b\"]\n[find]\nfullsize = [\"c\"]\n";
+        let options = parse_with(config, &[]).unwrap();
+        assert_eq!(options.fullsize, vec![PathBuf::from("c")]);
+        // A single value in the file is still replaced.
+        let options = parse_with("fullsize = \"a\"\n", &["--fullsize=c"]).unwrap();
+        assert_eq!(options.fullsize, vec![PathBuf::from("c")]);
+    }
+
+    #[test]
+    fn flags_set_in_the_file_are_unset_by_the_arguments() {
+        let config = "[find]\ndry-run = true\n";
+        assert!(parse_with(config, &[]).unwrap().dry_run);
+        let options = parse_with(config, &["--no-dry-run", "--", "--no-dry-run"]).unwrap();
+        assert!(!options.dry_run);
+        assert_eq!(options.rest, vec!["--no-dry-run".to_owned()]);
+        assert!(!parse_with("", &[]).unwrap().dry_run);
+    }
+
+    #[test]
+    fn flags_cannot_be_set_to_false_in_the_file() {
+        let error = parse_with("dry-run = false\n", &[]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("for dry-run: a flag can only be set to true, or unset with --no-dry-run in the arguments"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn unknown_options_and_commands_in_the_file_are_errors() {
+        let error = parse_with("[find]\nmax-distanse = 2\n", &[]).unwrap_err();
+        assert!(error.to_string().ends_with(": max-distanse"), "{}", error);
+        assert!(error.to_string().starts_with("Unknown option in config"));
+        let error = parse_with("[fnid]\nmax-distance = 2\n", &[]).unwrap_err();
+        assert!(error.to_string().starts_with("Unknown command in config"));
+    }
+
+    #[test]
+    fn top_level_options_only_apply_to_the_commands_taking_them() {
+        let config = "max-distance = 4\ndry-run = true\n";
+        assert_eq!(parse_with(config, &[]).unwrap().max_distance, Some(4));
+        let options: HashOptions = parse_command("hash", config, &[]).unwrap();
+        assert!(options.dry_run);
+        // No command takes it, so it is still an error.
+        let error = parse_command::<HashOptions>("hash", "max-distanse = 4\n", &[]).unwrap_err();
+        assert!(error.to_string().ends_with(": max-distanse"), "{}", error);
+        // Only the command's own table is checked against its options.
+        let error =
+            parse_command::<HashOptions>("hash", "[hash]\nmax-distance = 4\n", &[]).unwrap_err();
+        assert!(error.to_string().ends_with(": max-distance"), "{}", error);
+    }
+
+    #[test]
+    fn options_given_by_short_name_override_the_file() {
+        let config = "max-distance = 1\nquiet = true\n";
+        assert_eq!(
+            parse_with(config, &["-d", "3"]).unwrap().max_distance,
+            Some(3)
+        );
+        assert_eq!(parse_with(config, &["-d3"]).unwrap().max_distance, Some(3));
+        let options = parse_with(config, &["-qd", "3"]).unwrap();
+        assert_eq!(options.max_distance, Some(3));
+        // Flags conflicting with those given are left out.
+        let options = parse_with(config, &["-v"]).unwrap();
+        assert!(options.verbose && !options.quiet);
+    }
+
+    #[test]
+    fn printed_configs_give_the_options_parsed() {
+        let matches = Options::clap().get_matches_from(vec![
+            "find",
+            "--config=fromthumb.toml",
+            "-d",
+            "4",
+            "--fullsize=a",
+            "--fullsize=2019",
+            "-q",
+            "x",
+        ]);
+        let options = effective_options(&command_options(&Options::clap()), &matches);
+        let text = config_text(None, "find", options).unwrap();
+        assert_eq!(
+            text,
+            "# config: none\n[find]\nfullsize = [\"a\", 2019]\nmax-distance = 4\nquiet = true\n"
+        );
+        // Which parse back to the same options.
+        let options = parse_with(text.trim_start_matches("# config: none\n"), &[]).unwrap();
+        assert_eq!(options.max_distance, Some(4));
+        assert_eq!(
+            options.fullsize,
+            vec![PathBuf::from("a"), PathBuf::from("2019")]
+        );
+        assert!(options.quiet);
+    }
+}
@@ -0,0 +1,1147 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use opencv::{
+    core::{
+        self, Mat, MatTrait, Point, Point2f, Rect_, RotatedRectTrait, Scalar, Size, Size2f, Vector,
+    },
+    imgcodecs, imgproc,
+    types::VectorOfMat,
+};
+use structopt::StructOpt;
+
+use super::geometry::{
+    containing_rects, merge_rects, oversized, pad, upright, MinArea, Order, Rect, Rejection,
+    ShapeLimits,
+};
+use super::template::{Fields, NameTemplate};
+use crate::cli;
+
+/// Smallest rotation in degrees that `--deskew` straightens, below which
+/// subimages are cut out along their bounding box.
+const DESKEW_MIN_ANGLE: f32 = 0.5;
+
+/// How the grayscale page is split into background and subimages.
+#[derive(Debug, Clone, Copy)]
+enum Threshold {
+    /// Pixels brighter than this, from 0 to 255, are background.
+    Fixed(f64),
+    /// Pick the threshold for each page with Otsu's method, for pages whose
+    /// background is not white, such as yellowed album pages.
+    Auto,
+}
+
+impl FromStr for Threshold {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Threshold::Auto),
+            _ => match s.parse::<u8>() {
+                Ok(threshold) => Ok(Threshold::Fixed(f64::from(threshold))),
+                Err(_) => Err(anyhow!("Unknown threshold: {}", s)),
+            },
+        }
+    }
+}
+
+/// What was pulled out of a page.
+#[derive(Debug, Default)]
+struct PageSummary {
+    /// Number of subimages extracted.
+    extracted: usize,
+    /// Number of contours discarded for covering too much of the page, as when
+    /// touching subimages merge.
+    oversized: usize,
+    /// Numbers of subimages discarded for their shape, by the reason.
+    narrow: usize,
+    short: usize,
+    elongated: usize,
+}
+
+impl PageSummary {
+    /// Whether the page needs splitting by hand, as every subimage found
+    /// covered too much of it.
+    fn needs_splitting(&self) -> bool {
+        self.extracted == 0 && self.oversized > 0
+    }
+
+    /// Counts a subimage discarded for its shape.
+    fn reject(&mut self, rejection: Rejection) {
+        match rejection {
+            Rejection::Narrow => self.narrow += 1,
+            Rejection::Short => self.short += 1,
+            Rejection::Elongated => self.elongated += 1,
+        }
+    }
+}
+
+/// How subimages are pulled out of each page.
+#[derive(Debug)]
+struct PageOptions<'a> {
+    output_directory: &'a Path,
+    /// Directory to write the intermediate images and parameters of each page
+    /// to, if any.
+    debug_directory: Option<&'a Path>,
+    /// Center weight of the kernel sharpening the blurred page, if it is.
+    sharpen: Option<f32>,
+    threshold: Threshold,
+    min_area: MinArea,
+    max_area_frac: f64,
+    /// Side of the square kernel of the morphology operations.
+    morph_kernel: i32,
+    /// Times the page is opened, or 0 not to.
+    morph_open_iterations: i32,
+    /// Times the page is closed, or 0 not to.
+    morph_close_iterations: i32,
+    /// Straighten subimages that are rotated on the page.
+    deskew: bool,
+    /// Pixels added around each subimage, or taken off for negative padding.
+    padding: i32,
+    /// Gap in pixels below which subimages are merged, if they are.
+    merge_distance: Option<i32>,
+    /// Fraction of a subimage inside a larger one at which it is dropped.
+    contained_frac: f64,
+    /// Order the subimages of each page are numbered in.
+    order: Order,
+    /// Template of the file names of subimages.
+    name_template: NameTemplate,
+    /// Limits on the shape of the subimages kept.
+    shape: ShapeLimits,
+}
+
+/// Cuts the rectangle of `size` centered on `center` out of `image`, rotated
+/// by `angle` degrees so that it comes out upright. Only the patch is
+/// transformed, rather than the whole page.
+fn extract_rotated(image: &Mat, center: Point2f, angle: f32, size: Size2f) -> Result<Mat> {
+    let mut matrix = imgproc::get_rotation_matrix_2d(center, f64::from(angle), 1.0)?;
+    // The rotation keeps the center in place, so is followed by moving it to
+    // the center of the patch.
+    *matrix.at_2d_mut::<f64>(0, 2)? += f64::from(size.width) / 2.0 - f64::from(center.x);
+    *matrix.at_2d_mut::<f64>(1, 2)? += f64::from(size.height) / 2.0 - f64::from(center.y);
+    let mut patch = Mat::default()?;
+    imgproc::warp_affine(
+        image,
+        &mut patch,
+        &matrix,
+        Size::new(size.width.round() as i32, size.height.round() as i32),
+        imgproc::INTER_LINEAR,
+        core::BORDER_REPLICATE,
+        Scalar::default(),
+    )?;
+    Ok(patch)
+}
+
+/// Applies the morphology operation `op` to `image` `iterations` times, or
+/// returns it as it is for none.
+fn morphology(image: Mat, op: i32, kernel: &Mat, iterations: i32) -> Result<Mat> {
+    if iterations == 0 {
+        return Ok(image);
+    }
+    let mut result = Mat::default()?;
+    imgproc::morphology_ex(
+        &image,
+        &mut result,
+        op,
+        kernel,
+        Point::new(-1, -1),
+        iterations,
+        core::BORDER_CONSTANT,
+        imgproc::morphology_default_border_value()?,
+    )?;
+    Ok(result)
+}
+
+fn process_collage_page(path: &Path, options: &PageOptions) -> Result<PageSummary> {
+    info!("Processing collage page: {}", path.to_string_lossy());
+    let path_stem = path.file_stem().expect("No file stem.").to_string_lossy();
+
+    let mut img = imgcodecs::imread(
+        path.to_str().expect("OpenCV could not open input path."),
+        opencv::imgcodecs::IMREAD_COLOR,
+    )?;
+
+    let mut grey = Mat::default()?;
+    imgproc::cvt_color(&img, &mut grey, imgproc::COLOR_BGR2GRAY, 0)?;
+    let mut blur = Mat::default()?;
+    imgproc::median_blur(&grey, &mut blur, 5)?;
+    // Sharpening keeps the light edges of soft photos from breaking them up
+    // into several contours.
+    let sharpened = match options.sharpen {
+        Some(amount) => {
+            let kernel = Mat::from_slice_2d(&[
+                [-1.0, -1.0, -1.0],
+                [-1.0, amount, -1.0],
+                [-1.0, -1.0, -1.0],
+            ])?;
+            let mut sharpened = Mat::default()?;
+            imgproc::filter_2d(
+                &blur,
+                &mut sharpened,
+                -1,
+                &kernel,
+                Point::new(-1, -1),
+                0.0,
+                core::BORDER_DEFAULT,
+            )?;
+            Some(sharpened)
+        }
+        None => None,
+    };
+    let mut threshold = Mat::default()?;
+    let (level, typ) = match options.threshold {
+        Threshold::Fixed(level) => (level, imgproc::THRESH_BINARY_INV),
+        // The level is ignored, and the one picked returned.
+        Threshold::Auto => (0.0, imgproc::THRESH_BINARY_INV | imgproc::THRESH_OTSU),
+    };
+    let level = imgproc::threshold(
+        sharpened.as_ref().unwrap_or(&blur),
+        &mut threshold,
+        level,
+        255.0,
+        typ,
+    )?;
+    info!("Thresholding at {}", level);
+    let kernel = imgproc::get_structuring_element(
+        imgproc::MORPH_RECT,
+        Size::new(options.morph_kernel, options.morph_kernel),
+        Point::new(-1, -1),
+    )?;
+    debug!(
+        "Opening {} times and closing {} times with a {}x{} kernel",
+        options.morph_open_iterations,
+        options.morph_close_iterations,
+        options.morph_kernel,
+        options.morph_kernel
+    );
+    let open = morphology(
+        threshold,
+        imgproc::MORPH_OPEN,
+        &kernel,
+        options.morph_open_iterations,
+    )?;
+    let close = morphology(
+        open,
+        imgproc::MORPH_CLOSE,
+        &kernel,
+        options.morph_close_iterations,
+    )?;
+
+    let mut contours = VectorOfMat::default();
+    imgproc::find_contours(
+        &close,
+        &mut contours,
+        imgproc::RETR_EXTERNAL,
+        imgproc::CHAIN_APPROX_SIMPLE,
+        Point::default(),
+    )?;
+
+    let page_area = f64::from(img.rows()) * f64::from(img.cols());
+    let min_pixels = options.min_area.pixels(page_area);
+    let mut summary = PageSummary::default();
+    let mut candidates = Vec::new();
+    for (index, contour) in contours.iter().enumerate() {
+        let area = imgproc::contour_area(&contour, false)?;
+        if area <= min_pixels {
+            debug!(
+                "Discarding subimage with area {}, not above the {}",
+                area,
+                options.min_area.describe(page_area)
+            );
+            continue;
+        }
+        // Dropped before any others, so they never contain those kept.
+        let bounds = Rect::from(imgproc::bounding_rect(&contour)?);
+        if oversized(bounds, page_area, options.max_area_frac) {
+            summary.oversized += 1;
+            continue;
+        }
+        candidates.push((bounds, index));
+    }
+    // Subimages inside others are dropped, as are all but the first of
+    // duplicates.
+    let containers = containing_rects(
+        &candidates
+            .iter()
+            .map(|(bounds, _)| *bounds)
+            .collect::<Vec<_>>(),
+        options.contained_frac,
+    );
+    let mut kept = Vec::with_capacity(candidates.len());
+    for (index, ((bounds, contour), container)) in
+        candidates.into_iter().zip(containers).enumerate()
+    {
+        match container {
+            Some(container) => debug!(
+                "Discarding subimage {} of {}x{} pixels at {},{}, inside subimage {}",
+                index, bounds.width, bounds.height, bounds.x, bounds.y, container
+            ),
+            None => kept.push((bounds, contour)),
+        }
+    }
+    let candidates = kept;
+    let found: Vec<Rect> = candidates.iter().map(|(bounds, _)| *bounds).collect();
+    let merged = match options.merge_distance {
+        Some(distance) => {
+            let merged = merge_rects(&found, distance);
+            if merged.len() < found.len() {
+                info!("Merged {} subimages into {}", found.len(), merged.len());
+            }
+            merged
+        }
+        None => found
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| (*bounds, vec![index]))
+            .collect(),
+    };
+
+    let mut qualifying = Vec::new();
+    for (bounds, members) in merged.into_iter() {
+        if members.len() > 1 && oversized(bounds, page_area, options.max_area_frac) {
+            summary.oversized += 1;
+            continue;
+        }
+        // Merged subimages are cut out along their bounding box, as the
+        // rotations of their parts may differ.
+        let rect = match members.as_slice() {
+            [member] if options.deskew => Some(imgproc::min_area_rect(
+                &contours.get(candidates[*member].1)?,
+            )?),
+            _ => None,
+        };
+        // Shapes are checked once merged, as the parts of a photo may be
+        // thin when it is not, and on the size a subimage is written at, so
+        // upright if it is straightened.
+        let (width, height) = match &rect {
+            Some(rect) => {
+                let size = rect.size();
+                let (angle, (width, height)) = upright(rect.angle(), (size.width, size.height));
+                if angle.abs() > DESKEW_MIN_ANGLE {
+                    (width.round() as i32, height.round() as i32)
+                } else {
+                    (bounds.width, bounds.height)
+                }
+            }
+            None => (bounds.width, bounds.height),
+        };
+        if let Some(rejection) = options.shape.reject(width, height) {
+            debug!(
+                "Discarding subimage of {}x{} pixels at {},{}, {}",
+                width,
+                height,
+                bounds.x,
+                bounds.y,
+                rejection.describe()
+            );
+            summary.reject(rejection);
+            continue;
+        }
+        match pad(bounds, options.padding, img.cols(), img.rows()) {
+            Some(padded) => qualifying.push((bounds, (members, padded, rect))),
+            None => debug!(
+                "Discarding subimage of {}x{} pixels, shrunk away by the --padding of {}",
+                bounds.width, bounds.height, options.padding
+            ),
+        }
+    }
+
+    let mut patches = Vec::new();
+    for (_, (members, padded, rect)) in options.order.arrange(qualifying) {
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = padded;
+        let rotated = match rect {
+            Some(mut rect) => {
+                // Rotated patches are not clamped, as their corners may lie
+                // off the page anyway. The edge of the page is repeated there.
+                let size = rect.size();
+                let padding = 2.0 * options.padding as f32;
+                rect.set_size(Size2f::new(
+                    (size.width + padding).max(1.0),
+                    (size.height + padding).max(1.0),
+                ));
+                let size = rect.size();
+                let (angle, (width, height)) = upright(rect.angle(), (size.width, size.height));
+                Some((rect, angle, Size2f::new(width, height)))
+                    .filter(|_| angle.abs() > DESKEW_MIN_ANGLE)
+            }
+            None => None,
+        };
+        let patch = match &rotated {
+            Some((rect, angle, size)) => extract_rotated(&img, rect.center(), *angle, *size)?,
+            None => {
+                let cx: f32 = x as f32 + width as f32 / 2.0;
+                let cy: f32 = y as f32 + height as f32 / 2.0;
+                let mut patch = Mat::default()?;
+                imgproc::get_rect_sub_pix(
+                    &img,
+                    Size::new(width, height),
+                    Point2f::new(cx, cy),
+                    &mut patch,
+                    -1,
+                )?;
+                patch
+            }
+        };
+        let angle = rotated.as_ref().map_or(0.0, |(_, angle, _)| *angle);
+        let file_name = options.name_template.render(&Fields {
+            stem: &path_stem,
+            index: summary.extracted,
+            x,
+            y,
+            width: patch.cols(),
+            height: patch.rows(),
+            ext: "png",
+        });
+        let output_path = options.output_directory.join(&file_name);
+        if rotated.is_none() {
+            info!("Writing subimage: {}", output_path.to_string_lossy());
+        } else {
+            info!(
+                "Writing subimage: {} (straightened by {:.1} degrees)",
+                output_path.to_string_lossy(),
+                angle
+            );
+        }
+        imgcodecs::imwrite(&output_path.to_string_lossy(), &patch, &Vector::default())?;
+        patches.push(serde_json::json!({
+            "file": file_name,
+            "angle": angle,
+            "merged": members.len(),
+        }));
+        summary.extracted += 1;
+
+        // green
+        let color = Scalar::new(0.0, 255.0, 0.0, 255.0);
+        match &rotated {
+            Some((rect, _, _)) => {
+                let mut corners = [Point2f::default(); 4];
+                rect.points(&mut corners)?;
+                for (index, corner) in corners.iter().enumerate() {
+                    let next = corners[(index + 1) % corners.len()];
+                    imgproc::line(
+                        &mut img,
+                        Point::new(corner.x.round() as i32, corner.y.round() as i32),
+                        Point::new(next.x.round() as i32, next.y.round() as i32),
+                        color,
+                        10,
+                        imgproc::LINE_8,
+                        0,
+                    )?;
+                }
+            }
+            None => imgproc::rectangle(
+                &mut img,
+                Rect_::from_points(Point::new(x, y), Point::new(x + width, y + height)),
+                color,
+                10,
+                imgproc::LINE_8,
+                0,
+            )?,
+        }
+    }
+    // The subimages merged are drawn inside the rectangles they were merged
+    // into, once every patch is cut out.
+    if options.merge_distance.is_some() {
+        for bounds in found.iter() {
+            imgproc::rectangle(
+                &mut img,
+                Rect_::from(*bounds),
+                // blue
+                Scalar::new(255.0, 0.0, 0.0, 255.0),
+                4,
+                imgproc::LINE_8,
+                0,
+            )?;
+        }
+    }
+
+    if summary.narrow + summary.short + summary.elongated > 0 {
+        info!(
+            "Discarded {} subimages narrower than --min-width, {} shorter than --min-height and {} longer than --max-aspect: {}",
+            summary.narrow,
+            summary.short,
+            summary.elongated,
+            path.to_string_lossy()
+        );
+    }
+    if summary.needs_splitting() {
+        warn!(
+            "Page needs manual splitting, as every subimage found covers too much of it: {}",
+            path.to_string_lossy()
+        );
+    }
+
+    if let Some(debug_directory) = options.debug_directory {
+        let parameters = serde_json::json!({
+            "sharpen": options.sharpen,
+            "threshold": level,
+            "min_area": min_pixels,
+            "max_area_frac": options.max_area_frac,
+            "morph_kernel": options.morph_kernel,
+            "morph_open_iterations": options.morph_open_iterations,
+            "morph_close_iterations": options.morph_close_iterations,
+            "deskew": options.deskew,
+            "padding": options.padding,
+            "merge_distance": options.merge_distance,
+            "contained_frac": options.contained_frac,
+            "order": options.order.name(),
+            "min_width": options.shape.min_width,
+            "min_height": options.shape.min_height,
+            "max_aspect": options.shape.max_aspect,
+            "rejected": {
+                "narrow": summary.narrow,
+                "short": summary.short,
+                "elongated": summary.elongated,
+            },
+            "patches": patches,
+        });
+        fs::write(
+            debug_directory.join(format!("{}-parameters.json", path_stem)),
+            serde_json::to_string_pretty(&parameters)?,
+        )?;
+
+        imgcodecs::imwrite(
+            &debug_directory
+                .join(&format!("{}-patches.png", path_stem))
+                .to_string_lossy(),
+            &img,
+            &Vector::default(),
+        )?;
+
+        if let Some(sharpened) = &sharpened {
+            imgcodecs::imwrite(
+                &debug_directory
+                    .join(&format!("{}-sharpened.png", path_stem))
+                    .to_string_lossy(),
+                sharpened,
+                &Vector::default(),
+            )?;
+        }
+
+        imgcodecs::imwrite(
+            &debug_directory
+                .join(&format!("{}-processed.png", path_stem))
+                .to_string_lossy(),
+            &close,
+            &Vector::default(),
+        )?;
+    }
+
+    Ok(summary)
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "decollage",
+    about = "Pull sub images out of input images with a white background."
+)]
+pub struct DecollageOpt {
+    #[structopt(flatten)]
+    global: cli::GlobalArgs,
+
+    /// Input files
+    #[structopt(parse(from_os_str))]
+    input_directory: PathBuf,
+
+    /// Output directory.
+    #[structopt(parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// Output directory for debug files.
+    #[structopt(long = "debug", parse(from_os_str))]
+    debug_directory: Option<PathBuf>,
+
+    /// Don't sharpen the blurred page before thresholding it.
+    #[structopt(long = "no-sharpen")]
+    no_sharpen: bool,
+
+    /// Center weight of the 3x3 sharpening kernel, whose other weights are
+    /// -1. The default of 9 sharpens without brightening the page.
+    #[structopt(long = "sharpen-amount", default_value = "9")]
+    sharpen_amount: f32,
+
+    /// Gray level from 0 to 255 above which pixels are background, or `auto`
+    /// to pick one for each page, for backgrounds that are not white.
+    #[structopt(long = "threshold", default_value = "210")]
+    threshold: Threshold,
+
+    /// Area in pixels a subimage must be larger than to be extracted.
+    /// Increase to remove noise, decrease to ensure all subimages are
+    /// extracted.
+    #[structopt(long = "min-area", default_value = "5000")]
+    min_area: f64,
+
+    /// Fraction of the area of each page a subimage must be larger than to be
+    /// extracted, from 0 to 1, so the threshold follows the resolution of the
+    /// scan. Takes precedence over `--min-area`.
+    #[structopt(long = "min-area-frac")]
+    min_area_frac: Option<f64>,
+
+    /// Fraction of the area of each page, from 0 to 1, that the bounding box
+    /// of a subimage may cover. Larger subimages are discarded, as they are
+    /// usually touching subimages merged together, or the page itself.
+    #[structopt(long = "max-area-frac", default_value = "0.9")]
+    max_area_frac: f64,
+
+    /// Side in pixels of the square kernel that opens and closes the
+    /// thresholded page.
+    #[structopt(long = "morph-kernel", default_value = "3")]
+    morph_kernel: u32,
+
+    /// Times the thresholded page is opened, removing foreground outside of
+    /// subimages such as captions, or 0 not to.
+    #[structopt(long = "morph-open-iters", default_value = "2")]
+    morph_open_iterations: u32,
+
+    /// Times the thresholded page is closed, bridging gaps inside subimages,
+    /// or 0 not to. Increase to join a photo split in parts, decrease to keep
+    /// tightly packed subimages apart.
+    #[structopt(long = "morph-close-iters", default_value = "2")]
+    morph_close_iterations: u32,
+
+    /// Straighten subimages that are rotated on the page, such as photos
+    /// glued into an album at an angle, cutting each out along its smallest
+    /// enclosing rectangle rather than its upright bounding box.
+    #[structopt(long = "deskew")]
+    deskew: bool,
+
+    /// Pixels of the page to add around each subimage, clamped to the edges
+    /// of the page, or to take off each side if negative.
+    #[structopt(long = "padding", default_value = "0", allow_hyphen_values = true)]
+    padding: i32,
+
+    /// Merge subimages that overlap or are less than this many pixels apart,
+    /// repeating until none are, so photos split by large white areas inside
+    /// them, such as snow, are cut out whole. Off unless given.
+    #[structopt(long = "merge-distance")]
+    merge_distance: Option<u32>,
+
+    /// Fraction of a subimage, from 0 to 1, that must lie inside a larger one
+    /// for it to be dropped, such as a bright sky found inside the photo
+    /// around it. Of identical subimages, only one is kept.
+    #[structopt(long = "contained-frac", default_value = "0.9")]
+    contained_frac: f64,
+
+    /// Width in pixels a subimage must be at least to be extracted, checked
+    /// after merging and before padding.
+    #[structopt(long = "min-width", default_value = "0")]
+    min_width: u32,
+
+    /// Height in pixels a subimage must be at least to be extracted, checked
+    /// after merging and before padding.
+    #[structopt(long = "min-height", default_value = "0")]
+    min_height: u32,
+
+    /// Ratio of the long to the short side of a subimage above which it is
+    /// discarded, such as for smudges along the gutter of a page. Off unless
+    /// given.
+    #[structopt(long = "max-aspect")]
+    max_aspect: Option<f64>,
+
+    /// Order to number the subimages of each page in: `reading`, in rows from
+    /// top to bottom and left to right, `area`, from the largest, or `none`,
+    /// as they are found.
+    #[structopt(long = "order", default_value = "reading")]
+    order: Order,
+
+    /// Template of the file names of subimages, with the placeholders
+    /// `{stem}` of the page, `{index}` of the subimage on it, `{x}` and `{y}`
+    /// of its top left corner, `{width}`, `{height}` and `{ext}`. Numbers are
+    /// zero padded with `{index:03}`. It must include `{index}`, `{stem}` for
+    /// more than one page, end in `.{ext}` or an image extension such as
+    /// `.jpg`, and name a file in the output directory.
+    #[structopt(long = "name-template", default_value = "{stem}-{index:02}.{ext}")]
+    name_template: NameTemplate,
+}
+
+impl DecollageOpt {
+    /// Returns the options each page is processed with, once checked.
+    fn page_options(&self) -> Result<PageOptions> {
+        let min_area = match self.min_area_frac {
+            Some(fraction) if !(0.0..=1.0).contains(&fraction) => {
+                return Err(anyhow!("Min area fraction must be between 0 and 1"))
+            }
+            Some(fraction) => MinArea::Fraction(fraction),
+            None if self.min_area < 0.0 || !self.min_area.is_finite() => {
+                return Err(anyhow!("Min area must be zero or positive"))
+            }
+            None => MinArea::Pixels(self.min_area),
+        };
+        if !(0.0..=1.0).contains(&self.max_area_frac) {
+            return Err(anyhow!("Max area fraction must be between 0 and 1"));
+        }
+        if !(self.contained_frac > 0.0 && self.contained_frac <= 1.0) {
+            return Err(anyhow!("Contained fraction must be above 0, up to 1"));
+        }
+        if matches!(self.max_aspect, Some(max_aspect) if !(1.0..).contains(&max_aspect)) {
+            return Err(anyhow!("Max aspect ratio must be at least 1"));
+        }
+        let morph_kernel = match i32::try_from(self.morph_kernel) {
+            Ok(0) => return Err(anyhow!("Morphology kernel must be at least 1 pixel")),
+            Ok(kernel) => kernel,
+            Err(_) => {
+                return Err(anyhow!(
+                    "Morphology kernel must be at most {} pixels",
+                    i32::MAX
+                ))
+            }
+        };
+        let iterations = |iterations: u32, operation: &str| {
+            i32::try_from(iterations).map_err(|_| {
+                anyhow!(
+                    "Morphology {} iterations must be at most {}",
+                    operation,
+                    i32::MAX
+                )
+            })
+        };
+        let morph_open_iterations = iterations(self.morph_open_iterations, "open")?;
+        let morph_close_iterations = iterations(self.morph_close_iterations, "close")?;
+        Ok(PageOptions {
+            output_directory: &self.output_directory,
+            debug_directory: self.debug_directory.as_deref(),
+            sharpen: if self.no_sharpen {
+                None
+            } else {
+                Some(self.sharpen_amount)
+            },
+            threshold: self.threshold,
+            min_area,
+            max_area_frac: self.max_area_frac,
+            morph_kernel,
+            morph_open_iterations,
+            morph_close_iterations,
+            deskew: self.deskew,
+            padding: self.padding,
+            merge_distance: self.merge_distance.map(|distance| distance as i32),
+            contained_frac: self.contained_frac,
+            order: self.order,
+            name_template: self.name_template.clone(),
+            shape: ShapeLimits {
+                min_width: self.min_width as i32,
+                min_height: self.min_height as i32,
+                max_aspect: self.max_aspect,
+            },
+        })
+    }
+}
+
+/// Runs `decollage`.
+pub fn run(opt: DecollageOpt) -> Result<()> {
+    opt.global.init_logger(log::LevelFilter::Info);
+    opt.global.init_threads(None)?;
+    let options = opt.page_options()?;
+
+    let paths = fs::read_dir(&opt.input_directory)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    options.name_template.check_pages(paths.len())?;
+
+    let mut pages = 0;
+    let mut extracted = 0;
+    let mut needs_splitting = Vec::new();
+    for path in paths {
+        let summary = process_collage_page(&path, &options)?;
+        pages += 1;
+        extracted += summary.extracted;
+        if summary.needs_splitting() {
+            needs_splitting.push(path);
+        }
+    }
+
+    info!(
+        "Summary: {} pages, {} subimages extracted, {} pages need manual splitting",
+        pages,
+        extracted,
+        needs_splitting.len()
+    );
+    for path in needs_splitting.iter() {
+        warn!("Needs manual splitting: {}", path.to_string_lossy());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    /// A photo on a fixture page, of `size` pixels centered on `center` and
+    /// turned by `angle` degrees, shaded from 60 to 140 towards its bottom
+    /// right so that it is well below the default threshold.
+    struct Photo {
+        center: (f32, f32),
+        size: (f32, f32),
+        angle: f32,
+    }
+
+    impl Photo {
+        /// An upright photo with its top left corner at `x`, `y`.
+        fn at(x: u32, y: u32, width: u32, height: u32) -> Self {
+            Photo {
+                center: (
+                    x as f32 + width as f32 / 2.0,
+                    y as f32 + height as f32 / 2.0,
+                ),
+                size: (width as f32, height as f32),
+                angle: 0.0,
+            }
+        }
+
+        /// Returns the shade of the photo at the pixel `x`, `y` of the page,
+        /// if it covers it.
+        fn shade(&self, x: u32, y: u32) -> Option<u8> {
+            let (sin, cos) = self.angle.to_radians().sin_cos();
+            let (dx, dy) = (
+                x as f32 + 0.5 - self.center.0,
+                y as f32 + 0.5 - self.center.1,
+            );
+            // The pixel in the photo's own axes, from its center.
+            let (u, v) = (dx * cos + dy * sin, dy * cos - dx * sin);
+            let (half_width, half_height) = (self.size.0 / 2.0, self.size.1 / 2.0);
+            if u.abs() > half_width || v.abs() > half_height {
+                return None;
+            }
+            let along = (u + half_width) / self.size.0 + (v + half_height) / self.size.1;
+            Some((60.0 + 40.0 * along) as u8)
+        }
+    }
+
+    /// Writes a page of `width` by `height` pixels of `background` with
+    /// `photos` on it to `directory`, returning its path.
+    fn page(
+        directory: &Path,
+        width: u32,
+        height: u32,
+        background: [u8; 3],
+        photos: &[Photo],
+    ) -> PathBuf {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            match photos.iter().find_map(|photo| photo.shade(x, y)) {
+                Some(shade) => Rgb([shade; 3]),
+                None => Rgb(background),
+            }
+        });
+        let path = directory.join("page.png");
+        image.save(&path).unwrap();
+        path
+    }
+
+    /// Pulls the subimages out of the page at `path` with the options `args`
+    /// of `decollage`, returning what was pulled out and the subimages
+    /// written, in the order they are numbered.
+    fn decollage(path: &Path, args: &[&str]) -> (PageSummary, Vec<RgbImage>) {
+        let output = tempfile::tempdir().unwrap();
+        let mut command = vec![
+            "decollage".to_owned(),
+            path.parent().unwrap().to_string_lossy().into_owned(),
+            output.path().to_string_lossy().into_owned(),
+        ];
+        command.extend(args.iter().map(|arg| (*arg).to_owned()));
+        let opt = DecollageOpt::from_iter_safe(command).unwrap();
+        let summary = process_collage_page(path, &opt.page_options().unwrap()).unwrap();
+        let mut paths: Vec<PathBuf> = fs::read_dir(output.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        let subimages = paths
+            .iter()
+            .map(|path| image::open(path).unwrap().to_rgb())
+            .collect();
+        (summary, subimages)
+    }
+
+    fn dimensions(subimages: &[RgbImage]) -> Vec<(u32, u32)> {
+        subimages.iter().map(RgbImage::dimensions).collect()
+    }
+
+    #[test]
+    fn min_area_drops_small_subimages_by_pixels_or_fraction() {
+        let directory = tempfile::tempdir().unwrap();
+        // A photo of 30000 pixels, and one of 3600 on a page of 240000.
+        let path = page(
+            directory.path(),
+            600,
+            400,
+            WHITE,
+            &[Photo::at(50, 50, 200, 150), Photo::at(400, 250, 60, 60)],
+        );
+        let (summary, subimages) = decollage(&path, &[]);
+        assert_eq!(summary.extracted, 1);
+        assert_eq!(dimensions(&subimages), [(200, 150)]);
+        let (_, subimages) = decollage(&path, &["--min-area", "3000"]);
+        assert_eq!(dimensions(&subimages), [(200, 150), (60, 60)]);
+        let (_, subimages) = decollage(&path, &["--min-area-frac", "0.02"]);
+        assert_eq!(dimensions(&subimages), [(200, 150)]);
+        // The fraction takes precedence over the area in pixels.
+        let (_, subimages) = decollage(&path, &["--min-area-frac", "0.01", "--min-area", "50000"]);
+        assert_eq!(dimensions(&subimages), [(200, 150), (60, 60)]);
+        let (summary, _) = decollage(&path, &["--min-area", "40000"]);
+        assert_eq!(summary.extracted, 0);
+        assert!(!summary.needs_splitting());
+    }
+
+    #[test]
+    fn subimages_covering_most_of_the_page_flag_it_for_splitting() {
+        let directory = tempfile::tempdir().unwrap();
+        // Touching photos found as one, covering 92% of the page.
+        let path = page(
+            directory.path(),
+            600,
+            400,
+            WHITE,
+            &[Photo::at(10, 10, 290, 380), Photo::at(300, 10, 290, 380)],
+        );
+        let (summary, subimages) = decollage(&path, &[]);
+        assert!(subimages.is_empty());
+        assert_eq!(summary.oversized, 1);
+        assert!(summary.needs_splitting());
+        let (summary, subimages) = decollage(&path, &["--max-area-frac", "0.95"]);
+        assert_eq!(dimensions(&subimages), [(580, 380)]);
+        assert!(!summary.needs_splitting());
+
+        // Photos apart until merged.
+        let path = page(
+            directory.path(),
+            600,
+            400,
+            WHITE,
+            &[Photo::at(10, 10, 280, 380), Photo::at(310, 10, 280, 380)],
+        );
+        let (summary, subimages) = decollage(&path, &[]);
+        assert_eq!(dimensions(&subimages), [(280, 380), (280, 380)]);
+        assert!(!summary.needs_splitting());
+        let (summary, subimages) = decollage(&path, &["--merge-distance", "30"]);
+        assert!(subimages.is_empty());
+        assert_eq!(summary.oversized, 1);
+        assert!(summary.needs_splitting());
+    }
+
+    #[test]
+    fn morphology_options_must_fit_opencv() {
+        let error = |args: &[&str]| {
+            let command = ["decollage", "pages", "subimages"].iter().chain(args);
+            DecollageOpt::from_iter_safe(command)
+                .unwrap()
+                .page_options()
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&["--morph-kernel", "0"]),
+            "Morphology kernel must be at least 1 pixel"
+        );
+        assert_eq!(
+            error(&["--morph-kernel", "2147483648"]),
+            "Morphology kernel must be at most 2147483647 pixels"
+        );
+        assert_eq!(
+            error(&["--morph-open-iters", "4294967295"]),
+            "Morphology open iterations must be at most 2147483647"
+        );
+        assert_eq!(
+            error(&["--morph-close-iters", "2147483648"]),
+            "Morphology close iterations must be at most 2147483647"
+        );
+        let opt = DecollageOpt::from_iter_safe(
+            [
+                "decollage",
+                "pages",
+                "subimages",
+                "--morph-kernel",
+                "1",
+                "--morph-close-iters",
+                "2147483647",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        let options = opt.page_options().unwrap();
+        assert_eq!(options.morph_kernel, 1);
+        assert_eq!(options.morph_close_iterations, i32::MAX);
+    }
+
+    #[test]
+    fn thresholds_are_levels_or_auto() {
+        assert!(matches!("auto".parse(), Ok(Threshold::Auto)));
+        assert!(matches!("0".parse(), Ok(Threshold::Fixed(level)) if level == 0.0));
+        assert!(matches!("230".parse(), Ok(Threshold::Fixed(level)) if level == 230.0));
+        for threshold in ["256", "-1", "otsu", ""].iter() {
+            assert_eq!(
+                threshold.parse::<Threshold>().unwrap_err().to_string(),
+                format!("Unknown threshold: {}", threshold)
+            );
+        }
+    }
+
+    #[test]
+    fn auto_threshold_separates_photos_from_white_and_yellowed_pages() {
+        let directory = tempfile::tempdir().unwrap();
+        let photos = [Photo::at(50, 50, 200, 150), Photo::at(350, 200, 200, 150)];
+        let white = page(directory.path(), 600, 400, WHITE, &photos);
+        for args in [&[][..], &["--threshold", "auto"][..]].iter() {
+            let (_, subimages) = decollage(&white, args);
+            assert_eq!(dimensions(&subimages), [(200, 150), (200, 150)]);
+        }
+
+        // A background of gray level 199, below the default threshold, so
+        // that the whole page is taken for one subimage.
+        let yellowed = page(directory.path(), 600, 400, [215, 200, 150], &photos);
+        let (summary, subimages) = decollage(&yellowed, &[]);
+        assert!(subimages.is_empty());
+        assert!(summary.needs_splitting());
+        let (_, subimages) = decollage(&yellowed, &["--threshold", "auto"]);
+        assert_eq!(dimensions(&subimages), [(200, 150), (200, 150)]);
+        let (_, subimages) = decollage(&yellowed, &["--threshold", "170"]);
+        assert_eq!(dimensions(&subimages), [(200, 150), (200, 150)]);
+    }
+
+    #[test]
+    fn closing_joins_a_photo_split_by_a_light_line() {
+        let directory = tempfile::tempdir().unwrap();
+        // A photo split by a light line 6 pixels wide, which the default
+        // closing, in effect by a 5 pixel square, leaves apart.
+        let path = page(
+            directory.path(),
+            600,
+            400,
+            WHITE,
+            &[Photo::at(100, 100, 97, 150), Photo::at(203, 100, 97, 150)],
+        );
+        for args in [&[][..], &["--morph-close-iters", "0"][..]].iter() {
+            let (_, subimages) = decollage(&path, args);
+            assert_eq!(dimensions(&subimages), [(97, 150), (97, 150)]);
+        }
+        for args in [
+            &["--morph-close-iters", "3"][..],
+            &["--morph-kernel", "5"][..],
+        ]
+        .iter()
+        {
+            let (_, subimages) = decollage(&path, args);
+            assert_eq!(dimensions(&subimages), [(200, 150)]);
+        }
+    }
+
+    #[test]
+    fn sharpening_outlines_a_photo_with_pale_edges() {
+        let directory = tempfile::tempdir().unwrap();
+        // A photo 200 by 150 with pale edges and a pale band down its middle,
+        // lighter than the threshold, between two darker parts.
+        let photo = Rect::new(100, 100, 200, 150);
+        let parts = [Photo::at(110, 110, 80, 130), Photo::at(210, 110, 80, 130)];
+        let image = RgbImage::from_fn(600, 400, |x, y| {
+            let (x, y) = (x as i32, y as i32);
+            if x < photo.x
+                || y < photo.y
+                || x >= photo.x + photo.width
+                || y >= photo.y + photo.height
+            {
+                return Rgb(WHITE);
+            }
+            let shade = parts.iter().find_map(|part| part.shade(x as u32, y as u32));
+            Rgb([shade.unwrap_or(225); 3])
+        });
+        let path = directory.path().join("page.png");
+        image.save(&path).unwrap();
+
+        // Opening would wear away the thin outline sharpening draws around
+        // the pale edges, so the page is only closed.
+        let (_, subimages) = decollage(&path, &["--morph-open-iters", "0"]);
+        assert_eq!(dimensions(&subimages), [(200, 150)]);
+        let (_, subimages) = decollage(&path, &["--morph-open-iters", "0", "--no-sharpen"]);
+        assert_eq!(dimensions(&subimages), [(80, 130), (80, 130)]);
+    }
+
+    /// Whether `found` is within `tolerance` pixels of `expected` on each side.
+    fn near(found: (u32, u32), expected: (u32, u32), tolerance: u32) -> bool {
+        found.0.max(expected.0) - found.0.min(expected.0) <= tolerance
+            && found.1.max(expected.1) - found.1.min(expected.1) <= tolerance
+    }
+
+    #[test]
+    fn deskew_straightens_a_photo_glued_in_at_7_degrees() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = page(
+            directory.path(),
+            600,
+            400,
+            WHITE,
+            &[Photo {
+                center: (300.0, 200.0),
+                size: (200.0, 120.0),
+                angle: 7.0,
+            }],
+        );
+        // Cut out along its bounding box of about 213 by 143 pixels, with
+        // the page showing in the corners.
+        let (_, subimages) = decollage(&path, &[]);
+        assert_eq!(subimages.len(), 1);
+        assert!(
+            near(subimages[0].dimensions(), (213, 143), 2),
+            "{:?}",
+            dimensions(&subimages)
+        );
+        assert_eq!(subimages[0].get_pixel(0, 0), &Rgb(WHITE));
+
+        let (_, subimages) = decollage(&path, &["--deskew"]);
+        assert_eq!(subimages.len(), 1);
+        let subimage = &subimages[0];
+        let (width, height) = subimage.dimensions();
+        assert!(
+            near((width, height), (200, 120), 3),
+            "{:?}",
+            (width, height)
+        );
+        // Only the photo is left, up to its corners.
+        for (x, y) in [
+            (4, 4),
+            (width - 5, 4),
+            (4, height - 5),
+            (width - 5, height - 5),
+        ]
+        .iter()
+        {
+            let pixel = subimage.get_pixel(*x, *y);
+            assert!(pixel.0.iter().all(|channel| *channel < 160), "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn padding_is_clamped_to_the_page() {
+        let directory = tempfile::tempdir().unwrap();
+        // 10 pixels from the left of the page, and well inside it otherwise.
+        let path = page(
+            directory.path(),
+            600,
+            400,
+            WHITE,
+            &[Photo::at(10, 100, 150, 100)],
+        );
+        let (_, subimages) = decollage(&path, &["--padding", "20"]);
+        assert_eq!(dimensions(&subimages), [(180, 140)]);
+        // The padding shows the page around the photo, up to the edge.
+        assert_eq!(subimages[0].get_pixel(179, 139), &Rgb(WHITE));
+        assert_eq!(subimages[0].get_pixel(0, 20), &Rgb(WHITE));
+        assert_ne!(subimages[0].get_pixel(10, 20), &Rgb(WHITE));
+        let (_, subimages) = decollage(&path, &["--padding", "-10"]);
+        assert_eq!(dimensions(&subimages), [(130, 80)]);
+        let (summary, subimages) = decollage(&path, &["--padding", "-50"]);
+        assert!(subimages.is_empty());
+        assert_eq!(summary.extracted, 0);
+    }
+}
@@ -0,0 +1,5092 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, read_to_string, File, OpenOptions};
+use std::io::{BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use fs2::FileExt;
+use image::{DynamicImage, ImageError};
+use img_hash::ImageHash;
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use log::{debug, info, log, warn, Level};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use walkdir::WalkDir;
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::borders::{BorderColor, BorderOptions, BorderScan, SCAN_LINES};
+use crate::cli;
+use crate::matching::{
+    self, assign_greedy, assign_optimal, thumb_distance, Assignment, FullsizeSearch, Match,
+    Matcher, PathPhash, SearchHooks, SearchOptions,
+};
+use crate::phash::{
+    self, encode_file_name, raw_hex, thumbnail_transforms, CacheConfig, CacheKey, HashAlgorithm,
+    HashCache, HashConfig, HashRequest, Hashers, PrepareOptions, PreparedImage, Preprocess,
+    Provenance, ResizeFilter, Transform,
+};
+
+mod decisions;
+mod reports;
+mod transfers;
+mod watcher;
+
+use decisions::{decide, CopyPlan, Decision};
+use reports::{
+    create_report, write_gallery, write_montages, write_review_list, DistanceDump, DumpFormat,
+    MatchReport, ReportFormat,
+};
+use transfers::{
+    archive_matched, copy_files, quarantine_failures, ArchiveCounts, CollisionPolicy, Conversion,
+    Convert, CopyCounts, OutputNames, PlannedCopy, Preserve, Rename, Transfer,
+};
+use watcher::{ThumbnailWatcher, WatchOptions};
+
+/// Default distance within which `--consume-matches` removes a candidate.
+const CONSUME_DISTANCE_THRESHOLD: u32 = 2;
+/// Default number of candidates `--prefilter` keeps for `--matcher bruteforce`.
+const DEFAULT_PREFILTER: usize = 200;
+const UNMATCHED_FILE_NAME: &str = "unmatched.txt";
+const UNCLAIMED_FILE_NAME: &str = "unclaimed_fullsize.txt";
+const CANDIDATES_FILE_NAME: &str = "candidates.csv";
+const ASSIGNMENTS_FILE_NAME: &str = "assignments.csv";
+const REVERSE_FILE_NAME: &str = "reverse.csv";
+const ORPHANS_FILE_NAME: &str = "orphans.txt";
+const DUPLICATES_FILE_NAME: &str = "duplicates.json";
+const MARGINS_FILE_NAME: &str = "margins.csv";
+const CONSUMED_FILE_NAME: &str = "consumed.csv";
+const REVIEW_FILE_NAME: &str = "review.csv";
+const HASH_FILE_NAME: &str = "hash.json";
+/// Name of the lock file held in the cache directory during a run.
+const LOCK_FILE_NAME: &str = ".lock";
+/// Version of the `--export-hashes` manifest format.
+const MANIFEST_VERSION: u32 = 1;
+const MATCHES_FILE_NAME: &str = "matches.json";
+/// Directory in the output directory `--review-montages` are written to,
+/// without `--review-dir`.
+const MONTAGES_DIRECTORY_NAME: &str = "review";
+/// Version of the match state read by `--resume`.
+const MATCHES_VERSION: u32 = 1;
+/// Exit code under `--strict` when some match needs manual review.
+const EXIT_REVIEW: i32 = 2;
+/// Exit code under `--strict` when some image could not be hashed. Takes
+/// precedence over `EXIT_REVIEW`.
+const EXIT_FAILED: i32 = 3;
+/// Exit code when a run is interrupted by Ctrl-C.
+const EXIT_INTERRUPTED: i32 = 130;
+/// Which images have their borders removed before hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cleanup {
+    None,
+    Thumbs,
+    Fullsize,
+    Both,
+}
+
+impl Cleanup {
+    fn thumbs(self) -> bool {
+        self == Cleanup::Thumbs || self == Cleanup::Both
+    }
+
+    fn fullsize(self) -> bool {
+        self == Cleanup::Fullsize || self == Cleanup::Both
+    }
+}
+
+impl FromStr for Cleanup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Cleanup::None),
+            "thumbs" => Ok(Cleanup::Thumbs),
+            "fullsize" => Ok(Cleanup::Fullsize),
+            "both" => Ok(Cleanup::Both),
+            _ => Err(anyhow!("Unknown cleanup: {}", s)),
+        }
+    }
+}
+
+/// Returns a path as written to reports. Paths that are not valid UTF-8 are
+/// written lossily, followed by their raw bytes in hex to identify them.
+fn escape_path(path: &Path) -> Cow<'_, str> {
+    match path.to_str() {
+        Some(path) => Cow::Borrowed(path),
+        None => Cow::Owned(format!(
+            "{} [0x{}]",
+            path.to_string_lossy(),
+            raw_hex(path.as_os_str())
+        )),
+    }
+}
+
+/// Moves the cache entries of each image moved from the first path of
+/// `moves` to the second, so they are reused when the image is loaded from
+/// where it is now. `root` holds a cache directory per source directory.
+fn relocate_cache_entries(
+    root: &Path,
+    config: &CacheConfig,
+    moves: &[(PathBuf, PathBuf)],
+) -> Result<()> {
+    let mut renames: BTreeMap<(PathBuf, PathBuf), Vec<(String, String)>> = BTreeMap::new();
+    for (source, destination) in moves.iter() {
+        let (Some(from), Some(to)) = (source.parent(), destination.parent()) else {
+            continue;
+        };
+        renames
+            .entry((
+                root.join(directory_cache_key(from)),
+                root.join(directory_cache_key(to)),
+            ))
+            .or_default()
+            .push((
+                encode_file_name(source.file_name().unwrap_or_default()),
+                encode_file_name(destination.file_name().unwrap_or_default()),
+            ));
+    }
+    for ((from, to), names) in renames.into_iter() {
+        if !from.is_dir() {
+            continue;
+        }
+        let mut source_index = HashCache::read(&from, config.clone(), false)?;
+        let mut moved = Vec::new();
+        for (old, new) in names.iter() {
+            for (suffix, entry) in source_index.remove_source(old) {
+                moved.push((format!("{}{}", new, suffix), entry));
+            }
+        }
+        if moved.is_empty() {
+            continue;
+        }
+        fs::create_dir_all(&to)?;
+        let mut target_index = HashCache::read(&to, config.clone(), false)?;
+        target_index.write(moved)?;
+        source_index.write(Vec::new())?;
+    }
+    Ok(())
+}
+
+/// An image that could not be hashed, with why.
+#[derive(Debug, Clone)]
+struct FailedImage {
+    path: PathBuf,
+    error: String,
+    /// Whether the image itself could not be decoded, rather than read, so
+    /// that trying again won't help.
+    corrupt: bool,
+}
+
+/// Whether `error` came from decoding an image, rather than reading it.
+fn is_decode_error(error: &crate::Error) -> bool {
+    matches!(error, crate::Error::Image(error) if !matches!(error, ImageError::IoError(_)))
+}
+
+/// Counts of how the images of a side were loaded.
+#[derive(Debug, Default, Clone, Serialize)]
+struct LoadCounts {
+    /// Images skipped because they could not be hashed.
+    failed: usize,
+    #[serde(skip)]
+    failures: Vec<FailedImage>,
+    /// Cache entries computed.
+    hashed: usize,
+    /// Cache entries reused.
+    cached: usize,
+    /// Cache entries of images that no longer exist, removed or listed by
+    /// `--prune-cache`.
+    pruned: usize,
+    /// Images decoded to compute missing hashes.
+    decoded: usize,
+    /// Images skipped as they don't match `--include`.
+    not_included: usize,
+    /// Images skipped as they match `--exclude`.
+    excluded: usize,
+    /// Images not loaded, as the run was interrupted.
+    interrupted: usize,
+    /// Bytes of images read from disk, to decode or digest them.
+    bytes_read: u64,
+}
+
+impl std::ops::AddAssign for LoadCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.failed += other.failed;
+        self.failures.extend(other.failures);
+        self.hashed += other.hashed;
+        self.cached += other.cached;
+        self.pruned += other.pruned;
+        self.decoded += other.decoded;
+        self.not_included += other.not_included;
+        self.excluded += other.excluded;
+        self.interrupted += other.interrupted;
+        self.bytes_read += other.bytes_read;
+    }
+}
+
+/// Which sides of the cache `--rebuild-cache` rehashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rebuild {
+    All,
+    Thumbnails,
+    Fullsize,
+}
+
+impl Rebuild {
+    fn thumbnails(self) -> bool {
+        self == Rebuild::All || self == Rebuild::Thumbnails
+    }
+
+    fn fullsize(self) -> bool {
+        self == Rebuild::All || self == Rebuild::Fullsize
+    }
+}
+
+impl FromStr for Rebuild {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(Rebuild::All),
+            "thumbnails" => Ok(Rebuild::Thumbnails),
+            "fullsize" => Ok(Rebuild::Fullsize),
+            _ => Err(anyhow!("Unknown cache side: {}", s)),
+        }
+    }
+}
+
+/// What to do when an image cannot be hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ErrorPolicy {
+    /// Skip the image, listing it at the end of the run.
+    #[default]
+    Skip,
+    /// Stop the run.
+    Abort,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(ErrorPolicy::Skip),
+            "abort" => Ok(ErrorPolicy::Abort),
+            _ => Err(anyhow!("Unknown error policy: {}", s)),
+        }
+    }
+}
+
+/// Prepares the image at `path` for hashing, logging that it is hashed, and
+/// decoding it alone if it is over `--max-decode-pixels`.
+fn prepare_to_hash(path: &Path, options: &LoadOptions) -> crate::Result<PreparedImage> {
+    // Per-file logs would scroll the progress bar away.
+    let level = if options.progress_bar.is_some() {
+        Level::Debug
+    } else {
+        Level::Info
+    };
+    emit(level, Event::Hashing { file: path });
+    let _decoding = lock_large_decode(path, options.max_decode_pixels);
+    phash::prepare_image(path, &options.prepare_options())
+}
+
+/// Held while preparing an image over `--max-decode-pixels`.
+static LARGE_DECODE: Mutex<()> = Mutex::new(());
+
+/// Waits to decode the image at `path` if it has more than `max_pixels`.
+/// Images over the limit are decoded one at a time, so that at most one of
+/// them is held at full size.
+fn lock_large_decode(path: &Path, max_pixels: Option<u64>) -> Option<MutexGuard<'static, ()>> {
+    let large = max_pixels.is_some_and(|max_pixels| {
+        image::image_dimensions(path)
+            .is_ok_and(|(width, height)| u64::from(width) * u64::from(height) > max_pixels)
+    });
+    if !large {
+        return None;
+    }
+    debug!("Waiting to decode large image {}", path.to_string_lossy());
+    Some(
+        LARGE_DECODE
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    )
+}
+
+/// Decodes the image at `path` upright, one at a time if it has more than
+/// `max_decode_pixels`.
+fn open_upright(path: &Path, max_decode_pixels: Option<u64>) -> Result<DynamicImage> {
+    let _decoding = lock_large_decode(path, max_decode_pixels);
+    Ok(phash::open_upright(path)?)
+}
+
+/// Whether Ctrl-C was pressed. Work in progress is finished, but no more is
+/// started, so that caches and reports are written for what was done.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Stops starting work on the first Ctrl-C, and exits at once on the second.
+fn handle_interrupt() {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    warn!("Interrupted, finishing the work in progress; press Ctrl-C again to stop at once");
+}
+
+fn serialize_path<S: serde::Serializer>(
+    path: &&Path,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&escape_path(path))
+}
+
+fn seconds<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// The closest image to one left unmatched.
+#[derive(Debug, Serialize)]
+struct Closest<'a> {
+    #[serde(serialize_with = "serialize_path")]
+    file: &'a Path,
+    distance: u32,
+}
+
+/// Something that happened while hashing or matching, logged as a line of
+/// text or as a JSON object of its fields.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Hashing {
+        #[serde(serialize_with = "serialize_path")]
+        file: &'a Path,
+    },
+    HashFailed {
+        #[serde(serialize_with = "serialize_path")]
+        file: &'a Path,
+        error: String,
+    },
+    Matched {
+        #[serde(serialize_with = "serialize_path")]
+        thumb: &'a Path,
+        #[serde(serialize_with = "serialize_path")]
+        fullsize: &'a Path,
+        distance: u32,
+        transform: &'static str,
+    },
+    Unmatched {
+        #[serde(serialize_with = "serialize_path")]
+        thumb: &'a Path,
+        closest: Closest<'a>,
+    },
+    Ambiguous {
+        #[serde(serialize_with = "serialize_path")]
+        thumb: &'a Path,
+        closest: Closest<'a>,
+    },
+    DistanceReview {
+        #[serde(serialize_with = "serialize_path")]
+        thumb: &'a Path,
+        #[serde(serialize_with = "serialize_path")]
+        fullsize: &'a Path,
+        distance: u32,
+    },
+    ColorReview {
+        #[serde(serialize_with = "serialize_path")]
+        thumb: &'a Path,
+        #[serde(serialize_with = "serialize_path")]
+        fullsize: &'a Path,
+        similarity: f32,
+    },
+    /// A fullsize image matched by a thumbnail, in the reverse direction.
+    Found {
+        #[serde(serialize_with = "serialize_path")]
+        fullsize: &'a Path,
+        #[serde(serialize_with = "serialize_path")]
+        thumb: &'a Path,
+        distance: u32,
+    },
+    Orphan {
+        #[serde(serialize_with = "serialize_path")]
+        fullsize: &'a Path,
+        closest: Option<Closest<'a>>,
+    },
+    Phase {
+        name: &'static str,
+        #[serde(serialize_with = "seconds")]
+        duration: Duration,
+    },
+    Summary {
+        thumbnails: usize,
+        matched: usize,
+        review: usize,
+        failed: usize,
+        unmatched: usize,
+        #[serde(serialize_with = "seconds")]
+        loading: Duration,
+        hashed: usize,
+        cached: usize,
+        #[serde(serialize_with = "seconds")]
+        matching: Duration,
+    },
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Hashing { file } => write!(
+                f,
+                "Hashing: {}",
+                file.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            Event::HashFailed { file, error } => {
+                write!(f, "Skipping {}: {}", file.to_string_lossy(), error)
+            }
+            Event::Matched {
+                thumb,
+                fullsize,
+                transform,
+                ..
+            } => {
+                write!(
+                    f,
+                    "Matched: {} to {}",
+                    thumb.to_string_lossy(),
+                    fullsize.to_string_lossy()
+                )?;
+                if *transform != Transform::Identity.name() {
+                    write!(f, " with transform {}", transform)?;
+                }
+                Ok(())
+            }
+            Event::Unmatched { thumb, closest } => write!(
+                f,
+                "Unmatched: {} (closest was {} at distance {})",
+                thumb.to_string_lossy(),
+                closest.file.to_string_lossy(),
+                closest.distance
+            ),
+            Event::Ambiguous { thumb, closest } => write!(
+                f,
+                "Ambiguous: {} (closest was {} at distance {}), needs manual review",
+                thumb.to_string_lossy(),
+                closest.file.to_string_lossy(),
+                closest.distance
+            ),
+            Event::DistanceReview {
+                thumb,
+                fullsize,
+                distance,
+            } => write!(
+                f,
+                "Distance from {} to {} was {}, needs manual review",
+                thumb.to_string_lossy(),
+                fullsize.to_string_lossy(),
+                distance
+            ),
+            Event::ColorReview {
+                thumb,
+                fullsize,
+                similarity,
+            } => write!(
+                f,
+                "Color similarity from {} to {} was {:.2}, needs manual review",
+                thumb.to_string_lossy(),
+                fullsize.to_string_lossy(),
+                similarity
+            ),
+            Event::Found {
+                fullsize, thumb, ..
+            } => write!(
+                f,
+                "Matched: {} to {}",
+                escape_path(fullsize),
+                thumb.to_string_lossy()
+            ),
+            Event::Orphan { fullsize, closest } => {
+                write!(f, "Orphan: {}", escape_path(fullsize))?;
+                if let Some(closest) = closest {
+                    write!(
+                        f,
+                        " (closest was {} at distance {})",
+                        closest.file.to_string_lossy(),
+                        closest.distance
+                    )?;
+                }
+                Ok(())
+            }
+            Event::Phase { name, duration } => {
+                write!(f, "{} took: {}s", name, duration.as_secs())
+            }
+            Event::Summary {
+                thumbnails,
+                matched,
+                review,
+                failed,
+                unmatched,
+                loading,
+                hashed,
+                cached,
+                matching,
+            } => write!(
+                f,
+                "Summary: {} thumbnails, {} matched, {} need review, {} failed, {} unmatched, loading took {:.1}s ({} hashes computed, {} cached), matching took {:.1}s",
+                thumbnails,
+                matched,
+                review,
+                failed,
+                unmatched,
+                loading.as_secs_f64(),
+                hashed,
+                cached,
+                matching.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// Logs an event in the format chosen by `--log-format`.
+fn emit(level: Level, event: Event) {
+    if cli::JSON_LOGS.load(Ordering::Relaxed) {
+        log!(
+            target: cli::EVENT_TARGET,
+            level,
+            "{}",
+            serde_json::to_string(&event).unwrap_or_default()
+        );
+    } else {
+        log!(level, "{}", event);
+    }
+}
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Options controlling what is loaded for each image of a directory.
+#[derive(Debug, Default, Clone, Copy)]
+struct LoadOptions<'a> {
+    /// Cache directory for coarse hashes, if they are needed.
+    coarse_cache_dir: Option<&'a Path>,
+    /// Remove borders before hashing.
+    cleanup: bool,
+    border: BorderOptions,
+    preprocess: Preprocess,
+    /// Percentage of each dimension to keep, cropping around the center.
+    center_crop: Option<u32>,
+    /// Sigma of a Gaussian blur applied before downsizing.
+    preblur: Option<f32>,
+    /// Number of pixels above which images are decoded one at a time.
+    max_decode_pixels: Option<u64>,
+    need_dimensions: bool,
+    need_histogram: bool,
+    /// Transforms to hash, in addition to the original.
+    transforms: &'a [Transform],
+    hash: HashConfig,
+    cache_key: CacheKey,
+    /// Fail if a cache was hashed with a different configuration, instead of
+    /// rehashing.
+    cache_strict: bool,
+    /// Ignore cached hashes, rehashing every image.
+    rebuild: bool,
+    /// File name patterns of images to rehash.
+    invalidate: &'a [glob::Pattern],
+    /// Remove cache entries of images no longer in the source directory.
+    prune_cache: bool,
+    /// Only list the cache entries that would be pruned.
+    dry_run: bool,
+    /// What to do with images that cannot be hashed.
+    error_policy: ErrorPolicy,
+    /// Fraction of content at or below which images are skipped as blank.
+    skip_blank: Option<f64>,
+    /// Whether to show a progress bar while loading.
+    progress: bool,
+    /// Progress bar advanced as each image is loaded, if one is shown.
+    progress_bar: Option<&'a ProgressBar>,
+}
+
+impl LoadOptions<'_> {
+    /// What is hashed for the image at `path`.
+    fn request(&self, path: &Path) -> HashRequest<'_> {
+        let rebuild = self.rebuild
+            || path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|file_name| {
+                    self.invalidate
+                        .iter()
+                        .any(|pattern| pattern.matches(file_name))
+                });
+        HashRequest {
+            key: self.cache_key,
+            transforms: self.transforms,
+            dimensions: self.need_dimensions,
+            histogram: self.need_histogram,
+            rebuild,
+        }
+    }
+
+    /// How images are cleaned up before hashing.
+    fn prepare_options(&self) -> PrepareOptions {
+        PrepareOptions {
+            cleanup: self.cleanup,
+            border: self.border,
+            center_crop: self.center_crop,
+            preblur: self.preblur,
+            preprocess: self.preprocess,
+            skip_blank: self.skip_blank,
+            prescale: self.hash.prescale,
+        }
+    }
+
+    /// Describes the fine hashes, or the coarse ones, loaded with these
+    /// options.
+    fn cache_config(&self, coarse: bool) -> CacheConfig {
+        CacheConfig::new(
+            self.hash,
+            coarse,
+            if self.cleanup {
+                Some(&self.border)
+            } else {
+                None
+            },
+            self.center_crop,
+            self.preblur,
+            self.preprocess,
+        )
+    }
+}
+
+/// Hashes the single image at `path` without coarse hashes, reusing its
+/// hashes from `index` if they are cached.
+fn hash_image(
+    path: &Path,
+    index: &HashCache,
+    hashers: &Hashers,
+    options: &LoadOptions,
+) -> crate::Result<PathPhash> {
+    let (phash, _) = index.hash_file(path, &options.request(path), hashers, None, |path| {
+        prepare_to_hash(path, options)
+    })?;
+    Ok(phash)
+}
+
+/// Loads the phash of each of `source_paths`, from `source_directory`.
+/// Images that fail to load are skipped with a warning, and counted in the
+/// returned totals.
+fn load_phashes(
+    source_directory: &Path,
+    source_paths: Vec<PathBuf>,
+    phashes_cache_dir: &Path,
+    options: &LoadOptions,
+) -> Result<(Vec<PathPhash>, LoadCounts)> {
+    let mut index = HashCache::read(
+        phashes_cache_dir,
+        options.cache_config(false),
+        options.cache_strict,
+    )?;
+    let mut coarse_index = match options.coarse_cache_dir {
+        Some(coarse_cache_dir) => Some(HashCache::read(
+            coarse_cache_dir,
+            options.cache_config(true),
+            options.cache_strict,
+        )?),
+        None => None,
+    };
+
+    // Hashers are not `Sync`, so each worker builds its own. Computed entries
+    // are collected and merged into the indexes once every image is loaded,
+    // or once those in progress are when interrupted.
+    let results: Vec<_> = source_paths
+        .into_par_iter()
+        .map_init(
+            || Hashers::new(options.hash),
+            |hashers, path| {
+                if interrupted() {
+                    return (path, None);
+                }
+                let result = index.hash_file(
+                    &path,
+                    &options.request(&path),
+                    hashers,
+                    coarse_index.as_ref(),
+                    |path| prepare_to_hash(path, options),
+                );
+                if let Some(progress_bar) = options.progress_bar {
+                    progress_bar.inc(1);
+                }
+                (path, Some(result))
+            },
+        )
+        .collect();
+
+    let mut phashes = Vec::with_capacity(results.len());
+    let mut updates = Vec::new();
+    let mut coarse_updates = Vec::new();
+    let mut counts = LoadCounts::default();
+    for (path, result) in results {
+        let result = match result {
+            Some(result) => result,
+            None => {
+                counts.interrupted += 1;
+                continue;
+            }
+        };
+        match result {
+            Ok((phash, loaded_updates)) => {
+                phashes.push(phash);
+                counts.hashed += loaded_updates.fine.len() + loaded_updates.coarse.len();
+                counts.cached += loaded_updates.reused;
+                counts.decoded += usize::from(loaded_updates.decoded);
+                counts.bytes_read += loaded_updates.bytes_read;
+                updates.extend(loaded_updates.fine);
+                coarse_updates.extend(loaded_updates.coarse);
+            }
+            Err(error) if options.error_policy == ErrorPolicy::Abort => {
+                return Err(anyhow!(
+                    "Failed to hash {}: {}",
+                    path.to_string_lossy(),
+                    error
+                ));
+            }
+            Err(error) => {
+                suspend(options.progress_bar, || {
+                    emit(
+                        Level::Warn,
+                        Event::HashFailed {
+                            file: &path,
+                            error: error.to_string(),
+                        },
+                    )
+                });
+                counts.failed += 1;
+                counts.failures.push(FailedImage {
+                    path,
+                    error: error.to_string(),
+                    corrupt: is_decode_error(&error),
+                });
+            }
+        }
+    }
+    if options.prune_cache {
+        counts.pruned += index.prune(source_directory, options.dry_run)?;
+        if let Some(coarse_index) = coarse_index.as_mut() {
+            counts.pruned += coarse_index.prune(source_directory, options.dry_run)?;
+        }
+    }
+    index.write(updates)?;
+    if let Some(coarse_index) = coarse_index.as_mut() {
+        coarse_index.write(coarse_updates)?;
+    }
+    Ok((phashes, counts))
+}
+
+/// Whether the process `pid` is running, if that can be told.
+#[cfg(target_os = "linux")]
+fn process_running(pid: &str) -> Option<bool> {
+    let pid: u32 = pid.parse().ok()?;
+    Some(Path::new("/proc").join(pid.to_string()).exists())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_running(_pid: &str) -> Option<bool> {
+    None
+}
+
+/// Locks `cache_directory` against other runs until the returned file is
+/// dropped, or waits for the lock with `wait`. The lock is released by the
+/// operating system if a run crashes, so it can never go stale; the file only
+/// records the process that last held it. A holder that is no longer running
+/// has left the lock to a process it started, which still holds the file open.
+fn lock_cache(cache_directory: &Path, wait: bool) -> Result<File> {
+    fs::create_dir_all(cache_directory)?;
+    let lock_file = cache_directory.join(LOCK_FILE_NAME);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_file)?;
+    if file.try_lock_exclusive().is_err() {
+        let mut holder = String::new();
+        file.read_to_string(&mut holder)?;
+        let holder = match holder.trim() {
+            pid if process_running(pid) == Some(false) => format!(
+                "a process started by process {}, which is no longer running",
+                pid
+            ),
+            pid => format!("process {}", pid),
+        };
+        if !wait {
+            return Err(anyhow!(
+                "Cache {} is in use by {}, pass --wait-for-lock to wait for it",
+                cache_directory.to_string_lossy(),
+                holder
+            ));
+        }
+        info!(
+            "Waiting for {} to finish with cache {}",
+            holder,
+            cache_directory.to_string_lossy()
+        );
+        file.lock_exclusive()?;
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(file, "{}", std::process::id())?;
+    file.flush()?;
+    Ok(file)
+}
+
+/// Image paths, paired with the directory whose cache they use.
+type Source = (PathBuf, Vec<PathBuf>);
+
+/// Which entries of a source directory are loaded as images.
+#[derive(Debug, Clone)]
+struct EntryFilter {
+    /// Extensions of image files, compared ignoring case.
+    extensions: Vec<String>,
+    /// Whether to load files whose names start with `.`.
+    include_hidden: bool,
+    /// Directories written by the run, resolved, whose images are never
+    /// loaded even through symlinks.
+    excluded: Vec<PathBuf>,
+    /// Whether to load images in subdirectories too.
+    recursive: bool,
+    /// Whether to follow symlinks to directories when recursive.
+    follow_symlinks: bool,
+    /// Patterns of the paths under the source directory to load, or all if
+    /// there are none.
+    include: Vec<glob::Pattern>,
+    /// Patterns of the paths under the source directory never to load.
+    exclude: Vec<glob::Pattern>,
+    /// Whether a source directory with no images is loaded, rather than an
+    /// error.
+    allow_empty: bool,
+}
+
+impl EntryFilter {
+    /// Returns why `path` is not loaded, or `None` if it is.
+    fn skip_reason(&self, path: &Path) -> Option<&'static str> {
+        let hidden = path
+            .file_name()
+            .is_some_and(|file_name| file_name.to_string_lossy().starts_with('.'));
+        if hidden && !self.include_hidden {
+            return Some("hidden");
+        }
+        // Subdirectories, such as a cache nested in the source directory, are
+        // never loaded.
+        if !path.is_file() {
+            return Some("not a file");
+        }
+        let is_symlink = path
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if is_symlink {
+            let target = path.canonicalize().unwrap_or_default();
+            if self
+                .excluded
+                .iter()
+                .any(|directory| target.starts_with(directory))
+            {
+                return Some("in the cache or output directory");
+            }
+        }
+        let is_image = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|extension| {
+                self.extensions
+                    .iter()
+                    .any(|image_extension| image_extension.eq_ignore_ascii_case(extension))
+            });
+        if !is_image {
+            return Some("not an image extension");
+        }
+        None
+    }
+
+    /// Returns whether the image at `path`, `relative` to its source
+    /// directory, passes `include` and `exclude`, counting those that don't.
+    fn passes_globs(&self, path: &Path, relative: &Path, counts: &mut LoadCounts) -> bool {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches_path(relative));
+        if !included {
+            debug!("Ignoring {}: not included", path.to_string_lossy());
+            counts.not_included += 1;
+            return false;
+        }
+        if self
+            .exclude
+            .iter()
+            .any(|pattern| pattern.matches_path(relative))
+        {
+            debug!("Ignoring {}: excluded", path.to_string_lossy());
+            counts.excluded += 1;
+            return false;
+        }
+        true
+    }
+}
+
+/// Returns `path` made absolute with symlinks resolved, even if it doesn't
+/// exist yet, by resolving its longest existing ancestor.
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let absolute = std::env::current_dir()?.join(path);
+    let mut existing = absolute.as_path();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(resolved) = existing.canonicalize() {
+            return Ok(rest
+                .iter()
+                .rev()
+                .fold(resolved, |path, name| path.join(name)));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return Ok(absolute),
+        }
+    }
+}
+
+/// Checks that none of `written`, the directories a run writes to, are in
+/// one of `sources`, where what the run writes would be loaded by the next.
+/// Returns the written directories resolved.
+fn check_nested_directories(
+    sources: &[(&str, &Path)],
+    written: &[(&str, &Path)],
+) -> Result<Vec<PathBuf>> {
+    let mut resolved_written = Vec::with_capacity(written.len());
+    for (name, directory) in written.iter() {
+        let resolved = resolve_path(directory)?;
+        for (side, source) in sources.iter() {
+            let resolved_source = match source.canonicalize() {
+                Ok(resolved_source) => resolved_source,
+                Err(_) => continue,
+            };
+            if resolved.starts_with(&resolved_source) {
+                return Err(anyhow!(
+                    "The {} directory {} is inside the {} directory {}",
+                    name,
+                    directory.to_string_lossy(),
+                    side,
+                    source.to_string_lossy()
+                ));
+            }
+        }
+        resolved_written.push(resolved);
+    }
+    Ok(resolved_written)
+}
+
+/// Returns the image paths in `directory` passing `filter`, sorted.
+fn list_directory(
+    directory: &Path,
+    filter: &EntryFilter,
+    counts: &mut LoadCounts,
+) -> Result<Vec<PathBuf>> {
+    let mut source_paths = Vec::new();
+    let mut skipped = 0;
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        match filter.skip_reason(&path) {
+            Some(reason) => {
+                debug!("Ignoring {}: {}", path.to_string_lossy(), reason);
+                skipped += 1;
+            }
+            None => {
+                let relative = path.strip_prefix(directory).unwrap_or(&path);
+                if filter.passes_globs(&path, relative, counts) {
+                    source_paths.push(path);
+                }
+            }
+        }
+    }
+    if skipped > 0 {
+        debug!(
+            "Ignored {} entries of {} that are not images",
+            skipped,
+            directory.to_string_lossy()
+        );
+    }
+    // Directory order is arbitrary, so sort to keep matching and logs stable
+    // between runs.
+    source_paths.sort();
+    Ok(source_paths)
+}
+
+/// Returns the image paths under `directory` and its subdirectories passing
+/// `filter`, grouped by the directory containing them and sorted. Hidden
+/// subdirectories are skipped like hidden files, and a symlink leading back to
+/// a directory being walked is skipped with a warning.
+fn list_tree(
+    directory: &Path,
+    filter: &EntryFilter,
+    counts: &mut LoadCounts,
+) -> Result<Vec<Source>> {
+    let walker = WalkDir::new(directory)
+        .min_depth(1)
+        .follow_links(filter.follow_symlinks)
+        .into_iter()
+        .filter_entry(|entry| {
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            !entry.file_type().is_dir() || filter.include_hidden || !hidden
+        });
+    let mut sources: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let mut skipped = 0;
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => match (error.path(), error.loop_ancestor()) {
+                (Some(path), Some(ancestor)) => {
+                    warn!(
+                        "Not following {}, which links back to {}",
+                        path.to_string_lossy(),
+                        ancestor.to_string_lossy()
+                    );
+                    continue;
+                }
+                _ => return Err(error.into()),
+            },
+        };
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let path = entry.into_path();
+        match filter.skip_reason(&path) {
+            Some(reason) => {
+                debug!("Ignoring {}: {}", path.to_string_lossy(), reason);
+                skipped += 1;
+            }
+            None => {
+                let relative = path.strip_prefix(directory).unwrap_or(&path);
+                if filter.passes_globs(&path, relative, counts) {
+                    let parent = path.parent().unwrap_or(directory).to_owned();
+                    sources.entry(parent).or_default().push(path);
+                }
+            }
+        }
+    }
+    if skipped > 0 {
+        debug!(
+            "Ignored {} entries under {} that are not images",
+            skipped,
+            directory.to_string_lossy()
+        );
+    }
+    Ok(sources
+        .into_iter()
+        .map(|(parent, mut source_paths)| {
+            source_paths.sort();
+            (parent, source_paths)
+        })
+        .collect())
+}
+
+/// Returns the images of `directory` passing `filter`, as sources grouped by
+/// the directory containing them, including subdirectories if recursive.
+/// Unless the filter allows it, a directory with no images is an error, so
+/// that a mistyped path fails rather than matching nothing.
+fn list_sources(
+    directory: &Path,
+    side: &str,
+    filter: &EntryFilter,
+    counts: &mut LoadCounts,
+) -> Result<Vec<Source>> {
+    let sources = if filter.recursive {
+        list_tree(directory, filter, counts)?
+    } else {
+        vec![(
+            directory.to_owned(),
+            list_directory(directory, filter, counts)?,
+        )]
+    };
+    let empty = sources
+        .iter()
+        .all(|(_, source_paths)| source_paths.is_empty());
+    if empty && !filter.allow_empty {
+        return Err(anyhow!(
+            "The {} directory {} has no images, use --allow-empty to continue anyway",
+            side,
+            directory.to_string_lossy()
+        ));
+    }
+    Ok(sources)
+}
+
+/// Returns `path` relative to the closest of `roots` containing it.
+fn relative_path<'a>(path: &'a Path, roots: &[PathBuf]) -> Option<&'a Path> {
+    roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|relative| relative.components().count())
+}
+
+/// Sets the relative path of each of `phashes` from the closest of `roots`
+/// containing it. Images in none keep their file name.
+fn set_relative_paths(phashes: &mut [PathPhash], roots: &[PathBuf]) {
+    for phash in phashes.iter_mut() {
+        if let Some(relative) = relative_path(&phash.path, roots) {
+            phash.relative = relative.to_owned();
+        }
+    }
+}
+
+/// Checks that a source directory exists, rather than creating it, so that a
+/// mistyped path fails.
+fn check_source_directory(directory: &Path, side: &str) -> Result<()> {
+    if !directory.is_dir() {
+        return Err(anyhow!(
+            "The {} directory {} does not exist",
+            side,
+            directory.to_string_lossy()
+        ));
+    }
+    Ok(())
+}
+
+/// Reads a list of image paths, one per line, from `list` or stdin if it is
+/// `-`. Relative paths are resolved against the first of `directories`
+/// containing them, or the working directory if none are given. Returns the
+/// paths grouped by parent directory, and the number of missing entries,
+/// which are skipped with a warning.
+fn read_path_list(list: &Path, directories: &[PathBuf]) -> Result<(Vec<Source>, usize)> {
+    let contents = if list == Path::new("-") {
+        let mut contents = String::new();
+        std::io::stdin().read_to_string(&mut contents)?;
+        contents
+    } else {
+        read_to_string(list)?
+    };
+
+    let mut sources: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let mut missing = 0;
+    for (index, line) in contents.lines().enumerate() {
+        let entry = line.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let entry = Path::new(entry);
+        let resolved = if entry.is_absolute() || directories.is_empty() {
+            Some(entry.to_owned()).filter(|path| path.is_file())
+        } else {
+            directories
+                .iter()
+                .map(|directory| directory.join(entry))
+                .find(|path| path.is_file())
+        };
+        match resolved {
+            Some(path) => {
+                let parent = path.parent().unwrap_or_else(|| Path::new(".")).to_owned();
+                sources.entry(parent).or_default().push(path);
+            }
+            None => {
+                warn!(
+                    "{}:{}: no such file {}, skipping",
+                    list.to_string_lossy(),
+                    index + 1,
+                    entry.to_string_lossy()
+                );
+                missing += 1;
+            }
+        }
+    }
+    for paths in sources.values_mut() {
+        paths.sort();
+        paths.dedup();
+    }
+    Ok((sources.into_iter().collect(), missing))
+}
+
+/// Name of the cache subdirectory for a source directory, unique to its
+/// canonical path so that file names shared between directories do not
+/// collide.
+fn directory_cache_key(directory: &Path) -> String {
+    let canonical = fs::canonicalize(directory).unwrap_or_else(|_| directory.to_owned());
+    // FNV-1a, which unlike `DefaultHasher` is stable between releases.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in canonical.to_string_lossy().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    let name = canonical.file_name().unwrap_or_default().to_string_lossy();
+    format!("{}-{:016x}", name, hash)
+}
+
+/// Loads phashes from each of `directories` into a single pool, caching each
+/// directory, and each subdirectory if recursive, separately under
+/// `cache_directory/<side>/<hash config>`.
+fn load_directories(
+    directories: &[PathBuf],
+    cache_directory: &Path,
+    side: &str,
+    coarse: bool,
+    filter: &EntryFilter,
+    options: &LoadOptions,
+) -> Result<(Vec<PathPhash>, LoadCounts)> {
+    let mut listed = LoadCounts::default();
+    let mut sources = Vec::with_capacity(directories.len());
+    for directory in directories.iter() {
+        sources.extend(list_sources(directory, side, filter, &mut listed)?);
+    }
+    let (mut phashes, mut counts) = load_sources(sources, cache_directory, side, coarse, options)?;
+    set_relative_paths(&mut phashes, directories);
+    counts += listed;
+    Ok((phashes, counts))
+}
+
+/// Returns a progress bar over `total` items labelled with `message`, or
+/// `None` if progress is not shown.
+fn new_progress_bar(show: bool, total: usize, message: &str) -> Option<ProgressBar> {
+    if !show {
+        return None;
+    }
+    let progress_bar = ProgressBar::new(total as u64);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg} [{bar:40}] {pos}/{len} ({rate}/s, ETA {eta})")
+            .expect("Progress bar template is invalid.")
+            .with_key(
+                "rate",
+                |state: &ProgressState, writer: &mut dyn fmt::Write| {
+                    let _ = write!(writer, "{:.1}", state.per_sec());
+                },
+            )
+            .progress_chars("=> "),
+    );
+    progress_bar.set_message(message.to_owned());
+    Some(progress_bar)
+}
+
+/// Runs `f`, which logs, with `progress_bar` hidden so the two don't overlap.
+fn suspend<R>(progress_bar: Option<&ProgressBar>, f: impl FnOnce() -> R) -> R {
+    match progress_bar {
+        Some(progress_bar) => progress_bar.suspend(f),
+        None => f(),
+    }
+}
+
+/// Name of the cache directories of hashes loaded with `options`, which
+/// includes everything that changes the hash of an image.
+fn cache_hash_name(options: &LoadOptions) -> String {
+    let mut hash_name = options.hash.cache_name();
+    if options.cleanup {
+        hash_name.push_str("-cleanup");
+        hash_name.push_str(&options.border.cache_suffix());
+    }
+    if let Some(percent) = options.center_crop {
+        hash_name.push_str(&format!("-crop{}", percent));
+    }
+    if let Some(sigma) = options.preblur {
+        hash_name.push_str(&format!("-blur{}", sigma));
+    }
+    hash_name.push_str(&options.preprocess.cache_suffix());
+    // Blank images are only found when decoded, so cached hashes of images
+    // loaded without `--skip-blank` cannot be reused with it.
+    if let Some(threshold) = options.skip_blank {
+        hash_name.push_str(&format!("-skipblank{}", threshold));
+    }
+    hash_name
+}
+
+/// Loads phashes from each of `sources` into a single pool.
+fn load_sources(
+    sources: Vec<Source>,
+    cache_directory: &Path,
+    side: &str,
+    coarse: bool,
+    options: &LoadOptions,
+) -> Result<(Vec<PathPhash>, LoadCounts)> {
+    let hash_name = cache_hash_name(options);
+    let total = sources
+        .iter()
+        .map(|(_, source_paths)| source_paths.len())
+        .sum();
+    let progress_bar = new_progress_bar(options.progress, total, &format!("Hashing {}", side));
+    let mut phashes = Vec::new();
+    let mut counts = LoadCounts::default();
+    for (directory, source_paths) in sources.into_iter() {
+        let key = match options.cache_key {
+            CacheKey::Name => directory_cache_key(&directory),
+            CacheKey::Content => "content".to_owned(),
+        };
+        let cache_dir = cache_directory.join(side).join(&hash_name).join(&key);
+        fs::create_dir_all(&cache_dir)?;
+        let coarse_cache_dir = if coarse {
+            let coarse_cache_dir = cache_directory
+                .join(format!("{}-coarse", side))
+                .join(&hash_name)
+                .join(&key);
+            fs::create_dir_all(&coarse_cache_dir)?;
+            Some(coarse_cache_dir)
+        } else {
+            None
+        };
+        suspend(progress_bar.as_ref(), || {
+            info!(
+                "Loading directory: {} (cache: {})",
+                &directory.to_string_lossy(),
+                &cache_dir.to_string_lossy()
+            )
+        });
+        let (loaded, loaded_counts) = load_phashes(
+            &directory,
+            source_paths,
+            &cache_dir,
+            &LoadOptions {
+                coarse_cache_dir: coarse_cache_dir.as_deref(),
+                progress_bar: progress_bar.as_ref(),
+                ..*options
+            },
+        )?;
+        phashes.extend(loaded);
+        counts += loaded_counts;
+    }
+    if let Some(progress_bar) = progress_bar {
+        progress_bar.finish_and_clear();
+    }
+    Ok((phashes, counts))
+}
+
+/// Portable record of an image's hashes in a `--export-hashes` manifest.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    coarse: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    histogram: Option<Vec<f32>>,
+    size: Option<u64>,
+    modified: Option<u64>,
+}
+
+/// Hashes of a set of fullsize images, which can be matched against without
+/// the images themselves.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    config: CacheConfig,
+    images: Vec<ManifestEntry>,
+}
+
+/// Writes `phashes`, hashed with `config`, to a manifest at `path`.
+fn export_hashes(path: &Path, phashes: &[PathPhash], config: CacheConfig) -> Result<()> {
+    let manifest = Manifest {
+        version: MANIFEST_VERSION,
+        config,
+        images: phashes
+            .iter()
+            .map(|phash| ManifestEntry {
+                path: escape_path(&phash.path).into_owned(),
+                hash: phash.phash.to_base64(),
+                coarse: phash.coarse.as_ref().map(ImageHash::to_base64),
+                width: phash.dimensions.map(|(width, _)| width),
+                height: phash.dimensions.map(|(_, height)| height),
+                histogram: phash.histogram.clone(),
+                size: phash.provenance.map(|provenance| provenance.size),
+                modified: phash.provenance.and_then(|provenance| provenance.modified),
+            })
+            .collect(),
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, &manifest)?;
+    writer.flush()?;
+    info!(
+        "Exported {} hashes to {}",
+        manifest.images.len(),
+        path.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Reads the images of a manifest at `path`, which must have been hashed with
+/// `config`, as distances between different hashes are meaningless.
+fn import_hashes(path: &Path, config: &CacheConfig) -> Result<Vec<PathPhash>> {
+    let manifest: Manifest = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if manifest.version != MANIFEST_VERSION {
+        return Err(anyhow!(
+            "Manifest {} has version {}, not {}",
+            path.to_string_lossy(),
+            manifest.version,
+            MANIFEST_VERSION
+        ));
+    }
+    if manifest.config != *config {
+        return Err(anyhow!(
+            "Manifest {} was hashed with {:?}, not {:?}",
+            path.to_string_lossy(),
+            manifest.config,
+            config
+        ));
+    }
+
+    let mut phashes = Vec::with_capacity(manifest.images.len());
+    for entry in manifest.images.into_iter() {
+        let modified = entry.modified;
+        let path = PathBuf::from(entry.path);
+        phashes.push(PathPhash {
+            file_name: path.file_name().unwrap_or_default().to_owned(),
+            relative: PathBuf::from(path.file_name().unwrap_or_default()),
+            phash: ImageHash::from_base64(&entry.hash)?,
+            coarse: match &entry.coarse {
+                Some(coarse) => Some(ImageHash::from_base64(coarse)?),
+                None => None,
+            },
+            dimensions: entry.width.zip(entry.height),
+            transformed: Vec::new(),
+            histogram: entry.histogram,
+            provenance: entry.size.map(|size| Provenance { size, modified }),
+            path,
+        });
+    }
+    info!(
+        "Imported {} hashes from {}",
+        phashes.len(),
+        path.to_string_lossy()
+    );
+    Ok(phashes)
+}
+
+/// Record of an accepted match in `matches.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MatchRecord {
+    thumb: String,
+    fullsize: String,
+    distance: u32,
+    transform: String,
+    /// Copy of the fullsize image in the output directory, if one was made.
+    output: Option<String>,
+    /// Size in bytes of the fullsize image when matched, which its copy has
+    /// unless it was converted. Not recorded by earlier releases.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// XXH3 digest of the contents of the fullsize image when matched, in
+    /// hex, with `--record-digests`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+}
+
+impl MatchRecord {
+    /// Records `output`, copied to `copy`. The size and digest of the
+    /// fullsize image are left out if it is converted when copied, as its
+    /// copy differs.
+    fn new(
+        output: &Match,
+        fullsize: &PathPhash,
+        copy: Option<&Path>,
+        converted: bool,
+        record_digest: bool,
+    ) -> Self {
+        let digest = if record_digest && !converted {
+            match fs::read(&output.fullsize) {
+                Ok(contents) => Some(format!("{:032x}", xxh3_128(&contents))),
+                Err(error) => {
+                    warn!(
+                        "Failed to read {} to record its digest: {}",
+                        output.fullsize.to_string_lossy(),
+                        error
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        MatchRecord {
+            thumb: escape_path(&output.thumb).into_owned(),
+            fullsize: escape_path(&output.fullsize).into_owned(),
+            distance: output.distance,
+            transform: output.transform.name().to_owned(),
+            output: copy.map(|copy| escape_path(copy).into_owned()),
+            size: if converted {
+                None
+            } else {
+                fullsize.provenance.map(|provenance| provenance.size)
+            },
+            digest,
+        }
+    }
+}
+
+/// Matches accepted by the runs into an output directory, so that `--resume`
+/// can skip their thumbnails.
+#[derive(Debug, Serialize, Deserialize)]
+struct MatchState {
+    version: u32,
+    /// Whether the last run was interrupted, leaving some thumbnails
+    /// unmatched or uncopied for `--resume`.
+    #[serde(default)]
+    interrupted: bool,
+    matches: Vec<MatchRecord>,
+}
+
+impl Default for MatchState {
+    fn default() -> Self {
+        MatchState {
+            version: MATCHES_VERSION,
+            interrupted: false,
+            matches: Vec::new(),
+        }
+    }
+}
+
+impl MatchState {
+    /// Records the copies of matches written to another path than planned,
+    /// by the destination they were planned for, returning whether any were.
+    fn record_fallbacks(&mut self, fallbacks: &HashMap<PathBuf, PathBuf>) -> bool {
+        let fallbacks: HashMap<Cow<str>, Cow<str>> = fallbacks
+            .iter()
+            .map(|(planned, written)| (escape_path(planned), escape_path(written)))
+            .collect();
+        let mut recorded = false;
+        for record in self.matches.iter_mut() {
+            let written = record
+                .output
+                .as_deref()
+                .and_then(|output| fallbacks.get(output));
+            if let Some(written) = written {
+                record.output = Some(written.clone().into_owned());
+                recorded = true;
+            }
+        }
+        recorded
+    }
+
+    /// Drops the records of the thumbnails of `thumbs_phashes`, which are
+    /// matched again, so those now needing review or unmatched keep none.
+    fn forget(&mut self, thumbs_phashes: &[PathPhash]) {
+        let rematched: HashSet<Cow<str>> = thumbs_phashes
+            .iter()
+            .map(|thumb_phash| escape_path(&thumb_phash.path))
+            .collect();
+        self.matches
+            .retain(|record| !rematched.contains(record.thumb.as_str()));
+    }
+
+    /// Reads the state at `path`, which is empty if no run has written one.
+    fn read(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(MatchState::default());
+        }
+        let state: MatchState = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if state.version != MATCHES_VERSION {
+            return Err(anyhow!(
+                "Match state {} has version {}, not {}",
+                path.to_string_lossy(),
+                state.version,
+                MATCHES_VERSION
+            ));
+        }
+        Ok(state)
+    }
+
+    /// Returns the thumbnails whose matches still hold, which are those with
+    /// an output that still exists. Copies of images with paths that are not
+    /// valid UTF-8 are never found, so those are matched again.
+    fn resumable(&self) -> HashSet<&str> {
+        self.matches
+            .iter()
+            .filter(|record| {
+                record
+                    .output
+                    .as_ref()
+                    .is_none_or(|output| Path::new(output).exists())
+            })
+            .map(|record| record.thumb.as_str())
+            .collect()
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let temporary = path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+/// Returns whether two images have aspect ratios within a factor of
+/// `max_delta` of each other. Images of unknown size are always compatible.
+fn aspect_compatible(a: &PathPhash, b: &PathPhash, max_delta: f64) -> bool {
+    let aspect = |dimensions: (u32, u32)| f64::from(dimensions.0) / f64::from(dimensions.1);
+    match (a.dimensions, b.dimensions) {
+        (Some(a), Some(b)) if a.1 > 0 && b.1 > 0 => {
+            let (a, b) = (aspect(a), aspect(b));
+            a.max(b) / a.min(b) <= max_delta
+        }
+        _ => true,
+    }
+}
+
+/// A file name pattern from a groups file. Patterns containing glob
+/// metacharacters are globs, anything else is a prefix.
+#[derive(Debug)]
+enum NamePattern {
+    Prefix(String),
+    Glob(glob::Pattern),
+}
+
+impl NamePattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        if pattern.contains(['*', '?', '[']) {
+            Ok(NamePattern::Glob(glob::Pattern::new(pattern)?))
+        } else {
+            Ok(NamePattern::Prefix(pattern.to_owned()))
+        }
+    }
+
+    fn matches(&self, name: &OsStr) -> bool {
+        let name = name.to_string_lossy();
+        match self {
+            NamePattern::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            NamePattern::Glob(pattern) => pattern.matches(&name),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            NamePattern::Prefix(prefix) => prefix,
+            NamePattern::Glob(pattern) => pattern.as_str(),
+        }
+    }
+}
+
+/// Restricts thumbnails matching one pattern to candidates matching another.
+#[derive(Debug)]
+struct Group {
+    thumb: NamePattern,
+    fullsize: NamePattern,
+}
+
+/// Reads groups from a headerless CSV file of `thumbnail,fullsize` patterns.
+/// Lines starting with `#` are ignored.
+fn load_groups(path: &Path) -> Result<Vec<Group>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .comment(Some(b'#'))
+        .from_path(path)?;
+    let mut groups = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |position| position.line());
+        let parse = |pattern: &str| {
+            NamePattern::parse(pattern.trim()).map_err(|error| {
+                anyhow!(
+                    "{}: line {}: invalid pattern {:?}: {}",
+                    path.to_string_lossy(),
+                    line,
+                    pattern,
+                    error
+                )
+            })
+        };
+        match (record.get(0), record.get(1), record.len()) {
+            (Some(thumb), Some(fullsize), 2) => groups.push(Group {
+                thumb: parse(thumb)?,
+                fullsize: parse(fullsize)?,
+            }),
+            _ => {
+                return Err(anyhow!(
+                    "{}: line {}: expected 2 columns, found {}",
+                    path.to_string_lossy(),
+                    line,
+                    record.len()
+                ))
+            }
+        }
+    }
+    Ok(groups)
+}
+
+/// Returns the first group the thumbnail belongs to.
+fn find_group<'a>(groups: &'a [Group], thumb: &OsStr) -> Option<&'a Group> {
+    groups.iter().find(|group| group.thumb.matches(thumb))
+}
+
+/// Returns whether the fullsize image is a candidate for the thumbnail.
+/// Thumbnails outside of any group are compared against every image.
+fn in_group(groups: &[Group], thumb: &OsStr, fullsize: &OsStr) -> bool {
+    find_group(groups, thumb).is_none_or(|group| group.fullsize.matches(fullsize))
+}
+
+/// Reassigns matches so that no fullsize image is claimed more than once,
+/// writing a report comparing each thumbnail's preferred and assigned match.
+fn assign_unique(
+    fullsize_phashes: &[PathPhash],
+    thumbs_phashes: &[PathPhash],
+    preferred: Vec<Option<Match>>,
+    assignment: Assignment,
+    groups: &[Group],
+    report_path: &Path,
+    dry_run: bool,
+) -> Result<Vec<Option<Match>>> {
+    // Pairs in different groups are never assigned to each other.
+    let distances: Vec<Vec<u32>> = thumbs_phashes
+        .iter()
+        .map(|thumb_phash| {
+            fullsize_phashes
+                .iter()
+                .map(|fullsize_phash| {
+                    if in_group(groups, &thumb_phash.file_name, &fullsize_phash.file_name) {
+                        thumb_distance(thumb_phash, &fullsize_phash.phash).0
+                    } else {
+                        u32::MAX
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    let mut assigned = match assignment {
+        Assignment::Greedy => assign_greedy(&distances),
+        Assignment::Optimal => assign_optimal(&distances),
+    };
+    for (thumb_index, fullsize_index) in assigned.iter_mut().enumerate() {
+        if let Some(index) = fullsize_index {
+            if distances[thumb_index][*index] == u32::MAX {
+                *fullsize_index = None;
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(create_report(report_path, dry_run)?);
+    writer.write_record([
+        "thumb",
+        "preferred",
+        "preferred_distance",
+        "assigned",
+        "assigned_distance",
+    ])?;
+    let mut outputs = Vec::with_capacity(thumbs_phashes.len());
+    for ((thumb_index, fullsize_index), preferred) in
+        assigned.into_iter().enumerate().zip(preferred)
+    {
+        let thumb_phash = &thumbs_phashes[thumb_index];
+        let output = fullsize_index.map(|fullsize_index| {
+            let fullsize_phash = &fullsize_phashes[fullsize_index];
+            let (distance, transform) = thumb_distance(thumb_phash, &fullsize_phash.phash);
+            Match {
+                thumb: thumb_phash.path.clone(),
+                fullsize: fullsize_phash.path.clone(),
+                distance,
+                transform,
+            }
+        });
+
+        let (preferred_name, preferred_distance) = match &preferred {
+            Some(preferred) => (
+                escape_path(&preferred.fullsize).into_owned(),
+                preferred.distance.to_string(),
+            ),
+            None => (String::new(), String::new()),
+        };
+        let (assigned_name, assigned_distance) = match &output {
+            Some(output) => (
+                escape_path(&output.fullsize).into_owned(),
+                output.distance.to_string(),
+            ),
+            None => (String::new(), String::new()),
+        };
+        if preferred_name != assigned_name {
+            info!(
+                "Reassigned: {} from {} to {}",
+                thumb_phash.path.to_string_lossy(),
+                preferred_name,
+                assigned_name
+            );
+        }
+        writer.write_record([
+            escape_path(&thumb_phash.path).as_ref(),
+            &preferred_name,
+            &preferred_distance,
+            &assigned_name,
+            &assigned_distance,
+        ])?;
+        outputs.push(output);
+    }
+    writer.flush()?;
+
+    Ok(outputs)
+}
+
+/// Hooks of the search for candidates, which skips fullsize images outside
+/// the thumbnail's group, of too different an aspect ratio, or in
+/// `excluded`, and records each search in the distance dump and progress
+/// bar, if any.
+struct CandidateHooks<'a> {
+    options: &'a MatchOptions,
+    fullsize_phashes: &'a [PathPhash],
+    excluded: &'a HashSet<&'a PathBuf>,
+    dump: Option<&'a DistanceDump>,
+    progress_bar: Option<&'a ProgressBar>,
+}
+
+impl SearchHooks for CandidateHooks<'_> {
+    fn start(&self, _thumb: &PathPhash) -> bool {
+        !interrupted()
+    }
+
+    fn accept(&self, thumb_phash: &PathPhash, fullsize_phash: &PathPhash) -> bool {
+        !self.excluded.contains(&fullsize_phash.path)
+            && in_group(
+                &self.options.groups,
+                &thumb_phash.file_name,
+                &fullsize_phash.file_name,
+            )
+            && self
+                .options
+                .max_aspect_delta
+                .is_none_or(|max_delta| aspect_compatible(thumb_phash, fullsize_phash, max_delta))
+    }
+
+    fn record_distances(&self) -> bool {
+        self.dump.is_some()
+    }
+
+    fn finish(&self, thumb_phash: &PathPhash, distances: &[(usize, u32)]) {
+        if let Some(dump) = self.dump {
+            dump.write(thumb_phash, self.fullsize_phashes, distances);
+        }
+        if let Some(progress_bar) = self.progress_bar {
+            progress_bar.inc(1);
+        }
+    }
+}
+
+impl MatchOptions {
+    /// Options of a search for the `limit` closest candidates.
+    fn search(&self, limit: usize) -> SearchOptions {
+        SearchOptions {
+            limit,
+            prefilter: self.prefilter,
+            max_distance: None,
+        }
+    }
+}
+
+/// Returns the `limit` closest candidates for the thumbnail, in order,
+/// skipping any fullsize images in `excluded`.
+fn find_candidates(
+    thumb_phash: &PathPhash,
+    search: &FullsizeSearch,
+    options: &MatchOptions,
+    limit: usize,
+    excluded: &HashSet<&PathBuf>,
+    dump: Option<&DistanceDump>,
+) -> Vec<Match> {
+    let hooks = CandidateHooks {
+        options,
+        fullsize_phashes: search.phashes,
+        excluded,
+        dump,
+        progress_bar: None,
+    };
+    search.search(thumb_phash, &options.search(limit), &hooks)
+}
+
+/// Matches thumbnails in order of their best distance, removing each fullsize
+/// image matched within `threshold` from the pool for later thumbnails. The
+/// processing order is written to `consumed.csv`.
+fn consume_matches(
+    search: &FullsizeSearch,
+    thumbs_phashes: &[PathPhash],
+    preferred: Vec<Option<Match>>,
+    options: &MatchOptions,
+    threshold: u32,
+    report_path: &Path,
+) -> Result<Vec<Option<Match>>> {
+    let mut order: Vec<usize> = (0..thumbs_phashes.len()).collect();
+    order.sort_by_key(|index| {
+        (
+            preferred[*index]
+                .as_ref()
+                .map_or(u32::MAX, |preferred| preferred.distance),
+            *index,
+        )
+    });
+
+    let mut writer = csv::Writer::from_writer(create_report(report_path, options.dry_run)?);
+    writer.write_record(["order", "thumb", "fullsize", "distance", "consumed"])?;
+    let mut consumed = HashSet::new();
+    let mut outputs = preferred;
+    for (position, thumb_index) in order.into_iter().enumerate() {
+        let thumb_phash = &thumbs_phashes[thumb_index];
+        let still_available = outputs[thumb_index]
+            .as_ref()
+            .is_some_and(|output| !consumed.contains(&output.fullsize));
+        if !still_available {
+            outputs[thumb_index] =
+                find_candidates(thumb_phash, search, options, 1, &consumed, None)
+                    .into_iter()
+                    .next();
+        }
+
+        let output = &outputs[thumb_index];
+        let is_consumed = output
+            .as_ref()
+            .is_some_and(|output| output.distance <= threshold);
+        if let Some(output) = output {
+            if is_consumed {
+                let fullsize_phash = search
+                    .phashes
+                    .iter()
+                    .find(|fullsize_phash| fullsize_phash.path == output.fullsize)
+                    .expect("Matched fullsize image was not loaded.");
+                consumed.insert(&fullsize_phash.path);
+            }
+        }
+        writer.write_record([
+            &(position + 1).to_string(),
+            escape_path(&thumb_phash.path).as_ref(),
+            &output.as_ref().map_or_else(String::new, |output| {
+                escape_path(&output.fullsize).into_owned()
+            }),
+            &output
+                .as_ref()
+                .map_or_else(String::new, |output| output.distance.to_string()),
+            &is_consumed.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+
+    Ok(outputs)
+}
+
+/// Which set of images is searched for each image of the other set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    /// Find the best fullsize image for each thumbnail.
+    Forward,
+    /// Find the best thumbnail for each fullsize image.
+    Reverse,
+}
+
+impl FromStr for Direction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "forward" => Ok(Direction::Forward),
+            "reverse" => Ok(Direction::Reverse),
+            _ => Err(anyhow!("Unknown direction: {}", s)),
+        }
+    }
+}
+
+/// Options controlling how matches are selected and reported.
+#[derive(Debug)]
+struct MatchOptions {
+    direction: Direction,
+    max_distance: Option<u32>,
+    top: Option<usize>,
+    unique: Option<Assignment>,
+    copy_orphans: bool,
+    conflicts_directory: Option<PathBuf>,
+    matcher: Matcher,
+    /// Number of chunks hashes are split into by `--matcher multi-index`.
+    index_chunks: usize,
+    /// Number of coarse candidates to compare fine hashes against.
+    prefilter: Option<usize>,
+    /// Maximum factor between the aspect ratios of a thumbnail and candidate.
+    max_aspect_delta: Option<f64>,
+    /// Minimum distance between the best and second best candidates.
+    min_margin: Option<u32>,
+    /// Transforms of each thumbnail to hash, in addition to the original.
+    transforms: Vec<Transform>,
+    /// Minimum color histogram similarity for a match not to need review.
+    min_color_similarity: Option<f32>,
+    /// Groups restricting which candidates each thumbnail is compared with.
+    groups: Vec<Group>,
+    /// Distance within which a match removes its fullsize image from the pool.
+    consume_matches: Option<u32>,
+    /// Distance above which a match needs manual review.
+    warn_distance: u32,
+    /// Directory to copy matches needing review to, instead of the output.
+    review_directory: Option<PathBuf>,
+    /// Also copy matches needing review to the output directory.
+    review_copy_output: bool,
+    hash: HashConfig,
+    cleanup: Cleanup,
+    border: BorderOptions,
+    preprocess: Preprocess,
+    center_crop: Option<u32>,
+    preblur: Option<f32>,
+    max_decode_pixels: Option<u64>,
+    cache_key: CacheKey,
+    cache_strict: bool,
+    /// Sides of the cache to rehash.
+    rebuild: Option<Rebuild>,
+    /// File name patterns of images to rehash.
+    invalidate: Vec<glob::Pattern>,
+    prune_cache: bool,
+    dry_run: bool,
+    /// Manifest to write the fullsize hashes to.
+    export_hashes: Option<PathBuf>,
+    /// Manifest to read the fullsize hashes from, instead of the images.
+    import_hashes: Option<PathBuf>,
+    /// Whether to show progress bars while hashing and matching.
+    progress: bool,
+    /// Number of threads copying images.
+    io_threads: usize,
+    on_collision: CollisionPolicy,
+    rename: Rename,
+    transfer: Transfer,
+    absolute_symlinks: bool,
+    preserve: Preserve,
+    /// Directory to set images that fail to decode aside in.
+    quarantine: Option<PathBuf>,
+    /// Move quarantined images, rather than copying them.
+    quarantine_move: bool,
+    /// Which entries of the fullsize directories to load.
+    fullsize_filter: EntryFilter,
+    /// Which entries of the thumbnail directories to load.
+    thumbnail_filter: EntryFilter,
+    /// Copy images directly into the output directory, rather than to their
+    /// path under their source directory.
+    flatten: bool,
+    error_policy: ErrorPolicy,
+    skip_blank: Option<f64>,
+    /// Skip thumbnails with a match from an earlier run.
+    resume: bool,
+    /// File name patterns of thumbnails to match again when resuming.
+    force: Vec<glob::Pattern>,
+    /// File to list every match of the run in, if any.
+    report: Option<PathBuf>,
+    report_format: ReportFormat,
+    /// Page to show the matches of the run on, with previews, if any.
+    html_report: Option<PathBuf>,
+    /// Height of the montages of matches needing review to write, if any.
+    review_montages: Option<u32>,
+    /// File to write every distance computed while matching to, if any.
+    dump_distances: Option<PathBuf>,
+    dump_format: DumpFormat,
+    /// Directory to move matched fullsize images to once copied, if any.
+    archive_matched: Option<PathBuf>,
+    /// How matched fullsize images are re-encoded when copied, if they are.
+    convert: Option<Conversion>,
+    /// Extensions of the sidecars copied along with fullsize images.
+    sidecars: Vec<String>,
+    /// Record a digest of each matched fullsize image in `matches.json`.
+    record_digests: bool,
+}
+
+impl MatchOptions {
+    /// Returns how the fullsize images are loaded.
+    fn fullsize_load_options(&self) -> LoadOptions<'_> {
+        LoadOptions {
+            coarse_cache_dir: None,
+            cleanup: self.cleanup.fullsize(),
+            border: self.border,
+            preprocess: self.preprocess,
+            center_crop: self.center_crop,
+            preblur: self.preblur,
+            max_decode_pixels: self.max_decode_pixels,
+            need_dimensions: self.max_aspect_delta.is_some(),
+            need_histogram: self.min_color_similarity.is_some(),
+            transforms: &[],
+            hash: self.hash,
+            cache_key: self.cache_key,
+            cache_strict: self.cache_strict,
+            rebuild: self.rebuild.is_some_and(Rebuild::fullsize),
+            invalidate: &self.invalidate,
+            prune_cache: self.prune_cache,
+            dry_run: self.dry_run,
+            error_policy: self.error_policy,
+            skip_blank: self.skip_blank,
+            progress: self.progress,
+            progress_bar: None,
+        }
+    }
+
+    /// Returns how the thumbnails are loaded.
+    fn thumbnail_load_options(&self) -> LoadOptions<'_> {
+        LoadOptions {
+            cleanup: self.cleanup.thumbs(),
+            transforms: &self.transforms,
+            rebuild: self.rebuild.is_some_and(Rebuild::thumbnails),
+            ..self.fullsize_load_options()
+        }
+    }
+}
+
+/// A phase of a run being timed, until it is recorded in `Timings`.
+#[derive(Debug)]
+struct Phase {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Phase {
+    fn start(name: &'static str) -> Self {
+        Phase {
+            name,
+            start: Instant::now(),
+        }
+    }
+}
+
+/// Wall time of each phase of a run, in the order they were first timed.
+/// Phases timed more than once add up.
+#[derive(Debug, Default, Clone)]
+struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    fn record(&mut self, phase: Phase) {
+        let elapsed = phase.start.elapsed();
+        match self.phases.iter_mut().find(|(name, _)| *name == phase.name) {
+            Some((_, duration)) => *duration += elapsed,
+            None => self.phases.push((phase.name, elapsed)),
+        }
+    }
+
+    /// Runs `f` as part of the phase `name`.
+    fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let phase = Phase::start(name);
+        let result = f();
+        self.record(phase);
+        result
+    }
+
+    /// Total time of the phases `names`.
+    fn total(&self, names: &[&str]) -> Duration {
+        self.phases
+            .iter()
+            .filter(|(name, _)| names.contains(name))
+            .map(|(_, duration)| *duration)
+            .sum()
+    }
+
+    /// Seconds spent in each phase.
+    fn to_json(&self) -> serde_json::Value {
+        self.phases
+            .iter()
+            .map(|(name, duration)| ((*name).to_owned(), duration.as_secs_f64().into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+/// Upper bounds of the buckets of best-match distances in the summary, for
+/// hashes of 64 bits. The last bucket holds every greater distance.
+const DISTANCE_BUCKETS: [u32; 5] = [0, 2, 5, 10, 20];
+/// Width of the bar of the largest bucket of the distance histogram.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Counts `distances` into buckets, labelled with the distances they hold.
+/// The bounds of [`DISTANCE_BUCKETS`] are scaled to hashes of `bits` bits, so
+/// that each bucket holds the same fraction of differing bits.
+fn distance_histogram(distances: &[u32], bits: usize) -> Vec<(String, usize)> {
+    let mut bounds: Vec<u32> = DISTANCE_BUCKETS
+        .iter()
+        .map(|bound| (*bound as usize * bits / 64) as u32)
+        .collect();
+    bounds.dedup();
+    let mut buckets = Vec::new();
+    let mut low = 0;
+    for bound in bounds.iter() {
+        let label = if low == *bound {
+            low.to_string()
+        } else {
+            format!("{}-{}", low, bound)
+        };
+        let count = distances
+            .iter()
+            .filter(|distance| (low..=*bound).contains(*distance))
+            .count();
+        buckets.push((label, count));
+        low = bound + 1;
+    }
+    let count = distances
+        .iter()
+        .filter(|distance| **distance >= low)
+        .count();
+    buckets.push((format!(">{}", low - 1), count));
+    buckets
+}
+
+/// Best-match distances of the thumbnails of a run, to help pick thresholds.
+#[derive(Debug, Default)]
+struct DistanceSummary {
+    buckets: Vec<(String, usize)>,
+    min: u32,
+    median: u32,
+    max: u32,
+    /// The match of the greatest distance that was accepted.
+    worst: Option<Match>,
+}
+
+impl DistanceSummary {
+    /// Summarises `distances`, the best of each thumbnail, if there are any.
+    fn new(mut distances: Vec<u32>, worst: Option<&Match>, bits: usize) -> Option<Self> {
+        distances.sort_unstable();
+        Some(DistanceSummary {
+            buckets: distance_histogram(&distances, bits),
+            min: *distances.first()?,
+            median: distances[(distances.len() - 1) / 2],
+            max: *distances.last()?,
+            worst: worst.cloned(),
+        })
+    }
+
+    fn log(&self) {
+        info!(
+            "Best distances: min {}, median {}, max {}",
+            self.min, self.median, self.max
+        );
+        let largest = self.buckets.iter().map(|(_, count)| *count).max();
+        let label_width = self.buckets.iter().map(|(label, _)| label.len()).max();
+        for (label, count) in self.buckets.iter() {
+            let bar = count * HISTOGRAM_WIDTH / largest.unwrap_or(1).max(1);
+            let line = format!(
+                "{:>label_width$} | {:>5} {}",
+                label,
+                count,
+                "#".repeat(bar),
+                label_width = label_width.unwrap_or(0)
+            );
+            info!("{}", line.trim_end());
+        }
+        if let Some(worst) = &self.worst {
+            info!(
+                "Worst accepted match: {} to {} at distance {}",
+                worst.thumb.to_string_lossy(),
+                worst.fullsize.to_string_lossy(),
+                worst.distance
+            );
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "buckets": self
+                .buckets
+                .iter()
+                .map(|(label, count)| serde_json::json!({
+                    "distances": label,
+                    "count": count,
+                }))
+                .collect::<Vec<_>>(),
+            "min": self.min,
+            "median": self.median,
+            "max": self.max,
+            "worst": self.worst.as_ref().map(|worst| serde_json::json!({
+                "thumb": escape_path(&worst.thumb),
+                "fullsize": escape_path(&worst.fullsize),
+                "distance": worst.distance,
+            })),
+        })
+    }
+}
+
+/// Counts of how each image fared, logged at the end of a run.
+#[derive(Debug, Default)]
+struct Summary {
+    /// Thumbnails searched for.
+    thumbnails_total: usize,
+    matched: usize,
+    /// Matches needing manual review, including ambiguous thumbnails.
+    review: usize,
+    /// Images skipped because they could not be hashed.
+    failed: usize,
+    unmatched: usize,
+    /// Fullsize images matched by no thumbnail, in this run or those resumed.
+    unclaimed: usize,
+    /// Time spent loading and hashing images.
+    loading: Duration,
+    /// Time spent comparing hashes.
+    matching: Duration,
+    /// Cache entries computed, reused and pruned while loading.
+    hashed: usize,
+    cached: usize,
+    pruned: usize,
+    /// Whether this was a `--dry-run`, which only lists what it would do.
+    dry_run: bool,
+    /// How the images of each side were loaded, for `--stats`.
+    fullsize: LoadCounts,
+    thumbnails: LoadCounts,
+    timings: Timings,
+    /// Hash distances computed while matching.
+    comparisons: u64,
+    copied: CopyCounts,
+    archived: ArchiveCounts,
+    /// Thumbnails not matched, as the run was interrupted.
+    not_searched: usize,
+    /// Best-match distances, if any thumbnail was matched.
+    distances: Option<DistanceSummary>,
+}
+
+impl Summary {
+    fn log(&self) {
+        emit(
+            Level::Info,
+            Event::Summary {
+                thumbnails: self.thumbnails_total,
+                matched: self.matched,
+                review: self.review,
+                failed: self.failed,
+                unmatched: self.unmatched,
+                loading: self.loading,
+                hashed: self.hashed,
+                cached: self.cached,
+                matching: self.matching,
+            },
+        );
+        if let Some(distances) = &self.distances {
+            distances.log();
+        }
+        if self.unclaimed > 0 {
+            info!(
+                "{} fullsize images were not matched by any thumbnail",
+                self.unclaimed
+            );
+        }
+        let failures = self
+            .fullsize
+            .failures
+            .iter()
+            .chain(self.thumbnails.failures.iter());
+        for (index, failure) in failures.enumerate() {
+            if index == 0 {
+                info!("Failed files:");
+            }
+            warn!("{}: {}", failure.path.to_string_lossy(), failure.error);
+        }
+        let not_included = self.fullsize.not_included + self.thumbnails.not_included;
+        let excluded = self.fullsize.excluded + self.thumbnails.excluded;
+        if not_included > 0 || excluded > 0 {
+            info!(
+                "Skipped {} images not included, and {} excluded",
+                not_included, excluded
+            );
+        }
+        if self.dry_run {
+            info!(
+                "Dry run: {} files would be copied ({:.2} GB)",
+                self.copied.copied,
+                self.copied.bytes as f64 / 1e9
+            );
+        } else if self.copied.copied > 0 || self.copied.failed > 0 {
+            info!(
+                "Copied {} files ({:.1} MB), {} failed",
+                self.copied.copied,
+                self.copied.bytes as f64 / 1e6,
+                self.copied.failed
+            );
+        }
+        if self.archived.archived > 0 || self.archived.failed > 0 {
+            info!(
+                "{} {} matched fullsize images, {} failed",
+                if self.dry_run {
+                    "Would archive"
+                } else {
+                    "Archived"
+                },
+                self.archived.archived,
+                self.archived.failed
+            );
+        }
+        let not_loaded = self.fullsize.interrupted + self.thumbnails.interrupted;
+        if not_loaded > 0 || self.not_searched > 0 {
+            warn!(
+                "Not loaded {} images, and not matched {} thumbnails, as the run was interrupted",
+                not_loaded, self.not_searched
+            );
+        }
+        if self.copied.interrupted > 0 {
+            warn!(
+                "Not copied {} files, as the run was interrupted",
+                self.copied.interrupted
+            );
+        }
+        if self.pruned > 0 {
+            info!(
+                "{} {} cache entries of missing images",
+                if self.dry_run {
+                    "Would prune"
+                } else {
+                    "Pruned"
+                },
+                self.pruned
+            );
+        }
+    }
+
+    /// Writes the statistics of this run as JSON to `path`, or stdout for `-`.
+    fn write_stats(&self, path: &Path, threads: usize, io_threads: usize) -> Result<()> {
+        let stats = serde_json::json!({
+            "threads": threads,
+            "io_threads": io_threads,
+            "thumbnails_total": self.thumbnails_total,
+            "matched": self.matched,
+            "review": self.review,
+            "failed": self.failed,
+            "unmatched": self.unmatched,
+            "unclaimed": self.unclaimed,
+            "fullsize": self.fullsize,
+            "thumbnails": self.thumbnails,
+            "phases": self.timings.to_json(),
+            "comparisons": self.comparisons,
+            "distances": self.distances.as_ref().map(DistanceSummary::to_json),
+            "copies": self.copied,
+            "archived": self.archived,
+            "failures": self
+                .fullsize
+                .failures
+                .iter()
+                .chain(self.thumbnails.failures.iter())
+                .map(|failure| serde_json::json!({
+                    "path": escape_path(&failure.path),
+                    "error": failure.error,
+                }))
+                .collect::<Vec<_>>(),
+        });
+        if path == Path::new("-") {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            serde_json::to_writer_pretty(&mut stdout, &stats)?;
+            writeln!(stdout)?;
+        } else {
+            let mut writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer_pretty(&mut writer, &stats)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Exit code for `--strict`, zero if nothing needs attention.
+    fn exit_code(&self) -> i32 {
+        if self.failed > 0 {
+            EXIT_FAILED
+        } else if self.review > 0 {
+            EXIT_REVIEW
+        } else {
+            0
+        }
+    }
+}
+
+/// Finds the best thumbnail for each fullsize image, writing them to
+/// `reverse.csv`. Fullsize images with no thumbnail within `--max-distance`,
+/// or the review threshold, are listed in `orphans.txt`, and optionally copied
+/// to the output directory.
+fn match_fullsizes(
+    fullsize_phashes: &[PathPhash],
+    thumbs_phashes: &[PathPhash],
+    output_directory: &Path,
+    options: &MatchOptions,
+    copies: &mut Vec<PlannedCopy>,
+    names: &mut OutputNames,
+) -> Result<Summary> {
+    let max_distance = options.max_distance.unwrap_or(options.warn_distance);
+    let mut writer = csv::Writer::from_writer(create_report(
+        &output_directory.join(REVERSE_FILE_NAME),
+        options.dry_run,
+    )?);
+    writer.write_record(["fullsize", "thumb", "distance"])?;
+
+    let mut orphans = Vec::new();
+    for fullsize_phash in fullsize_phashes.iter() {
+        let best = thumbs_phashes
+            .iter()
+            .map(|thumb_phash| {
+                (
+                    thumb_distance(thumb_phash, &fullsize_phash.phash).0,
+                    &thumb_phash.path,
+                )
+            })
+            .min();
+
+        let fullsize = escape_path(&fullsize_phash.path);
+        match best {
+            Some((distance, thumb)) => {
+                writer.write_record([
+                    fullsize.as_ref(),
+                    escape_path(thumb).as_ref(),
+                    &distance.to_string(),
+                ])?;
+                if distance > max_distance {
+                    emit(
+                        Level::Info,
+                        Event::Orphan {
+                            fullsize: &fullsize_phash.path,
+                            closest: Some(Closest {
+                                file: thumb,
+                                distance,
+                            }),
+                        },
+                    );
+                    orphans.push(fullsize_phash);
+                } else {
+                    emit(
+                        Level::Info,
+                        Event::Found {
+                            fullsize: &fullsize_phash.path,
+                            thumb,
+                            distance,
+                        },
+                    );
+                }
+            }
+            None => {
+                writer.write_record([fullsize.as_ref(), "", ""])?;
+                emit(
+                    Level::Info,
+                    Event::Orphan {
+                        fullsize: &fullsize_phash.path,
+                        closest: None,
+                    },
+                );
+                orphans.push(fullsize_phash);
+            }
+        }
+    }
+    writer.flush()?;
+
+    let mut file = create_report(&output_directory.join(ORPHANS_FILE_NAME), options.dry_run)?;
+    for orphan in orphans.iter() {
+        writeln!(file, "{}", escape_path(&orphan.path))?;
+    }
+
+    if options.copy_orphans {
+        for orphan in orphans.iter() {
+            if let Some(destination) = names.destination(output_directory, orphan, None, true)? {
+                let copy = PlannedCopy {
+                    source: orphan.path.clone(),
+                    destination,
+                    transfer: options.transfer,
+                    convert: names.conversion(orphan),
+                };
+                names.plan_with_sidecars(copies, copy, &options.sidecars);
+            }
+        }
+    }
+
+    Ok(Summary {
+        matched: fullsize_phashes.len() - orphans.len(),
+        unmatched: orphans.len(),
+        ..Summary::default()
+    })
+}
+
+/// Groups matches by fullsize image, keeping only those claimed by more than
+/// one thumbnail.
+fn find_conflicts(matches: &[Match]) -> BTreeMap<&PathBuf, Vec<&Match>> {
+    let mut claims: BTreeMap<&PathBuf, Vec<&Match>> = BTreeMap::new();
+    for output in matches.iter() {
+        claims.entry(&output.fullsize).or_default().push(output);
+    }
+    claims.retain(|_, claims| claims.len() > 1);
+    claims
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicatePair {
+    a: String,
+    b: String,
+    distance: u32,
+}
+
+/// Images which are all within the duplicate distance of another member.
+#[derive(Debug, Serialize)]
+struct DuplicateCluster {
+    members: Vec<String>,
+    distances: Vec<DuplicatePair>,
+}
+
+fn find_root(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+/// Clusters images connected by pairs at most `max_distance` apart.
+fn cluster_duplicates(phashes: &[PathPhash], max_distance: u32) -> Vec<DuplicateCluster> {
+    let pairs: Vec<(usize, usize, u32)> = (0..phashes.len())
+        .into_par_iter()
+        .flat_map(|a| {
+            (a + 1..phashes.len())
+                .filter_map(|b| {
+                    let distance = phashes[a].phash.dist(&phashes[b].phash);
+                    if distance <= max_distance {
+                        Some((a, b, distance))
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut parents: Vec<usize> = (0..phashes.len()).collect();
+    for (a, b, _) in pairs.iter() {
+        let root_a = find_root(&mut parents, *a);
+        let root_b = find_root(&mut parents, *b);
+        parents[root_b] = root_a;
+    }
+
+    let mut clusters: BTreeMap<usize, DuplicateCluster> = BTreeMap::new();
+    for (a, b, distance) in pairs.into_iter() {
+        let root = find_root(&mut parents, a);
+        let cluster = clusters.entry(root).or_insert_with(|| DuplicateCluster {
+            members: Vec::new(),
+            distances: Vec::new(),
+        });
+        cluster.distances.push(DuplicatePair {
+            a: escape_path(&phashes[a].path).into_owned(),
+            b: escape_path(&phashes[b].path).into_owned(),
+            distance,
+        });
+    }
+    for (index, phash) in phashes.iter().enumerate() {
+        let root = find_root(&mut parents, index);
+        if let Some(cluster) = clusters.get_mut(&root) {
+            cluster.members.push(escape_path(&phash.path).into_owned());
+        }
+    }
+
+    let mut clusters: Vec<DuplicateCluster> = clusters.into_values().collect();
+    for cluster in clusters.iter_mut() {
+        cluster.members.sort();
+    }
+    clusters.sort_by(|a, b| a.members.cmp(&b.members));
+    clusters
+}
+
+/// Reports clusters of near-duplicate fullsize images to `duplicates.json`.
+fn find_duplicates(
+    fullsize_directories: &[PathBuf],
+    cache_directory: &Path,
+    output_directory: &Path,
+    max_distance: u32,
+    filter: &EntryFilter,
+    load_options: &LoadOptions,
+) -> Result<()> {
+    for fullsize_directory in fullsize_directories.iter() {
+        check_source_directory(fullsize_directory, "fullsize")?;
+    }
+    if !load_options.dry_run {
+        fs::create_dir_all(output_directory)?;
+    }
+
+    let (fullsize_phashes, _) = load_directories(
+        fullsize_directories,
+        cache_directory,
+        "fullsize",
+        false,
+        filter,
+        load_options,
+    )?;
+    if interrupted() {
+        warn!("Not finding duplicates, as the run was interrupted");
+        return Ok(());
+    }
+    let clusters = cluster_duplicates(&fullsize_phashes, max_distance);
+    for cluster in clusters.iter() {
+        warn!("Duplicates: {}", cluster.members.join(", "));
+    }
+    info!("Found {} clusters of duplicates", clusters.len());
+
+    let file = create_report(
+        &output_directory.join(DUPLICATES_FILE_NAME),
+        load_options.dry_run,
+    )?;
+    serde_json::to_writer_pretty(file, &clusters)?;
+    Ok(())
+}
+
+fn match_thumbs(
+    fullsize_directories: &[PathBuf],
+    thumbnail_directories: &[PathBuf],
+    thumbnail_list: Option<&Path>,
+    cache_directory: &Path,
+    output_directory: &Path,
+    options: &MatchOptions,
+) -> Result<Summary> {
+    for fullsize_directory in fullsize_directories.iter() {
+        check_source_directory(fullsize_directory, "fullsize")?;
+    }
+    for thumbnail_directory in thumbnail_directories.iter() {
+        check_source_directory(thumbnail_directory, "thumbnail")?;
+    }
+    if !options.dry_run {
+        fs::create_dir_all(output_directory)?;
+        if let Some(review_directory) = &options.review_directory {
+            fs::create_dir_all(review_directory)?;
+        }
+    }
+
+    // Distances are only comparable between runs with the same hash.
+    let file = create_report(&output_directory.join(HASH_FILE_NAME), options.dry_run)?;
+    serde_json::to_writer_pretty(file, &options.hash.to_json())?;
+
+    let mut thumbs_listed = LoadCounts::default();
+    let (mut thumbnail_sources, thumbs_missing) = match thumbnail_list {
+        Some(thumbnail_list) => read_path_list(thumbnail_list, thumbnail_directories)?,
+        None => {
+            let mut sources = Vec::with_capacity(thumbnail_directories.len());
+            for directory in thumbnail_directories.iter() {
+                sources.extend(list_sources(
+                    directory,
+                    "thumbnail",
+                    &options.thumbnail_filter,
+                    &mut thumbs_listed,
+                )?);
+            }
+            (sources, 0)
+        }
+    };
+
+    // Thumbnails matched again replace their earlier records, and the rest
+    // are kept as they are.
+    let state_path = output_directory.join(MATCHES_FILE_NAME);
+    let mut state = if options.resume {
+        MatchState::read(&state_path)?
+    } else {
+        MatchState::default()
+    };
+    if options.resume {
+        let resumable = state.resumable();
+        let mut resumed = 0;
+        for (_, source_paths) in thumbnail_sources.iter_mut() {
+            source_paths.retain(|path| {
+                let forced = path
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .is_some_and(|file_name| {
+                        options
+                            .force
+                            .iter()
+                            .any(|pattern| pattern.matches(file_name))
+                    });
+                let skip = !forced && resumable.contains(escape_path(path).as_ref());
+                if skip {
+                    resumed += 1;
+                }
+                !skip
+            });
+        }
+        info!("Resuming: skipping {} thumbnails matched before", resumed);
+    }
+
+    let mut timings = Timings::default();
+    let fullsize_options = options.fullsize_load_options();
+    let fullsize_config = fullsize_options.cache_config(false);
+    let (fullsize_phashes, fullsize_counts) =
+        timings.time("load_fullsize", || match &options.import_hashes {
+            Some(manifest) => import_hashes(manifest, &fullsize_config)
+                .map(|phashes| (phashes, LoadCounts::default())),
+            None => load_directories(
+                fullsize_directories,
+                cache_directory,
+                "fullsize",
+                options.prefilter.is_some(),
+                &options.fullsize_filter,
+                &fullsize_options,
+            ),
+        })?;
+    if let Some(manifest) = &options.export_hashes {
+        if interrupted() {
+            warn!(
+                "Not exporting hashes to {}, as the run was interrupted",
+                manifest.to_string_lossy()
+            );
+        } else {
+            export_hashes(manifest, &fullsize_phashes, fullsize_config)?;
+        }
+    }
+    let phase = Phase::start("load_thumbnails");
+    let (mut thumbs_phashes, mut thumbs_counts) = load_sources(
+        thumbnail_sources,
+        cache_directory,
+        "thumbnail",
+        options.prefilter.is_some(),
+        &options.thumbnail_load_options(),
+    )?;
+    set_relative_paths(&mut thumbs_phashes, thumbnail_directories);
+    thumbs_counts += thumbs_listed;
+    timings.record(phase);
+    let loading = timings.total(&["load_fullsize", "load_thumbnails"]);
+    emit(
+        Level::Info,
+        Event::Phase {
+            name: "Loading phashes",
+            duration: loading,
+        },
+    );
+
+    let mut counts = fullsize_counts.clone();
+    counts += thumbs_counts.clone();
+    let failed = counts.failed + thumbs_missing;
+    if let Some(quarantine) = &options.quarantine {
+        quarantine_failures(
+            &fullsize_counts.failures,
+            fullsize_directories,
+            &quarantine.join("fullsize"),
+            options.quarantine_move,
+            options.preserve,
+            options.dry_run,
+        );
+        quarantine_failures(
+            &thumbs_counts.failures,
+            thumbnail_directories,
+            &quarantine.join("thumbnail"),
+            options.quarantine_move,
+            options.preserve,
+            options.dry_run,
+        );
+    }
+
+    // Matches against a partly loaded side would be wrong, so an interrupted
+    // run matches nothing.
+    if options.direction == Direction::Reverse && interrupted() {
+        return Ok(Summary {
+            failed,
+            loading,
+            hashed: counts.hashed,
+            cached: counts.cached,
+            pruned: counts.pruned,
+            dry_run: options.dry_run,
+            fullsize: fullsize_counts,
+            thumbnails: thumbs_counts,
+            timings,
+            ..Summary::default()
+        });
+    }
+    if options.direction == Direction::Reverse {
+        let mut copies = Vec::new();
+        let mut names = OutputNames::new(
+            options.on_collision,
+            options.rename,
+            options.flatten,
+            options.convert,
+        );
+        let summary = timings.time("match", || {
+            match_fullsizes(
+                &fullsize_phashes,
+                &thumbs_phashes,
+                output_directory,
+                options,
+                &mut copies,
+                &mut names,
+            )
+        })?;
+        let copied = timings.time("copy", || {
+            copy_files(
+                copies,
+                options.io_threads,
+                options.preserve,
+                options.absolute_symlinks,
+                options.dry_run,
+                options.progress,
+            )
+        })?;
+        return Ok(Summary {
+            copied: copied.counts,
+            failed,
+            loading,
+            hashed: counts.hashed,
+            cached: counts.cached,
+            pruned: counts.pruned,
+            dry_run: options.dry_run,
+            matching: timings.total(&["match"]),
+            fullsize: fullsize_counts,
+            thumbnails: thumbs_counts,
+            timings,
+            comparisons: matching::comparisons(),
+            ..summary
+        });
+    }
+
+    let MatchOptions {
+        max_distance,
+        top,
+        unique,
+        ..
+    } = *options;
+    let mut candidates_report = match top {
+        Some(_) => {
+            let mut writer = csv::Writer::from_writer(create_report(
+                &output_directory.join(CANDIDATES_FILE_NAME),
+                options.dry_run,
+            )?);
+            writer.write_record([
+                "thumb",
+                "rank",
+                "fullsize",
+                "distance",
+                "transform",
+                "group",
+            ])?;
+            Some(writer)
+        }
+        None => None,
+    };
+    let mut margins_report = match options.min_margin {
+        Some(_) => {
+            let mut writer = csv::Writer::from_writer(create_report(
+                &output_directory.join(MARGINS_FILE_NAME),
+                options.dry_run,
+            )?);
+            writer.write_record([
+                "thumb",
+                "best",
+                "best_distance",
+                "second",
+                "second_distance",
+                "margin",
+                "ambiguous",
+            ])?;
+            Some(writer)
+        }
+        None => None,
+    };
+    // The margin needs the runner up, even if only the best is reported.
+    let candidates_limit = std::cmp::max(
+        top.unwrap_or(1),
+        if options.min_margin.is_some() { 2 } else { 1 },
+    );
+    let mut ambiguous = BTreeSet::new();
+
+    // Candidates are found in parallel, then reported in thumbnail order.
+    let phase = Phase::start("match");
+    let search = FullsizeSearch::new(&fullsize_phashes, options.matcher, options.index_chunks);
+    let dump = match &options.dump_distances {
+        Some(path) => Some(DistanceDump::new(
+            path,
+            options.dump_format,
+            &thumbs_phashes,
+            &fullsize_phashes,
+            options,
+        )?),
+        None => None,
+    };
+    let progress_bar = new_progress_bar(options.progress, thumbs_phashes.len(), "Matching");
+    let all_candidates = matching::best_matches(
+        &thumbs_phashes,
+        &search,
+        &options.search(candidates_limit),
+        &CandidateHooks {
+            options,
+            fullsize_phashes: &fullsize_phashes,
+            excluded: &HashSet::new(),
+            dump: dump.as_ref(),
+            progress_bar: progress_bar.as_ref(),
+        },
+    );
+    if let Some(progress_bar) = progress_bar {
+        progress_bar.finish_and_clear();
+    }
+    if let Some(dump) = dump {
+        dump.finish()?;
+    }
+    // Thumbnails not searched before an interrupt keep their earlier records,
+    // and are matched by the next `--resume`.
+    let searched = all_candidates
+        .iter()
+        .filter(|candidates| candidates.is_some())
+        .count();
+    let not_searched = thumbs_phashes.len() - searched;
+    let (thumbs_phashes, all_candidates): (Vec<PathPhash>, Vec<Vec<Match>>) = thumbs_phashes
+        .into_iter()
+        .zip(all_candidates)
+        .filter_map(|(thumb_phash, candidates)| Some((thumb_phash, candidates?)))
+        .unzip();
+
+    let mut outputs = Vec::with_capacity(thumbs_phashes.len());
+    for (thumb_phash, candidates) in thumbs_phashes.iter().zip(all_candidates) {
+        let group = find_group(&options.groups, &thumb_phash.file_name);
+        if let Some(group) = group {
+            debug!(
+                "Grouped: {} with {}",
+                thumb_phash.path.to_string_lossy(),
+                group.fullsize.as_str()
+            );
+        }
+
+        if let Some(writer) = candidates_report.as_mut() {
+            for (rank, candidate) in candidates.iter().take(top.unwrap_or(0)).enumerate() {
+                writer.write_record([
+                    escape_path(&candidate.thumb).as_ref(),
+                    &(rank + 1).to_string(),
+                    escape_path(&candidate.fullsize).as_ref(),
+                    &candidate.distance.to_string(),
+                    candidate.transform.name(),
+                    group.map_or("", |group| group.thumb.as_str()),
+                ])?;
+            }
+        }
+
+        if let (Some(writer), Some(min_margin)) = (margins_report.as_mut(), options.min_margin) {
+            if let Some(best) = candidates.first() {
+                let second = candidates.get(1);
+                let margin = second.map(|second| second.distance - best.distance);
+                let is_ambiguous = margin.is_some_and(|margin| margin < min_margin);
+                writer.write_record([
+                    escape_path(&best.thumb).as_ref(),
+                    escape_path(&best.fullsize).as_ref(),
+                    &best.distance.to_string(),
+                    &second.map_or_else(String::new, |second| {
+                        escape_path(&second.fullsize).into_owned()
+                    }),
+                    &second.map_or_else(String::new, |second| second.distance.to_string()),
+                    &margin.map_or_else(String::new, |margin| margin.to_string()),
+                    &is_ambiguous.to_string(),
+                ])?;
+                if is_ambiguous {
+                    ambiguous.insert(best.thumb.clone());
+                }
+            }
+        }
+
+        outputs.push(candidates.into_iter().next());
+    }
+
+    if let Some(mut writer) = margins_report {
+        writer.flush()?;
+    }
+
+    if let Some(threshold) = options.consume_matches {
+        outputs = consume_matches(
+            &search,
+            &thumbs_phashes,
+            outputs,
+            options,
+            threshold,
+            &output_directory.join(CONSUMED_FILE_NAME),
+        )?;
+    }
+
+    if let Some(assignment) = unique {
+        outputs = assign_unique(
+            &fullsize_phashes,
+            &thumbs_phashes,
+            outputs,
+            assignment,
+            &options.groups,
+            &output_directory.join(ASSIGNMENTS_FILE_NAME),
+            options.dry_run,
+        )?;
+    }
+    timings.record(phase);
+
+    let fullsize_by_path: HashMap<&PathBuf, &PathPhash> = fullsize_phashes
+        .iter()
+        .map(|fullsize_phash| (&fullsize_phash.path, fullsize_phash))
+        .collect();
+    let thumbs_by_path: HashMap<&PathBuf, &PathPhash> = thumbs_phashes
+        .iter()
+        .map(|thumb_phash| (&thumb_phash.path, thumb_phash))
+        .collect();
+
+    state.forget(&thumbs_phashes);
+
+    // Copies are made together once every match is known. Copies kept from
+    // earlier runs keep their names.
+    let mut copies = Vec::new();
+    let mut names = OutputNames::new(
+        options.on_collision,
+        options.rename,
+        options.flatten,
+        options.convert,
+    );
+    for record in state.matches.iter() {
+        if let Some(output) = &record.output {
+            names
+                .sources
+                .insert(PathBuf::from(output), PathBuf::from(&record.fullsize));
+        }
+    }
+    let mut unmatched = Vec::new();
+    let mut matched = Vec::new();
+    // Fullsize images of matches needing review, which are never archived.
+    let mut reviewed = HashSet::new();
+    let mut gallery = Vec::new();
+    let mut montages = Vec::new();
+    // Matches to report once copied, with the paths their copies are planned
+    // for.
+    let mut reported = Vec::new();
+    let mut report = match &options.report {
+        Some(path) => Some(MatchReport::new(path, options.report_format)?),
+        None => None,
+    };
+    let mut color_review = Vec::new();
+    let mut review = Vec::new();
+    let mut summary = Summary {
+        failed,
+        loading,
+        hashed: counts.hashed,
+        cached: counts.cached,
+        pruned: counts.pruned,
+        dry_run: options.dry_run,
+        matching: timings.total(&["match"]),
+        fullsize: fullsize_counts,
+        thumbnails: thumbs_counts,
+        not_searched,
+        thumbnails_total: thumbs_phashes.len(),
+        ..Summary::default()
+    };
+    let best_distances: Vec<u32> = outputs
+        .iter()
+        .flatten()
+        .map(|output| output.distance)
+        .collect();
+    // Thumbnails without any candidate, such as those whose groups have no
+    // fullsize images, are unmatched too.
+    for (thumb_phash, output) in thumbs_phashes.iter().zip(outputs.iter()) {
+        if output.is_none() {
+            unmatched.push(thumb_phash.path.clone());
+        }
+    }
+    for output in outputs.into_iter().flatten() {
+        let decision = decide(
+            &output,
+            thumbs_by_path[&output.thumb],
+            fullsize_by_path[&output.fullsize],
+            ambiguous.contains(&output.thumb),
+            options,
+            &mut CopyPlan {
+                output_directory,
+                names: &mut names,
+                copies: &mut copies,
+            },
+        )?;
+        let decided = match decision {
+            Decision::Unmatched => {
+                unmatched.push(output.thumb);
+                continue;
+            }
+            Decision::Ambiguous => {
+                summary.review += 1;
+                continue;
+            }
+            Decision::Matched(decided) => *decided,
+        };
+        if let Some(similarity) = decided.color_similarity {
+            color_review.push((output.clone(), similarity));
+        }
+        if decided.needs_review {
+            summary.review += 1;
+            reviewed.insert(output.fullsize.clone());
+            if options.review_directory.is_some() {
+                review.push(output.clone());
+            }
+            if options.review_montages.is_some() {
+                montages.push(output.clone());
+            }
+        }
+        state.matches.extend(decided.record);
+        if report.is_some() {
+            reported.push((
+                output.clone(),
+                decided.needs_review,
+                decided.copy,
+                decided.sidecars,
+            ));
+        }
+        if options.html_report.is_some() {
+            gallery.push((output.clone(), decided.needs_review));
+        }
+        matched.push(output);
+    }
+    state.interrupted = interrupted();
+    if !options.dry_run {
+        state.write(&state_path)?;
+    }
+
+    let conflicts = find_conflicts(&matched);
+    if !conflicts.is_empty() {
+        info!("Conflicts:");
+    }
+    for (fullsize, claims) in conflicts.iter() {
+        let thumbs: Vec<String> = claims
+            .iter()
+            .map(|claim| format!("{} ({})", claim.thumb.to_string_lossy(), claim.distance))
+            .collect();
+        warn!(
+            "{} was matched by {} thumbnails: {}",
+            fullsize.to_string_lossy(),
+            claims.len(),
+            thumbs.join(", ")
+        );
+
+        if let Some(conflicts_directory) = &options.conflicts_directory {
+            let fullsize_stem = fullsize.file_stem().expect("No file stem.");
+            let conflict_directory = conflicts_directory.join(fullsize_stem);
+            if !options.dry_run {
+                fs::create_dir_all(&conflict_directory)?;
+            }
+            for claim in claims.iter() {
+                let thumb = thumbs_by_path[&claim.thumb];
+                if let Some(destination) =
+                    names.destination(&conflict_directory, thumb, None, false)?
+                {
+                    copies.push(PlannedCopy {
+                        source: claim.thumb.clone(),
+                        destination,
+                        transfer: Transfer::Copy,
+                        convert: None,
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(review_directory) = &options.review_directory {
+        write_review_list(
+            &review_directory.join(REVIEW_FILE_NAME),
+            &review,
+            false,
+            options.dry_run,
+        )?;
+    }
+
+    if !color_review.is_empty() {
+        info!("Color review:");
+    }
+    for (output, similarity) in color_review.iter() {
+        warn!(
+            "{} to {} has color similarity {:.2}",
+            output.thumb.to_string_lossy(),
+            output.fullsize.to_string_lossy(),
+            similarity
+        );
+    }
+
+    if let Some(mut writer) = candidates_report {
+        writer.flush()?;
+    }
+
+    unmatched.sort();
+    if max_distance.is_some() {
+        let mut file = create_report(&output_directory.join(UNMATCHED_FILE_NAME), options.dry_run)?;
+        for thumb in unmatched.iter() {
+            writeln!(file, "{}", escape_path(thumb))?;
+        }
+    }
+
+    // Fullsize images are told apart by their full path, as those in
+    // different source directories may share a name. Matches kept from
+    // earlier runs claim their images too.
+    let claimed: HashSet<Cow<str>> = matched
+        .iter()
+        .map(|output| escape_path(&output.fullsize))
+        .chain(
+            state
+                .matches
+                .iter()
+                .map(|record| Cow::Borrowed(record.fullsize.as_str())),
+        )
+        .collect();
+    let mut unclaimed: Vec<&PathBuf> = fullsize_phashes
+        .iter()
+        .map(|fullsize_phash| &fullsize_phash.path)
+        .filter(|path| !claimed.contains(&escape_path(path)))
+        .collect();
+    unclaimed.sort();
+    let mut file = create_report(&output_directory.join(UNCLAIMED_FILE_NAME), options.dry_run)?;
+    for fullsize in unclaimed.iter() {
+        writeln!(file, "{}", escape_path(fullsize))?;
+    }
+
+    if let (Some(height), false) = (options.review_montages, options.dry_run) {
+        let directory = match &options.review_directory {
+            Some(review_directory) => review_directory.clone(),
+            None => output_directory.join(MONTAGES_DIRECTORY_NAME),
+        };
+        write_montages(&directory, montages, height, options.max_decode_pixels)?;
+    }
+
+    if let Some(html_report) = &options.html_report {
+        write_gallery(
+            html_report,
+            &gallery,
+            &unmatched,
+            &conflicts,
+            options.max_decode_pixels,
+        )?;
+    }
+
+    let archived_copies = match options.archive_matched {
+        Some(_) => copies.clone(),
+        None => Vec::new(),
+    };
+    let copied = timings.time("copy", || {
+        copy_files(
+            copies,
+            options.io_threads,
+            options.preserve,
+            options.absolute_symlinks,
+            options.dry_run,
+            options.progress,
+        )
+    })?;
+    summary.copied = copied.counts;
+    if state.record_fallbacks(&copied.fallbacks) && !options.dry_run {
+        state.write(&state_path)?;
+    }
+    if let Some(report) = report.as_mut() {
+        for (output, needs_review, copy, sidecars) in reported.iter() {
+            let copy = copy.as_deref().map(|copy| copied.written(copy));
+            report.push(output, *needs_review, copy, sidecars)?;
+        }
+    }
+    if let Some(archive) = &options.archive_matched {
+        if interrupted() {
+            warn!("Not archiving matched fullsize images, as the run was interrupted");
+        } else {
+            let mut sources: Vec<&PathBuf> = matched
+                .iter()
+                .map(|output| &output.fullsize)
+                .filter(|fullsize| !reviewed.contains(*fullsize))
+                .collect();
+            sources.sort();
+            sources.dedup();
+            let (moves, counts) = timings.time("archive", || {
+                archive_matched(
+                    &sources,
+                    &archived_copies,
+                    fullsize_directories,
+                    archive,
+                    options.preserve,
+                    options.dry_run,
+                )
+            });
+            summary.archived = counts;
+            if options.cache_key == CacheKey::Name && !moves.is_empty() {
+                let hash_name = cache_hash_name(&fullsize_options);
+                relocate_cache_entries(
+                    &cache_directory.join("fullsize").join(&hash_name),
+                    &fullsize_options.cache_config(false),
+                    &moves,
+                )?;
+                if options.prefilter.is_some() {
+                    relocate_cache_entries(
+                        &cache_directory.join("fullsize-coarse").join(&hash_name),
+                        &fullsize_options.cache_config(true),
+                        &moves,
+                    )?;
+                }
+            }
+        }
+    }
+    if interrupted() && !state.interrupted && !options.dry_run {
+        state.interrupted = true;
+        state.write(&state_path)?;
+    }
+    if let Some(report) = report {
+        report.finish(serde_json::json!({
+            "hash": options.hash.to_json(),
+            "warn_distance": options.warn_distance,
+            "max_distance": max_distance,
+            "min_margin": options.min_margin,
+            "min_color_similarity": options.min_color_similarity,
+            "timings": timings.to_json(),
+            "interrupted": state.interrupted,
+            "unmatched_thumbnails": unmatched
+                .iter()
+                .map(|thumb| escape_path(thumb))
+                .collect::<Vec<_>>(),
+            "unclaimed_fullsize": unclaimed
+                .iter()
+                .map(|fullsize| escape_path(fullsize))
+                .collect::<Vec<_>>(),
+        }))?;
+    }
+    summary.distances = DistanceSummary::new(
+        best_distances,
+        matched.iter().max_by_key(|output| output.distance),
+        options.hash.bits(),
+    );
+    summary.matched = matched.len();
+    summary.unmatched = unmatched.len();
+    summary.unclaimed = unclaimed.len();
+    summary.timings = timings;
+    summary.comparisons = matching::comparisons();
+    Ok(summary)
+}
+
+// Options choosing how images are hashed, shared by `find`, `hash` and `dist`.
+// Not a doc comment, as structopt would take it for the about of both.
+#[derive(Debug, StructOpt)]
+struct HashArgs {
+    /// Hashing algorithm: `mean`, `gradient`, `double-gradient`, `dct` or
+    /// `blockhash`. Each algorithm has its own cache.
+    #[structopt(long = "hash-alg", default_value = "gradient")]
+    hash_algorithm: HashAlgorithm,
+
+    /// Width and optional height of the hash, from 2 to 64. Distances, and so
+    /// every distance threshold, count differing bits, so grow with the hash
+    /// size. Each size has its own cache.
+    #[structopt(
+        long = "hash-size",
+        default_value = "8",
+        min_values = 1,
+        max_values = 2
+    )]
+    hash_size: Vec<u32>,
+
+    /// Filter used to downsize images while hashing: `nearest`, `triangle`,
+    /// `catmull-rom`, `gaussian` or `lanczos3`. Each filter has its own cache.
+    #[structopt(long = "hash-filter", default_value = "lanczos3")]
+    hash_filter: ResizeFilter,
+
+    /// Images to remove borders from before hashing: `none`, `thumbs`,
+    /// `fullsize` or `both`.
+    #[structopt(long = "cleanup", default_value = "thumbs")]
+    cleanup: Cleanup,
+
+    /// Color of the borders removed by cleanup: `white`, `black`, or `auto` to
+    /// use the most common corner color of each image.
+    #[structopt(long = "border-color", default_value = "white")]
+    border_color: BorderColor,
+
+    /// How close pixels must be to the border color to be removed, from 0 to
+    /// 255. Pixels are border if every channel is within 255 minus this of the
+    /// border color.
+    #[structopt(long = "border-threshold", default_value = "230")]
+    border_threshold: u8,
+
+    /// Number of scan lines across each axis that must agree on an edge of
+    /// the inner image, so that specks of dust on the border are ignored. A
+    /// sparse `--border-scan` has three lines.
+    #[structopt(long = "min-confirmations", default_value = "2")]
+    min_confirmations: usize,
+
+    /// Largest fraction of an image's area that cleanup may crop away.
+    /// Images that would lose more are hashed uncropped, with a warning.
+    #[structopt(long = "max-crop-fraction", default_value = "0.6")]
+    max_crop_fraction: f64,
+
+    /// Lines scanned for borders: `sparse` scans three lines at the quarters
+    /// of each axis, `dense` every 8th line, and `full` every line, which
+    /// finds small images on a large mount at the cost of speed.
+    #[structopt(long = "border-scan", default_value = "sparse")]
+    border_scan: BorderScan,
+
+    /// Comma separated normalization steps applied to every image before
+    /// hashing: `grayscale`, `autocontrast` and `equalize`. Steps always run
+    /// in that order, and each combination has its own cache.
+    #[structopt(long = "preprocess")]
+    preprocess: Option<Preprocess>,
+
+    /// Only hash the central percentage of each dimension of every image,
+    /// after cleanup, such as `80` to ignore captions along the edges.
+    #[structopt(long = "center-crop")]
+    center_crop: Option<u32>,
+
+    /// Sigma of a Gaussian blur applied to every image before hashing, to
+    /// smooth out halftone dots and scanning noise, or 0 for none. Blurring
+    /// happens before downsizing, so slows hashing.
+    #[structopt(long = "preblur", default_value = "0")]
+    preblur: f32,
+
+    /// Decode images with more pixels than this one at a time, to bound the
+    /// memory used by giant scans.
+    #[structopt(long = "max-decode-pixels")]
+    max_decode_pixels: Option<u64>,
+
+    /// Size in pixels that images are downsized to fit before hashing, or 0
+    /// to hash them at full size. Each size has its own cache.
+    #[structopt(long = "prescale", default_value = "255")]
+    prescale: u32,
+}
+
+/// How images are hashed, as chosen by `HashArgs`.
+#[derive(Debug, Clone, Copy)]
+struct Hashing {
+    hash: HashConfig,
+    border: BorderOptions,
+    preprocess: Preprocess,
+    center_crop: Option<u32>,
+    preblur: Option<f32>,
+    max_decode_pixels: Option<u64>,
+}
+
+impl HashArgs {
+    /// Checks the options, returning how images are hashed with them.
+    fn hashing(&self) -> Result<Hashing> {
+        if let Some(percent) = self.center_crop {
+            if percent == 0 || percent > 100 {
+                return Err(anyhow!(
+                    "Center crop of {}% is outside the range 1 to 100",
+                    percent
+                ));
+            }
+        }
+        if self.preblur < 0.0 || !self.preblur.is_finite() {
+            return Err(anyhow!("Blur sigma must be zero or positive"));
+        }
+        if self.min_confirmations == 0
+            || (self.border_scan == BorderScan::Sparse && self.min_confirmations > SCAN_LINES)
+        {
+            return Err(anyhow!(
+                "Edges are confirmed by 1 to {} scan lines, not {}",
+                SCAN_LINES,
+                self.min_confirmations
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.max_crop_fraction) {
+            return Err(anyhow!("Max crop fraction must be between 0 and 1"));
+        }
+        Ok(Hashing {
+            hash: HashConfig::new(
+                self.hash_algorithm,
+                &self.hash_size,
+                self.hash_filter,
+                self.prescale,
+            )?,
+            border: BorderOptions {
+                color: self.border_color,
+                threshold: self.border_threshold,
+                min_confirmations: self.min_confirmations,
+                max_crop_fraction: self.max_crop_fraction,
+                scan: self.border_scan,
+            },
+            preprocess: self.preprocess.unwrap_or_default(),
+            center_crop: self.center_crop,
+            preblur: if self.preblur > 0.0 {
+                Some(self.preblur)
+            } else {
+                None
+            },
+            max_decode_pixels: self.max_decode_pixels,
+        })
+    }
+}
+
+/// Prints the perceptual hash of each image, hashed as `find` would, to check
+/// what an image hashes to and whether its cached hash agrees.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "find hash",
+    about = "Print the perceptual hash of each image, as `find` hashes it."
+)]
+pub struct HashOpt {
+    #[structopt(flatten)]
+    global: cli::GlobalArgs,
+
+    /// Images to hash.
+    #[structopt(parse(from_os_str), required = true)]
+    paths: Vec<PathBuf>,
+
+    #[structopt(flatten)]
+    hash_args: HashArgs,
+
+    /// Hash the images as fullsize images, rather than thumbnails, which
+    /// chooses whether `--cleanup` applies and which cache is compared with.
+    #[structopt(long = "fullsize")]
+    fullsize: bool,
+
+    /// Cache directory to compare the hash of each image with the cached
+    /// hash of, as given to `find --cache`.
+    #[structopt(long = "compare-cache", parse(from_os_str))]
+    compare_cache: Option<PathBuf>,
+
+    /// How the compared cache is keyed: by file `name`, or `content`.
+    #[structopt(long = "cache-key", default_value = "name")]
+    cache_key: CacheKey,
+}
+
+/// Reads the cache index under `cache_directory` that `find` would keep the
+/// hash of an image in, if there is one.
+fn read_image_cache(
+    path: &Path,
+    cache_directory: &Path,
+    side: &str,
+    options: &LoadOptions,
+) -> Result<Option<HashCache>> {
+    let key = match options.cache_key {
+        CacheKey::Name => directory_cache_key(path.parent().unwrap_or_else(|| Path::new("."))),
+        CacheKey::Content => "content".to_owned(),
+    };
+    let directory = cache_directory
+        .join(side)
+        .join(cache_hash_name(options))
+        .join(key);
+    if !directory.is_dir() {
+        return Ok(None);
+    }
+    Ok(Some(HashCache::read(
+        &directory,
+        options.cache_config(false),
+        false,
+    )?))
+}
+
+/// How the hash of an image compares with its cached hash.
+fn compare_cached(
+    path: &Path,
+    phash: &ImageHash,
+    cache_directory: &Path,
+    side: &str,
+    options: &LoadOptions,
+) -> Result<String> {
+    let index = match read_image_cache(path, cache_directory, side, options)? {
+        Some(index) => index,
+        None => return Ok("missing".to_owned()),
+    };
+    let name = match options.cache_key {
+        CacheKey::Name => encode_file_name(path.file_name().unwrap_or_default()),
+        CacheKey::Content => format!("{:032x}", xxh3_128(&fs::read(path)?)),
+    };
+    Ok(match index.get(&name, None) {
+        None => "missing".to_owned(),
+        Some(entry) if entry.is_stale(Provenance::read(path)?) => "stale".to_owned(),
+        Some(entry) if entry.hash == phash.to_base64() => "same".to_owned(),
+        Some(entry) => format!("differs ({})", entry.hash),
+    })
+}
+
+/// Runs `fromthumb hash`, exiting with `EXIT_FAILED` if any image fails to hash.
+pub fn hash_command(opt: HashOpt) -> Result<()> {
+    opt.global.init_logger(log::LevelFilter::Warn);
+    opt.global.init_threads(None)?;
+    let hashing = opt.hash_args.hashing()?;
+    let cleanup = if opt.fullsize {
+        opt.hash_args.cleanup.fullsize()
+    } else {
+        opt.hash_args.cleanup.thumbs()
+    };
+    let side = if opt.fullsize {
+        "fullsize"
+    } else {
+        "thumbnail"
+    };
+    let options = LoadOptions {
+        cleanup,
+        border: hashing.border,
+        preprocess: hashing.preprocess,
+        center_crop: hashing.center_crop,
+        preblur: hashing.preblur,
+        max_decode_pixels: hashing.max_decode_pixels,
+        hash: hashing.hash,
+        cache_key: opt.cache_key,
+        rebuild: true,
+        ..LoadOptions::default()
+    };
+    let index = HashCache::empty(options.cache_config(false));
+    let hashers = Hashers::new(hashing.hash);
+    let mut failed = false;
+    for path in opt.paths.iter() {
+        let phash = match hash_image(path, &index, &hashers, &options) {
+            Ok(phash) => phash.phash,
+            Err(error) => {
+                warn!("Failed to hash {}: {}", path.to_string_lossy(), error);
+                failed = true;
+                continue;
+            }
+        };
+        match &opt.compare_cache {
+            Some(cache_directory) => println!(
+                "{} {} cache: {}",
+                phash.to_base64(),
+                escape_path(path),
+                compare_cached(path, &phash, cache_directory, side, &options)?
+            ),
+            None => println!("{} {}", phash.to_base64(), escape_path(path)),
+        }
+    }
+    if failed {
+        std::process::exit(EXIT_FAILED);
+    }
+    Ok(())
+}
+
+/// Prints the distance between a thumbnail and a fullsize image, hashed as
+/// `find` would, to check why a pair of images did or did not match.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "find dist",
+    about = "Print the hash distance between a thumbnail and a fullsize image, as `find` hashes them."
+)]
+pub struct DistOpt {
+    #[structopt(flatten)]
+    global: cli::GlobalArgs,
+
+    /// Thumbnail image, hashed as `find` hashes thumbnails.
+    #[structopt(parse(from_os_str))]
+    thumbnail: PathBuf,
+
+    /// Fullsize image, hashed as `find` hashes fullsize images.
+    #[structopt(parse(from_os_str))]
+    fullsize: PathBuf,
+
+    #[structopt(flatten)]
+    hash_args: HashArgs,
+
+    /// Also print the dimensions of each image, and the bounds its border
+    /// was cropped to by `--cleanup`.
+    #[structopt(long = "explain")]
+    explain: bool,
+
+    /// Cache directory to reuse the hashes of the images from, as given to
+    /// `find --cache`. Images not in the cache are hashed, but the cache is
+    /// not written to.
+    #[structopt(long = "cache", parse(from_os_str))]
+    cache_directory: Option<PathBuf>,
+
+    /// How the cache is keyed: by file `name`, or `content`.
+    #[structopt(long = "cache-key", default_value = "name")]
+    cache_key: CacheKey,
+}
+
+/// Describes how an image was cleaned up before hashing, for `--explain`.
+fn explain_cleanup(path: &Path, options: &LoadOptions) -> Result<String> {
+    let prepared = prepare_to_hash(path, options)?;
+    let (width, height) = prepared.original;
+    Ok(match prepared.crop {
+        _ if !options.cleanup => format!("{}x{}, not cleaned up", width, height),
+        Some((x, y, crop_width, crop_height)) => format!(
+            "{}x{}, border cropped to {}x{} at {},{}",
+            width, height, crop_width, crop_height, x, y
+        ),
+        None => format!("{}x{}, no border cropped", width, height),
+    })
+}
+
+/// Runs `fromthumb dist`.
+pub fn dist_command(opt: DistOpt) -> Result<()> {
+    opt.global.init_logger(log::LevelFilter::Warn);
+    let hashing = opt.hash_args.hashing()?;
+    let hashers = Hashers::new(hashing.hash);
+    let sides = [
+        (&opt.thumbnail, "thumbnail", opt.hash_args.cleanup.thumbs()),
+        (&opt.fullsize, "fullsize", opt.hash_args.cleanup.fullsize()),
+    ];
+    let mut lines = Vec::with_capacity(sides.len());
+    let mut phashes = Vec::with_capacity(sides.len());
+    for (path, side, cleanup) in sides.iter() {
+        let options = LoadOptions {
+            cleanup: *cleanup,
+            border: hashing.border,
+            preprocess: hashing.preprocess,
+            center_crop: hashing.center_crop,
+            preblur: hashing.preblur,
+            max_decode_pixels: hashing.max_decode_pixels,
+            hash: hashing.hash,
+            cache_key: opt.cache_key,
+            ..LoadOptions::default()
+        };
+        let cached = match &opt.cache_directory {
+            Some(cache_directory) => read_image_cache(path, cache_directory, side, &options)?,
+            None => None,
+        };
+        let index = cached.unwrap_or_else(|| HashCache::empty(options.cache_config(false)));
+        let phash = hash_image(path, &index, &hashers, &options)
+            .map_err(|error| anyhow!("Failed to hash {}: {}", path.to_string_lossy(), error))?;
+        lines.push(format!("{} {}", phash.phash.to_base64(), escape_path(path)));
+        if opt.explain {
+            lines.push(format!("  {}", explain_cleanup(path, &options)?));
+        }
+        phashes.push(phash.phash);
+    }
+    println!("distance {}", phashes[0].dist(&phashes[1]));
+    for line in lines.iter() {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Checks the copies recorded in the `matches.json` of an output directory,
+/// to catch any deleted or corrupted since they were made.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "fromthumb verify",
+    about = "Check the copies in an output directory against its recorded matches."
+)]
+pub struct VerifyOpt {
+    #[structopt(flatten)]
+    global: cli::GlobalArgs,
+
+    /// Output directory to check, as given to `find --output`.
+    #[structopt(parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// Also hash each copy and its thumbnail again, checking they are still
+    /// within the recorded distance of each other. Give the hashing options
+    /// the matches were made with.
+    #[structopt(long = "rehash")]
+    rehash: bool,
+
+    #[structopt(flatten)]
+    hash_args: HashArgs,
+}
+
+/// Outcome of checking the copy of a recorded match.
+enum Verified {
+    Passed,
+    /// No copy was recorded, as with imported hashes.
+    Skipped,
+    Failed(String),
+}
+
+/// Returns the transform with `name`, as recorded in `matches.json`.
+fn transform_named(name: &str) -> Option<Transform> {
+    std::iter::once(Transform::Identity)
+        .chain(thumbnail_transforms(true, true))
+        .find(|transform| transform.name() == name)
+}
+
+/// Checks the copy of `record` exists with the size or digest recorded, and
+/// with `rehash`, that it is still within the recorded distance of its
+/// thumbnail.
+fn verify_record(
+    record: &MatchRecord,
+    rehash: Option<(&Hashing, Cleanup)>,
+    hashers: &Hashers,
+) -> Result<Verified> {
+    let output = match &record.output {
+        Some(output) => Path::new(output),
+        None => return Ok(Verified::Skipped),
+    };
+    let metadata = match fs::metadata(output) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Verified::Failed("missing".to_owned()))
+        }
+        Err(error) => return Err(error.into()),
+    };
+    if let Some(digest) = &record.digest {
+        let actual = format!("{:032x}", xxh3_128(&fs::read(output)?));
+        if actual != *digest {
+            return Ok(Verified::Failed(format!(
+                "digest {}, expected {}",
+                actual, digest
+            )));
+        }
+    } else if let Some(size) = record.size {
+        if metadata.len() != size {
+            return Ok(Verified::Failed(format!(
+                "size {}, expected {}",
+                metadata.len(),
+                size
+            )));
+        }
+    }
+    if let Some((hashing, cleanup)) = rehash {
+        let transform = transform_named(&record.transform)
+            .ok_or_else(|| anyhow!("Unknown transform in matches: {}", record.transform))?;
+        let transforms = [transform];
+        let options = LoadOptions {
+            border: hashing.border,
+            preprocess: hashing.preprocess,
+            center_crop: hashing.center_crop,
+            preblur: hashing.preblur,
+            max_decode_pixels: hashing.max_decode_pixels,
+            hash: hashing.hash,
+            rebuild: true,
+            ..LoadOptions::default()
+        };
+        let thumb_options = LoadOptions {
+            cleanup: cleanup.thumbs(),
+            transforms: if transform == Transform::Identity {
+                &[]
+            } else {
+                &transforms
+            },
+            ..options
+        };
+        let fullsize_options = LoadOptions {
+            cleanup: cleanup.fullsize(),
+            ..options
+        };
+        let index = HashCache::empty(options.cache_config(false));
+        let thumb = match hash_image(Path::new(&record.thumb), &index, hashers, &thumb_options) {
+            Ok(thumb) => thumb,
+            Err(error) => {
+                return Ok(Verified::Failed(format!(
+                    "failed to hash thumbnail {}: {}",
+                    record.thumb, error
+                )))
+            }
+        };
+        let copy = match hash_image(output, &index, hashers, &fullsize_options) {
+            Ok(copy) => copy,
+            Err(error) => return Ok(Verified::Failed(format!("failed to hash: {}", error))),
+        };
+        let (distance, _) = thumb_distance(&thumb, &copy.phash);
+        if distance > record.distance {
+            return Ok(Verified::Failed(format!(
+                "distance {} from {}, recorded {}",
+                distance, record.thumb, record.distance
+            )));
+        }
+    }
+    Ok(Verified::Passed)
+}
+
+/// Runs `fromthumb verify`, printing whether each recorded copy passed, and
+/// exiting with `EXIT_FAILED` if any failed.
+pub fn verify_command(opt: VerifyOpt) -> Result<()> {
+    opt.global.init_logger(log::LevelFilter::Warn);
+    opt.global.init_threads(None)?;
+    let hashing = opt.hash_args.hashing()?;
+    let state_path = opt.output_directory.join(MATCHES_FILE_NAME);
+    if !state_path.is_file() {
+        return Err(anyhow!(
+            "No matches recorded in {}",
+            opt.output_directory.to_string_lossy()
+        ));
+    }
+    let state = MatchState::read(&state_path)?;
+    let rehash = if opt.rehash {
+        Some((&hashing, opt.hash_args.cleanup))
+    } else {
+        None
+    };
+    let results: Vec<Result<Verified>> = state
+        .matches
+        .par_iter()
+        .map_init(
+            || Hashers::new(hashing.hash),
+            |hashers, record| verify_record(record, rehash, hashers),
+        )
+        .collect();
+    let (mut passed, mut skipped, mut failed) = (0, 0, 0);
+    for (record, result) in state.matches.iter().zip(results) {
+        let output = record.output.as_deref().unwrap_or_default();
+        match result {
+            Ok(Verified::Passed) => {
+                passed += 1;
+                println!("pass {}", output);
+            }
+            Ok(Verified::Skipped) => {
+                skipped += 1;
+                println!("skip {}: no copy recorded", record.thumb);
+            }
+            Ok(Verified::Failed(reason)) => {
+                failed += 1;
+                println!("fail {}: {}", output, reason);
+            }
+            Err(error) => {
+                failed += 1;
+                println!("fail {}: {}", output, error);
+            }
+        }
+    }
+    println!(
+        "{} copies passed, {} failed, {} skipped",
+        passed, failed, skipped
+    );
+    if failed > 0 {
+        std::process::exit(EXIT_FAILED);
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "find", about = "Find matching images from a large set.")]
+pub struct FindOpt {
+    #[structopt(flatten)]
+    global: cli::GlobalArgs,
+
+    /// Fullsize image files (to search through for a match). May be given
+    /// more than once to search several directories.
+    #[structopt(
+        long = "fullsize",
+        parse(from_os_str),
+        required_unless = "import-hashes",
+        number_of_values = 1
+    )]
+    fullsize_directories: Vec<PathBuf>,
+
+    /// Thumbnail image files (to find a match for). May be given more than
+    /// once to match thumbnails from several directories.
+    #[structopt(
+        long = "thumbnail",
+        parse(from_os_str),
+        required_unless_one = &["find-duplicates", "thumbnail-list", "export-hashes"],
+        number_of_values = 1
+    )]
+    thumbnail_directories: Vec<PathBuf>,
+
+    /// File listing the thumbnails to match, one per line, or `-` for stdin.
+    /// Relative paths are resolved against the thumbnail directories.
+    #[structopt(long = "thumbnail-list", parse(from_os_str))]
+    thumbnail_list: Option<PathBuf>,
+
+    #[structopt(long = "cache", parse(from_os_str))]
+    cache_directory: PathBuf,
+
+    /// Wait for another run using the same cache to finish, instead of
+    /// failing.
+    #[structopt(long = "wait-for-lock")]
+    wait_for_lock: bool,
+
+    /// Output directory.
+    #[structopt(long = "output", parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// `forward` finds the best fullsize image for each thumbnail. `reverse`
+    /// finds the best thumbnail for each fullsize image, listing fullsize
+    /// images without one in `orphans.txt`.
+    #[structopt(long = "direction", default_value = "forward")]
+    direction: Direction,
+
+    /// In `reverse` direction, copy orphaned fullsize images to the output
+    /// directory.
+    #[structopt(long = "copy-orphans")]
+    copy_orphans: bool,
+
+    /// Distance above which a match needs manual review.
+    #[structopt(long = "warn-distance", default_value = "10")]
+    warn_distance: u32,
+
+    /// Copy matches needing review into this directory instead of the output
+    /// directory, and list them in `review.csv` there.
+    #[structopt(long = "review-dir", parse(from_os_str))]
+    review_directory: Option<PathBuf>,
+
+    /// Write an image of each match needing review beside its thumbnail,
+    /// named so the furthest sort first, to the `--review-dir`, or `review` in
+    /// the output directory.
+    #[structopt(long = "review-montages")]
+    review_montages: bool,
+
+    /// Height of `--review-montages`, which both images are scaled to.
+    #[structopt(long = "montage-height", default_value = "512")]
+    montage_height: u32,
+
+    /// With `--review-dir`, also copy matches needing review to the output
+    /// directory.
+    #[structopt(long = "review-copy-output", requires = "review-directory")]
+    review_copy_output: bool,
+
+    /// Maximum distance for a match to be accepted. Thumbnails without a
+    /// candidate this close are not copied, and are listed in `unmatched.txt`.
+    /// In `reverse` direction, this defaults to the review threshold.
+    #[structopt(long = "max-distance")]
+    max_distance: Option<u32>,
+
+    /// Minimum distance between the best and second best candidates for a
+    /// match to be accepted. Closer matches are not copied, and are flagged as
+    /// ambiguous in `margins.csv`.
+    #[structopt(long = "min-margin")]
+    min_margin: Option<u32>,
+
+    /// Number of closest candidates to list per thumbnail in `candidates.csv`.
+    #[structopt(long = "top")]
+    top: Option<usize>,
+
+    /// Assign each fullsize image to at most one thumbnail. Reassignments are
+    /// listed in `assignments.csv`.
+    #[structopt(long = "unique")]
+    unique: bool,
+
+    /// Match thumbnails in order of confidence, removing each fullsize image
+    /// matched within this distance (default 2) from the pool. The processing
+    /// order is listed in `consumed.csv`.
+    #[structopt(long = "consume-matches", conflicts_with = "unique")]
+    consume_matches: Option<Option<u32>>,
+
+    /// Strategy used by `--unique`: `greedy` or `optimal`.
+    #[structopt(long = "assignment", default_value = "greedy")]
+    assignment: Assignment,
+
+    /// Copy thumbnails that matched the same fullsize image into this
+    /// directory, grouped by fullsize image, for review.
+    #[structopt(long = "conflicts", parse(from_os_str))]
+    conflicts_directory: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    hash_args: HashArgs,
+
+    /// Key cached hashes by file `name`, or by a digest of the file `content`
+    /// so that renamed images are not hashed again.
+    #[structopt(long = "cache-key", default_value = "name")]
+    cache_key: CacheKey,
+
+    /// Fail if a cache was hashed with a different configuration, instead of
+    /// hashing its images again.
+    #[structopt(long = "cache-strict")]
+    cache_strict: bool,
+
+    /// Ignore and rewrite the cached hashes of `all` images, or only the
+    /// `thumbnails` or `fullsize` images, before matching.
+    #[structopt(long = "rebuild-cache")]
+    rebuild_cache: Option<Rebuild>,
+
+    /// Rehash images whose file names match this glob, such as `scan-*.png`.
+    /// May be given more than once.
+    #[structopt(long = "invalidate", number_of_values = 1)]
+    invalidate: Vec<glob::Pattern>,
+
+    /// Write the hashes of the fullsize images to a manifest file, which can
+    /// be matched against with `--import-hashes` elsewhere.
+    #[structopt(long = "export-hashes", parse(from_os_str))]
+    export_hashes: Option<PathBuf>,
+
+    /// Match against the fullsize hashes of a manifest written by
+    /// `--export-hashes`, instead of the images. Matches are only reported, as
+    /// there are no images to copy.
+    #[structopt(
+        long = "import-hashes",
+        parse(from_os_str),
+        conflicts_with_all = &["fullsize-directories", "find-duplicates", "export-hashes"]
+    )]
+    import_hashes: Option<PathBuf>,
+
+    /// Remove cache entries of images no longer in their source directory.
+    #[structopt(long = "prune-cache")]
+    prune_cache: bool,
+
+    /// Match without changing the output, review, conflicts or quarantine
+    /// directories: copies are listed instead of made, and the report is
+    /// written to stdout unless `--report` is given. Hashes are still cached,
+    /// and `--prune-cache` only lists what it would remove.
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// How to search for the closest fullsize images: `bktree`,
+    /// `multi-index`, or `bruteforce` to compare against every candidate.
+    #[structopt(long = "matcher", default_value = "bktree")]
+    matcher: Matcher,
+
+    /// Number of chunks hashes are split into by `--matcher multi-index`.
+    /// Every match closer than this is found, and more chunks compare against
+    /// more candidates.
+    #[structopt(long = "index-chunks", default_value = "4")]
+    index_chunks: usize,
+
+    /// With `--matcher bruteforce`, only compare fine hashes against this
+    /// many candidates, chosen by closest coarse hash, 200 by default. Other
+    /// matchers don't prefilter, and ignore it with a warning.
+    #[structopt(long = "prefilter")]
+    prefilter: Option<usize>,
+
+    /// With `--matcher bruteforce`, compare fine hashes against every
+    /// candidate.
+    #[structopt(long = "no-prefilter")]
+    no_prefilter: bool,
+
+    /// Skip candidates whose aspect ratio differs from the thumbnail by more
+    /// than this factor, such as `1.2`.
+    #[structopt(long = "max-aspect-delta")]
+    max_aspect_delta: Option<f64>,
+
+    /// Also hash each thumbnail rotated by 90, 180 and 270 degrees, and match
+    /// using the closest orientation.
+    #[structopt(long = "try-rotations")]
+    try_rotations: bool,
+
+    /// Also hash each thumbnail mirrored horizontally and vertically. With
+    /// `--try-rotations`, all eight orientations are tried.
+    #[structopt(long = "try-flips")]
+    try_flips: bool,
+
+    /// Compare color histograms of each match, and flag those below
+    /// `--color-threshold` for review.
+    #[structopt(long = "verify-color")]
+    verify_color: bool,
+
+    /// Minimum histogram similarity, from 0 to 1, for `--verify-color`.
+    #[structopt(long = "color-threshold", default_value = "0.5")]
+    color_threshold: f32,
+
+    /// CSV file of `thumbnail,fullsize` file name patterns (globs or
+    /// prefixes). Thumbnails matching a pattern are only compared with
+    /// fullsize images matching the paired pattern.
+    #[structopt(long = "groups", parse(from_os_str))]
+    groups: Option<PathBuf>,
+
+    /// Instead of matching, report clusters of near-duplicate fullsize images
+    /// in `duplicates.json`.
+    #[structopt(long = "find-duplicates")]
+    find_duplicates: bool,
+
+    /// Maximum distance between two fullsize images to be duplicates.
+    #[structopt(long = "duplicate-distance", default_value = "2")]
+    duplicate_distance: u32,
+
+    /// Exit with status 2 if any match needs manual review, or 3 if any image
+    /// could not be hashed.
+    #[structopt(long = "strict")]
+    strict: bool,
+
+    /// Skip thumbnails accepted as matched by an earlier run into the output
+    /// directory, as long as their copy of the fullsize image still exists.
+    #[structopt(long = "resume")]
+    resume: bool,
+
+    /// When resuming, match thumbnails whose file names match this glob
+    /// again. May be given more than once.
+    #[structopt(long = "force", number_of_values = 1, requires = "resume")]
+    force: Vec<glob::Pattern>,
+
+    /// After matching, keep watching the thumbnail directories, and match,
+    /// copy and report each thumbnail added to them, until Ctrl-C. Needs
+    /// `--format jsonl`, or `--no-report`.
+    #[structopt(long = "watch", conflicts_with_all = &["thumbnail-list", "unique", "consume-matches", "find-duplicates"])]
+    watch: bool,
+
+    /// Seconds a thumbnail added while watching must go unchanged before it
+    /// is matched, so that thumbnails still being written are not.
+    #[structopt(long = "watch-settle", default_value = "2")]
+    watch_settle: f64,
+
+    /// While watching, load the fullsize directories again every this many
+    /// seconds, to match against fullsize images added since.
+    #[structopt(long = "watch-rescan", requires = "watch")]
+    watch_rescan: Option<f64>,
+
+    /// File to list the matches of the run in, with their distances, whether
+    /// they need review and their copies. Defaults to `matches.csv` in the
+    /// output directory, or `matches-report.json` or `matches.jsonl` with
+    /// `--format`.
+    #[structopt(long = "report", parse(from_os_str))]
+    report: Option<PathBuf>,
+
+    /// Don't write the report of matches.
+    #[structopt(long = "no-report", conflicts_with = "report")]
+    no_report: bool,
+
+    /// Write a page to this file showing each match beside its thumbnail,
+    /// highlighting those needing review, followed by unmatched thumbnails
+    /// and conflicts. Previews are written to `previews` beside it.
+    #[structopt(long = "html-report", parse(from_os_str))]
+    html_report: Option<PathBuf>,
+
+    /// Write every distance computed while matching to this file, for
+    /// analysis elsewhere. Unless every pair is compared, only those compared
+    /// are listed, as noted in its header.
+    #[structopt(long = "dump-distances", parse(from_os_str))]
+    dump_distances: Option<PathBuf>,
+
+    /// Format of `--dump-distances`: `csv`, or `binary` for a compact list of
+    /// indexes into the thumbnails and fullsize images listed in its header.
+    #[structopt(long = "dump-format", default_value = "csv")]
+    dump_format: DumpFormat,
+
+    /// Format of the report: `csv`, `json` (a single document with the
+    /// hash, thresholds and timings of the run) or `jsonl` (one match per
+    /// line, written as each is made).
+    #[structopt(long = "format", default_value = "csv")]
+    report_format: ReportFormat,
+
+    /// Write statistics of the run as JSON to this file, or stdout for `-`:
+    /// how each side was loaded, the time spent in each phase and the number
+    /// of hash comparisons.
+    #[structopt(long = "stats", parse(from_os_str))]
+    stats: Option<PathBuf>,
+
+    /// Don't show progress bars, which are only shown when stderr is a
+    /// terminal.
+    #[structopt(long = "no-progress")]
+    no_progress: bool,
+
+    /// Extensions of the files loaded as images, ignoring case.
+    #[structopt(
+        long = "extensions",
+        use_delimiter = true,
+        default_value = "jpg,jpeg,png,tif,tiff,bmp,gif,webp"
+    )]
+    extensions: Vec<String>,
+
+    /// Also load files whose names start with `.`.
+    #[structopt(long = "include-hidden")]
+    include_hidden: bool,
+
+    /// Continue when a fullsize or thumbnail directory has no images, rather
+    /// than failing.
+    #[structopt(long = "allow-empty")]
+    allow_empty: bool,
+
+    /// Load fullsize images in subdirectories too. Matches are copied to the
+    /// same path under the output directory as under their source directory.
+    #[structopt(long = "recursive")]
+    recursive: bool,
+
+    /// Load thumbnails in subdirectories too.
+    #[structopt(long = "recursive-thumbnails")]
+    recursive_thumbnails: bool,
+
+    /// Follow symlinks to directories when loading subdirectories. Symlinks
+    /// leading back to a directory being loaded are skipped.
+    #[structopt(long = "follow-symlinks")]
+    follow_symlinks: bool,
+
+    /// Copy matches directly into the output directory, rather than to their
+    /// path under their source directory.
+    #[structopt(long = "flatten")]
+    flatten: bool,
+
+    /// Only load images whose paths under their source directory match this
+    /// glob, such as `*.tif`. May be given more than once.
+    #[structopt(long = "include", number_of_values = 1)]
+    include: Vec<glob::Pattern>,
+
+    /// Never load images whose paths under their source directory match this
+    /// glob, such as `*rejects*`. May be given more than once.
+    #[structopt(long = "exclude", number_of_values = 1)]
+    exclude: Vec<glob::Pattern>,
+
+    /// As `--include`, for fullsize images only.
+    #[structopt(long = "fullsize-include", number_of_values = 1)]
+    fullsize_include: Vec<glob::Pattern>,
+
+    /// As `--exclude`, for fullsize images only.
+    #[structopt(long = "fullsize-exclude", number_of_values = 1)]
+    fullsize_exclude: Vec<glob::Pattern>,
+
+    /// As `--include`, for thumbnails only.
+    #[structopt(long = "thumbnail-include", number_of_values = 1)]
+    thumbnail_include: Vec<glob::Pattern>,
+
+    /// As `--exclude`, for thumbnails only.
+    #[structopt(long = "thumbnail-exclude", number_of_values = 1)]
+    thumbnail_exclude: Vec<glob::Pattern>,
+
+    /// Skip images that are blank, having at most `--blank-threshold` of
+    /// their pixels differ from the border color, such as empty scan slots.
+    #[structopt(long = "skip-blank")]
+    skip_blank: bool,
+
+    /// Fraction of an image's pixels that must differ from the border color
+    /// for `--skip-blank` not to skip it.
+    #[structopt(long = "blank-threshold", default_value = "0.01")]
+    blank_threshold: f64,
+
+    /// What to do when an image cannot be decoded or hashed: `skip` it,
+    /// listing it at the end of the run, or `abort` the run.
+    #[structopt(long = "error-policy", default_value = "skip")]
+    error_policy: ErrorPolicy,
+
+    /// Directory to copy images that fail to decode to, under `fullsize` or
+    /// `thumbnail` at their path under their source directory, with their
+    /// errors in `quarantine.log`. Images that fail to be read are not copied.
+    #[structopt(long = "quarantine", parse(from_os_str))]
+    quarantine: Option<PathBuf>,
+
+    /// Move images to the `--quarantine` directory, rather than copying them.
+    #[structopt(long = "quarantine-move", requires = "quarantine")]
+    quarantine_move: bool,
+
+    /// Number of threads copying images, separate from the threads hashing
+    /// and matching them.
+    #[structopt(long = "io-threads", default_value = "4")]
+    io_threads: usize,
+
+    /// What to do when different images with the same file name would be
+    /// copied to the same directory: `suffix` later ones as `name-1.jpg` and
+    /// so on, `skip` them, or `error`.
+    #[structopt(long = "on-collision", default_value = "suffix")]
+    on_collision: CollisionPolicy,
+
+    /// Names matched fullsize images are copied under: `fullsize`, their own,
+    /// `thumbnail`, that of the thumbnail they matched with their own
+    /// extension, or `both`, as `thumbnail__fullsize.jpg`. Renamed copies are
+    /// laid out like the thumbnails.
+    #[structopt(long = "rename", default_value = "fullsize")]
+    rename: Rename,
+
+    /// How fullsize images are put in the output directory: `copy`,
+    /// `hardlink` (copying to another filesystem), `symlink` or `move`
+    /// (copying to another filesystem, and removing the source once the copy
+    /// is verified).
+    #[structopt(long = "transfer", default_value = "copy")]
+    transfer: Transfer,
+
+    /// With `--transfer symlink`, link to the absolute path of each image
+    /// rather than its path relative to the link.
+    #[structopt(long = "absolute-symlinks")]
+    absolute_symlinks: bool,
+
+    /// Once every copy of a matched fullsize image is verified, move it to
+    /// this directory at its path under its source directory, with its cached
+    /// hashes. Images of matches needing review are kept.
+    #[structopt(long = "archive-matched", parse(from_os_str))]
+    archive_matched: Option<PathBuf>,
+
+    /// Format to convert matched fullsize images to when copying them, with
+    /// its extension: `none`, `jpeg` or `png`. Images are turned
+    /// upright by their EXIF orientation, and those already in the format are
+    /// copied as they are. An image that fails to convert is copied as it is
+    /// instead, with its own extension.
+    #[structopt(long = "convert", default_value = "none")]
+    convert: Convert,
+
+    /// Quality of images converted to `jpeg`, from 1 to 100.
+    #[structopt(long = "quality", default_value = "90")]
+    quality: u8,
+
+    /// With `--convert`, re-encode images already in the format too.
+    #[structopt(long = "force-reencode")]
+    force_reencode: bool,
+
+    /// Comma separated extensions of sidecar files, such as `xmp`, to copy
+    /// along with each fullsize image from beside it, named after its copy.
+    #[structopt(long = "sidecars", use_delimiter = true)]
+    sidecars: Vec<String>,
+
+    /// Record a digest of the contents of each matched fullsize image in
+    /// `matches.json`, for `fromthumb verify` to check its copy against.
+    /// Reads each matched image once more.
+    #[structopt(long = "record-digests")]
+    record_digests: bool,
+
+    /// Metadata of each image kept in its copies: `times`, keeping its
+    /// modification and access times, or `none`.
+    #[structopt(long = "preserve", default_value = "times")]
+    preserve: Preserve,
+
+    /// Deprecated, use `--threads`.
+    #[structopt(hidden = true, conflicts_with = "threads")]
+    num_threads: Option<usize>,
+}
+
+/// Runs `find`.
+pub fn run(opt: FindOpt) -> Result<()> {
+    opt.global.init_logger(log::LevelFilter::Info);
+    ctrlc::set_handler(handle_interrupt)?;
+
+    if opt.num_threads.is_some() {
+        warn!("The number of threads as an argument is deprecated, use --threads instead");
+    }
+    if opt.prefilter.is_some() && opt.matcher != Matcher::BruteForce {
+        warn!(
+            "Only --matcher bruteforce prefilters candidates, so --prefilter has no effect with --matcher {}",
+            opt.matcher.name()
+        );
+    }
+    let threads = opt.global.init_threads(opt.num_threads)?;
+
+    let Hashing {
+        hash,
+        border,
+        preprocess,
+        center_crop,
+        preblur,
+        max_decode_pixels,
+    } = opt.hash_args.hashing()?;
+    if opt.import_hashes.is_some() && (opt.copy_orphans || opt.review_directory.is_some()) {
+        return Err(anyhow!(
+            "Imported fullsize images cannot be copied, so --copy-orphans and --review-directory are not supported with --import-hashes"
+        ));
+    }
+    if opt.convert != Convert::None {
+        if opt.quality == 0 || opt.quality > 100 {
+            return Err(anyhow!(
+                "Quality of {} is outside the range 1 to 100",
+                opt.quality
+            ));
+        }
+        if opt.transfer != Transfer::Copy {
+            return Err(anyhow!(
+                "Converted images are always copied, so --convert is not supported with --transfer {}",
+                opt.transfer.name()
+            ));
+        }
+    }
+    if opt.archive_matched.is_some() {
+        if opt.import_hashes.is_some() || opt.direction == Direction::Reverse {
+            return Err(anyhow!(
+                "Only fullsize images copied in the forward direction can be archived, so --archive-matched is not supported with --import-hashes or in reverse"
+            ));
+        }
+        if matches!(opt.transfer, Transfer::Move | Transfer::Symlink) {
+            return Err(anyhow!(
+                "Archived images must stay where their copies point, so --archive-matched is not supported with --transfer {}",
+                opt.transfer.name()
+            ));
+        }
+    }
+    if opt.resume && opt.direction == Direction::Reverse {
+        return Err(anyhow!(
+            "Only thumbnails can be resumed, so --resume is not supported in reverse"
+        ));
+    }
+    if opt.watch && opt.direction == Direction::Reverse {
+        return Err(anyhow!(
+            "Only thumbnails can be watched for, so --watch is not supported in reverse"
+        ));
+    }
+    if opt.watch && !opt.no_report && opt.report_format != ReportFormat::Jsonl {
+        return Err(anyhow!(
+            "Matches made while watching are added to the report as they are made, so --watch needs --format jsonl or --no-report"
+        ));
+    }
+    if opt.watch_settle < 0.0 || opt.watch_rescan.is_some_and(|rescan| rescan <= 0.0) {
+        return Err(anyhow!(
+            "Watch settle time must not be negative, and rescan interval must be positive"
+        ));
+    }
+    if opt.prune_cache && opt.cache_key == CacheKey::Content {
+        return Err(anyhow!(
+            "Content keyed caches are shared between directories, so cannot be pruned"
+        ));
+    }
+    if !(0.0..=1.0).contains(&opt.blank_threshold) {
+        return Err(anyhow!("Blank threshold must be between 0 and 1"));
+    }
+    if opt.montage_height == 0 {
+        return Err(anyhow!("Montage height must be at least 1"));
+    }
+    let skip_blank = if opt.skip_blank {
+        Some(opt.blank_threshold)
+    } else {
+        None
+    };
+    if opt.index_chunks == 0 || opt.index_chunks > hash.bits() {
+        return Err(anyhow!(
+            "Hashes of {} bits cannot be split into {} chunks",
+            hash.bits(),
+            opt.index_chunks
+        ));
+    }
+    info!(
+        "Hashing with {} at {}x{} ({} bits) using {} resizing",
+        hash.algorithm.name(),
+        hash.width,
+        hash.height,
+        hash.bits(),
+        hash.filter.name()
+    );
+
+    let progress = !opt.no_progress && std::io::stderr().is_terminal();
+    let mut sources: Vec<(&str, &Path)> = Vec::new();
+    for directory in opt.fullsize_directories.iter() {
+        sources.push(("fullsize", directory));
+    }
+    for directory in opt.thumbnail_directories.iter() {
+        sources.push(("thumbnail", directory));
+    }
+    let mut written: Vec<(&str, &Path)> = vec![
+        ("cache", &opt.cache_directory),
+        ("output", &opt.output_directory),
+    ];
+    if let Some(directory) = &opt.review_directory {
+        written.push(("review", directory));
+    }
+    if let Some(directory) = &opt.conflicts_directory {
+        written.push(("conflicts", directory));
+    }
+    if let Some(directory) = &opt.quarantine {
+        written.push(("quarantine", directory));
+    }
+    if let Some(directory) = &opt.archive_matched {
+        written.push(("archive", directory));
+    }
+    let excluded = check_nested_directories(&sources, &written)?;
+    let entry_filter = EntryFilter {
+        extensions: opt
+            .extensions
+            .iter()
+            .map(|extension| extension.trim_start_matches('.').to_owned())
+            .collect(),
+        include_hidden: opt.include_hidden,
+        excluded,
+        recursive: opt.recursive,
+        follow_symlinks: opt.follow_symlinks,
+        include: [opt.include.clone(), opt.fullsize_include.clone()].concat(),
+        exclude: [opt.exclude.clone(), opt.fullsize_exclude.clone()].concat(),
+        allow_empty: opt.allow_empty,
+    };
+    let thumbnail_filter = EntryFilter {
+        recursive: opt.recursive_thumbnails,
+        include: [opt.include.clone(), opt.thumbnail_include.clone()].concat(),
+        exclude: [opt.exclude.clone(), opt.thumbnail_exclude.clone()].concat(),
+        ..entry_filter.clone()
+    };
+    let _lock = lock_cache(&opt.cache_directory, opt.wait_for_lock)?;
+    if opt.find_duplicates {
+        find_duplicates(
+            &opt.fullsize_directories,
+            &opt.cache_directory,
+            &opt.output_directory,
+            opt.duplicate_distance,
+            &entry_filter,
+            &LoadOptions {
+                cleanup: opt.hash_args.cleanup.fullsize(),
+                border,
+                preprocess,
+                center_crop,
+                preblur,
+                max_decode_pixels,
+                hash,
+                cache_key: opt.cache_key,
+                cache_strict: opt.cache_strict,
+                rebuild: opt.rebuild_cache.is_some_and(Rebuild::fullsize),
+                invalidate: &opt.invalidate,
+                prune_cache: opt.prune_cache,
+                dry_run: opt.dry_run,
+                error_policy: opt.error_policy,
+                skip_blank,
+                progress,
+                ..LoadOptions::default()
+            },
+        )?;
+        if interrupted() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        return Ok(());
+    }
+
+    // Started before the first pass, so thumbnails added during it are
+    // matched too.
+    let watcher = if opt.watch {
+        Some(ThumbnailWatcher::new(
+            &opt.thumbnail_directories,
+            opt.recursive_thumbnails,
+        )?)
+    } else {
+        None
+    };
+    let options = MatchOptions {
+        direction: opt.direction,
+        max_distance: opt.max_distance,
+        top: opt.top,
+        unique: if opt.unique {
+            Some(opt.assignment)
+        } else {
+            None
+        },
+        copy_orphans: opt.copy_orphans,
+        conflicts_directory: opt.conflicts_directory.clone(),
+        matcher: opt.matcher,
+        index_chunks: opt.index_chunks,
+        prefilter: match opt.matcher {
+            Matcher::BruteForce if !opt.no_prefilter => {
+                Some(opt.prefilter.unwrap_or(DEFAULT_PREFILTER)).filter(|prefilter| *prefilter > 0)
+            }
+            _ => None,
+        },
+        max_aspect_delta: opt.max_aspect_delta,
+        min_margin: opt.min_margin,
+        transforms: thumbnail_transforms(opt.try_rotations, opt.try_flips),
+        min_color_similarity: if opt.verify_color {
+            Some(opt.color_threshold)
+        } else {
+            None
+        },
+        groups: match &opt.groups {
+            Some(groups) => load_groups(groups)?,
+            None => Vec::new(),
+        },
+        consume_matches: opt
+            .consume_matches
+            .map(|threshold| threshold.unwrap_or(CONSUME_DISTANCE_THRESHOLD)),
+        warn_distance: opt.warn_distance,
+        review_directory: opt.review_directory.clone(),
+        review_copy_output: opt.review_copy_output,
+        hash,
+        cleanup: opt.hash_args.cleanup,
+        border,
+        preprocess,
+        center_crop,
+        preblur,
+        max_decode_pixels,
+        cache_key: opt.cache_key,
+        cache_strict: opt.cache_strict,
+        rebuild: opt.rebuild_cache,
+        invalidate: opt.invalidate.clone(),
+        prune_cache: opt.prune_cache,
+        dry_run: opt.dry_run,
+        export_hashes: opt.export_hashes.clone(),
+        import_hashes: opt.import_hashes.clone(),
+        progress,
+        resume: opt.resume,
+        force: opt.force.clone(),
+        report: if opt.no_report {
+            None
+        } else {
+            // A dry run leaves the output directory untouched, so shows
+            // its report instead.
+            Some(opt.report.clone().unwrap_or_else(|| {
+                if opt.dry_run {
+                    PathBuf::from("-")
+                } else {
+                    opt.output_directory.join(opt.report_format.file_name())
+                }
+            }))
+        },
+        report_format: opt.report_format,
+        html_report: opt.html_report.clone(),
+        review_montages: if opt.review_montages {
+            Some(opt.montage_height)
+        } else {
+            None
+        },
+        dump_distances: opt.dump_distances.clone(),
+        dump_format: opt.dump_format,
+        archive_matched: opt.archive_matched.clone(),
+        convert: match opt.convert {
+            Convert::None => None,
+            format => Some(Conversion {
+                format,
+                quality: opt.quality,
+                force: opt.force_reencode,
+                max_decode_pixels,
+            }),
+        },
+        sidecars: opt
+            .sidecars
+            .iter()
+            .map(|extension| extension.trim_start_matches('.').to_owned())
+            .collect(),
+        record_digests: opt.record_digests,
+        io_threads: opt.io_threads,
+        on_collision: opt.on_collision,
+        rename: opt.rename,
+        transfer: opt.transfer,
+        absolute_symlinks: opt.absolute_symlinks,
+        preserve: opt.preserve,
+        quarantine: opt.quarantine.clone(),
+        quarantine_move: opt.quarantine_move,
+        fullsize_filter: entry_filter,
+        thumbnail_filter,
+        flatten: opt.flatten,
+        error_policy: opt.error_policy,
+        skip_blank,
+    };
+    let summary = match_thumbs(
+        &opt.fullsize_directories,
+        &opt.thumbnail_directories,
+        opt.thumbnail_list.as_deref(),
+        &opt.cache_directory,
+        &opt.output_directory,
+        &options,
+    )?;
+
+    summary.log();
+    if let Some(stats) = &opt.stats {
+        summary.write_stats(stats, threads, opt.io_threads)?;
+    }
+    if let (Some(watcher), false) = (watcher, interrupted()) {
+        // Watching only stops at Ctrl-C, which ends it cleanly.
+        return watcher.run(
+            &opt.fullsize_directories,
+            &opt.thumbnail_directories,
+            &opt.cache_directory,
+            &opt.output_directory,
+            &options,
+            WatchOptions {
+                settle: Duration::from_secs_f64(opt.watch_settle),
+                rescan: opt.watch_rescan.map(Duration::from_secs_f64),
+            },
+        );
+    }
+    if interrupted() {
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if opt.strict {
+        std::process::exit(summary.exit_code());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    /// An image loaded from `path`, at `relative` under its source directory.
+    pub(super) fn image(path: &str, relative: &str) -> PathPhash {
+        let path = PathBuf::from(path);
+        PathPhash {
+            file_name: path.file_name().unwrap_or_default().to_owned(),
+            relative: PathBuf::from(relative),
+            phash: ImageHash::from_base64("tA22PdJkZ20=").unwrap(),
+            coarse: None,
+            dimensions: None,
+            transformed: Vec::new(),
+            histogram: None,
+            provenance: None,
+            path,
+        }
+    }
+    #[cfg(unix)]
+    #[test]
+    fn escaped_paths_identify_paths_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        assert_eq!(escape_path(Path::new("a, \"b\"\nc.jpg")), "a, \"b\"\nc.jpg");
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9.jpg"));
+        assert_eq!(escape_path(path), "caf\u{fffd}.jpg [0x636166e92e6a7067]");
+    }
+    #[test]
+    fn only_images_over_the_maximum_pixels_are_decoded_one_at_a_time() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("image.png");
+        image::RgbImage::new(10, 10).save(&path).unwrap();
+        assert!(lock_large_decode(&path, None).is_none());
+        assert!(lock_large_decode(&path, Some(100)).is_none());
+        let guard = lock_large_decode(&path, Some(99));
+        assert!(guard.is_some());
+        assert!(LARGE_DECODE.try_lock().is_err());
+        drop(guard);
+        assert!(LARGE_DECODE.try_lock().is_ok());
+        // Images that can't be read are left to fail when decoded.
+        assert!(lock_large_decode(&directory.path().join("missing.png"), Some(1)).is_none());
+    }
+    #[test]
+    fn match_states_resume_the_thumbnails_whose_copies_remain() {
+        let directory = tempfile::tempdir().unwrap();
+        let copy = directory.path().join("full-0.png");
+        fs::write(&copy, b"copied").unwrap();
+        let record = |thumb: &str, output: Option<&Path>| MatchRecord {
+            thumb: thumb.to_owned(),
+            fullsize: "fullsize.png".to_owned(),
+            distance: 0,
+            transform: "none".to_owned(),
+            output: output.map(|output| escape_path(output).into_owned()),
+            size: None,
+            digest: None,
+        };
+        let state = MatchState {
+            matches: vec![
+                record("copied.png", Some(&copy)),
+                record("removed.png", Some(&directory.path().join("full-1.png"))),
+                record("imported.png", None),
+            ],
+            ..MatchState::default()
+        };
+        let path = directory.path().join(MATCHES_FILE_NAME);
+        assert!(MatchState::read(&path).unwrap().matches.is_empty());
+        state.write(&path).unwrap();
+        let read = MatchState::read(&path).unwrap();
+        assert_eq!(
+            read.resumable(),
+            ["copied.png", "imported.png"].iter().copied().collect()
+        );
+
+        fs::write(&path, r#"{"version": 0, "matches": []}"#).unwrap();
+        let error = MatchState::read(&path).unwrap_err().to_string();
+        assert!(error.ends_with("has version 0, not 1"), "{}", error);
+    }
+    #[test]
+    fn match_states_forget_the_thumbnails_matched_again() {
+        let record = |thumb: &str| MatchRecord {
+            thumb: thumb.to_owned(),
+            fullsize: "fullsize.png".to_owned(),
+            distance: 0,
+            transform: "none".to_owned(),
+            output: None,
+            size: None,
+            digest: None,
+        };
+        let mut state = MatchState {
+            matches: vec![
+                record("thumbs/a.png"),
+                record("thumbs/b.png"),
+                record("thumbs/c.png"),
+            ],
+            ..MatchState::default()
+        };
+        state.forget(&[
+            image("thumbs/b.png", "b.png"),
+            image("thumbs/d.png", "d.png"),
+        ]);
+        let thumbs: Vec<&str> = state
+            .matches
+            .iter()
+            .map(|record| record.thumb.as_str())
+            .collect();
+        assert_eq!(thumbs, ["thumbs/a.png", "thumbs/c.png"]);
+    }
+    #[test]
+    fn written_directories_may_not_be_inside_a_source_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        let fullsize = root.join("fullsize");
+        let thumbnails = root.join("thumbnails");
+        fs::create_dir_all(&fullsize).unwrap();
+        fs::create_dir_all(&thumbnails).unwrap();
+        let sources = [("fullsize", fullsize.as_path()), ("thumbnail", &thumbnails)];
+
+        let output = root.join("output");
+        let resolved = check_nested_directories(
+            &sources,
+            &[("output", &output), ("cache", &root.join("cache"))],
+        )
+        .unwrap();
+        assert_eq!(
+            resolved,
+            vec![
+                root.canonicalize().unwrap().join("output"),
+                root.canonicalize().unwrap().join("cache")
+            ]
+        );
+        // Even if the directory doesn't exist yet.
+        let cache = thumbnails.join("nested/cache");
+        let error = check_nested_directories(&sources, &[("output", &output), ("cache", &cache)])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "The cache directory {} is inside the thumbnail directory {}",
+                cache.display(),
+                thumbnails.display()
+            )
+        );
+
+        // Or reached through a symlink.
+        #[cfg(unix)]
+        {
+            let linked = root.join("linked");
+            std::os::unix::fs::symlink(&fullsize, &linked).unwrap();
+            let error = check_nested_directories(&sources, &[("output", &linked.join("output"))])
+                .unwrap_err();
+            assert!(
+                error.to_string().ends_with(&format!(
+                    "is inside the fullsize directory {}",
+                    fullsize.display()
+                )),
+                "{}",
+                error
+            );
+            let output = root.join("output-link");
+            std::os::unix::fs::symlink(&thumbnails, &output).unwrap();
+            assert!(check_nested_directories(&sources, &[("output", &output)]).is_err());
+        }
+        // Sources that don't exist are reported when loaded instead.
+        let missing = root.join("missing");
+        assert!(check_nested_directories(
+            &[("fullsize", &missing)],
+            &[("output", &missing.join("out"))]
+        )
+        .is_ok());
+    }
+    #[test]
+    fn distance_histograms_scale_their_buckets_to_the_hash_size() {
+        let buckets = |buckets: &[(&str, usize)]| -> Vec<(String, usize)> {
+            buckets
+                .iter()
+                .map(|(label, count)| ((*label).to_owned(), *count))
+                .collect()
+        };
+        let distances = [0, 0, 1, 2, 3, 5, 6, 10, 11, 20, 21, 64];
+        assert_eq!(
+            distance_histogram(&distances, 64),
+            buckets(&[
+                ("0", 2),
+                ("1-2", 2),
+                ("3-5", 2),
+                ("6-10", 2),
+                ("11-20", 2),
+                (">20", 2)
+            ])
+        );
+        // Each bucket holds the same fraction of the bits of larger hashes.
+        assert_eq!(
+            distance_histogram(&[0, 8, 9, 40, 41, 80, 81, 256], 256),
+            buckets(&[
+                ("0", 1),
+                ("1-8", 1),
+                ("9-20", 1),
+                ("21-40", 1),
+                ("41-80", 2),
+                (">80", 2)
+            ])
+        );
+        // And buckets that would be empty ranges of smaller hashes are merged.
+        assert_eq!(
+            distance_histogram(&[0, 1, 2, 3, 6], 16),
+            buckets(&[("0", 1), ("1", 1), ("2", 1), ("3-5", 1), (">5", 1)])
+        );
+        assert_eq!(
+            distance_histogram(&[], 8),
+            buckets(&[("0", 0), ("1", 0), ("2", 0), (">2", 0)])
+        );
+    }
+    #[test]
+    fn directories_sharing_a_name_are_cached_apart() {
+        let directory = tempfile::tempdir().unwrap();
+        let first = directory.path().join("2019/fullsize");
+        let second = directory.path().join("2020/fullsize");
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        let key = directory_cache_key(&first);
+        assert!(key.starts_with("fullsize-"), "{}", key);
+        assert_ne!(key, directory_cache_key(&second));
+        // The same directory, however it is named, has the same key.
+        assert_eq!(key, directory_cache_key(&first.join("../fullsize/.")));
+    }
+    #[test]
+    fn conflicts_are_the_fullsize_images_claimed_by_several_thumbnails() {
+        let matched = |thumb: &str, fullsize: &str, distance: u32| Match {
+            thumb: PathBuf::from(thumb),
+            fullsize: PathBuf::from(fullsize),
+            distance,
+            transform: Transform::Identity,
+        };
+        let matches = vec![
+            matched("thumbs/c.png", "full/b.jpg", 3),
+            matched("thumbs/a.png", "full/a.jpg", 0),
+            matched("thumbs/b.png", "full/b.jpg", 1),
+            matched("thumbs/d.png", "full/c.jpg", 2),
+            matched("thumbs/e.png", "full/b.jpg", 0),
+        ];
+        let conflicts = find_conflicts(&matches);
+        assert_eq!(
+            conflicts.keys().collect::<Vec<_>>(),
+            vec![&&PathBuf::from("full/b.jpg")]
+        );
+        // Claims are kept in the order matched.
+        let claims: Vec<(&Path, u32)> = conflicts[&PathBuf::from("full/b.jpg")]
+            .iter()
+            .map(|claim| (claim.thumb.as_path(), claim.distance))
+            .collect();
+        assert_eq!(
+            claims,
+            vec![
+                (Path::new("thumbs/c.png"), 3),
+                (Path::new("thumbs/b.png"), 1),
+                (Path::new("thumbs/e.png"), 0)
+            ]
+        );
+        assert_eq!(
+            find_conflicts(&matches[1..])[&PathBuf::from("full/b.jpg")].len(),
+            2
+        );
+        assert!(find_conflicts(&matches[1..4]).is_empty());
+        assert!(find_conflicts(&matches[..2]).is_empty());
+        assert!(find_conflicts(&[]).is_empty());
+    }
+    #[test]
+    fn caches_are_locked_until_the_holder_is_dropped() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = directory.path().join("cache");
+        let held = lock_cache(&cache, false).unwrap();
+        let error = lock_cache(&cache, false).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("in use by process {},", std::process::id())),
+            "{}",
+            error
+        );
+        // The lock stays with the open file when the recorded holder is gone.
+        fs::write(cache.join(LOCK_FILE_NAME), u32::MAX.to_string()).unwrap();
+        let error = lock_cache(&cache, false).unwrap_err().to_string();
+        if process_running("1").is_some() {
+            assert!(
+                error.contains(&format!(
+                    "in use by a process started by process {}, which is no longer running",
+                    u32::MAX
+                )),
+                "{}",
+                error
+            );
+        }
+        drop(held);
+        lock_cache(&cache, false).unwrap();
+    }
+}
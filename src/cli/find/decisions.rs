@@ -0,0 +1,199 @@
+//! What becomes of the best match of each thumbnail, decided the same way by
+//! the first pass of `find` and by `--watch`: whether it is unmatched,
+//! ambiguous, matched or needs review, and where its fullsize image is
+//! copied.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::Level;
+
+use crate::matching::{Match, PathPhash};
+use crate::phash::histogram_similarity;
+
+use super::transfers::{OutputNames, PlannedCopy};
+use super::{emit, Closest, Event, MatchOptions, MatchRecord};
+
+/// What became of the best match of a thumbnail.
+pub enum Decision {
+    /// Further than `--max-distance`, so the thumbnail is unmatched.
+    Unmatched,
+    /// Too close to the runner up for `--min-margin`, so it needs review and
+    /// nothing is copied.
+    Ambiguous,
+    Matched(Box<Matched>),
+}
+
+/// A match, which may need review, with the copies planned for it.
+pub struct Matched {
+    /// Whether it needs review, for its distance or its colors.
+    pub needs_review: bool,
+    /// Similarity of its colors, if below `--min-color-similarity`.
+    pub color_similarity: Option<f32>,
+    /// Where its fullsize image is copied, if it is.
+    pub copy: Option<PathBuf>,
+    /// Where the sidecars of its fullsize image are copied.
+    pub sidecars: Vec<PathBuf>,
+    /// Its record in the match state, unless it needs review.
+    pub record: Option<MatchRecord>,
+}
+
+/// The copies planned for matches so far, and the names they claim.
+pub struct CopyPlan<'a> {
+    pub output_directory: &'a Path,
+    pub names: &'a mut OutputNames,
+    pub copies: &'a mut Vec<PlannedCopy>,
+}
+
+impl CopyPlan<'_> {
+    /// Plans the copy of `fullsize` into `directory`, named after `thumb`,
+    /// returning its destination and those of its sidecars unless it is
+    /// skipped.
+    fn plan(
+        &mut self,
+        directory: &Path,
+        fullsize: &PathPhash,
+        thumb: &PathPhash,
+        options: &MatchOptions,
+    ) -> Result<Option<(PathBuf, Vec<PathBuf>)>> {
+        let destination = match self
+            .names
+            .destination(directory, fullsize, Some(thumb), true)?
+        {
+            Some(destination) => destination,
+            None => return Ok(None),
+        };
+        let sidecars = self.names.plan_with_sidecars(
+            self.copies,
+            PlannedCopy {
+                source: fullsize.path.clone(),
+                destination: destination.clone(),
+                transfer: options.transfer,
+                convert: self.names.conversion(fullsize),
+            },
+            &options.sidecars,
+        );
+        Ok(Some((destination, sidecars)))
+    }
+}
+
+/// Decides what becomes of `output`, the best match of `thumb` to
+/// `fullsize`, which is `ambiguous` if the runner up is too close. Logs the
+/// decision, and plans the copies of the fullsize image: to the review
+/// directory if it needs review and there is one, and otherwise, or with
+/// `--review-copy-output` too, to the output directory.
+pub fn decide(
+    output: &Match,
+    thumb: &PathPhash,
+    fullsize: &PathPhash,
+    ambiguous: bool,
+    options: &MatchOptions,
+    plan: &mut CopyPlan,
+) -> Result<Decision> {
+    let closest = Closest {
+        file: &output.fullsize,
+        distance: output.distance,
+    };
+    if options
+        .max_distance
+        .is_some_and(|max_distance| output.distance > max_distance)
+    {
+        emit(
+            Level::Info,
+            Event::Unmatched {
+                thumb: &output.thumb,
+                closest,
+            },
+        );
+        return Ok(Decision::Unmatched);
+    }
+    if ambiguous {
+        emit(
+            Level::Info,
+            Event::Ambiguous {
+                thumb: &output.thumb,
+                closest,
+            },
+        );
+        return Ok(Decision::Ambiguous);
+    }
+    emit(
+        Level::Info,
+        Event::Matched {
+            thumb: &output.thumb,
+            fullsize: &output.fullsize,
+            distance: output.distance,
+            transform: output.transform.name(),
+        },
+    );
+    let mut needs_review = output.distance > options.warn_distance;
+    if needs_review {
+        emit(
+            Level::Info,
+            Event::DistanceReview {
+                thumb: &output.thumb,
+                fullsize: &output.fullsize,
+                distance: output.distance,
+            },
+        );
+    }
+    let color_similarity = match (
+        options.min_color_similarity,
+        &thumb.histogram,
+        &fullsize.histogram,
+    ) {
+        (Some(min_similarity), Some(thumb_histogram), Some(fullsize_histogram)) => {
+            Some(histogram_similarity(thumb_histogram, fullsize_histogram))
+                .filter(|similarity| *similarity < min_similarity)
+        }
+        _ => None,
+    };
+    if let Some(similarity) = color_similarity {
+        emit(
+            Level::Info,
+            Event::ColorReview {
+                thumb: &output.thumb,
+                fullsize: &output.fullsize,
+                similarity,
+            },
+        );
+        needs_review = true;
+    }
+
+    let mut planned = None;
+    // Imported fullsize images are not available to copy.
+    if options.import_hashes.is_none() {
+        let review_directory = options.review_directory.as_deref().filter(|_| needs_review);
+        if let Some(review_directory) = review_directory {
+            planned = plan.plan(review_directory, fullsize, thumb, options)?;
+        }
+        if review_directory.is_none() || options.review_copy_output {
+            let output_directory = plan.output_directory;
+            if let Some(copy) = plan.plan(output_directory, fullsize, thumb, options)? {
+                planned = Some(copy);
+            }
+        }
+    }
+    let (copy, sidecars) = match planned {
+        Some((copy, sidecars)) => (Some(copy), sidecars),
+        None => (None, Vec::new()),
+    };
+    let record = if needs_review {
+        None
+    } else {
+        Some(MatchRecord::new(
+            output,
+            fullsize,
+            copy.as_deref(),
+            plan.names.conversion(fullsize).is_some(),
+            options.record_digests,
+        ))
+    };
+    Ok(Decision::Matched(Box::new(Matched {
+        needs_review,
+        color_similarity,
+        copy,
+        sidecars,
+        record,
+    })))
+}
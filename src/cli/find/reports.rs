@@ -0,0 +1,693 @@
+//! The reports `find` writes of its matches: the match report in each
+//! `--format`, `review.csv`, `--dump-distances`, and the `--html-report`
+//! gallery and `--review-montages` drawn from the images.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, GenericImageView, RgbImage};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::matching::{Match, Matcher, PathPhash};
+use crate::phash::raw_hex;
+
+use super::{escape_path, open_upright, MatchOptions};
+
+/// Directory beside the `--html-report` holding its previews.
+const PREVIEWS_DIRECTORY_NAME: &str = "previews";
+
+/// Size previews in the `--html-report` are downsized to fit.
+const PREVIEW_SIZE: u32 = 256;
+
+/// Width of the gap between the images of a `--review-montages` montage.
+const MONTAGE_GAP: u32 = 8;
+
+/// Creates the file at `path` for a report in an output directory. A dry
+/// run leaves the output directories untouched, so what is written is
+/// discarded.
+pub fn create_report(path: &Path, dry_run: bool) -> Result<Box<dyn Write>> {
+    if dry_run {
+        return Ok(Box::new(std::io::sink()));
+    }
+    Ok(Box::new(BufWriter::new(File::create(path)?)))
+}
+
+/// Format of `--dump-distances`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpFormat {
+    /// Rows of thumbnail, fullsize image and distance.
+    #[default]
+    Csv,
+    /// A header line, a JSON line listing the thumbnails and fullsize images,
+    /// then for each pair the little-endian `u32` indexes of the thumbnail and
+    /// fullsize image and `u16` distance.
+    Binary,
+}
+
+impl FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(DumpFormat::Csv),
+            "binary" => Ok(DumpFormat::Binary),
+            _ => Err(anyhow!("Unknown dump format: {}", s)),
+        }
+    }
+}
+
+/// Streams the distances computed while matching to a file, for
+/// `--dump-distances`. Workers write the pairs of each thumbnail together,
+/// so memory use doesn't grow with the number of pairs.
+pub struct DistanceDump {
+    format: DumpFormat,
+    thumbs: HashMap<PathBuf, u32>,
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// The first error writing the dump, reported once matching is done.
+    error: Mutex<Option<std::io::Error>>,
+}
+
+impl DistanceDump {
+    /// Creates the dump at `path`, noting in its header how the pairs were
+    /// chosen, as only the pairs compared are listed.
+    pub fn new(
+        path: &Path,
+        format: DumpFormat,
+        thumbs_phashes: &[PathPhash],
+        fullsize_phashes: &[PathPhash],
+        options: &MatchOptions,
+    ) -> Result<Self> {
+        let sparse = options.matcher != Matcher::BruteForce
+            || options.prefilter.is_some()
+            || !options.groups.is_empty()
+            || options.max_aspect_delta.is_some();
+        let description = format!(
+            "matcher {}{}, {}",
+            options.matcher.name(),
+            options
+                .prefilter
+                .map_or_else(String::new, |prefilter| format!(
+                    ", prefilter {}",
+                    prefilter
+                )),
+            if sparse {
+                "sparse: only the pairs compared are listed"
+            } else {
+                "complete: every pair is listed"
+            }
+        );
+        let mut writer: Box<dyn Write + Send> = Box::new(BufWriter::new(File::create(path)?));
+        match format {
+            DumpFormat::Csv => {
+                writeln!(writer, "# {}", description)?;
+                writeln!(writer, "thumb,fullsize,distance")?;
+            }
+            DumpFormat::Binary => {
+                writeln!(writer, "fromthumb-distances 1")?;
+                let header = serde_json::json!({
+                    "description": description,
+                    "thumbs": thumbs_phashes
+                        .iter()
+                        .map(|phash| escape_path(&phash.path))
+                        .collect::<Vec<_>>(),
+                    "fullsize": fullsize_phashes
+                        .iter()
+                        .map(|phash| escape_path(&phash.path))
+                        .collect::<Vec<_>>(),
+                });
+                serde_json::to_writer(&mut writer, &header)?;
+                writeln!(writer)?;
+            }
+        }
+        Ok(DistanceDump {
+            format,
+            thumbs: thumbs_phashes
+                .iter()
+                .enumerate()
+                .map(|(index, phash)| (phash.path.clone(), index as u32))
+                .collect(),
+            writer: Mutex::new(writer),
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Writes the distances from `thumb_phash` to each of `computed`, given
+    /// as indexes into `fullsize_phashes`.
+    pub fn write(
+        &self,
+        thumb_phash: &PathPhash,
+        fullsize_phashes: &[PathPhash],
+        computed: &[(usize, u32)],
+    ) {
+        let mut buffer = Vec::new();
+        match self.format {
+            DumpFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(&mut buffer);
+                let thumb = escape_path(&thumb_phash.path);
+                for (image, distance) in computed.iter() {
+                    let _ = writer.write_record([
+                        thumb.as_ref(),
+                        escape_path(&fullsize_phashes[*image].path).as_ref(),
+                        &distance.to_string(),
+                    ]);
+                }
+                let _ = writer.flush();
+            }
+            DumpFormat::Binary => {
+                let thumb = self.thumbs[&thumb_phash.path];
+                for (image, distance) in computed.iter() {
+                    buffer.extend_from_slice(&thumb.to_le_bytes());
+                    buffer.extend_from_slice(&(*image as u32).to_le_bytes());
+                    buffer.extend_from_slice(&(*distance as u16).to_le_bytes());
+                }
+            }
+        }
+        let result = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .write_all(&buffer);
+        if let Err(error) = result {
+            let mut first = self
+                .error
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            first.get_or_insert(error);
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if let Some(error) = self
+            .error
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+        {
+            return Err(anyhow!("Failed to write distances: {}", error));
+        }
+        self.writer
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .flush()?;
+        Ok(())
+    }
+}
+
+/// Format of the report of matches.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReportFormat {
+    /// One row per match, in thumbnail order.
+    #[default]
+    Csv,
+    /// A single document describing the run, with its matches in thumbnail
+    /// order.
+    Json,
+    /// One object per line, written as each match is made.
+    Jsonl,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            "jsonl" => Ok(ReportFormat::Jsonl),
+            _ => Err(anyhow!("Unknown report format: {}", s)),
+        }
+    }
+}
+
+impl ReportFormat {
+    /// Name of the report in the output directory, unless `--report` is
+    /// given. JSON reports are not named `matches.json`, which holds the
+    /// state read by `--resume`.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "matches.csv",
+            ReportFormat::Json => "matches-report.json",
+            ReportFormat::Jsonl => "matches.jsonl",
+        }
+    }
+}
+
+/// Lists the matches copied to the review directory in `review.csv` at
+/// `path`, or with `append` adds them to the list, as matches are while
+/// watching.
+pub fn write_review_list(path: &Path, review: &[Match], append: bool, dry_run: bool) -> Result<()> {
+    let (file, header): (Box<dyn Write>, bool) = if append && !dry_run {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        (Box::new(BufWriter::new(file)), empty)
+    } else {
+        (create_report(path, dry_run)?, true)
+    };
+    let mut writer = csv::Writer::from_writer(file);
+    if header {
+        writer.write_record(["thumb", "fullsize", "distance"])?;
+    }
+    for output in review.iter() {
+        writer.write_record([
+            escape_path(&output.thumb).as_ref(),
+            escape_path(&output.fullsize).as_ref(),
+            &output.distance.to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Creates the file at `path`, or writes to stdout for `-`.
+fn create_output(path: &Path) -> Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(std::io::stdout()));
+    }
+    Ok(Box::new(BufWriter::new(File::create(path)?)))
+}
+
+/// Returns the raw bytes of a path in hex if it is not valid UTF-8, so that
+/// JSON reports identify it exactly.
+fn path_hex(path: &Path) -> Option<String> {
+    match path.to_str() {
+        Some(_) => None,
+        None => Some(raw_hex(path.as_os_str())),
+    }
+}
+
+/// A match as listed in JSON reports. Paths are given lossily, with their raw
+/// bytes alongside if they are not valid UTF-8.
+#[derive(Debug, Serialize)]
+struct ReportEntry {
+    thumb: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumb_hex: Option<String>,
+    fullsize: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fullsize_hex: Option<String>,
+    distance: u32,
+    transform: &'static str,
+    needs_review: bool,
+    /// Copy of the fullsize image made for the match, if any.
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_hex: Option<String>,
+    /// Copies of the sidecars of the fullsize image carried along with it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sidecars: Vec<String>,
+}
+
+impl ReportEntry {
+    fn new(output: &Match, needs_review: bool, copy: Option<&Path>, sidecars: &[PathBuf]) -> Self {
+        ReportEntry {
+            thumb: output.thumb.to_string_lossy().into_owned(),
+            thumb_hex: path_hex(&output.thumb),
+            fullsize: output.fullsize.to_string_lossy().into_owned(),
+            fullsize_hex: path_hex(&output.fullsize),
+            distance: output.distance,
+            transform: output.transform.name(),
+            needs_review,
+            output: copy.map(|copy| copy.to_string_lossy().into_owned()),
+            output_hex: copy.and_then(path_hex),
+            sidecars: sidecars
+                .iter()
+                .map(|sidecar| escape_path(sidecar).into_owned())
+                .collect(),
+        }
+    }
+}
+
+/// The report of the matches of a run. JSON Lines are written as each match
+/// is made, so they can be followed during a run, while the other formats are
+/// written in thumbnail order once matching is done.
+pub struct MatchReport {
+    path: PathBuf,
+    format: ReportFormat,
+    rows: Vec<(Match, bool, Option<PathBuf>, Vec<PathBuf>)>,
+    lines: Option<Box<dyn Write>>,
+}
+
+impl MatchReport {
+    pub fn new(path: &Path, format: ReportFormat) -> Result<Self> {
+        let lines = match format {
+            ReportFormat::Jsonl => Some(create_output(path)?),
+            _ => None,
+        };
+        Ok(MatchReport {
+            path: path.to_owned(),
+            format,
+            rows: Vec::new(),
+            lines,
+        })
+    }
+
+    /// Opens the JSON Lines report at `path` to add to, for `--watch`.
+    pub fn append(path: &Path) -> Result<Self> {
+        let lines: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(BufWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ))
+        };
+        Ok(MatchReport {
+            path: path.to_owned(),
+            format: ReportFormat::Jsonl,
+            rows: Vec::new(),
+            lines: Some(lines),
+        })
+    }
+
+    pub fn push(
+        &mut self,
+        output: &Match,
+        needs_review: bool,
+        copy: Option<&Path>,
+        sidecars: &[PathBuf],
+    ) -> Result<()> {
+        match self.lines.as_mut() {
+            Some(lines) => {
+                serde_json::to_writer(
+                    &mut *lines,
+                    &ReportEntry::new(output, needs_review, copy, sidecars),
+                )?;
+                writeln!(lines)?;
+                lines.flush()?;
+            }
+            None => self.rows.push((
+                output.clone(),
+                needs_review,
+                copy.map(Path::to_owned),
+                sidecars.to_vec(),
+            )),
+        }
+        Ok(())
+    }
+
+    /// Writes the report, describing the run with `run` in JSON.
+    pub fn finish(mut self, run: serde_json::Value) -> Result<()> {
+        self.rows
+            .sort_by(|(a, _, _, _), (b, _, _, _)| a.thumb.cmp(&b.thumb));
+        match self.format {
+            ReportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(create_output(&self.path)?);
+                writer.write_record([
+                    "thumb",
+                    "fullsize",
+                    "distance",
+                    "needs_review",
+                    "output",
+                    "sidecars",
+                ])?;
+                for (output, needs_review, copy, sidecars) in self.rows.iter() {
+                    let sidecars: Vec<Cow<str>> = sidecars
+                        .iter()
+                        .map(|sidecar| escape_path(sidecar))
+                        .collect();
+                    writer.write_record([
+                        escape_path(&output.thumb).as_ref(),
+                        escape_path(&output.fullsize).as_ref(),
+                        &output.distance.to_string(),
+                        &needs_review.to_string(),
+                        copy.as_ref()
+                            .map_or("".into(), |copy| escape_path(copy))
+                            .as_ref(),
+                        &sidecars.join(";"),
+                    ])?;
+                }
+                writer.flush()?;
+            }
+            ReportFormat::Json => {
+                let matches: Vec<ReportEntry> = self
+                    .rows
+                    .iter()
+                    .map(|(output, needs_review, copy, sidecars)| {
+                        ReportEntry::new(output, *needs_review, copy.as_deref(), sidecars)
+                    })
+                    .collect();
+                let mut document = run;
+                document["matches"] = serde_json::to_value(matches)?;
+                let mut writer = create_output(&self.path)?;
+                serde_json::to_writer_pretty(&mut writer, &document)?;
+                writeln!(writer)?;
+                writer.flush()?;
+            }
+            ReportFormat::Jsonl => {}
+        }
+        Ok(())
+    }
+}
+
+/// Escapes text for HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Writes a preview of the image at `source` to `destination`, downsized to
+/// fit `PREVIEW_SIZE`.
+fn write_preview(source: &Path, destination: &Path, max_decode_pixels: Option<u64>) -> Result<()> {
+    let image = open_upright(source, max_decode_pixels)?;
+    let preview = image.thumbnail(PREVIEW_SIZE, PREVIEW_SIZE).to_rgb();
+    preview.save(destination)?;
+    Ok(())
+}
+
+/// Writes an image of the thumbnail of `output` beside its fullsize image, both
+/// scaled to `height`, to `destination`.
+fn write_montage(
+    output: &Match,
+    destination: &Path,
+    height: u32,
+    max_decode_pixels: Option<u64>,
+) -> Result<()> {
+    let scale = |image: DynamicImage| {
+        let width = std::cmp::max(
+            1,
+            (u64::from(image.width()) * u64::from(height) / u64::from(image.height().max(1)))
+                as u32,
+        );
+        image
+            .resize_exact(width, height, image::imageops::FilterType::Triangle)
+            .to_rgb()
+    };
+    let thumb = scale(open_upright(&output.thumb, max_decode_pixels)?);
+    let fullsize = scale(open_upright(&output.fullsize, max_decode_pixels)?);
+    let mut canvas = RgbImage::from_pixel(
+        thumb.width() + MONTAGE_GAP + fullsize.width(),
+        height,
+        image::Rgb([255, 255, 255]),
+    );
+    image::imageops::replace(&mut canvas, &thumb, 0, 0);
+    image::imageops::replace(&mut canvas, &fullsize, thumb.width() + MONTAGE_GAP, 0);
+    canvas.save(destination)?;
+    Ok(())
+}
+
+/// Writes a montage of each of `outputs` to `directory`, named so that the
+/// furthest matches sort first, with their distance and thumbnail in the
+/// name.
+pub fn write_montages(
+    directory: &Path,
+    mut outputs: Vec<Match>,
+    height: u32,
+    max_decode_pixels: Option<u64>,
+) -> Result<()> {
+    fs::create_dir_all(directory)?;
+    outputs.sort_by(|a, b| {
+        b.distance
+            .cmp(&a.distance)
+            .then_with(|| a.thumb.cmp(&b.thumb))
+    });
+    outputs.par_iter().enumerate().for_each(|(rank, output)| {
+        let name = format!(
+            "{:04}-distance{}-{}.jpg",
+            rank + 1,
+            output.distance,
+            Path::new(output.thumb.file_stem().unwrap_or_default()).to_string_lossy()
+        );
+        if let Err(error) = write_montage(output, &directory.join(name), height, max_decode_pixels)
+        {
+            warn!(
+                "Failed to write a montage of {}: {}",
+                output.thumb.to_string_lossy(),
+                error
+            );
+        }
+    });
+    info!(
+        "Wrote {} montages of matches needing review to {}",
+        outputs.len(),
+        directory.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Writes a page to `path` showing each match beside its thumbnail, followed
+/// by any unmatched thumbnails and conflicts. Previews of the images are
+/// written to `previews` beside the page, linked relatively so the two can be
+/// moved or shared together.
+pub fn write_gallery(
+    path: &Path,
+    matches: &[(Match, bool)],
+    unmatched: &[PathBuf],
+    conflicts: &BTreeMap<&PathBuf, Vec<&Match>>,
+    max_decode_pixels: Option<u64>,
+) -> Result<()> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let previews_directory = directory.join(PREVIEWS_DIRECTORY_NAME);
+    fs::create_dir_all(&previews_directory)?;
+
+    let mut matches: Vec<&(Match, bool)> = matches.iter().collect();
+    matches.sort_by(|(a, _), (b, _)| a.thumb.cmp(&b.thumb));
+    let mut images = BTreeSet::new();
+    for (output, _) in matches.iter() {
+        images.insert(&output.thumb);
+        images.insert(&output.fullsize);
+    }
+    images.extend(unmatched.iter());
+    for (fullsize, claims) in conflicts.iter() {
+        images.insert(fullsize);
+        images.extend(claims.iter().map(|claim| &claim.thumb));
+    }
+
+    // Each image is previewed once, however often it is shown.
+    let images: Vec<&PathBuf> = images.into_iter().collect();
+    let previews: HashMap<&PathBuf, Option<String>> = images
+        .par_iter()
+        .enumerate()
+        .map(|(index, image)| {
+            let name = format!("{}.jpg", index);
+            let preview =
+                match write_preview(image, &previews_directory.join(&name), max_decode_pixels) {
+                    Ok(()) => Some(format!("{}/{}", PREVIEWS_DIRECTORY_NAME, name)),
+                    Err(error) => {
+                        warn!("Failed to preview {}: {}", image.to_string_lossy(), error);
+                        None
+                    }
+                };
+            (*image, preview)
+        })
+        .collect();
+    let figure = |image: &PathBuf| {
+        let caption = escape_html(&escape_path(image));
+        match &previews[image] {
+            Some(preview) => format!(
+                "<figure><img src=\"{}\" alt=\"{}\"><figcaption>{}</figcaption></figure>",
+                preview, caption, caption
+            ),
+            None => format!("<figure><figcaption>{}</figcaption></figure>", caption),
+        }
+    };
+
+    let mut page = BufWriter::new(File::create(path)?);
+    writeln!(page, "<!DOCTYPE html>")?;
+    writeln!(page, "<html>")?;
+    writeln!(page, "<head>")?;
+    writeln!(page, "<meta charset=\"utf-8\">")?;
+    writeln!(page, "<title>Matches</title>")?;
+    writeln!(
+        page,
+        "<style>body {{ font-family: sans-serif; }} td {{ padding: 4px; vertical-align: top; }} tr.review {{ background: #fdd; }} figure {{ display: inline-block; margin: 4px; }} figcaption {{ font-size: small; word-break: break-all; max-width: {}px; }}</style>",
+        PREVIEW_SIZE
+    )?;
+    writeln!(page, "</head>")?;
+    writeln!(page, "<body>")?;
+    writeln!(page, "<h1>Matches</h1>")?;
+    writeln!(page, "<table>")?;
+    writeln!(
+        page,
+        "<tr><th>Thumbnail</th><th>Fullsize</th><th>Distance</th></tr>"
+    )?;
+    for (output, needs_review) in matches.iter() {
+        writeln!(
+            page,
+            "<tr{}><td>{}</td><td>{}</td><td>{}{}</td></tr>",
+            if *needs_review {
+                " class=\"review\""
+            } else {
+                ""
+            },
+            figure(&output.thumb),
+            figure(&output.fullsize),
+            output.distance,
+            if *needs_review { " (needs review)" } else { "" }
+        )?;
+    }
+    writeln!(page, "</table>")?;
+    if !unmatched.is_empty() {
+        writeln!(page, "<h1>Unmatched</h1>")?;
+        for thumb in unmatched.iter() {
+            writeln!(page, "{}", figure(thumb))?;
+        }
+    }
+    if !conflicts.is_empty() {
+        writeln!(page, "<h1>Conflicts</h1>")?;
+        writeln!(page, "<table>")?;
+        writeln!(page, "<tr><th>Fullsize</th><th>Thumbnails</th></tr>")?;
+        for (fullsize, claims) in conflicts.iter() {
+            let thumbs: Vec<String> = claims.iter().map(|claim| figure(&claim.thumb)).collect();
+            writeln!(
+                page,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                figure(fullsize),
+                thumbs.join("")
+            )?;
+        }
+        writeln!(page, "</table>")?;
+    }
+    writeln!(page, "</body>")?;
+    writeln!(page, "</html>")?;
+    page.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::phash::Transform;
+
+    use super::super::REVIEW_FILE_NAME;
+    use super::*;
+
+    #[test]
+    fn review_lists_added_to_keep_one_header() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(REVIEW_FILE_NAME);
+        let review = |thumb: &str, distance: u32| Match {
+            thumb: PathBuf::from(thumb),
+            fullsize: PathBuf::from("full/a.jpg"),
+            distance,
+            transform: Transform::Identity,
+        };
+        write_review_list(&path, &[review("thumbs/a.png", 12)], true, false).unwrap();
+        write_review_list(&path, &[review("thumbs/b.png", 14)], true, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "thumb,fullsize,distance\nthumbs/a.png,full/a.jpg,12\nthumbs/b.png,full/a.jpg,14\n"
+        );
+        write_review_list(&path, &[review("thumbs/c.png", 13)], true, true).unwrap();
+        write_review_list(&path, &[], false, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "thumb,fullsize,distance\n"
+        );
+    }
+}
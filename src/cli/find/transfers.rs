@@ -0,0 +1,1494 @@
+//! Getting fullsize images to where `find` puts them: naming each copy in its
+//! output directory, and copying, linking, moving or converting it there
+//! without leaving partial files behind, as well as quarantining and
+//! archiving images.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{anyhow, Result};
+use filetime::FileTime;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::matching::PathPhash;
+
+use super::{escape_path, interrupted, new_progress_bar, open_upright, relative_path, FailedImage};
+
+const QUARANTINE_FILE_NAME: &str = "quarantine.log";
+
+/// Prefix of the temporary files images are copied to before being renamed
+/// into place.
+const COPY_TEMP_PREFIX: &str = ".fromthumb-partial-";
+
+/// Whether a failure to preserve the times of a copy has been warned of, as
+/// the rest on the same filesystem are likely to fail too.
+static PRESERVE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Whether hardlinks falling back to copies have been warned of, as the rest
+/// to the same filesystem will too.
+static HARDLINK_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// An image to copy once matching is done.
+#[derive(Debug, Clone)]
+pub struct PlannedCopy {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub transfer: Transfer,
+    /// How the image is re-encoded instead of copied verbatim, if it is.
+    pub convert: Option<Conversion>,
+}
+
+/// Format matched fullsize images are converted to when copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Convert {
+    /// Copy images verbatim.
+    #[default]
+    None,
+    Jpeg,
+    Png,
+}
+
+impl Convert {
+    fn name(self) -> &'static str {
+        match self {
+            Convert::None => "none",
+            Convert::Jpeg => "jpeg",
+            Convert::Png => "png",
+        }
+    }
+
+    /// Format of the converted images, if they are converted.
+    fn format(self) -> Option<ImageFormat> {
+        match self {
+            Convert::None => None,
+            Convert::Jpeg => Some(ImageFormat::Jpeg),
+            Convert::Png => Some(ImageFormat::Png),
+        }
+    }
+
+    /// Extension of the converted images.
+    fn extension(self) -> &'static str {
+        match self {
+            Convert::None => "",
+            Convert::Jpeg => "jpg",
+            Convert::Png => "png",
+        }
+    }
+}
+
+impl FromStr for Convert {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Convert::None),
+            "jpeg" => Ok(Convert::Jpeg),
+            "png" => Ok(Convert::Png),
+            _ => Err(anyhow!("Unknown format to convert to: {}", s)),
+        }
+    }
+}
+
+/// How matched fullsize images are re-encoded when copied, for `--convert`.
+#[derive(Debug, Clone, Copy)]
+pub struct Conversion {
+    pub format: Convert,
+    /// Quality of lossy formats, from 1 to 100.
+    pub quality: u8,
+    /// Re-encode images already in the format, rather than copying them.
+    pub force: bool,
+    pub max_decode_pixels: Option<u64>,
+}
+
+impl Conversion {
+    /// Returns whether the image at `path` is re-encoded, which images already
+    /// in the format, by their extension, are not unless forced.
+    fn converts(&self, path: &Path) -> bool {
+        self.force || ImageFormat::from_path(path).ok() != self.format.format()
+    }
+}
+
+/// How fullsize images are put in the output directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transfer {
+    #[default]
+    Copy,
+    /// Hardlink, or copy if the output directory is on another filesystem.
+    Hardlink,
+    /// Symlink, relative to the link unless `--absolute-symlinks` is given.
+    Symlink,
+    /// Move, or copy then remove once the copy is verified if the output
+    /// directory is on another filesystem.
+    Move,
+}
+
+impl FromStr for Transfer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "copy" => Ok(Transfer::Copy),
+            "hardlink" => Ok(Transfer::Hardlink),
+            "symlink" => Ok(Transfer::Symlink),
+            "move" => Ok(Transfer::Move),
+            _ => Err(anyhow!("Unknown transfer: {}", s)),
+        }
+    }
+}
+
+impl Transfer {
+    pub fn name(self) -> &'static str {
+        match self {
+            Transfer::Copy => "copy",
+            Transfer::Hardlink => "hardlink",
+            Transfer::Symlink => "symlink",
+            Transfer::Move => "move",
+        }
+    }
+}
+
+/// What to do when two different images would be copied to the same path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollisionPolicy {
+    /// Copy the later image as `name-1.jpg`, `name-2.jpg` and so on.
+    #[default]
+    Suffix,
+    /// Copy only the first image.
+    Skip,
+    /// Stop the run.
+    Error,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "suffix" => Ok(CollisionPolicy::Suffix),
+            "skip" => Ok(CollisionPolicy::Skip),
+            "error" => Ok(CollisionPolicy::Error),
+            _ => Err(anyhow!("Unknown collision policy: {}", s)),
+        }
+    }
+}
+
+/// Which names matched fullsize images are copied under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rename {
+    /// The name of the fullsize image.
+    #[default]
+    Fullsize,
+    /// The name of the thumbnail, with the extension of the fullsize image.
+    Thumbnail,
+    /// Both, as `thumbnail__fullsize.jpg`.
+    Both,
+}
+
+impl FromStr for Rename {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fullsize" => Ok(Rename::Fullsize),
+            "thumbnail" => Ok(Rename::Thumbnail),
+            "both" => Ok(Rename::Both),
+            _ => Err(anyhow!("Unknown rename: {}", s)),
+        }
+    }
+}
+
+/// Destinations planned so far, and the sources copied to each, so that
+/// different images sharing a file name don't overwrite each other.
+#[derive(Debug, Default)]
+pub struct OutputNames {
+    policy: CollisionPolicy,
+    rename: Rename,
+    /// Copy every image directly into the directory, rather than to its path
+    /// under its source directory.
+    flatten: bool,
+    /// How fullsize images are re-encoded, which changes their extension.
+    convert: Option<Conversion>,
+    pub sources: HashMap<PathBuf, PathBuf>,
+}
+
+impl OutputNames {
+    pub fn new(
+        policy: CollisionPolicy,
+        rename: Rename,
+        flatten: bool,
+        convert: Option<Conversion>,
+    ) -> Self {
+        OutputNames {
+            policy,
+            rename,
+            flatten,
+            convert,
+            ..OutputNames::default()
+        }
+    }
+
+    /// Returns how the fullsize `image` is re-encoded when copied, if it is.
+    pub fn conversion(&self, image: &PathPhash) -> Option<Conversion> {
+        self.convert
+            .filter(|conversion| conversion.converts(&image.path))
+    }
+
+    /// Plans `copy` of a fullsize image, and copies of its sidecars: the files
+    /// beside it with its stem and one of `extensions`, in either case. They
+    /// are named after its destination, unless another image is copied
+    /// there. Returns the destinations of the sidecars.
+    pub fn plan_with_sidecars(
+        &mut self,
+        copies: &mut Vec<PlannedCopy>,
+        copy: PlannedCopy,
+        extensions: &[String],
+    ) -> Vec<PathBuf> {
+        let mut sidecars = Vec::new();
+        for extension in extensions.iter() {
+            let sidecar = [extension.to_lowercase(), extension.to_uppercase()]
+                .iter()
+                .map(|extension| copy.source.with_extension(extension))
+                .find(|sidecar| *sidecar != copy.source && sidecar.is_file());
+            let sidecar = match sidecar {
+                Some(sidecar) => sidecar,
+                None => continue,
+            };
+            let destination = copy
+                .destination
+                .with_extension(sidecar.extension().unwrap_or_default());
+            match self.sources.get(&destination) {
+                Some(claimed) if *claimed == sidecar => {}
+                Some(claimed) => {
+                    warn!(
+                        "Not copying {} to {}, which {} is copied to",
+                        sidecar.to_string_lossy(),
+                        destination.to_string_lossy(),
+                        claimed.to_string_lossy()
+                    );
+                    continue;
+                }
+                None => {
+                    self.sources.insert(destination.clone(), sidecar.clone());
+                }
+            }
+            copies.push(PlannedCopy {
+                source: sidecar,
+                destination: destination.clone(),
+                transfer: copy.transfer,
+                convert: None,
+            });
+            sidecars.push(destination);
+        }
+        copies.push(copy);
+        sidecars
+    }
+
+    /// Returns the path under the output directory `image` is copied to,
+    /// named as chosen by `rename` if it was matched by `thumb`.
+    fn name(&self, image: &PathPhash, thumb: Option<&PathPhash>) -> PathBuf {
+        let thumb = match thumb {
+            Some(thumb) if self.rename != Rename::Fullsize => thumb,
+            _ => return self.layout(image),
+        };
+        let mut file_name = Path::new(&thumb.file_name)
+            .file_stem()
+            .unwrap_or_default()
+            .to_owned();
+        if self.rename == Rename::Both {
+            file_name.push("__");
+            file_name.push(&image.file_name);
+        } else if let Some(extension) = Path::new(&image.file_name).extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        self.layout(thumb).with_file_name(file_name)
+    }
+
+    /// Returns the path under the output directory `image` is copied to by
+    /// its own name.
+    fn layout(&self, image: &PathPhash) -> PathBuf {
+        if self.flatten {
+            PathBuf::from(&image.file_name)
+        } else {
+            image.relative.clone()
+        }
+    }
+
+    /// Returns where in `directory` to copy `image` to, claiming it, or
+    /// `None` if the copy is skipped as another image has its name. Fullsize
+    /// images are named after the `thumb` they matched, if any, as chosen by
+    /// `rename`, and given the extension of the format they are converted to
+    /// if `fullsize`, claiming their fallback destination too.
+    pub fn destination(
+        &mut self,
+        directory: &Path,
+        image: &PathPhash,
+        thumb: Option<&PathPhash>,
+        fullsize: bool,
+    ) -> Result<Option<PathBuf>> {
+        let source = image.path.as_path();
+        let mut name = self.name(image, thumb);
+        let conversion = self.conversion(image).filter(|_| fullsize);
+        if let Some(conversion) = conversion {
+            name.set_extension(conversion.format.extension());
+        }
+        let converted = conversion.is_some();
+        let mut destination = directory.join(&name);
+        let mut suffix = 0;
+        loop {
+            let fallback = fallback_destination(source, &destination);
+            let claimed = match self.sources.get(&destination) {
+                None if converted => self
+                    .sources
+                    .get(&fallback)
+                    .filter(|claimed| *claimed != source),
+                claimed => claimed,
+            };
+            match claimed {
+                None => {
+                    if converted {
+                        self.sources.insert(fallback, source.to_path_buf());
+                    }
+                    break;
+                }
+                Some(claimed) if claimed == source => return Ok(Some(destination)),
+                Some(claimed) => match self.policy {
+                    CollisionPolicy::Suffix => {
+                        suffix += 1;
+                        destination =
+                            directory.join(name.with_file_name(suffixed_file_name(&name, suffix)));
+                    }
+                    CollisionPolicy::Skip => {
+                        warn!(
+                            "Not copying {} to {}, which {} is copied to",
+                            source.to_string_lossy(),
+                            destination.to_string_lossy(),
+                            claimed.to_string_lossy()
+                        );
+                        return Ok(None);
+                    }
+                    CollisionPolicy::Error => {
+                        return Err(anyhow!(
+                            "{} and {} would both be copied to {}",
+                            claimed.to_string_lossy(),
+                            source.to_string_lossy(),
+                            destination.to_string_lossy()
+                        ))
+                    }
+                },
+            }
+        }
+        self.sources
+            .insert(destination.clone(), source.to_path_buf());
+        Ok(Some(destination))
+    }
+}
+
+/// Returns the file name of `path` with `-suffix` before its extension.
+fn suffixed_file_name(path: &Path, suffix: usize) -> OsString {
+    let mut file_name = path.file_stem().unwrap_or_default().to_owned();
+    file_name.push(format!("-{}", suffix));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    file_name
+}
+
+/// How the copies of a run went.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct CopyCounts {
+    pub copied: usize,
+    pub failed: usize,
+    /// Copies not started, as the run was interrupted.
+    pub interrupted: usize,
+    /// Bytes copied by the successful copies.
+    pub bytes: u64,
+}
+
+/// The copies made by `copy_files`.
+#[derive(Debug, Default)]
+pub struct CopiedFiles {
+    pub counts: CopyCounts,
+    /// Paths images were copied to as they are, as converting them failed,
+    /// by the destination they were planned for.
+    pub fallbacks: HashMap<PathBuf, PathBuf>,
+}
+
+impl CopiedFiles {
+    /// Returns the path the copy planned for `destination` was written to.
+    pub fn written<'a>(&'a self, destination: &'a Path) -> &'a Path {
+        self.fallbacks
+            .get(destination)
+            .map_or(destination, PathBuf::as_path)
+    }
+}
+
+/// What to keep of the source file's metadata when copying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preserve {
+    /// Modification and access times.
+    #[default]
+    Times,
+    None,
+}
+
+impl FromStr for Preserve {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "times" => Ok(Preserve::Times),
+            "none" => Ok(Preserve::None),
+            _ => Err(anyhow!("Unknown metadata to preserve: {}", s)),
+        }
+    }
+}
+
+/// Sets the modification and access times of `destination` to those of
+/// `source`.
+fn preserve_times(source: &Path, destination: &Path) -> std::io::Result<()> {
+    let metadata = fs::metadata(source)?;
+    filetime::set_file_times(
+        destination,
+        FileTime::from_last_access_time(&metadata),
+        FileTime::from_last_modification_time(&metadata),
+    )
+}
+
+/// Copies `source` to a temporary file beside `destination`, renamed into
+/// place once complete, so that an interrupted copy never leaves a truncated
+/// image behind. Failing to preserve times is warned of once, and doesn't fail
+/// the copy.
+fn copy_atomically(source: &Path, destination: &Path, preserve: Preserve) -> std::io::Result<u64> {
+    copy_atomically_with(source, destination, preserve, |source, temporary| {
+        fs::copy(source, temporary)
+    })
+}
+
+/// Copies `source` to `destination` as `copy_atomically` does, with `copy`
+/// copying it to the temporary file.
+fn copy_atomically_with(
+    source: &Path,
+    destination: &Path,
+    preserve: Preserve,
+    copy: impl FnOnce(&Path, &Path) -> std::io::Result<u64>,
+) -> std::io::Result<u64> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temporary_name = OsString::from(COPY_TEMP_PREFIX);
+    temporary_name.push(destination.file_name().unwrap_or_default());
+    let temporary = destination.with_file_name(temporary_name);
+    let result = copy(source, &temporary).and_then(|bytes| {
+        if preserve == Preserve::Times {
+            if let Err(error) = preserve_times(source, &temporary) {
+                if !PRESERVE_WARNED.swap(true, Ordering::SeqCst) {
+                    warn!(
+                        "Failed to preserve the times of {}: {}",
+                        destination.to_string_lossy(),
+                        error
+                    );
+                }
+            }
+        }
+        fs::rename(&temporary, destination).map(|_| bytes)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Decodes `source` upright and encodes it at `destination` as chosen by
+/// `conversion`, through a temporary file beside it, returning the bytes
+/// written.
+fn encode_atomically(
+    source: &Path,
+    destination: &Path,
+    conversion: Conversion,
+    preserve: Preserve,
+) -> Result<u64> {
+    let image = open_upright(source, conversion.max_decode_pixels)?;
+    let mut encoded = Vec::new();
+    match conversion.format {
+        // JPEG has no alpha channel.
+        Convert::Jpeg => DynamicImage::ImageRgb8(image.to_rgb())
+            .write_to(&mut encoded, ImageOutputFormat::Jpeg(conversion.quality))?,
+        Convert::Png => image.write_to(&mut encoded, ImageOutputFormat::Png)?,
+        format => return Err(anyhow!("Cannot encode images as {}", format.name())),
+    }
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temporary_name = OsString::from(COPY_TEMP_PREFIX);
+    temporary_name.push(destination.file_name().unwrap_or_default());
+    let temporary = destination.with_file_name(temporary_name);
+    let result = fs::write(&temporary, &encoded).and_then(|_| {
+        if preserve == Preserve::Times {
+            preserve_times(source, &temporary)?;
+        }
+        fs::rename(&temporary, destination)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result?;
+    Ok(encoded.len() as u64)
+}
+
+/// Returns where `source` is copied verbatim if converting it to
+/// `destination` fails: beside it, with its own extension.
+fn fallback_destination(source: &Path, destination: &Path) -> PathBuf {
+    match source.extension() {
+        Some(extension) => destination.with_extension(extension),
+        None => destination.with_extension(""),
+    }
+}
+
+/// Converts `source` to `destination`, or if that fails copies it verbatim
+/// to its fallback destination, returning the path and bytes written.
+fn convert_file(
+    source: &Path,
+    destination: &Path,
+    conversion: Conversion,
+    preserve: Preserve,
+) -> std::io::Result<(PathBuf, u64)> {
+    match encode_atomically(source, destination, conversion, preserve) {
+        Ok(bytes) => Ok((destination.to_path_buf(), bytes)),
+        Err(error) => {
+            let verbatim = fallback_destination(source, destination);
+            warn!(
+                "Failed to convert {} to {}, copying it to {} instead: {}",
+                source.to_string_lossy(),
+                conversion.format.name(),
+                verbatim.to_string_lossy(),
+                error
+            );
+            let bytes = copy_atomically(source, &verbatim, preserve)?;
+            Ok((verbatim, bytes))
+        }
+    }
+}
+
+/// Creates a link at `destination` with `link`, which is given a temporary
+/// path beside it that is renamed into place once made.
+fn link_atomically(
+    destination: &Path,
+    link: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temporary_name = OsString::from(COPY_TEMP_PREFIX);
+    temporary_name.push(destination.file_name().unwrap_or_default());
+    let temporary = destination.with_file_name(temporary_name);
+    let _ = fs::remove_file(&temporary);
+    let result = link(&temporary).and_then(|_| fs::rename(&temporary, destination));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Returns the path of `source` relative to the directory of `link`, with
+/// symlinks resolved.
+fn relative_link_target(source: &Path, link: &Path) -> std::io::Result<PathBuf> {
+    let source = source.canonicalize()?;
+    let directory = link
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+        .canonicalize()?;
+    let common = source
+        .components()
+        .zip(directory.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut target = PathBuf::new();
+    for _ in directory.components().skip(common) {
+        target.push("..");
+    }
+    for component in source.components().skip(common) {
+        target.push(component);
+    }
+    Ok(target)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Symlinks are only supported on unix",
+    ))
+}
+
+/// Moves `source` to `destination`. Across filesystems, `source` is copied,
+/// and only removed once the copy is read back the same.
+fn move_file(source: &Path, destination: &Path, preserve: Preserve) -> std::io::Result<u64> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(source, destination) {
+        Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {}
+        result => return result.map(|_| 0),
+    }
+    let bytes = copy_atomically(source, destination, preserve)?;
+    if xxh3_128(&fs::read(source)?) != xxh3_128(&fs::read(destination)?) {
+        return Err(std::io::Error::other(format!(
+            "Copy at {} differs from its source, which is kept",
+            destination.to_string_lossy()
+        )));
+    }
+    fs::remove_file(source)?;
+    Ok(bytes)
+}
+
+/// Links `source` to `destination` with `hard_link`, or copies it if they are
+/// on different filesystems, returning the bytes copied.
+fn hardlink_file(
+    source: &Path,
+    destination: &Path,
+    preserve: Preserve,
+    hard_link: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> std::io::Result<u64> {
+    match link_atomically(destination, |temporary| hard_link(source, temporary)) {
+        Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {
+            if !HARDLINK_WARNED.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "Copying {} instead of hardlinking it, as {} is on another filesystem",
+                    source.to_string_lossy(),
+                    destination.to_string_lossy()
+                );
+            }
+            copy_atomically(source, destination, preserve)
+        }
+        result => result.map(|_| 0),
+    }
+}
+
+/// Puts `source` at `destination` as chosen by `transfer`, returning the
+/// bytes copied.
+fn transfer_file(
+    source: &Path,
+    destination: &Path,
+    transfer: Transfer,
+    preserve: Preserve,
+    absolute_symlinks: bool,
+) -> std::io::Result<u64> {
+    match transfer {
+        Transfer::Copy => copy_atomically(source, destination, preserve),
+        Transfer::Hardlink => hardlink_file(source, destination, preserve, |source, link| {
+            fs::hard_link(source, link)
+        }),
+        Transfer::Symlink => link_atomically(destination, |temporary| {
+            let target = if absolute_symlinks {
+                source.canonicalize()?
+            } else {
+                relative_link_target(source, temporary)?
+            };
+            symlink(&target, temporary)
+        })
+        .map(|_| 0),
+        Transfer::Move => move_file(source, destination, preserve),
+    }
+}
+
+/// Removes temporary copies left in the directories of `copies` by a run
+/// that was killed.
+fn remove_partial_copies(copies: &[PlannedCopy]) -> Result<()> {
+    let directories: BTreeSet<&Path> = copies
+        .iter()
+        .filter_map(|copy| copy.destination.parent())
+        .collect();
+    let mut removed = 0;
+    for directory in directories {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(COPY_TEMP_PREFIX)
+            {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+    }
+    if removed > 0 {
+        info!("Removed {} partial copies left by an earlier run", removed);
+    }
+    Ok(())
+}
+
+/// Makes each of `copies` on a pool of `threads` threads of its own, as
+/// copies wait on IO rather than the CPU. Each destination is copied to once,
+/// from the first source planned for it. A failed copy doesn't stop the
+/// others, and failures are logged once every copy is done. Once interrupted,
+/// copies in progress finish but no more are started. A source moved to
+/// several destinations is copied to all but the last, which it is moved to
+/// once the others are done.
+pub fn copy_files(
+    copies: Vec<PlannedCopy>,
+    threads: usize,
+    preserve: Preserve,
+    absolute_symlinks: bool,
+    dry_run: bool,
+    progress: bool,
+) -> Result<CopiedFiles> {
+    let mut sources: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut unique = Vec::with_capacity(copies.len());
+    for copy in copies.into_iter() {
+        match sources.get(&copy.destination) {
+            Some(source) if *source == copy.source => {}
+            Some(source) => warn!(
+                "Not copying {} to {}, which {} is copied to",
+                copy.source.to_string_lossy(),
+                copy.destination.to_string_lossy(),
+                source.to_string_lossy()
+            ),
+            None => {
+                sources.insert(copy.destination.clone(), copy.source.clone());
+                unique.push(copy);
+            }
+        }
+    }
+
+    // A dry run only lists the copies, counting the bytes they would copy.
+    if dry_run {
+        let mut counts = CopyCounts::default();
+        for copy in unique.iter() {
+            info!(
+                "Would {} {} to {}",
+                match copy.convert {
+                    Some(conversion) => conversion.format.name(),
+                    None => copy.transfer.name(),
+                },
+                copy.source.to_string_lossy(),
+                copy.destination.to_string_lossy()
+            );
+            counts.copied += 1;
+            counts.bytes += fs::metadata(&copy.source).map_or(0, |metadata| metadata.len());
+        }
+        return Ok(CopiedFiles {
+            counts,
+            ..CopiedFiles::default()
+        });
+    }
+
+    remove_partial_copies(&unique)?;
+
+    let mut last_moves: HashMap<&Path, usize> = HashMap::new();
+    for (index, copy) in unique.iter().enumerate() {
+        if copy.transfer == Transfer::Move {
+            last_moves.insert(&copy.source, index);
+        }
+    }
+    let transfers: Vec<Transfer> = unique
+        .iter()
+        .enumerate()
+        .map(|(index, copy)| match copy.transfer {
+            Transfer::Move if last_moves[copy.source.as_path()] != index => Transfer::Copy,
+            transfer => transfer,
+        })
+        .collect();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    let progress_bar = new_progress_bar(progress, unique.len(), "Copying");
+    let transfer = |index: usize| {
+        if interrupted() {
+            return None;
+        }
+        let copy = &unique[index];
+        let result = match copy.convert {
+            Some(conversion) => convert_file(&copy.source, &copy.destination, conversion, preserve),
+            None => transfer_file(
+                &copy.source,
+                &copy.destination,
+                transfers[index],
+                preserve,
+                absolute_symlinks,
+            )
+            .map(|bytes| (copy.destination.clone(), bytes)),
+        };
+        if let Some(progress_bar) = &progress_bar {
+            progress_bar.inc(1);
+        }
+        Some(result)
+    };
+    let mut results: Vec<_> = pool.install(|| {
+        (0..unique.len())
+            .into_par_iter()
+            .map(|index| match transfers[index] {
+                Transfer::Move => None,
+                _ => transfer(index),
+            })
+            .collect()
+    });
+    let moves: Vec<usize> = (0..unique.len())
+        .filter(|index| transfers[*index] == Transfer::Move)
+        .collect();
+    let moved: Vec<_> = pool.install(|| moves.par_iter().map(|index| transfer(*index)).collect());
+    for (index, result) in moves.into_iter().zip(moved) {
+        results[index] = result;
+    }
+    if let Some(progress_bar) = progress_bar {
+        progress_bar.finish_and_clear();
+    }
+
+    let mut copied = CopiedFiles::default();
+    let counts = &mut copied.counts;
+    for (copy, result) in unique.iter().zip(results) {
+        let result = match result {
+            Some(result) => result,
+            None => {
+                counts.interrupted += 1;
+                continue;
+            }
+        };
+        match result {
+            Ok((path, bytes)) => {
+                counts.copied += 1;
+                counts.bytes += bytes;
+                if path != copy.destination {
+                    copied.fallbacks.insert(copy.destination.clone(), path);
+                }
+            }
+            Err(error) => {
+                warn!(
+                    "Failed to copy {} to {}: {}",
+                    copy.source.to_string_lossy(),
+                    copy.destination.to_string_lossy(),
+                    error
+                );
+                counts.failed += 1;
+            }
+        }
+    }
+    Ok(copied)
+}
+
+/// Sets aside the corrupt images among `failures` in `directory`, at their path
+/// under the closest of `roots`, so they can be scanned again. Images are
+/// copied, or moved if `move_files`, and each error is appended to
+/// `quarantine.log`. Images that could not be read are left alone, as trying
+/// again may succeed, and each image that fails to be set aside is warned of.
+/// Returns how many images were quarantined.
+pub fn quarantine_failures(
+    failures: &[FailedImage],
+    roots: &[PathBuf],
+    directory: &Path,
+    move_files: bool,
+    preserve: Preserve,
+    dry_run: bool,
+) -> usize {
+    let corrupt: Vec<&FailedImage> = failures.iter().filter(|failure| failure.corrupt).collect();
+    if corrupt.is_empty() {
+        return 0;
+    }
+    if dry_run {
+        info!(
+            "Would quarantine {} images that failed to decode in {}",
+            corrupt.len(),
+            directory.to_string_lossy()
+        );
+        return corrupt.len();
+    }
+    let log = fs::create_dir_all(directory).and_then(|_| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join(QUARANTINE_FILE_NAME))
+    });
+    let mut log = match log {
+        Ok(log) => log,
+        Err(error) => {
+            warn!(
+                "Failed to quarantine {} images in {}: {}",
+                corrupt.len(),
+                directory.to_string_lossy(),
+                error
+            );
+            return 0;
+        }
+    };
+    let mut quarantined = 0;
+    for failure in corrupt.iter() {
+        let relative = relative_path(&failure.path, roots)
+            .unwrap_or_else(|| Path::new(failure.path.file_name().unwrap_or_default()));
+        let destination = directory.join(relative);
+        let result = if move_files {
+            move_file(&failure.path, &destination, preserve)
+        } else {
+            copy_atomically(&failure.path, &destination, preserve)
+        }
+        .and_then(|_| writeln!(log, "{}\t{}", escape_path(&failure.path), failure.error));
+        match result {
+            Ok(()) => quarantined += 1,
+            Err(error) => warn!(
+                "Failed to quarantine {} to {}: {}",
+                failure.path.to_string_lossy(),
+                destination.to_string_lossy(),
+                error
+            ),
+        }
+    }
+    info!(
+        "Quarantined {} images that failed to decode in {}",
+        quarantined,
+        directory.to_string_lossy()
+    );
+    quarantined
+}
+
+/// How the matched fullsize images of a run were archived.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct ArchiveCounts {
+    pub archived: usize,
+    pub failed: usize,
+}
+
+/// Returns whether `path` holds the same contents as the file of `digest`.
+fn has_digest(path: &Path, digest: u128) -> bool {
+    fs::read(path).is_ok_and(|contents| xxh3_128(&contents) == digest)
+}
+
+/// Moves each of `sources` to its path under the closest of `roots` in
+/// `directory`, once each of its `copies` is verified to hold the same
+/// contents, or to exist if converted. Sources without copies, or with one
+/// missing or differing, are kept, and each failure is warned of. Returns the
+/// moves made.
+pub fn archive_matched(
+    sources: &[&PathBuf],
+    copies: &[PlannedCopy],
+    roots: &[PathBuf],
+    directory: &Path,
+    preserve: Preserve,
+    dry_run: bool,
+) -> (Vec<(PathBuf, PathBuf)>, ArchiveCounts) {
+    let mut destinations: HashMap<&Path, Vec<&PlannedCopy>> = HashMap::new();
+    for copy in copies.iter() {
+        destinations.entry(&copy.source).or_default().push(copy);
+    }
+    let mut moves = Vec::new();
+    let mut counts = ArchiveCounts::default();
+    for source in sources.iter() {
+        let relative = relative_path(source, roots)
+            .unwrap_or_else(|| Path::new(source.file_name().unwrap_or_default()));
+        let archived = directory.join(relative);
+        let copied = match destinations.get(source.as_path()) {
+            Some(copied) => copied,
+            None => {
+                debug!(
+                    "Not archiving {}, which was not copied",
+                    source.to_string_lossy()
+                );
+                continue;
+            }
+        };
+        if dry_run {
+            info!(
+                "Would archive {} to {}",
+                source.to_string_lossy(),
+                archived.to_string_lossy()
+            );
+            counts.archived += 1;
+            continue;
+        }
+        let result = fs::read(source)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| {
+                let digest = xxh3_128(&contents);
+                let unverified = copied.iter().find(|copy| match copy.convert {
+                    Some(_) => !copy.destination.is_file(),
+                    None => !has_digest(&copy.destination, digest),
+                });
+                if let Some(copy) = unverified {
+                    return Err(anyhow!(
+                        "Copy at {} is missing or differs from it",
+                        copy.destination.to_string_lossy()
+                    ));
+                }
+                if archived.exists() {
+                    return Err(anyhow!("{} already exists", archived.to_string_lossy()));
+                }
+                Ok(move_file(source, &archived, preserve)?)
+            });
+        match result {
+            Ok(_) => {
+                counts.archived += 1;
+                moves.push(((*source).clone(), archived));
+            }
+            Err(error) => {
+                warn!("Failed to archive {}: {}", source.to_string_lossy(), error);
+                counts.failed += 1;
+            }
+        }
+    }
+    (moves, counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::image;
+    use super::super::INTERRUPTED;
+    use super::*;
+
+    fn output_names(policy: CollisionPolicy) -> OutputNames {
+        OutputNames::new(policy, Rename::Fullsize, false, None)
+    }
+
+    /// Plans the copies of `images` with `names`, returning their destinations
+    /// under `output`.
+    fn destinations(names: &mut OutputNames, images: &[PathPhash]) -> Result<Vec<Option<String>>> {
+        images
+            .iter()
+            .map(|image| {
+                let destination = names.destination(Path::new("output"), image, None, true)?;
+                Ok(destination.map(|destination| destination.to_string_lossy().into_owned()))
+            })
+            .collect()
+    }
+
+    fn same_names() -> Vec<PathPhash> {
+        vec![
+            image("a/photo.jpg", "photo.jpg"),
+            image("b/photo.jpg", "photo.jpg"),
+            image("c/photo.jpg", "photo.jpg"),
+        ]
+    }
+
+    #[test]
+    fn suffixed_file_names_keep_the_extension() {
+        assert_eq!(
+            suffixed_file_name(Path::new("2019/photo.jpg"), 1),
+            OsString::from("photo-1.jpg")
+        );
+        assert_eq!(
+            suffixed_file_name(Path::new("photo.tar.gz"), 12),
+            OsString::from("photo.tar-12.gz")
+        );
+        assert_eq!(
+            suffixed_file_name(Path::new("README"), 2),
+            OsString::from("README-2")
+        );
+    }
+
+    #[test]
+    fn colliding_images_are_suffixed_in_the_order_planned() {
+        let mut names = output_names(CollisionPolicy::Suffix);
+        let mut images = same_names();
+        // Planning the same image again gives the same destination.
+        images.push(image("b/photo.jpg", "photo.jpg"));
+        images.push(image("b/other.jpg", "other.jpg"));
+        assert_eq!(
+            destinations(&mut names, &images).unwrap(),
+            vec![
+                Some("output/photo.jpg".to_owned()),
+                Some("output/photo-1.jpg".to_owned()),
+                Some("output/photo-2.jpg".to_owned()),
+                Some("output/photo-1.jpg".to_owned()),
+                Some("output/other.jpg".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn suffixes_skip_names_already_claimed() {
+        let mut names = output_names(CollisionPolicy::Suffix);
+        let images = vec![
+            image("a/photo-1.jpg", "photo-1.jpg"),
+            image("a/photo.jpg", "photo.jpg"),
+            image("b/photo.jpg", "photo.jpg"),
+        ];
+        assert_eq!(
+            destinations(&mut names, &images).unwrap(),
+            vec![
+                Some("output/photo-1.jpg".to_owned()),
+                Some("output/photo.jpg".to_owned()),
+                Some("output/photo-2.jpg".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn colliding_images_are_skipped_or_an_error_by_policy() {
+        let mut names = output_names(CollisionPolicy::Skip);
+        assert_eq!(
+            destinations(&mut names, &same_names()).unwrap(),
+            vec![Some("output/photo.jpg".to_owned()), None, None]
+        );
+        let mut names = output_names(CollisionPolicy::Error);
+        let error = destinations(&mut names, &same_names()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "a/photo.jpg and b/photo.jpg would both be copied to output/photo.jpg"
+        );
+    }
+
+    #[test]
+    fn images_in_different_directories_only_collide_when_flattened() {
+        let images = vec![
+            image("fullsize/2019/photo.jpg", "2019/photo.jpg"),
+            image("fullsize/2020/photo.jpg", "2020/photo.jpg"),
+        ];
+        let mut names = output_names(CollisionPolicy::Error);
+        assert_eq!(
+            destinations(&mut names, &images).unwrap(),
+            vec![
+                Some("output/2019/photo.jpg".to_owned()),
+                Some("output/2020/photo.jpg".to_owned()),
+            ]
+        );
+        let mut names = OutputNames::new(CollisionPolicy::Suffix, Rename::Fullsize, true, None);
+        assert_eq!(
+            destinations(&mut names, &images).unwrap(),
+            vec![
+                Some("output/photo.jpg".to_owned()),
+                Some("output/photo-1.jpg".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn converted_images_collide_by_their_new_extension() {
+        let conversion = Conversion {
+            format: Convert::Jpeg,
+            quality: 90,
+            force: false,
+            max_decode_pixels: None,
+        };
+        let mut names = OutputNames::new(
+            CollisionPolicy::Suffix,
+            Rename::Fullsize,
+            false,
+            Some(conversion),
+        );
+        let images = vec![
+            image("a/photo.tif", "photo.tif"),
+            image("a/photo.jpg", "photo.jpg"),
+            image("a/photo.png", "photo.png"),
+        ];
+        assert_eq!(
+            destinations(&mut names, &images).unwrap(),
+            vec![
+                Some("output/photo.jpg".to_owned()),
+                Some("output/photo-1.jpg".to_owned()),
+                Some("output/photo-2.jpg".to_owned()),
+            ]
+        );
+        // Thumbnails keep their extension.
+        let thumb = image("thumbs/photo.png", "photo.png");
+        assert_eq!(
+            names
+                .destination(Path::new("output"), &thumb, None, false)
+                .unwrap(),
+            Some(PathBuf::from("output/photo.png"))
+        );
+    }
+
+    #[test]
+    fn converted_images_collide_by_the_copy_they_fall_back_to() {
+        let conversion = Conversion {
+            format: Convert::Jpeg,
+            quality: 90,
+            force: false,
+            max_decode_pixels: None,
+        };
+        let mut names = OutputNames::new(
+            CollisionPolicy::Suffix,
+            Rename::Fullsize,
+            false,
+            Some(conversion),
+        );
+        // A thumbnail copied as it is claims the fallback of the fullsize image.
+        let thumb = image("thumbs/photo.tif", "photo.tif");
+        names
+            .destination(Path::new("output"), &thumb, None, false)
+            .unwrap();
+        let images = vec![
+            image("a/photo.tif", "photo.tif"),
+            image("b/photo.png", "photo.png"),
+        ];
+        assert_eq!(
+            destinations(&mut names, &images).unwrap(),
+            vec![
+                Some("output/photo-1.jpg".to_owned()),
+                Some("output/photo.jpg".to_owned()),
+            ]
+        );
+        // Which is claimed by the fullsize image in turn.
+        let thumb = image("thumbs/photo-1.tif", "photo-1.tif");
+        assert_eq!(
+            names
+                .destination(Path::new("output"), &thumb, None, false)
+                .unwrap(),
+            Some(PathBuf::from("output/photo-1-1.tif"))
+        );
+        assert_eq!(
+            fallback_destination(Path::new("a/photo.tif"), Path::new("output/photo-1.jpg")),
+            PathBuf::from("output/photo-1.tif")
+        );
+    }
+
+    #[test]
+    fn renamed_images_collide_by_the_name_of_their_thumbnail() {
+        let thumb = image("thumbs/scan.png", "scan.png");
+        let mut names = OutputNames::new(CollisionPolicy::Suffix, Rename::Thumbnail, false, None);
+        let planned: Vec<PathBuf> = [image("a/one.jpg", "one.jpg"), image("a/two.jpg", "two.jpg")]
+            .iter()
+            .map(|fullsize| {
+                names
+                    .destination(Path::new("output"), fullsize, Some(&thumb), true)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            planned,
+            [
+                PathBuf::from("output/scan.jpg"),
+                PathBuf::from("output/scan-1.jpg")
+            ]
+        );
+    }
+
+    #[test]
+    fn link_targets_are_relative_to_the_directory_of_the_link() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path();
+        fs::create_dir_all(root.join("fullsize/2019")).unwrap();
+        fs::create_dir_all(root.join("output/2019")).unwrap();
+        let source = root.join("fullsize/2019/photo.jpg");
+        fs::write(&source, b"photo").unwrap();
+        assert_eq!(
+            relative_link_target(&source, &root.join("output/2019/photo.jpg")).unwrap(),
+            PathBuf::from("../../fullsize/2019/photo.jpg")
+        );
+        assert_eq!(
+            relative_link_target(&source, &root.join("fullsize/2019/link.jpg")).unwrap(),
+            PathBuf::from("photo.jpg")
+        );
+        assert_eq!(
+            relative_link_target(&source, &root.join("fullsize/link.jpg")).unwrap(),
+            PathBuf::from("2019/photo.jpg")
+        );
+        // Symlinked directories are resolved on both sides.
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("fullsize/2019"), root.join("linked")).unwrap();
+            std::os::unix::fs::symlink(root.join("output/2019"), root.join("out")).unwrap();
+            assert_eq!(
+                relative_link_target(&root.join("linked/photo.jpg"), &root.join("out/photo.jpg"))
+                    .unwrap(),
+                PathBuf::from("../../fullsize/2019/photo.jpg")
+            );
+            let link = root.join("output/2019/photo.jpg");
+            transfer_file(&source, &link, Transfer::Symlink, Preserve::None, false).unwrap();
+            assert_eq!(
+                fs::read_link(&link).unwrap(),
+                PathBuf::from("../../fullsize/2019/photo.jpg")
+            );
+            assert_eq!(fs::read(&link).unwrap(), b"photo");
+        }
+        assert!(relative_link_target(&root.join("missing.jpg"), &root.join("link.jpg")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlinks_across_filesystems_fall_back_to_copies() {
+        use std::os::unix::fs::MetadataExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("photo.jpg");
+        fs::write(&source, b"photo").unwrap();
+        let linked = directory.path().join("output/linked.jpg");
+        assert_eq!(
+            hardlink_file(&source, &linked, Preserve::None, |source, link| {
+                fs::hard_link(source, link)
+            })
+            .unwrap(),
+            0
+        );
+        assert_eq!(fs::metadata(&source).unwrap().nlink(), 2);
+
+        let copied = directory.path().join("output/copied.jpg");
+        let bytes = hardlink_file(&source, &copied, Preserve::None, |_, _| {
+            Err(std::io::ErrorKind::CrossesDevices.into())
+        })
+        .unwrap();
+        assert_eq!(bytes, 5);
+        assert_eq!(fs::read(&copied).unwrap(), b"photo");
+        assert_eq!(fs::metadata(&copied).unwrap().nlink(), 1);
+        assert_eq!(fs::metadata(&source).unwrap().nlink(), 2);
+        // Other failures are not copied around.
+        let failed = directory.path().join("output/failed.jpg");
+        assert!(hardlink_file(&source, &failed, Preserve::None, |_, _| {
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        })
+        .is_err());
+        assert!(!failed.exists());
+        assert_eq!(
+            entry_names(&directory.path().join("output")),
+            ["copied.jpg", "linked.jpg"]
+        );
+    }
+
+    /// Returns the names of the entries of `directory`, sorted.
+    fn entry_names(directory: &Path) -> Vec<OsString> {
+        let mut names: Vec<OsString> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn failed_copies_leave_no_partial_file_behind() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("photo.jpg");
+        fs::write(&source, b"photo").unwrap();
+        let output = directory.path().join("output");
+        let destination = output.join("photo.jpg");
+        let error = copy_atomically_with(&source, &destination, Preserve::None, |_, temporary| {
+            fs::write(temporary, b"pho")?;
+            Err(std::io::Error::other("disk full"))
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+        assert!(
+            entry_names(&output).is_empty(),
+            "{:?}",
+            entry_names(&output)
+        );
+
+        // Nor does one that can't be renamed into place.
+        fs::create_dir_all(destination.join("taken")).unwrap();
+        assert!(copy_atomically(&source, &destination, Preserve::None).is_err());
+        assert_eq!(entry_names(&output), ["photo.jpg"]);
+        assert!(destination.is_dir());
+    }
+
+    #[test]
+    fn partial_copies_of_a_killed_run_are_removed() {
+        let directory = tempfile::tempdir().unwrap();
+        let output = directory.path().join("output");
+        fs::create_dir_all(output.join("2019")).unwrap();
+        for name in [
+            ".fromthumb-partial-a.jpg",
+            "b.jpg",
+            "2019/.fromthumb-partial-c.jpg",
+        ]
+        .iter()
+        {
+            fs::write(output.join(name), b"partial").unwrap();
+        }
+        let copy = |destination: &str| PlannedCopy {
+            source: directory.path().join("source.jpg"),
+            destination: output.join(destination),
+            transfer: Transfer::Copy,
+            convert: None,
+        };
+        remove_partial_copies(&[copy("a.jpg"), copy("a.jpg"), copy("missing/d.jpg")]).unwrap();
+        assert_eq!(entry_names(&output), ["2019", "b.jpg"]);
+        // Only the directories copied to are cleaned.
+        assert_eq!(
+            entry_names(&output.join("2019")),
+            [".fromthumb-partial-c.jpg"]
+        );
+        remove_partial_copies(&[copy("2019/c.jpg")]).unwrap();
+        assert!(entry_names(&output.join("2019")).is_empty());
+    }
+
+    #[test]
+    fn copies_keep_the_times_of_their_image_unless_told_not_to() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("photo.jpg");
+        fs::write(&source, b"photo").unwrap();
+        let accessed = FileTime::from_unix_time(1_000_000_000, 0);
+        let modified = FileTime::from_unix_time(1_200_000_000, 500);
+        filetime::set_file_times(&source, accessed, modified).unwrap();
+        // Reading the image may update its access time, so only the
+        // modification time is known.
+        let modified_time =
+            |path: &Path| FileTime::from_last_modification_time(&fs::metadata(path).unwrap());
+
+        let kept = directory.path().join("output/kept.jpg");
+        copy_atomically(&source, &kept, Preserve::Times).unwrap();
+        assert_eq!(modified_time(&kept), modified);
+        let converted = directory.path().join("output/converted.png");
+        let png = directory.path().join("photo.png");
+        image::RgbImage::new(2, 2).save(&png).unwrap();
+        filetime::set_file_times(&png, accessed, modified).unwrap();
+        let conversion = Conversion {
+            format: Convert::Png,
+            quality: 90,
+            force: true,
+            max_decode_pixels: None,
+        };
+        convert_file(&png, &converted, conversion, Preserve::Times).unwrap();
+        assert_eq!(modified_time(&converted), modified);
+
+        let fresh = directory.path().join("output/fresh.jpg");
+        copy_atomically(&source, &fresh, Preserve::None).unwrap();
+        assert_ne!(modified_time(&fresh), modified);
+        assert_eq!("none".parse::<Preserve>().unwrap(), Preserve::None);
+        assert!("owner".parse::<Preserve>().is_err());
+    }
+
+    #[test]
+    fn interrupted_runs_start_no_more_copies() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("photo.jpg");
+        fs::write(&source, b"photo").unwrap();
+        let output = directory.path().join("output");
+        let copies: Vec<PlannedCopy> = ["a.jpg", "b.jpg"]
+            .iter()
+            .map(|name| PlannedCopy {
+                source: source.clone(),
+                destination: output.join(name),
+                transfer: Transfer::Copy,
+                convert: None,
+            })
+            .collect();
+
+        // No other test does work that stops once interrupted.
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        assert!(interrupted());
+        let copied = copy_files(copies.clone(), 2, Preserve::None, false, false, false);
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let counts = copied.unwrap().counts;
+        assert_eq!((counts.copied, counts.interrupted), (0, 2));
+        assert!(!output.join("a.jpg").exists() && !output.join("b.jpg").exists());
+
+        assert!(!interrupted());
+        let counts = copy_files(copies, 2, Preserve::None, false, false, false)
+            .unwrap()
+            .counts;
+        assert_eq!(
+            (counts.copied, counts.interrupted, counts.bytes),
+            (2, 0, 10)
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! `--watch`, which keeps matching thumbnails as they are added to the
+//! thumbnail directories once the first pass is done.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use notify::Watcher;
+
+use crate::matching::FullsizeSearch;
+
+use super::decisions::{decide, CopyPlan, Decision};
+use super::reports::{write_review_list, MatchReport};
+use super::transfers::{copy_files, OutputNames};
+use super::{
+    find_candidates, import_hashes, interrupted, load_directories, load_sources,
+    set_relative_paths, EntryFilter, LoadCounts, LoadOptions, MatchOptions, MatchState, Source,
+    MATCHES_FILE_NAME, REVIEW_FILE_NAME,
+};
+
+/// How often `--watch` looks at whether new thumbnails have settled.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Options of `--watch`.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchOptions {
+    /// How long a new thumbnail must go unchanged before it is matched, so
+    /// that files still being written are not.
+    pub settle: Duration,
+    /// How often the fullsize directories are loaded again, if ever.
+    pub rescan: Option<Duration>,
+}
+
+/// The matches made while watching, recorded as the first pass records its
+/// own.
+struct Watched {
+    names: OutputNames,
+    state: MatchState,
+    report: Option<MatchReport>,
+    matched: usize,
+    review: usize,
+    unmatched: usize,
+    failed: usize,
+}
+
+/// Watcher of the thumbnail directories for `--watch`, started before the
+/// first pass so that no thumbnail added during it is missed.
+pub struct ThumbnailWatcher {
+    _watcher: notify::RecommendedWatcher,
+    events: Receiver<notify::RawEvent>,
+    /// Each watched directory with its resolved path, which events are given
+    /// under.
+    directories: Vec<(PathBuf, PathBuf)>,
+}
+
+impl ThumbnailWatcher {
+    pub fn new(directories: &[PathBuf], recursive: bool) -> Result<Self> {
+        let (sender, events) = channel();
+        let mut watcher = notify::raw_watcher(sender)?;
+        let mode = if recursive {
+            notify::RecursiveMode::Recursive
+        } else {
+            notify::RecursiveMode::NonRecursive
+        };
+        let mut resolved = Vec::with_capacity(directories.len());
+        for directory in directories.iter() {
+            watcher.watch(directory, mode)?;
+            resolved.push((directory.clone(), directory.canonicalize()?));
+        }
+        Ok(ThumbnailWatcher {
+            _watcher: watcher,
+            events,
+            directories: resolved,
+        })
+    }
+
+    /// Returns the path of an event as it is under the watched directory
+    /// given, rather than resolved.
+    fn source_path(&self, path: &Path) -> Option<PathBuf> {
+        self.directories.iter().find_map(|(directory, resolved)| {
+            path.strip_prefix(resolved)
+                .ok()
+                .map(|relative| directory.join(relative))
+        })
+    }
+
+    /// Returns the directory of the thumbnail at `path` whose cache it is kept
+    /// in, if it is loaded.
+    fn source_directory(&self, path: &Path, filter: &EntryFilter) -> Option<PathBuf> {
+        let relative = self
+            .directories
+            .iter()
+            .find_map(|(root, _)| path.strip_prefix(root).ok())?;
+        if filter.skip_reason(path).is_some()
+            || !filter.passes_globs(path, relative, &mut LoadCounts::default())
+        {
+            return None;
+        }
+        path.parent().map(Path::to_owned)
+    }
+
+    /// Matches thumbnails as they are added, against the fullsize images in
+    /// `fullsize_directories`, until interrupted.
+    pub fn run(
+        self,
+        fullsize_directories: &[PathBuf],
+        thumbnail_directories: &[PathBuf],
+        cache_directory: &Path,
+        output_directory: &Path,
+        options: &MatchOptions,
+        watch: WatchOptions,
+    ) -> Result<()> {
+        let state_path = output_directory.join(MATCHES_FILE_NAME);
+        let state = MatchState::read(&state_path)?;
+        let mut names = OutputNames::new(
+            options.on_collision,
+            options.rename,
+            options.flatten,
+            options.convert,
+        );
+        for record in state.matches.iter() {
+            if let Some(output) = &record.output {
+                names
+                    .sources
+                    .insert(PathBuf::from(output), PathBuf::from(&record.fullsize));
+            }
+        }
+        // Only JSON Lines reports can be added to, which `run` checks for.
+        let report = match &options.report {
+            Some(path) => Some(MatchReport::append(path)?),
+            None => None,
+        };
+        let mut watched = Watched {
+            names,
+            state,
+            report,
+            matched: 0,
+            review: 0,
+            unmatched: 0,
+            failed: 0,
+        };
+        // Thumbnails waiting to settle, with their size and modification time
+        // when last seen, and when those last changed.
+        let mut pending: HashMap<PathBuf, (u64, Option<SystemTime>, Instant)> = HashMap::new();
+        info!(
+            "Watching for new thumbnails in: {}",
+            thumbnail_directories
+                .iter()
+                .map(|directory| directory.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        while !interrupted() {
+            let (fullsize_phashes, _) = match &options.import_hashes {
+                Some(manifest) => (
+                    import_hashes(
+                        manifest,
+                        &options.fullsize_load_options().cache_config(false),
+                    )?,
+                    LoadCounts::default(),
+                ),
+                None => load_directories(
+                    fullsize_directories,
+                    cache_directory,
+                    "fullsize",
+                    options.prefilter.is_some(),
+                    &options.fullsize_filter,
+                    &LoadOptions {
+                        progress: false,
+                        ..options.fullsize_load_options()
+                    },
+                )?,
+            };
+            let search =
+                FullsizeSearch::new(&fullsize_phashes, options.matcher, options.index_chunks);
+            let rescan_at = watch.rescan.map(|rescan| Instant::now() + rescan);
+            while !interrupted() && rescan_at.is_none_or(|rescan_at| Instant::now() < rescan_at) {
+                match self.events.recv_timeout(WATCH_POLL_INTERVAL) {
+                    Ok(event) => {
+                        if event
+                            .op
+                            .as_ref()
+                            .is_ok_and(|op| op.contains(notify::Op::RESCAN))
+                        {
+                            warn!("Some changes to the thumbnail directories were missed, run again to match every thumbnail");
+                        }
+                        // Thumbnails changed during the first pass may have
+                        // been loaded by it, but are matched again in case
+                        // they were still being written.
+                        if let Some(path) = event.path.and_then(|path| self.source_path(&path)) {
+                            pending.entry(path).or_insert((0, None, Instant::now()));
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(anyhow!(
+                            "Stopped receiving changes to the thumbnail directories"
+                        ))
+                    }
+                }
+
+                let now = Instant::now();
+                let mut settled = Vec::new();
+                pending.retain(|path, (size, modified, changed)| {
+                    let metadata = match fs::metadata(path) {
+                        Ok(metadata) => metadata,
+                        Err(_) => {
+                            debug!(
+                                "Thumbnail removed before it was matched: {}",
+                                path.to_string_lossy()
+                            );
+                            return false;
+                        }
+                    };
+                    let seen = (metadata.len(), metadata.modified().ok());
+                    if seen != (*size, *modified) {
+                        *size = seen.0;
+                        *modified = seen.1;
+                        *changed = now;
+                        true
+                    } else if now.duration_since(*changed) >= watch.settle {
+                        settled.push(path.clone());
+                        false
+                    } else {
+                        true
+                    }
+                });
+                if settled.is_empty() {
+                    continue;
+                }
+                settled.sort();
+                self.match_new(
+                    settled,
+                    &search,
+                    cache_directory,
+                    output_directory,
+                    options,
+                    &mut watched,
+                )?;
+                if !options.dry_run {
+                    watched.state.write(&state_path)?;
+                }
+            }
+        }
+        info!(
+            "Stopped watching: {} thumbnails matched, {} need review, {} unmatched, {} failed",
+            watched.matched, watched.review, watched.unmatched, watched.failed
+        );
+        Ok(())
+    }
+
+    /// Hashes the thumbnails at `paths`, matches them against `search`, and
+    /// copies and records their matches as the first pass would.
+    fn match_new(
+        &self,
+        paths: Vec<PathBuf>,
+        search: &FullsizeSearch,
+        cache_directory: &Path,
+        output_directory: &Path,
+        options: &MatchOptions,
+        watched: &mut Watched,
+    ) -> Result<()> {
+        let mut sources: Vec<Source> = Vec::new();
+        for path in paths.into_iter() {
+            let directory = match self.source_directory(&path, &options.thumbnail_filter) {
+                Some(directory) => directory,
+                None => continue,
+            };
+            match sources.iter_mut().find(|(source, _)| *source == directory) {
+                Some((_, source_paths)) => source_paths.push(path),
+                None => sources.push((directory, vec![path])),
+            }
+        }
+        if sources.is_empty() {
+            return Ok(());
+        }
+        let roots: Vec<PathBuf> = self
+            .directories
+            .iter()
+            .map(|(directory, _)| directory.clone())
+            .collect();
+        let (mut thumbs_phashes, loaded) = load_sources(
+            sources,
+            cache_directory,
+            "thumbnail",
+            options.prefilter.is_some(),
+            &LoadOptions {
+                progress: false,
+                ..options.thumbnail_load_options()
+            },
+        )?;
+        set_relative_paths(&mut thumbs_phashes, &roots);
+        watched.failed += loaded.failed;
+        // Thumbnails matched again replace their earlier records, as in the
+        // first pass.
+        watched.state.forget(&thumbs_phashes);
+
+        let candidates_limit = if options.min_margin.is_some() { 2 } else { 1 };
+        let mut copies = Vec::new();
+        let mut reported = Vec::new();
+        let mut review = Vec::new();
+        for thumb_phash in thumbs_phashes.iter() {
+            let candidates = find_candidates(
+                thumb_phash,
+                search,
+                options,
+                candidates_limit,
+                &HashSet::new(),
+                None,
+            );
+            let output = match candidates.first() {
+                Some(output) => output.clone(),
+                None => {
+                    info!("Unmatched: {}", thumb_phash.path.to_string_lossy());
+                    watched.unmatched += 1;
+                    continue;
+                }
+            };
+            let ambiguous = options.min_margin.is_some_and(|min_margin| {
+                candidates
+                    .get(1)
+                    .is_some_and(|second| second.distance - output.distance < min_margin)
+            });
+            let fullsize = search
+                .phashes
+                .iter()
+                .find(|fullsize_phash| fullsize_phash.path == output.fullsize)
+                .expect("Matched fullsize image not searched.");
+            let decision = decide(
+                &output,
+                thumb_phash,
+                fullsize,
+                ambiguous,
+                options,
+                &mut CopyPlan {
+                    output_directory,
+                    names: &mut watched.names,
+                    copies: &mut copies,
+                },
+            )?;
+            let decided = match decision {
+                Decision::Unmatched => {
+                    watched.unmatched += 1;
+                    continue;
+                }
+                Decision::Ambiguous => {
+                    watched.review += 1;
+                    continue;
+                }
+                Decision::Matched(decided) => *decided,
+            };
+            if decided.needs_review {
+                watched.review += 1;
+                if options.review_directory.is_some() {
+                    review.push(output.clone());
+                }
+            } else {
+                watched.matched += 1;
+            }
+            watched.state.matches.extend(decided.record);
+            if watched.report.is_some() {
+                reported.push((output, decided.needs_review, decided.copy, decided.sidecars));
+            }
+        }
+        let copied = copy_files(
+            copies,
+            options.io_threads,
+            options.preserve,
+            options.absolute_symlinks,
+            options.dry_run,
+            false,
+        )?;
+        watched.state.record_fallbacks(&copied.fallbacks);
+        if let Some(report) = watched.report.as_mut() {
+            for (output, needs_review, copy, sidecars) in reported.iter() {
+                let copy = copy.as_deref().map(|copy| copied.written(copy));
+                report.push(output, *needs_review, copy, sidecars)?;
+            }
+        }
+        if let (Some(review_directory), false) = (&options.review_directory, review.is_empty()) {
+            write_review_list(
+                &review_directory.join(REVIEW_FILE_NAME),
+                &review,
+                true,
+                options.dry_run,
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,663 @@
+//! Rectangles of the subimages `decollage` finds on a page, and how they are
+//! merged, filtered and ordered, apart from the OpenCV types they come from.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use log::info;
+#[cfg(feature = "decollage")]
+use opencv::core::Rect_;
+
+/// A rectangle of pixels on a page, from its top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+#[cfg(feature = "decollage")]
+impl From<Rect_<i32>> for Rect {
+    fn from(rect: Rect_<i32>) -> Self {
+        Rect::new(rect.x, rect.y, rect.width, rect.height)
+    }
+}
+
+#[cfg(feature = "decollage")]
+impl From<Rect> for Rect_<i32> {
+    fn from(rect: Rect) -> Self {
+        Rect_::new(rect.x, rect.y, rect.width, rect.height)
+    }
+}
+
+/// Order in which the subimages of a page are numbered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// In rows from top to bottom, and from left to right within each row.
+    Reading,
+    /// From the largest to the smallest.
+    Area,
+    /// In the order their contours are found.
+    None,
+}
+
+impl Order {
+    pub fn name(self) -> &'static str {
+        match self {
+            Order::Reading => "reading",
+            Order::Area => "area",
+            Order::None => "none",
+        }
+    }
+
+    /// Sorts `patches` into this order by their bounds.
+    pub fn arrange<T>(self, mut patches: Vec<(Rect, T)>) -> Vec<(Rect, T)> {
+        match self {
+            Order::Reading => {
+                let bounds: Vec<Rect> = patches.iter().map(|(bounds, _)| *bounds).collect();
+                let mut rows: Vec<_> = reading_rows(&bounds).into_iter().zip(patches).collect();
+                rows.sort_by_key(|(row, (bounds, _))| (*row, bounds.x, bounds.y));
+                rows.into_iter().map(|(_, patch)| patch).collect()
+            }
+            Order::Area => {
+                patches.sort_by_key(|(bounds, _)| std::cmp::Reverse(rect_area(*bounds)));
+                patches
+            }
+            Order::None => patches,
+        }
+    }
+}
+
+impl FromStr for Order {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reading" => Ok(Order::Reading),
+            "area" => Ok(Order::Area),
+            "none" => Ok(Order::None),
+            _ => Err(anyhow!("Unknown order: {}", s)),
+        }
+    }
+}
+
+/// Smallest area of a subimage that is extracted, below which it is taken for
+/// noise.
+#[derive(Debug, Clone, Copy)]
+pub enum MinArea {
+    /// An area in pixels, for `--min-area`.
+    Pixels(f64),
+    /// A fraction of the area of the page, for `--min-area-frac`.
+    Fraction(f64),
+}
+
+impl MinArea {
+    /// Returns the smallest area extracted from a page of `page_area` pixels.
+    pub fn pixels(self, page_area: f64) -> f64 {
+        match self {
+            MinArea::Pixels(pixels) => pixels,
+            MinArea::Fraction(fraction) => fraction * page_area,
+        }
+    }
+
+    /// Describes the threshold for the log, on a page of `page_area` pixels.
+    pub fn describe(self, page_area: f64) -> String {
+        match self {
+            MinArea::Pixels(pixels) => format!("--min-area of {}", pixels),
+            MinArea::Fraction(fraction) => format!(
+                "--min-area-frac of {} ({} of {} pixels)",
+                fraction,
+                self.pixels(page_area),
+                page_area
+            ),
+        }
+    }
+}
+
+/// Why a subimage was discarded for its shape.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// Narrower than `--min-width`.
+    Narrow,
+    /// Shorter than `--min-height`.
+    Short,
+    /// Longer than `--max-aspect` times its breadth.
+    Elongated,
+}
+
+impl Rejection {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Narrow => "narrower than the --min-width",
+            Self::Short => "shorter than the --min-height",
+            Self::Elongated => "longer than the --max-aspect times its breadth",
+        }
+    }
+}
+
+/// Limits on the shape of the subimages kept.
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeLimits {
+    /// Width and height in pixels below which subimages are dropped.
+    pub min_width: i32,
+    pub min_height: i32,
+    /// Ratio of the long to the short side above which subimages are dropped,
+    /// if any.
+    pub max_aspect: Option<f64>,
+}
+
+impl ShapeLimits {
+    /// Returns why a subimage of `width` by `height` pixels is discarded for
+    /// its shape, if it is, checking the width, then the height, then the
+    /// aspect ratio.
+    pub fn reject(&self, width: i32, height: i32) -> Option<Rejection> {
+        if width < self.min_width {
+            return Some(Rejection::Narrow);
+        }
+        if height < self.min_height {
+            return Some(Rejection::Short);
+        }
+        let long = width.max(height);
+        let short = width.min(height).max(1);
+        match self.max_aspect {
+            Some(max_aspect) if f64::from(long) / f64::from(short) > max_aspect => {
+                Some(Rejection::Elongated)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether `bounds` covers more than `max_area_frac` of a page of `page_area`
+/// pixels, logging it if so.
+pub fn oversized(bounds: Rect, page_area: f64, max_area_frac: f64) -> bool {
+    let fraction = f64::from(bounds.width) * f64::from(bounds.height) / page_area;
+    if fraction > max_area_frac {
+        info!(
+            "Discarding subimage covering {:.1}% of the page, above the --max-area-frac of {}",
+            fraction * 100.0,
+            max_area_frac
+        );
+    }
+    fraction > max_area_frac
+}
+
+/// Returns the area of `rect` in pixels.
+pub fn rect_area(rect: Rect) -> i64 {
+    i64::from(rect.width) * i64::from(rect.height)
+}
+
+/// Returns, for each of `rects`, the index of another that at least
+/// `fraction` of its area lies inside, and that is larger, or the same size
+/// and earlier, so that of duplicates only the first is kept.
+pub fn containing_rects(rects: &[Rect], fraction: f64) -> Vec<Option<usize>> {
+    rects
+        .iter()
+        .enumerate()
+        .map(|(index, rect)| {
+            let area = rect_area(*rect);
+            (0..rects.len()).find(|other_index| {
+                let other = &rects[*other_index];
+                let other_area = rect_area(*other);
+                if *other_index == index
+                    || other_area < area
+                    || (other_area == area && *other_index > index)
+                {
+                    return false;
+                }
+                let width = (rect.x + rect.width).min(other.x + other.width) - rect.x.max(other.x);
+                let height =
+                    (rect.y + rect.height).min(other.y + other.height) - rect.y.max(other.y);
+                width > 0
+                    && height > 0
+                    && (i64::from(width) * i64::from(height)) as f64 >= fraction * area as f64
+            })
+        })
+        .collect()
+}
+
+/// Returns the row of each of `rects` on the page, counting from the top.
+/// Taking them from the top down, each joins the row above it if they are
+/// level for at least half the height of the shorter, so that rows of photos
+/// glued in unevenly stay together.
+pub fn reading_rows(rects: &[Rect]) -> Vec<usize> {
+    let mut by_top: Vec<usize> = (0..rects.len()).collect();
+    by_top.sort_by_key(|index| (rects[*index].y, rects[*index].x));
+    let mut rows = vec![0; rects.len()];
+    // The row so far, with its top and bottom.
+    let mut current: Option<(usize, i32, i32)> = None;
+    for index in by_top {
+        let rect = rects[index];
+        let bottom = rect.y + rect.height;
+        let (row, row_top, row_bottom) = match current {
+            Some((row, row_top, row_bottom)) => {
+                let overlap = bottom.min(row_bottom) - rect.y.max(row_top);
+                if 2 * overlap >= rect.height.min(row_bottom - row_top) {
+                    (row, row_top, row_bottom.max(bottom))
+                } else {
+                    (row + 1, rect.y, bottom)
+                }
+            }
+            None => (0, rect.y, bottom),
+        };
+        rows[index] = row;
+        current = Some((row, row_top, row_bottom));
+    }
+    rows
+}
+
+/// Merges the rectangles of `rects` that overlap, or are less than `distance`
+/// pixels apart, into the rectangle bounding both, until no two are. Returns
+/// the merged rectangles in the order of their first part, with the indexes
+/// of their parts.
+pub fn merge_rects(rects: &[Rect], distance: i32) -> Vec<(Rect, Vec<usize>)> {
+    let mut merged: Vec<(Rect, Vec<usize>)> = rects
+        .iter()
+        .enumerate()
+        .map(|(index, rect)| (*rect, vec![index]))
+        .collect();
+    // Merging grows a rectangle, which may then reach those already passed.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut first = 0;
+        while first < merged.len() {
+            let mut second = first + 1;
+            while second < merged.len() {
+                if rect_gap(merged[first].0, merged[second].0) < distance {
+                    let (rect, parts) = merged.remove(second);
+                    merged[first].0 = rect_union(merged[first].0, rect);
+                    merged[first].1.extend(parts);
+                    changed = true;
+                } else {
+                    second += 1;
+                }
+            }
+            first += 1;
+        }
+    }
+    for (_, parts) in merged.iter_mut() {
+        parts.sort_unstable();
+    }
+    merged
+}
+
+/// Returns the gap in pixels between two rectangles, along the axis they are
+/// furthest apart on, which is negative if they overlap.
+pub fn rect_gap(a: Rect, b: Rect) -> i32 {
+    let gap_x = a.x.max(b.x) - (a.x + a.width).min(b.x + b.width);
+    let gap_y = a.y.max(b.y) - (a.y + a.height).min(b.y + b.height);
+    gap_x.max(gap_y)
+}
+
+/// Returns the smallest rectangle containing both `a` and `b`.
+pub fn rect_union(a: Rect, b: Rect) -> Rect {
+    let left = a.x.min(b.x);
+    let top = a.y.min(b.y);
+    let right = (a.x + a.width).max(b.x + b.width);
+    let bottom = (a.y + a.height).max(b.y + b.height);
+    Rect::new(left, top, right - left, bottom - top)
+}
+
+/// Grows `rect` by `padding` pixels on each side, or shrinks it for negative
+/// padding, clamped to an image of `width` by `height` pixels. Returns `None`
+/// if nothing is left of it.
+pub fn pad(rect: Rect, padding: i32, width: i32, height: i32) -> Option<Rect> {
+    let left = (rect.x - padding).max(0);
+    let top = (rect.y - padding).max(0);
+    let right = (rect.x + rect.width + padding).min(width);
+    let bottom = (rect.y + rect.height + padding).min(height);
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some(Rect::new(left, top, right - left, bottom - top))
+}
+
+/// Returns the rotation in degrees, from -45 to 45, of a rectangle of `size`
+/// rotated by `angle`, and its width and height once rotated back by it.
+/// Rectangles reported at nearly 90 degrees are only slightly rotated, with
+/// their width and height swapped.
+pub fn upright(angle: f32, size: (f32, f32)) -> (f32, (f32, f32)) {
+    let (mut angle, mut size) = (angle, size);
+    while angle > 45.0 {
+        angle -= 90.0;
+        size = (size.1, size.0);
+    }
+    while angle < -45.0 {
+        angle += 90.0;
+        size = (size.1, size.0);
+    }
+    (angle, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_rects_merges_overlapping_rects() {
+        let rects = [Rect::new(0, 0, 20, 20), Rect::new(10, 5, 20, 30)];
+        assert_eq!(
+            merge_rects(&rects, 0),
+            vec![(Rect::new(0, 0, 30, 35), vec![0, 1])]
+        );
+        // Touching edges are no gap apart.
+        let rects = [Rect::new(0, 0, 20, 20), Rect::new(20, 0, 20, 20)];
+        assert_eq!(merge_rects(&rects, 0).len(), 2);
+        assert_eq!(
+            merge_rects(&rects, 1),
+            vec![(Rect::new(0, 0, 40, 20), vec![0, 1])]
+        );
+    }
+
+    #[test]
+    fn merge_rects_merges_rects_closer_than_the_distance() {
+        let rects = [Rect::new(0, 0, 20, 20), Rect::new(0, 29, 20, 20)];
+        assert_eq!(
+            merge_rects(&rects, 10),
+            vec![(Rect::new(0, 0, 20, 49), vec![0, 1])]
+        );
+        let rects = [Rect::new(0, 0, 20, 20), Rect::new(0, 30, 20, 20)];
+        assert_eq!(merge_rects(&rects, 10).len(), 2);
+        // Diagonal neighbours are as far apart as on their furthest axis.
+        let rects = [Rect::new(0, 0, 20, 20), Rect::new(25, 28, 20, 20)];
+        assert_eq!(rect_gap(rects[0], rects[1]), 8);
+        assert_eq!(merge_rects(&rects, 8).len(), 2);
+        assert_eq!(merge_rects(&rects, 9).len(), 1);
+    }
+
+    #[test]
+    fn merge_rects_keeps_disjoint_rects_in_order() {
+        let rects = [
+            Rect::new(100, 0, 20, 20),
+            Rect::new(0, 0, 20, 20),
+            Rect::new(0, 100, 20, 20),
+        ];
+        assert_eq!(
+            merge_rects(&rects, 10),
+            vec![
+                (rects[0], vec![0]),
+                (rects[1], vec![1]),
+                (rects[2], vec![2]),
+            ]
+        );
+        assert!(merge_rects(&[], 10).is_empty());
+    }
+
+    #[test]
+    fn merge_rects_follows_chains_of_near_rects() {
+        // The first only reaches the second once merged with the third.
+        let rects = [
+            Rect::new(0, 0, 10, 10),
+            Rect::new(30, 0, 10, 10),
+            Rect::new(15, 0, 10, 10),
+        ];
+        assert_eq!(
+            merge_rects(&rects, 6),
+            vec![(Rect::new(0, 0, 40, 10), vec![0, 1, 2])]
+        );
+        // Two chains, each merged apart from the other.
+        let rects = [
+            Rect::new(0, 0, 10, 10),
+            Rect::new(0, 100, 10, 10),
+            Rect::new(0, 12, 10, 10),
+            Rect::new(0, 112, 10, 10),
+            Rect::new(0, 24, 10, 10),
+        ];
+        assert_eq!(
+            merge_rects(&rects, 5),
+            vec![
+                (Rect::new(0, 0, 10, 34), vec![0, 2, 4]),
+                (Rect::new(0, 100, 10, 22), vec![1, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn containing_rects_finds_rects_inside_larger_ones() {
+        let rects = [
+            Rect::new(0, 0, 100, 100),
+            Rect::new(10, 10, 20, 20),
+            Rect::new(200, 0, 50, 50),
+            Rect::new(210, 10, 10, 10),
+        ];
+        assert_eq!(
+            containing_rects(&rects, 0.9),
+            vec![None, Some(0), None, Some(2)]
+        );
+        // The smaller of two is never taken to contain the larger.
+        let rects = [Rect::new(10, 10, 20, 20), Rect::new(0, 0, 100, 100)];
+        assert_eq!(containing_rects(&rects, 0.9), vec![Some(1), None]);
+    }
+
+    #[test]
+    fn containing_rects_keeps_partly_overlapping_rects() {
+        // A quarter of the smaller lies inside the larger.
+        let rects = [Rect::new(0, 0, 100, 100), Rect::new(90, 90, 20, 20)];
+        assert_eq!(containing_rects(&rects, 0.9), vec![None, None]);
+        assert_eq!(containing_rects(&rects, 0.25), vec![None, Some(0)]);
+        assert_eq!(containing_rects(&rects, 0.26), vec![None, None]);
+        // Rects touching along an edge don't overlap.
+        let rects = [Rect::new(0, 0, 100, 100), Rect::new(100, 0, 20, 20)];
+        assert_eq!(containing_rects(&rects, 0.0), vec![None, None]);
+    }
+
+    #[test]
+    fn containing_rects_keeps_the_first_of_identical_rects() {
+        let rect = Rect::new(5, 5, 40, 30);
+        assert_eq!(
+            containing_rects(&[rect, rect, rect], 0.9),
+            vec![None, Some(0), Some(0)]
+        );
+        // Of the same size but offset, the first is kept if enough overlaps.
+        let rects = [rect, Rect::new(7, 5, 40, 30)];
+        assert_eq!(containing_rects(&rects, 0.9), vec![None, Some(0)]);
+        assert_eq!(containing_rects(&rects, 0.99), vec![None, None]);
+    }
+
+    #[test]
+    fn shape_limits_check_the_width_then_the_height_then_the_aspect() {
+        let limits = ShapeLimits {
+            min_width: 50,
+            min_height: 40,
+            max_aspect: Some(3.0),
+        };
+        assert_eq!(limits.reject(50, 40), None);
+        assert_eq!(limits.reject(49, 400), Some(Rejection::Narrow));
+        assert_eq!(limits.reject(49, 39), Some(Rejection::Narrow));
+        assert_eq!(limits.reject(500, 39), Some(Rejection::Short));
+        assert_eq!(limits.reject(150, 50), None);
+        assert_eq!(limits.reject(151, 50), Some(Rejection::Elongated));
+        assert_eq!(limits.reject(50, 151), Some(Rejection::Elongated));
+        let unlimited = ShapeLimits {
+            min_width: 0,
+            min_height: 0,
+            max_aspect: None,
+        };
+        assert_eq!(unlimited.reject(1000, 1), None);
+        assert_eq!(unlimited.reject(0, 0), None);
+    }
+
+    #[test]
+    fn shape_limits_apply_to_merged_rects() {
+        let limits = ShapeLimits {
+            min_width: 0,
+            min_height: 0,
+            max_aspect: Some(4.0),
+        };
+        // Strips of a photo broken up along its light edges.
+        let parts = [
+            Rect::new(0, 0, 100, 10),
+            Rect::new(0, 12, 100, 10),
+            Rect::new(0, 24, 100, 10),
+            Rect::new(0, 36, 100, 10),
+        ];
+        assert!(parts
+            .iter()
+            .all(|part| limits.reject(part.width, part.height).is_some()));
+        let merged = merge_rects(&parts, 5);
+        assert_eq!(merged.len(), 1);
+        let (bounds, _) = merged[0];
+        assert_eq!(limits.reject(bounds.width, bounds.height), None);
+    }
+
+    #[test]
+    fn upright_turns_rects_back_within_45_degrees() {
+        assert_eq!(upright(0.0, (100.0, 20.0)), (0.0, (100.0, 20.0)));
+        assert_eq!(upright(-7.0, (100.0, 20.0)), (-7.0, (100.0, 20.0)));
+        assert_eq!(upright(45.0, (100.0, 20.0)), (45.0, (100.0, 20.0)));
+        // Nearly a quarter turn is a slight turn the other way, on its side.
+        assert_eq!(upright(83.0, (20.0, 100.0)), (-7.0, (100.0, 20.0)));
+        assert_eq!(upright(-83.0, (20.0, 100.0)), (7.0, (100.0, 20.0)));
+        assert_eq!(upright(90.0, (20.0, 100.0)), (0.0, (100.0, 20.0)));
+        assert_eq!(upright(180.0, (100.0, 20.0)), (0.0, (100.0, 20.0)));
+    }
+
+    #[test]
+    fn shape_limits_of_deskewed_rects_apply_to_their_upright_size() {
+        let limits = ShapeLimits {
+            min_width: 60,
+            min_height: 0,
+            max_aspect: Some(4.0),
+        };
+        // A strip turned nearly on its side, which is wide once upright.
+        let (_, (width, height)) = upright(85.0, (20.0, 100.0));
+        assert_eq!(limits.reject(20, 100), Some(Rejection::Narrow));
+        assert_eq!(
+            limits.reject(width as i32, height as i32),
+            Some(Rejection::Elongated)
+        );
+    }
+
+    /// Returns the indexes of `rects` in `order`.
+    fn arranged(order: Order, rects: &[Rect]) -> Vec<usize> {
+        let patches = rects
+            .iter()
+            .copied()
+            .enumerate()
+            .map(|(index, rect)| (rect, index))
+            .collect();
+        order
+            .arrange(patches)
+            .into_iter()
+            .map(|(_, index)| index)
+            .collect()
+    }
+
+    #[test]
+    fn reading_rows_keep_staggered_photos_together() {
+        // Two rows of three, glued in unevenly, found right to left.
+        let rects = [
+            Rect::new(400, 30, 150, 100),
+            Rect::new(200, 0, 150, 120),
+            Rect::new(0, 20, 150, 100),
+            Rect::new(400, 200, 150, 100),
+            Rect::new(200, 170, 150, 100),
+            Rect::new(0, 190, 150, 100),
+        ];
+        assert_eq!(reading_rows(&rects), vec![0, 0, 0, 1, 1, 1]);
+        assert_eq!(arranged(Order::Reading, &rects), vec![2, 1, 0, 5, 4, 3]);
+    }
+
+    #[test]
+    fn reading_rows_of_a_single_column_go_down_the_page() {
+        let rects = [
+            Rect::new(10, 300, 200, 100),
+            Rect::new(0, 0, 200, 100),
+            Rect::new(20, 150, 200, 100),
+        ];
+        assert_eq!(reading_rows(&rects), vec![2, 0, 1]);
+        assert_eq!(arranged(Order::Reading, &rects), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn reading_rows_join_rects_level_for_half_the_shorter() {
+        // Level for exactly half of the shorter, then for less.
+        let rects = [Rect::new(0, 0, 100, 100), Rect::new(200, 50, 100, 100)];
+        assert_eq!(reading_rows(&rects), vec![0, 0]);
+        let rects = [Rect::new(0, 0, 100, 100), Rect::new(200, 51, 100, 100)];
+        assert_eq!(reading_rows(&rects), vec![0, 1]);
+        // A small photo beside a tall one shares its row.
+        let rects = [Rect::new(0, 0, 100, 300), Rect::new(200, 260, 50, 60)];
+        assert_eq!(reading_rows(&rects), vec![0, 0]);
+        // At the same height, photos are ordered from the left.
+        let rects = [Rect::new(300, 0, 100, 100), Rect::new(0, 0, 100, 100)];
+        assert_eq!(reading_rows(&rects), vec![0, 0]);
+        assert_eq!(arranged(Order::Reading, &rects), vec![1, 0]);
+    }
+
+    #[test]
+    fn other_orders_go_by_area_or_as_found() {
+        let rects = [
+            Rect::new(0, 0, 10, 10),
+            Rect::new(100, 0, 30, 30),
+            Rect::new(0, 100, 20, 20),
+            Rect::new(100, 100, 30, 30),
+        ];
+        // Of equal areas, the first found is first.
+        assert_eq!(arranged(Order::Area, &rects), vec![1, 3, 2, 0]);
+        assert_eq!(arranged(Order::None, &rects), vec![0, 1, 2, 3]);
+        assert_eq!(arranged(Order::Reading, &rects), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn min_area_is_in_pixels_or_a_fraction_of_the_page() {
+        assert_eq!(MinArea::Pixels(5000.0).pixels(1_000_000.0), 5000.0);
+        assert_eq!(MinArea::Fraction(0.01).pixels(1_000_000.0), 10_000.0);
+        assert_eq!(MinArea::Fraction(0.01).pixels(250_000.0), 2500.0);
+        assert_eq!(
+            MinArea::Pixels(5000.0).describe(1_000_000.0),
+            "--min-area of 5000"
+        );
+        assert_eq!(
+            MinArea::Fraction(0.01).describe(250_000.0),
+            "--min-area-frac of 0.01 (2500 of 250000 pixels)"
+        );
+    }
+
+    #[test]
+    fn oversized_rects_cover_more_than_the_fraction_of_the_page() {
+        let page_area = 600.0 * 400.0;
+        assert!(oversized(Rect::new(10, 10, 580, 380), page_area, 0.9));
+        assert!(!oversized(Rect::new(10, 10, 580, 380), page_area, 0.95));
+        // Covering exactly the fraction is allowed.
+        assert!(!oversized(Rect::new(0, 0, 600, 360), page_area, 0.9));
+        assert!(oversized(Rect::new(0, 0, 600, 361), page_area, 0.9));
+        assert!(oversized(Rect::new(0, 0, 600, 400), page_area, 0.99));
+    }
+
+    #[test]
+    fn pad_grows_rects_within_the_page() {
+        let rect = Rect::new(100, 50, 200, 100);
+        assert_eq!(pad(rect, 0, 600, 400), Some(rect));
+        assert_eq!(pad(rect, 20, 600, 400), Some(Rect::new(80, 30, 240, 140)));
+        // Clamped at the edges of the page.
+        assert_eq!(pad(rect, 60, 600, 400), Some(Rect::new(40, 0, 320, 210)));
+        assert_eq!(
+            pad(Rect::new(10, 300, 585, 100), 20, 600, 400),
+            Some(Rect::new(0, 280, 600, 120))
+        );
+        assert_eq!(pad(rect, 1000, 600, 400), Some(Rect::new(0, 0, 600, 400)));
+    }
+
+    #[test]
+    fn negative_padding_shrinks_rects_until_nothing_is_left() {
+        let rect = Rect::new(100, 50, 200, 100);
+        assert_eq!(pad(rect, -10, 600, 400), Some(Rect::new(110, 60, 180, 80)));
+        assert_eq!(pad(rect, -49, 600, 400), Some(Rect::new(149, 99, 102, 2)));
+        assert_eq!(pad(rect, -50, 600, 400), None);
+        assert_eq!(pad(rect, -200, 600, 400), None);
+    }
+}
@@ -0,0 +1,394 @@
+//! Templates for the file names of the subimages `decollage` writes, such as
+//! `{stem}-{index:02}.{ext}`.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+/// Extensions of the formats subimages can be written in, which a template
+/// may end in instead of `.{ext}`.
+const IMAGE_EXTENSIONS: [&str; 7] = ["png", "jpg", "jpeg", "tif", "tiff", "bmp", "webp"];
+
+/// Field of a subimage that a placeholder is replaced by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Stem,
+    Index,
+    X,
+    Y,
+    Width,
+    Height,
+    Ext,
+}
+
+impl Field {
+    const ALL: [Field; 7] = [
+        Field::Stem,
+        Field::Index,
+        Field::X,
+        Field::Y,
+        Field::Width,
+        Field::Height,
+        Field::Ext,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Stem => "stem",
+            Field::Index => "index",
+            Field::X => "x",
+            Field::Y => "y",
+            Field::Width => "width",
+            Field::Height => "height",
+            Field::Ext => "ext",
+        }
+    }
+
+    /// Whether the field is a number, which may be zero padded.
+    fn is_number(self) -> bool {
+        !matches!(self, Field::Stem | Field::Ext)
+    }
+}
+
+/// What is known of a subimage when it is named.
+#[derive(Debug)]
+pub struct Fields<'a> {
+    /// Stem of the file name of the page it was pulled out of.
+    pub stem: &'a str,
+    /// Number of the subimage on its page, from 0.
+    pub index: usize,
+    /// Position of its top left corner on the page, in pixels.
+    pub x: i32,
+    pub y: i32,
+    /// Size of the written subimage, in pixels.
+    pub width: i32,
+    pub height: i32,
+    /// Extension of the format it is written in, without the dot.
+    pub ext: &'a str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    /// A field, zero padded to a width if it is a number.
+    Placeholder(Field, usize),
+}
+
+/// A file name template, in which `{name}` is replaced by a field of the
+/// subimage, `{name:03}` by a number zero padded to 3 digits, and `{{` and
+/// `}}` by literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl NameTemplate {
+    /// Returns the file name of the subimage with `fields`.
+    pub fn render(&self, fields: &Fields) -> String {
+        let mut name = String::new();
+        for part in self.parts.iter() {
+            let (field, width) = match part {
+                Part::Literal(literal) => {
+                    name.push_str(literal);
+                    continue;
+                }
+                Part::Placeholder(field, width) => (*field, *width),
+            };
+            let number = match field {
+                Field::Stem => {
+                    name.push_str(fields.stem);
+                    continue;
+                }
+                Field::Ext => {
+                    name.push_str(fields.ext);
+                    continue;
+                }
+                Field::Index => fields.index as i64,
+                Field::X => i64::from(fields.x),
+                Field::Y => i64::from(fields.y),
+                Field::Width => i64::from(fields.width),
+                Field::Height => i64::from(fields.height),
+            };
+            // Writing to a string can't fail.
+            let _ = write!(name, "{:01$}", number, width);
+        }
+        name
+    }
+
+    /// Checks that the template names the subimages of `pages` pages apart,
+    /// which takes `{stem}` for more than one.
+    pub fn check_pages(&self, pages: usize) -> Result<()> {
+        if pages > 1
+            && !self
+                .parts
+                .iter()
+                .any(|part| matches!(part, Part::Placeholder(Field::Stem, _)))
+        {
+            return Err(anyhow!(
+                "Name template has no {{stem}}, so the subimages of the {} pages would overwrite each other",
+                pages
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| anyhow!("Unclosed {{ in name template: {}", s))?;
+                    let (name, padding) = match rest[..end].split_once(':') {
+                        Some((name, padding)) => (name, Some(padding)),
+                        None => (&rest[..end], None),
+                    };
+                    let field = Field::ALL
+                        .iter()
+                        .copied()
+                        .find(|field| field.name() == name)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "Unknown placeholder {{{}}} in name template, expected one of: {}",
+                                name,
+                                Field::ALL
+                                    .iter()
+                                    .map(|field| format!("{{{}}}", field.name()))
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            )
+                        })?;
+                    let width = match padding {
+                        Some(_) if !field.is_number() => {
+                            return Err(anyhow!(
+                                "Only numbers can be zero padded in name template, not {{{}}}",
+                                name
+                            ))
+                        }
+                        Some(padding) => match padding.strip_prefix('0').map(str::parse) {
+                            Some(Ok(width)) => width,
+                            _ => {
+                                return Err(anyhow!(
+                                    "Invalid padding {:?} of {{{}}} in name template, expected a width such as 03",
+                                    padding,
+                                    name
+                                ))
+                            }
+                        },
+                        None => 0,
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(field, width));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(anyhow!("Unmatched }} in name template: {}", s)),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        if parts.is_empty() {
+            return Err(anyhow!("Name template is empty"));
+        }
+        if !parts
+            .iter()
+            .any(|part| matches!(part, Part::Placeholder(Field::Index, _)))
+        {
+            return Err(anyhow!(
+                "Name template has no {{index}}, so the subimages of a page would overwrite each other: {}",
+                s
+            ));
+        }
+        // Names are joined to the output directory, so must not leave it.
+        if parts.iter().any(|part| match part {
+            Part::Literal(literal) => {
+                literal.chars().any(std::path::is_separator) || literal.contains("..")
+            }
+            Part::Placeholder(..) => false,
+        }) {
+            return Err(anyhow!(
+                "Name template must name a file in the output directory, without / or ..: {}",
+                s
+            ));
+        }
+        // The format subimages are written in is picked by their extension.
+        let extension = match parts.as_slice() {
+            [.., Part::Literal(literal), Part::Placeholder(Field::Ext, _)] => {
+                literal.ends_with('.')
+            }
+            [.., Part::Literal(literal)] => literal.rsplit_once('.').is_some_and(|(_, ext)| {
+                IMAGE_EXTENSIONS
+                    .iter()
+                    .any(|known| known.eq_ignore_ascii_case(ext))
+            }),
+            _ => false,
+        };
+        if !extension {
+            return Err(anyhow!(
+                "Name template must end in .{{ext}} or the extension of an image format, one of: {}: {}",
+                IMAGE_EXTENSIONS.join(", "),
+                s
+            ));
+        }
+        Ok(NameTemplate { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields<'static> {
+        Fields {
+            stem: "page",
+            index: 3,
+            x: 120,
+            y: 7,
+            width: 640,
+            height: 480,
+            ext: "png",
+        }
+    }
+
+    fn render(template: &str) -> String {
+        template.parse::<NameTemplate>().unwrap().render(&fields())
+    }
+
+    fn error(template: &str) -> String {
+        template.parse::<NameTemplate>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn default_template_names_subimages_as_before() {
+        for index in [0, 7, 10, 123].iter() {
+            let fields = Fields {
+                index: *index,
+                ..fields()
+            };
+            assert_eq!(
+                "{stem}-{index:02}.{ext}"
+                    .parse::<NameTemplate>()
+                    .unwrap()
+                    .render(&fields),
+                format!("{}-{:02}.png", "page", index)
+            );
+        }
+    }
+
+    #[test]
+    fn placeholders_are_replaced_by_fields() {
+        assert_eq!(render("{stem}_{index}.{ext}"), "page_3.png");
+        assert_eq!(
+            render("{index}-{x}x{y}-{width}x{height}.{ext}"),
+            "3-120x7-640x480.png"
+        );
+        assert_eq!(
+            render("{x:05}-{index:03}-{index:01}.jpg"),
+            "00120-003-3.jpg"
+        );
+        // Numbers longer than the padding are left whole.
+        assert_eq!(render("{width:02}-{index}.TIF"), "640-3.TIF");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{{index}}}.{ext}"), "{3}.png");
+        assert_eq!(render("{{index}}-{index}.png"), "{index}-3.png");
+        assert_eq!(render("}}{index}{{.png"), "}3{.png");
+    }
+
+    #[test]
+    fn invalid_templates_are_errors() {
+        assert_eq!(error(""), "Name template is empty");
+        assert_eq!(error("{index"), "Unclosed { in name template: {index");
+        assert_eq!(error("{index}}"), "Unmatched } in name template: {index}}");
+        assert_eq!(
+            error("{page}-{index}"),
+            "Unknown placeholder {page} in name template, expected one of: {stem}, {index}, {x}, {y}, {width}, {height}, {ext}"
+        );
+        assert_eq!(
+            error("{stem:02}-{index}"),
+            "Only numbers can be zero padded in name template, not {stem}"
+        );
+        assert_eq!(
+            error("{index:3}"),
+            "Invalid padding \"3\" of {index} in name template, expected a width such as 03"
+        );
+        assert_eq!(
+            error("{index:2x}"),
+            "Invalid padding \"2x\" of {index} in name template, expected a width such as 03"
+        );
+    }
+
+    #[test]
+    fn templates_must_name_one_file_per_subimage_in_the_output_directory() {
+        assert_eq!(
+            error("{stem}.{ext}"),
+            "Name template has no {index}, so the subimages of a page would overwrite each other: {stem}.{ext}"
+        );
+        for template in ["{stem}/{index}.png", "../{index}.png", "{index}..png"].iter() {
+            assert_eq!(
+                error(template),
+                format!(
+                    "Name template must name a file in the output directory, without / or ..: {}",
+                    template
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn templates_must_end_in_the_extension_of_an_image_format() {
+        for template in [
+            "{stem}-{index}",
+            "{stem}-{index}.{ext}-old",
+            "{stem}-{index}{ext}",
+            "{stem}-{index}.txt",
+            "{index}.png.{x}",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error(template),
+                format!(
+                    "Name template must end in .{{ext}} or the extension of an image format, one of: png, jpg, jpeg, tif, tiff, bmp, webp: {}",
+                    template
+                )
+            );
+        }
+        assert_eq!(render("{ext}-{index}.JPEG"), "png-3.JPEG");
+    }
+
+    #[test]
+    fn templates_without_a_stem_only_name_the_subimages_of_one_page() {
+        let template = "album-{index:03}.{ext}".parse::<NameTemplate>().unwrap();
+        template.check_pages(0).unwrap();
+        template.check_pages(1).unwrap();
+        assert_eq!(
+            template.check_pages(2).unwrap_err().to_string(),
+            "Name template has no {stem}, so the subimages of the 2 pages would overwrite each other"
+        );
+        let template = "{stem}-{index}.{ext}".parse::<NameTemplate>().unwrap();
+        template.check_pages(2).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use image::ImageError;
+
+use crate::phash::{CacheConfig, MAX_HASH_SIZE};
+
+/// Errors returned by the library.
+#[derive(Debug)]
+pub enum Error {
+    /// An image or cache file could not be read or written.
+    Io(io::Error),
+    /// An image could not be decoded.
+    Image(ImageError),
+    /// A cache index could not be parsed or written.
+    Json(serde_json::Error),
+    /// A cached hash is not valid base64.
+    InvalidHash(String),
+    /// A cache was hashed with a different configuration, when reading it
+    /// strictly.
+    CacheConfig {
+        directory: PathBuf,
+        found: Box<CacheConfig>,
+        expected: Box<CacheConfig>,
+    },
+    /// A hash size was given as something other than a width and optional
+    /// height.
+    HashSides,
+    /// A side of the hash is outside the supported range.
+    HashSize(u32),
+    /// An image was refused as blank, with this fraction of content.
+    Blank(f64),
+    /// A path has no file name to key its cache entries by.
+    NoFileName(PathBuf),
+    /// The name of an option is not recognised.
+    Unknown { kind: &'static str, value: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => error.fmt(f),
+            Error::Image(error) => error.fmt(f),
+            Error::Json(error) => error.fmt(f),
+            Error::InvalidHash(error) => write!(f, "Invalid cached hash: {}", error),
+            Error::CacheConfig {
+                directory,
+                found,
+                expected,
+            } => write!(
+                f,
+                "Cache {} was hashed with {:?}, not {:?}",
+                directory.to_string_lossy(),
+                found,
+                expected
+            ),
+            Error::HashSides => write!(f, "Expected a hash width and optional height"),
+            Error::HashSize(side) => write!(
+                f,
+                "Hash size {} is outside the supported range 2 to {}",
+                side, MAX_HASH_SIZE
+            ),
+            Error::Blank(fraction) => {
+                write!(f, "Image is blank, with {:.2}% content", fraction * 100.0)
+            }
+            Error::NoFileName(path) => {
+                write!(f, "Path has no file name: {}", path.to_string_lossy())
+            }
+            Error::Unknown { kind, value } => write!(f, "Unknown {}: {}", kind, value),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Image(error) => Some(error),
+            Error::Json(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<ImageError> for Error {
+    fn from(error: ImageError) -> Self {
+        Error::Image(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
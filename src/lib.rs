@@ -0,0 +1,20 @@
+//! Finds the fullsize images that thumbnails were made from, by comparing
+//! perceptual hashes of each image once its borders have been removed.
+//!
+//! The `find` binary is a command line interface over this library, which
+//! can also be used directly:
+//!
+//! - `borders` finds and removes the borders around scanned images.
+//! - `phash` hashes images, keeping their hashes in a `HashCache`.
+//! - `matching` finds the fullsize image closest to each thumbnail.
+
+pub mod borders;
+// The commands of the binaries, kept here so the `fromthumb` binary and the
+// shims for the binaries it replaced run the same code.
+#[doc(hidden)]
+pub mod cli;
+mod error;
+pub mod matching;
+pub mod phash;
+
+pub use error::{Error, Result};
@@ -0,0 +1,1347 @@
+//! Matching thumbnails to the fullsize images they were made from, by the
+//! distance between their hashes.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use img_hash::ImageHash;
+use rayon::prelude::*;
+
+use crate::phash::{Provenance, Transform};
+use crate::{Error, Result};
+
+/// Default number of chunks hashes are split into by a `MultiIndex`.
+pub const DEFAULT_INDEX_CHUNKS: usize = 4;
+
+/// The hashes of an image, with where it was loaded from.
+#[derive(Debug)]
+pub struct PathPhash {
+    /// Path the image was loaded from, identifying it between directories.
+    pub path: PathBuf,
+    /// File name of the image, used for groups and output file names.
+    pub file_name: OsString,
+    /// Path of the image under the source directory it was found in, where
+    /// it is copied to under the output directory.
+    pub relative: PathBuf,
+    pub phash: ImageHash,
+    /// Smaller hash used to prefilter candidates, if requested.
+    pub coarse: Option<ImageHash>,
+    /// Width and height of the image after cleanup, if known.
+    pub dimensions: Option<(u32, u32)>,
+    /// Hashes of transformed copies of the image, if requested.
+    pub transformed: Vec<TransformedPhash>,
+    /// Coarse color histogram of the image after cleanup, if requested.
+    pub histogram: Option<Vec<f32>>,
+    /// Size and modification time of the image, if known.
+    pub provenance: Option<Provenance>,
+}
+
+/// The hashes of a transformed copy of an image.
+#[derive(Debug)]
+pub struct TransformedPhash {
+    pub transform: Transform,
+    pub phash: ImageHash,
+    pub coarse: Option<ImageHash>,
+}
+
+/// A fullsize image found for a thumbnail.
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// Path of the thumbnail, including its directory.
+    pub thumb: PathBuf,
+    /// Path of the fullsize image, including its directory.
+    pub fullsize: PathBuf,
+    pub distance: u32,
+    /// Transform of the thumbnail that was closest to the fullsize image.
+    pub transform: Transform,
+}
+
+/// Returns the smallest distance from any hashed orientation of the thumbnail
+/// to `fullsize`, with the transform that produced it.
+pub fn thumb_distance(thumb_phash: &PathPhash, fullsize: &ImageHash) -> (u32, Transform) {
+    let mut best = (thumb_phash.phash.dist(fullsize), Transform::Identity);
+    for transformed in thumb_phash.transformed.iter() {
+        let distance = transformed.phash.dist(fullsize);
+        if distance < best.0 {
+            best = (distance, transformed.transform);
+        }
+    }
+    best
+}
+
+/// Hashes packed into contiguous 64 bit words, so that a distance is an XOR
+/// and popcount over a few words, rather than over each byte of the hash. All
+/// the hashes must have the same number of bits.
+#[derive(Debug, Default)]
+struct PackedHashes {
+    /// Words per hash.
+    words: usize,
+    data: Vec<u64>,
+}
+
+impl PackedHashes {
+    fn new<'a>(hashes: impl IntoIterator<Item = &'a ImageHash>) -> Self {
+        let mut packed = PackedHashes::default();
+        for hash in hashes {
+            let bytes = hash.as_bytes();
+            packed.words = bytes.len().div_ceil(8);
+            packed.data.extend(bytes.chunks(8).map(|chunk| {
+                let mut word = [0; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            }));
+        }
+        packed
+    }
+
+    fn get(&self, index: usize) -> &[u64] {
+        &self.data[index * self.words..(index + 1) * self.words]
+    }
+}
+
+/// Number of differing bits between two packed hashes, as `ImageHash::dist`.
+fn packed_distance(a: &[u64], b: &[u64]) -> u32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum()
+}
+
+/// Each hashed orientation of a thumbnail, packed to compare against the
+/// packed fullsize hashes.
+#[derive(Debug)]
+struct PackedThumb {
+    hashes: PackedHashes,
+    transforms: Vec<Transform>,
+}
+
+impl PackedThumb {
+    fn new(thumb_phash: &PathPhash) -> Self {
+        let mut transforms = vec![Transform::Identity];
+        transforms.extend(
+            thumb_phash
+                .transformed
+                .iter()
+                .map(|transformed| transformed.transform),
+        );
+        PackedThumb {
+            hashes: PackedHashes::new(
+                std::iter::once(&thumb_phash.phash).chain(
+                    thumb_phash
+                        .transformed
+                        .iter()
+                        .map(|transformed| &transformed.phash),
+                ),
+            ),
+            transforms,
+        }
+    }
+
+    fn orientations(&self) -> usize {
+        self.transforms.len()
+    }
+
+    /// Distance from each orientation to the packed `fullsize` hash.
+    fn distances<'a>(&'a self, fullsize: &'a [u64]) -> impl Iterator<Item = u32> + 'a {
+        (0..self.orientations()).map(move |index| packed_distance(self.hashes.get(index), fullsize))
+    }
+
+    /// As `thumb_distance`, against a packed fullsize hash.
+    fn distance(&self, fullsize: &[u64]) -> (u32, Transform) {
+        let (best, distance) = self
+            .distances(fullsize)
+            .enumerate()
+            .min_by_key(|(index, distance)| (*distance, *index))
+            .expect("No thumbnail hashes.");
+        (distance, self.transforms[best])
+    }
+}
+
+/// As `thumb_distance`, but between coarse hashes.
+fn thumb_coarse_distance(thumb_phash: &PathPhash, fullsize: &ImageHash) -> Option<u32> {
+    let mut best = thumb_phash.coarse.as_ref()?.dist(fullsize);
+    for transformed in thumb_phash.transformed.iter() {
+        best = std::cmp::min(best, transformed.coarse.as_ref()?.dist(fullsize));
+    }
+    Some(best)
+}
+
+/// Returns the `limit` fullsize images closest to the thumbnail by coarse
+/// hash, or `None` if the prefilter does not apply.
+fn prefilter_candidates(
+    thumb_phash: &PathPhash,
+    fullsize_phashes: &[PathPhash],
+    searched: &[usize],
+    limit: usize,
+) -> Option<Vec<usize>> {
+    if searched.len() <= limit {
+        return None;
+    }
+    let mut coarse_distances = searched
+        .iter()
+        .map(|image| {
+            let coarse = fullsize_phashes[*image].coarse.as_ref()?;
+            Some((thumb_coarse_distance(thumb_phash, coarse)?, *image))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    coarse_distances.select_nth_unstable(limit - 1);
+    coarse_distances.truncate(limit);
+    Some(
+        coarse_distances
+            .into_iter()
+            .map(|(_, image)| image)
+            .collect(),
+    )
+}
+
+/// How the fullsize images closest to each thumbnail are searched for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Matcher {
+    /// Compare against every candidate, or only those closest by coarse hash
+    /// with a prefilter.
+    BruteForce,
+    /// Search a BK-tree of the fullsize hashes, which finds the same matches
+    /// as comparing against every candidate, with far fewer comparisons.
+    BkTree,
+    /// Only compare against candidates with some chunk of their hash equal to
+    /// the thumbnail's, which finds every match closer than the number of
+    /// chunks.
+    MultiIndex,
+}
+
+impl FromStr for Matcher {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bruteforce" => Ok(Matcher::BruteForce),
+            "bktree" => Ok(Matcher::BkTree),
+            "multi-index" => Ok(Matcher::MultiIndex),
+            _ => Err(Error::Unknown {
+                kind: "matcher",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+impl Matcher {
+    pub fn name(self) -> &'static str {
+        match self {
+            Matcher::BruteForce => "bruteforce",
+            Matcher::BkTree => "bktree",
+            Matcher::MultiIndex => "multi-index",
+        }
+    }
+}
+
+/// BK-tree over the fine hashes of the fullsize images. Each child is at a
+/// known distance from its parent, so by the triangle inequality whole
+/// subtrees can be skipped when they must be further from the thumbnail than
+/// the candidates already found.
+#[derive(Debug)]
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+#[derive(Debug)]
+struct BkNode {
+    /// Index of the image in the fullsize images.
+    pub image: usize,
+    /// Indexes of the child nodes, with their distance from this one.
+    pub children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn new(phashes: &[PathPhash]) -> Self {
+        let mut nodes: Vec<BkNode> = Vec::with_capacity(phashes.len());
+        for (image, phash) in phashes.iter().enumerate() {
+            let node = nodes.len();
+            let mut parent = 0;
+            while parent < node {
+                let distance = phashes[nodes[parent].image].phash.dist(&phash.phash);
+                match nodes[parent]
+                    .children
+                    .iter()
+                    .find(|(child_distance, _)| *child_distance == distance)
+                {
+                    Some(&(_, child)) => parent = child,
+                    None => {
+                        nodes[parent].children.push((distance, node));
+                        break;
+                    }
+                }
+            }
+            nodes.push(BkNode {
+                image,
+                children: Vec::new(),
+            });
+        }
+        BkTree { nodes }
+    }
+
+    /// Returns the `limit` fullsize images closest to any hashed orientation
+    /// of the thumbnail, among those passing `accept`, ordered as by
+    /// `insert_candidate`. Each image compared is passed to `record` with its
+    /// distance.
+    fn search(
+        &self,
+        thumb_phash: &PathPhash,
+        queries: &PackedThumb,
+        search: &FullsizeSearch,
+        limit: usize,
+        accept: impl Fn(&PathPhash) -> bool,
+        record: &mut impl FnMut(usize, u32),
+    ) -> Vec<Match> {
+        let fullsize_phashes = search.phashes;
+        let packed = &search.packed;
+        let mut candidates: Vec<Match> = Vec::with_capacity(limit + 1);
+        let mut stack = if self.nodes.is_empty() {
+            Vec::new()
+        } else {
+            vec![0]
+        };
+        let mut distances = Vec::with_capacity(queries.orientations());
+        let mut compared = 0;
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            compared += queries.orientations();
+            let fullsize_phash = &fullsize_phashes[node.image];
+            distances.clear();
+            distances.extend(queries.distances(packed.get(node.image)));
+
+            if accept(fullsize_phash) {
+                // The first closest orientation, as in `thumb_distance`.
+                let (best, distance) = distances
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(index, distance)| (**distance, *index))
+                    .expect("No thumbnail hashes.");
+                record(node.image, *distance);
+                insert_candidate(
+                    &mut candidates,
+                    Match {
+                        fullsize: fullsize_phash.path.clone(),
+                        thumb: thumb_phash.path.clone(),
+                        distance: *distance,
+                        transform: queries.transforms[best],
+                    },
+                    limit,
+                );
+            }
+
+            // Ties with the furthest candidate may still displace it by name.
+            let radius = if candidates.len() < limit {
+                u32::MAX
+            } else {
+                candidates.last().map_or(u32::MAX, |worst| worst.distance)
+            };
+            for (child_distance, child) in node.children.iter() {
+                let within = distances
+                    .iter()
+                    .any(|distance| distance.abs_diff(*child_distance) <= radius);
+                if within {
+                    stack.push(*child);
+                }
+            }
+        }
+        count_comparisons(compared);
+        candidates
+    }
+}
+
+/// Tables of the fullsize images by each chunk of their fine hash. Hashes
+/// closer than the number of chunks must have some chunk in common, so only
+/// images sharing a chunk with the thumbnail need to be compared.
+#[derive(Debug)]
+struct MultiIndex {
+    /// Indexes of the fullsize images by the value of each chunk.
+    tables: Vec<HashMap<Vec<u8>, Vec<usize>>>,
+}
+
+impl MultiIndex {
+    fn new(phashes: &[PathPhash], chunks: usize) -> Self {
+        let mut tables = vec![HashMap::new(); chunks];
+        for (image, phash) in phashes.iter().enumerate() {
+            for (chunk, table) in tables.iter_mut().enumerate() {
+                table
+                    .entry(hash_chunk(&phash.phash, chunk, chunks))
+                    .or_insert_with(Vec::new)
+                    .push(image);
+            }
+        }
+        MultiIndex { tables }
+    }
+
+    /// Returns the indexes of the fullsize images sharing a chunk with any
+    /// hashed orientation of the thumbnail, in order.
+    fn candidates(&self, thumb_phash: &PathPhash) -> BTreeSet<usize> {
+        let chunks = self.tables.len();
+        let queries = std::iter::once(&thumb_phash.phash).chain(
+            thumb_phash
+                .transformed
+                .iter()
+                .map(|transformed| &transformed.phash),
+        );
+        let mut candidates = BTreeSet::new();
+        for query in queries {
+            for (chunk, table) in self.tables.iter().enumerate() {
+                if let Some(images) = table.get(&hash_chunk(query, chunk, chunks)) {
+                    candidates.extend(images.iter().copied());
+                }
+            }
+        }
+        candidates
+    }
+}
+
+/// Returns the bits of the `chunk`th of `chunks` equal parts of `hash`.
+fn hash_chunk(hash: &ImageHash, chunk: usize, chunks: usize) -> Vec<u8> {
+    let bytes = hash.as_bytes();
+    let bits = bytes.len() * 8;
+    let (start, end) = (chunk * bits / chunks, (chunk + 1) * bits / chunks);
+    let mut value = vec![0; (end - start).div_ceil(8)];
+    for (offset, bit) in (start..end).enumerate() {
+        if bytes[bit / 8] >> (bit % 8) & 1 == 1 {
+            value[offset / 8] |= 1 << (offset % 8);
+        }
+    }
+    value
+}
+
+/// Index over the fullsize hashes used by a `Matcher`.
+#[derive(Debug)]
+enum FullsizeIndex {
+    BkTree(BkTree),
+    MultiIndex(MultiIndex),
+}
+
+/// Fullsize hashes prepared for searching.
+#[derive(Debug)]
+pub struct FullsizeSearch<'a> {
+    pub phashes: &'a [PathPhash],
+    packed: PackedHashes,
+    index: Option<FullsizeIndex>,
+}
+
+impl<'a> FullsizeSearch<'a> {
+    pub fn new(phashes: &'a [PathPhash], matcher: Matcher, index_chunks: usize) -> Self {
+        FullsizeSearch {
+            phashes,
+            packed: PackedHashes::new(phashes.iter().map(|phash| &phash.phash)),
+            index: match matcher {
+                Matcher::BruteForce => None,
+                Matcher::BkTree => Some(FullsizeIndex::BkTree(BkTree::new(phashes))),
+                Matcher::MultiIndex => Some(FullsizeIndex::MultiIndex(MultiIndex::new(
+                    phashes,
+                    index_chunks,
+                ))),
+            },
+        }
+    }
+
+    /// Returns the `limit` fullsize images closest to any hashed orientation
+    /// of the thumbnail, among those passing `accept`, ordered as by
+    /// `insert_candidate`. Without a BK-tree, only the `prefilter` images
+    /// closest by coarse hash are compared, if given. Each image compared is
+    /// passed to `record` with its distance.
+    pub fn candidates(
+        &self,
+        thumb_phash: &PathPhash,
+        limit: usize,
+        prefilter: Option<usize>,
+        accept: impl Fn(&PathPhash) -> bool,
+        record: &mut impl FnMut(usize, u32),
+    ) -> Vec<Match> {
+        let fullsize_phashes = self.phashes;
+        let queries = PackedThumb::new(thumb_phash);
+        let mut searched: Vec<usize> = match &self.index {
+            Some(FullsizeIndex::BkTree(tree)) => {
+                return tree.search(thumb_phash, &queries, self, limit, accept, record)
+            }
+            Some(FullsizeIndex::MultiIndex(multi_index)) => multi_index
+                .candidates(thumb_phash)
+                .into_iter()
+                .filter(|image| accept(&fullsize_phashes[*image]))
+                .collect(),
+            None => Vec::new(),
+        };
+        let orientations = queries.orientations();
+        // Without a shared chunk, fall back to comparing every candidate.
+        // Images are compared in order, walking the packed hashes from start
+        // to end.
+        if searched.is_empty() {
+            searched = (0..fullsize_phashes.len())
+                .filter(|image| accept(&fullsize_phashes[*image]))
+                .collect();
+            if let Some(prefiltered) = prefilter.and_then(|limit| {
+                prefilter_candidates(thumb_phash, fullsize_phashes, &searched, limit)
+            }) {
+                count_comparisons(searched.len() * orientations);
+                searched = prefiltered;
+            }
+        }
+        count_comparisons(searched.len() * orientations);
+
+        let mut candidates: Vec<Match> = Vec::with_capacity(limit + 1);
+        for image in searched.into_iter() {
+            let fullsize_phash = &fullsize_phashes[image];
+            let (distance, transform) = queries.distance(self.packed.get(image));
+            record(image, distance);
+            insert_candidate(
+                &mut candidates,
+                Match {
+                    fullsize: fullsize_phash.path.clone(),
+                    thumb: thumb_phash.path.clone(),
+                    distance,
+                    transform,
+                },
+                limit,
+            );
+        }
+        candidates
+    }
+}
+
+/// How `best_matches` searches for the fullsize images of each thumbnail.
+#[derive(Debug, Clone, Copy)]
+pub struct SearchOptions {
+    /// Number of candidates found for each thumbnail, closest first.
+    pub limit: usize,
+    /// Without an index, compare only this many images closest by coarse
+    /// hash. Thumbnails and fullsize images need coarse hashes for it.
+    pub prefilter: Option<usize>,
+    /// Distance beyond which candidates are dropped.
+    pub max_distance: Option<u32>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            limit: 1,
+            prefilter: None,
+            max_distance: None,
+        }
+    }
+}
+
+/// Hooks into the search for each thumbnail, to choose which fullsize images
+/// it may match and to follow the search. Each has a default, so `()`
+/// searches every fullsize image for every thumbnail.
+pub trait SearchHooks: Sync {
+    /// Whether to search for `thumb`, such as until the search is
+    /// interrupted. Thumbnails not searched are left without candidates.
+    fn start(&self, _thumb: &PathPhash) -> bool {
+        true
+    }
+
+    /// Whether `fullsize` may be a candidate for `thumb`.
+    fn accept(&self, _thumb: &PathPhash, _fullsize: &PathPhash) -> bool {
+        true
+    }
+
+    /// Whether to record the distance to each fullsize image compared.
+    fn record_distances(&self) -> bool {
+        false
+    }
+
+    /// Called once `thumb` has been searched, with the index and distance of
+    /// each fullsize image compared, if they are recorded.
+    fn finish(&self, _thumb: &PathPhash, _distances: &[(usize, u32)]) {}
+}
+
+impl SearchHooks for () {}
+
+impl FullsizeSearch<'_> {
+    /// Returns the candidates for one thumbnail, as `best_matches` finds
+    /// them, for thumbnails searched one at a time.
+    pub fn search(
+        &self,
+        thumb_phash: &PathPhash,
+        options: &SearchOptions,
+        hooks: &impl SearchHooks,
+    ) -> Vec<Match> {
+        let record_distances = hooks.record_distances();
+        let mut distances = Vec::new();
+        let mut candidates = self.candidates(
+            thumb_phash,
+            options.limit,
+            options.prefilter,
+            |fullsize_phash| hooks.accept(thumb_phash, fullsize_phash),
+            &mut |image, distance| {
+                if record_distances {
+                    distances.push((image, distance));
+                }
+            },
+        );
+        if let Some(max_distance) = options.max_distance {
+            candidates.retain(|candidate| candidate.distance <= max_distance);
+        }
+        hooks.finish(thumb_phash, &distances);
+        candidates
+    }
+}
+
+/// Returns the closest fullsize images in `search` to each thumbnail, up to
+/// the limit of `options`, searching thumbnails in parallel. Candidates at
+/// the same distance are ordered by the path of the fullsize image.
+/// Thumbnails that `hooks` doesn't start are left as `None`.
+pub fn best_matches(
+    thumbs_phashes: &[PathPhash],
+    search: &FullsizeSearch,
+    options: &SearchOptions,
+    hooks: &impl SearchHooks,
+) -> Vec<Option<Vec<Match>>> {
+    thumbs_phashes
+        .par_iter()
+        .map(|thumb_phash| {
+            if !hooks.start(thumb_phash) {
+                return None;
+            }
+            Some(search.search(thumb_phash, options, hooks))
+        })
+        .collect()
+}
+
+/// Inserts `candidate` into the sorted `candidates`, keeping at most `limit`
+/// entries. Candidates are ordered by distance, then by fullsize file name.
+fn insert_candidate(candidates: &mut Vec<Match>, candidate: Match, limit: usize) {
+    let position = candidates
+        .binary_search_by(|existing| {
+            (existing.distance, &existing.fullsize).cmp(&(candidate.distance, &candidate.fullsize))
+        })
+        .unwrap_or_else(|position| position);
+    if position < limit {
+        candidates.insert(position, candidate);
+        candidates.truncate(limit);
+    }
+}
+
+/// Strategy for giving each thumbnail a distinct fullsize image.
+#[derive(Debug, Clone, Copy)]
+pub enum Assignment {
+    /// Repeatedly take the closest remaining pair.
+    Greedy,
+    /// Minimise the total distance over all pairs.
+    Optimal,
+}
+
+impl FromStr for Assignment {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "greedy" => Ok(Assignment::Greedy),
+            "optimal" => Ok(Assignment::Optimal),
+            _ => Err(Error::Unknown {
+                kind: "assignment strategy",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Returns the fullsize index assigned to each thumbnail, taking the closest
+/// remaining pair each time.
+pub fn assign_greedy(distances: &[Vec<u32>]) -> Vec<Option<usize>> {
+    let mut pairs = Vec::new();
+    for (thumb_index, row) in distances.iter().enumerate() {
+        for (fullsize_index, distance) in row.iter().enumerate() {
+            pairs.push((*distance, thumb_index, fullsize_index));
+        }
+    }
+    pairs.sort_unstable();
+
+    let mut assigned = vec![None; distances.len()];
+    let mut claimed = vec![false; distances.first().map_or(0, |row| row.len())];
+    for (_, thumb_index, fullsize_index) in pairs {
+        if assigned[thumb_index].is_none() && !claimed[fullsize_index] {
+            assigned[thumb_index] = Some(fullsize_index);
+            claimed[fullsize_index] = true;
+        }
+    }
+    assigned
+}
+
+/// Returns the fullsize index assigned to each thumbnail, minimising the total
+/// distance using the Hungarian algorithm.
+pub fn assign_optimal(distances: &[Vec<u32>]) -> Vec<Option<usize>> {
+    let rows = distances.len();
+    let columns = distances.first().map_or(0, |row| row.len());
+    if rows > columns {
+        // The algorithm below needs at least as many columns as rows, so solve
+        // the transposed problem and invert the result.
+        let transposed: Vec<Vec<u32>> = (0..columns)
+            .map(|column| distances.iter().map(|row| row[column]).collect())
+            .collect();
+        let mut assigned = vec![None; rows];
+        for (column, row) in assign_optimal(&transposed).into_iter().enumerate() {
+            if let Some(row) = row {
+                assigned[row] = Some(column);
+            }
+        }
+        return assigned;
+    }
+
+    // Potentials and matching are 1-indexed, with index 0 as a sentinel.
+    let mut row_potential = vec![0i64; rows + 1];
+    let mut column_potential = vec![0i64; columns + 1];
+    let mut column_row = vec![0usize; columns + 1];
+    let mut way = vec![0usize; columns + 1];
+    for row in 1..=rows {
+        column_row[0] = row;
+        let mut column = 0;
+        let mut min_reduced = vec![i64::MAX; columns + 1];
+        let mut used = vec![false; columns + 1];
+        loop {
+            used[column] = true;
+            let current_row = column_row[column];
+            let mut delta = i64::MAX;
+            let mut next_column = 0;
+            for candidate in 1..=columns {
+                if used[candidate] {
+                    continue;
+                }
+                let reduced = i64::from(distances[current_row - 1][candidate - 1])
+                    - row_potential[current_row]
+                    - column_potential[candidate];
+                if reduced < min_reduced[candidate] {
+                    min_reduced[candidate] = reduced;
+                    way[candidate] = column;
+                }
+                if min_reduced[candidate] < delta {
+                    delta = min_reduced[candidate];
+                    next_column = candidate;
+                }
+            }
+            for candidate in 0..=columns {
+                if used[candidate] {
+                    row_potential[column_row[candidate]] += delta;
+                    column_potential[candidate] -= delta;
+                } else {
+                    min_reduced[candidate] -= delta;
+                }
+            }
+            column = next_column;
+            if column_row[column] == 0 {
+                break;
+            }
+        }
+        loop {
+            let previous_column = way[column];
+            column_row[column] = column_row[previous_column];
+            column = previous_column;
+            if column == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assigned = vec![None; rows];
+    for (column, row) in column_row.into_iter().enumerate().skip(1) {
+        if row != 0 {
+            assigned[row - 1] = Some(column - 1);
+        }
+    }
+    assigned
+}
+
+/// Hash distances computed while matching, for `comparisons`. Each search adds
+/// its count once, so workers rarely contend for it.
+static COMPARISONS: AtomicU64 = AtomicU64::new(0);
+
+fn count_comparisons(count: usize) {
+    COMPARISONS.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// Returns how many hash distances have been computed while matching, over
+/// every search so far.
+pub fn comparisons() -> u64 {
+    COMPARISONS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use super::*;
+    use crate::phash::{HashConfig, Hashers};
+
+    /// Xorshift generator, so that the hashes are the same on every run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn byte(&mut self) -> u8 {
+            (self.next() >> 56) as u8
+        }
+    }
+
+    /// Builds a hash of the given bits, through its base64 form.
+    fn hash_of(bytes: &[u8]) -> ImageHash {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::new();
+        for chunk in bytes.chunks(3) {
+            let mut group = [0; 3];
+            group[..chunk.len()].copy_from_slice(chunk);
+            let bits = u32::from(group[0]) << 16 | u32::from(group[1]) << 8 | u32::from(group[2]);
+            for digit in 0..4 {
+                if digit <= chunk.len() {
+                    encoded.push(char::from(
+                        ALPHABET[(bits >> (18 - 6 * digit) & 63) as usize],
+                    ));
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        ImageHash::from_base64(&encoded).unwrap()
+    }
+
+    fn path_phash(path: String, phash: ImageHash) -> PathPhash {
+        let path = PathBuf::from(path);
+        PathPhash {
+            file_name: path.file_name().map(OsString::from).unwrap_or_default(),
+            relative: path.clone(),
+            path,
+            phash,
+            coarse: None,
+            dimensions: None,
+            transformed: Vec::new(),
+            histogram: None,
+            provenance: None,
+        }
+    }
+
+    /// Returns `count` small images of noise, which hash evenly over the space.
+    fn random_images(count: usize, seed: u64) -> Vec<RgbImage> {
+        let mut random = Random(seed);
+        (0..count)
+            .map(|_| {
+                RgbImage::from_fn(9, 8, |_, _| {
+                    Rgb([random.byte(), random.byte(), random.byte()])
+                })
+            })
+            .collect()
+    }
+
+    /// Hashes each of `images`, named `<side>/<index>.png`, with their coarse
+    /// hashes if `coarse`.
+    fn hash_images(images: &[RgbImage], side: &str, coarse: bool) -> Vec<PathPhash> {
+        let hashers = Hashers::new(HashConfig::default());
+        images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| {
+                let image = DynamicImage::ImageRgb8(image.clone());
+                let mut phash = path_phash(
+                    format!("{}/{}.png", side, index),
+                    hashers.fine.hash_image(&image),
+                );
+                if coarse {
+                    phash.coarse = Some(hashers.coarse.hash_image(&image));
+                }
+                phash
+            })
+            .collect()
+    }
+
+    /// Hashes `count` images of noise, giving hashes spread evenly over the
+    /// space, named `<side>/<index>.png`.
+    fn random_phashes(count: usize, side: &str, seed: u64) -> Vec<PathPhash> {
+        hash_images(&random_images(count, seed), side, false)
+    }
+
+    /// Searches only the fullsize images named with an even number, and
+    /// thumbnails other than the first.
+    struct EvenHooks;
+
+    impl SearchHooks for EvenHooks {
+        fn start(&self, thumb: &PathPhash) -> bool {
+            thumb.file_name != "0.png"
+        }
+
+        fn accept(&self, _thumb: &PathPhash, fullsize: &PathPhash) -> bool {
+            let name = fullsize.file_name.to_str().unwrap();
+            name.trim_end_matches(".png").parse::<usize>().unwrap() % 2 == 0
+        }
+    }
+
+    #[test]
+    fn best_matches_finds_the_closest_accepted_images() {
+        let fullsize = random_phashes(200, "fullsize", 1);
+        let thumbs = random_phashes(20, "thumbnail", 2);
+        let search = FullsizeSearch::new(&fullsize, Matcher::BruteForce, DEFAULT_INDEX_CHUNKS);
+        let options = SearchOptions {
+            limit: 3,
+            ..SearchOptions::default()
+        };
+        let matches = best_matches(&thumbs, &search, &options, &EvenHooks);
+        assert!(matches[0].is_none());
+        for (thumb, candidates) in thumbs.iter().zip(matches.iter()).skip(1) {
+            let candidates = candidates.as_ref().unwrap();
+            let mut expected: Vec<(u32, &PathBuf)> = fullsize
+                .iter()
+                .filter(|fullsize| EvenHooks.accept(thumb, fullsize))
+                .map(|fullsize| (thumb.phash.dist(&fullsize.phash), &fullsize.path))
+                .collect();
+            expected.sort();
+            expected.truncate(3);
+            let found: Vec<(u32, &PathBuf)> = candidates
+                .iter()
+                .map(|candidate| (candidate.distance, &candidate.fullsize))
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn best_matches_in_parallel_are_those_found_one_thumbnail_at_a_time() {
+        let fullsize = random_phashes(300, "fullsize", 21);
+        let thumbs = random_phashes(40, "thumbnail", 22);
+        let options = SearchOptions {
+            limit: 5,
+            ..SearchOptions::default()
+        };
+        let found = |matches: Vec<Option<Vec<Match>>>| -> Vec<Option<Vec<(PathBuf, u32)>>> {
+            matches
+                .into_iter()
+                .map(|candidates| {
+                    candidates.map(|candidates| {
+                        candidates
+                            .into_iter()
+                            .map(|candidate| (candidate.fullsize, candidate.distance))
+                            .collect()
+                    })
+                })
+                .collect()
+        };
+        for matcher in [Matcher::BruteForce, Matcher::BkTree, Matcher::MultiIndex].iter() {
+            let search = FullsizeSearch::new(&fullsize, *matcher, DEFAULT_INDEX_CHUNKS);
+            let serial = found(
+                thumbs
+                    .iter()
+                    .map(|thumb| {
+                        if EvenHooks.start(thumb) {
+                            Some(search.search(thumb, &options, &EvenHooks))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect(),
+            );
+            for threads in [1, 2, 8].iter() {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(*threads)
+                    .build()
+                    .unwrap();
+                let parallel =
+                    pool.install(|| best_matches(&thumbs, &search, &options, &EvenHooks));
+                assert_eq!(
+                    found(parallel),
+                    serial,
+                    "{:?} on {} threads",
+                    matcher,
+                    threads
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn best_matches_drops_candidates_beyond_the_max_distance() {
+        let fullsize = random_phashes(50, "fullsize", 3);
+        let thumbs = random_phashes(50, "thumbnail", 4);
+        let search = FullsizeSearch::new(&fullsize, Matcher::BruteForce, DEFAULT_INDEX_CHUNKS);
+        let nearest = best_matches(&thumbs, &search, &SearchOptions::default(), &());
+        let max_distance = 20;
+        let options = SearchOptions {
+            max_distance: Some(max_distance),
+            ..SearchOptions::default()
+        };
+        let within = best_matches(&thumbs, &search, &options, &());
+        for (nearest, within) in nearest.iter().zip(within.iter()) {
+            let nearest = &nearest.as_ref().unwrap()[0];
+            let within = within.as_ref().unwrap();
+            if nearest.distance <= max_distance {
+                assert_eq!(within[0].fullsize, nearest.fullsize);
+            } else {
+                assert!(within.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn packed_distance_matches_image_hash_distance() {
+        let mut random = Random(5);
+        // Hashes filling whole words, and ending part way through one.
+        for bytes in [8usize, 18, 32].iter() {
+            let hashes: Vec<ImageHash> = (0..20)
+                .map(|_| hash_of(&(0..*bytes).map(|_| random.byte()).collect::<Vec<_>>()))
+                .collect();
+            let packed = PackedHashes::new(hashes.iter());
+            assert_eq!(packed.words, bytes.div_ceil(8));
+            for (a, a_hash) in hashes.iter().enumerate() {
+                assert_eq!(packed_distance(packed.get(a), packed.get(a)), 0);
+                for (b, b_hash) in hashes.iter().enumerate() {
+                    assert_eq!(
+                        packed_distance(packed.get(a), packed.get(b)),
+                        a_hash.dist(b_hash)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packed_thumb_distance_matches_thumb_distance() {
+        let fullsize = random_phashes(50, "fullsize", 6);
+        let mut thumbs = random_phashes(20, "thumbnail", 7);
+        let mut transformed = random_phashes(60, "transformed", 8).into_iter();
+        for thumb in thumbs.iter_mut() {
+            for transform in [Transform::FlipHorizontal, Transform::FlipVertical].iter() {
+                thumb.transformed.push(TransformedPhash {
+                    transform: *transform,
+                    phash: transformed.next().unwrap().phash,
+                    coarse: None,
+                });
+            }
+        }
+        let packed = PackedHashes::new(fullsize.iter().map(|phash| &phash.phash));
+        for thumb in thumbs.iter() {
+            let queries = PackedThumb::new(thumb);
+            for (image, fullsize) in fullsize.iter().enumerate() {
+                assert_eq!(
+                    queries.distance(packed.get(image)),
+                    thumb_distance(thumb, &fullsize.phash)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prefilter_finds_the_same_best_matches_as_comparing_every_image() {
+        let fullsize_images = random_images(300, 9);
+        // Thumbnails are lightly altered copies of every tenth fullsize image.
+        let mut random = Random(10);
+        let thumb_images: Vec<RgbImage> = fullsize_images
+            .iter()
+            .step_by(10)
+            .map(|image| {
+                let mut thumb = image.clone();
+                for pixel in thumb.pixels_mut() {
+                    for channel in pixel.0.iter_mut() {
+                        *channel = channel.saturating_add(random.byte() % 8);
+                    }
+                }
+                thumb
+            })
+            .collect();
+        let fullsize = hash_images(&fullsize_images, "fullsize", true);
+        let thumbs = hash_images(&thumb_images, "thumbnail", true);
+        let search = FullsizeSearch::new(&fullsize, Matcher::BruteForce, DEFAULT_INDEX_CHUNKS);
+
+        let every = best_matches(&thumbs, &search, &SearchOptions::default(), &());
+        let options = SearchOptions {
+            prefilter: Some(20),
+            ..SearchOptions::default()
+        };
+        let prefiltered = best_matches(&thumbs, &search, &options, &());
+        for (index, (thumb, (every, prefiltered))) in thumbs
+            .iter()
+            .zip(every.iter().zip(prefiltered.iter()))
+            .enumerate()
+        {
+            let every = &every.as_ref().unwrap()[0];
+            let prefiltered = &prefiltered.as_ref().unwrap()[0];
+            assert_eq!(every.fullsize, fullsize[index * 10].path);
+            assert_eq!(prefiltered.fullsize, every.fullsize);
+            // Distances are still those between the fine hashes.
+            assert_eq!(
+                prefiltered.distance,
+                thumb.phash.dist(&fullsize[index * 10].phash)
+            );
+        }
+    }
+
+    #[test]
+    fn prefilter_larger_than_the_candidates_compares_every_image() {
+        let fullsize = hash_images(&random_images(50, 11), "fullsize", true);
+        let thumbs = hash_images(&random_images(20, 12), "thumbnail", true);
+        let search = FullsizeSearch::new(&fullsize, Matcher::BruteForce, DEFAULT_INDEX_CHUNKS);
+        let options = SearchOptions {
+            limit: 5,
+            ..SearchOptions::default()
+        };
+        let every = best_matches(&thumbs, &search, &options, &());
+        let options = SearchOptions {
+            prefilter: Some(50),
+            ..options
+        };
+        let prefiltered = best_matches(&thumbs, &search, &options, &());
+        for (every, prefiltered) in every.iter().zip(prefiltered.iter()) {
+            let found = |matches: &Option<Vec<Match>>| -> Vec<(u32, PathBuf)> {
+                matches
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .map(|candidate| (candidate.distance, candidate.fullsize.clone()))
+                    .collect()
+            };
+            assert_eq!(found(prefiltered), found(every));
+        }
+    }
+
+    /// Returns `hash` with `count` distinct bits flipped.
+    fn flip_bits(hash: &[u8], count: usize, random: &mut Random) -> Vec<u8> {
+        let mut flipped = hash.to_vec();
+        let mut bits = BTreeSet::new();
+        while bits.len() < count {
+            bits.insert(random.next() as usize % (hash.len() * 8));
+        }
+        for bit in bits {
+            flipped[bit / 8] ^= 1 << (bit % 8);
+        }
+        flipped
+    }
+
+    #[test]
+    fn multi_index_finds_every_match_closer_than_the_chunks() {
+        let mut random = Random(13);
+        for chunks in [2, 4, 8].iter() {
+            let thumb_bytes: Vec<Vec<u8>> = (0..10)
+                .map(|_| (0..8).map(|_| random.byte()).collect())
+                .collect();
+            // Near copies of each thumbnail at every distance the index
+            // guarantees, among unrelated hashes.
+            let mut fullsize_bytes: Vec<Vec<u8>> = (0..100)
+                .map(|_| (0..8).map(|_| random.byte()).collect())
+                .collect();
+            for bytes in thumb_bytes.iter() {
+                for distance in 0..*chunks {
+                    fullsize_bytes.push(flip_bits(bytes, distance, &mut random));
+                }
+            }
+            let thumbs: Vec<PathPhash> = thumb_bytes
+                .iter()
+                .enumerate()
+                .map(|(index, bytes)| {
+                    path_phash(format!("thumbnail/{}.png", index), hash_of(bytes))
+                })
+                .collect();
+            let fullsize: Vec<PathPhash> = fullsize_bytes
+                .iter()
+                .enumerate()
+                .map(|(index, bytes)| path_phash(format!("fullsize/{}.png", index), hash_of(bytes)))
+                .collect();
+
+            let options = SearchOptions {
+                limit: fullsize.len(),
+                max_distance: Some(*chunks as u32 - 1),
+                ..SearchOptions::default()
+            };
+            let brute_force = FullsizeSearch::new(&fullsize, Matcher::BruteForce, *chunks);
+            let multi_index = FullsizeSearch::new(&fullsize, Matcher::MultiIndex, *chunks);
+            let expected = best_matches(&thumbs, &brute_force, &options, &());
+            let found = best_matches(&thumbs, &multi_index, &options, &());
+            for (expected, found) in expected.iter().zip(found.iter()) {
+                let matches = |matches: &Option<Vec<Match>>| -> Vec<(u32, PathBuf)> {
+                    matches
+                        .as_ref()
+                        .unwrap()
+                        .iter()
+                        .map(|candidate| (candidate.distance, candidate.fullsize.clone()))
+                        .collect()
+                };
+                let expected = matches(expected);
+                assert!(expected.len() >= *chunks);
+                assert_eq!(matches(found), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn multi_index_without_a_shared_chunk_compares_every_image() {
+        let fullsize = random_phashes(100, "fullsize", 14);
+        let thumbs = random_phashes(20, "thumbnail", 15);
+        // With 32 bit chunks, unrelated hashes share none.
+        let index = MultiIndex::new(&fullsize, 2);
+        assert!(thumbs
+            .iter()
+            .all(|thumb| index.candidates(thumb).is_empty()));
+
+        let brute_force = FullsizeSearch::new(&fullsize, Matcher::BruteForce, 2);
+        let multi_index = FullsizeSearch::new(&fullsize, Matcher::MultiIndex, 2);
+        let options = SearchOptions::default();
+        for thumb in thumbs.iter() {
+            let expected = &brute_force.search(thumb, &options, &())[0];
+            let found = &multi_index.search(thumb, &options, &())[0];
+            assert_eq!(
+                (found.distance, &found.fullsize),
+                (expected.distance, &expected.fullsize)
+            );
+        }
+    }
+
+    #[test]
+    fn bk_tree_finds_the_same_matches_as_brute_force() {
+        let fullsize = random_phashes(300, "fullsize", 16);
+        let mut thumbs = random_phashes(30, "thumbnail", 17);
+        let mut transformed = random_phashes(30, "transformed", 18).into_iter();
+        for thumb in thumbs.iter_mut().step_by(2) {
+            thumb.transformed.push(TransformedPhash {
+                transform: Transform::Rotate90,
+                phash: transformed.next().unwrap().phash,
+                coarse: None,
+            });
+        }
+        let brute_force = FullsizeSearch::new(&fullsize, Matcher::BruteForce, DEFAULT_INDEX_CHUNKS);
+        let bk_tree = FullsizeSearch::new(&fullsize, Matcher::BkTree, DEFAULT_INDEX_CHUNKS);
+        let matches =
+            |matches: Vec<Option<Vec<Match>>>| -> Vec<Option<Vec<(u32, PathBuf, Transform)>>> {
+                matches
+                    .into_iter()
+                    .map(|matches| {
+                        matches.map(|matches| {
+                            matches
+                                .into_iter()
+                                .map(|candidate| {
+                                    (candidate.distance, candidate.fullsize, candidate.transform)
+                                })
+                                .collect()
+                        })
+                    })
+                    .collect()
+            };
+        for limit in [1, 3, 10, 300].iter() {
+            let options = SearchOptions {
+                limit: *limit,
+                ..SearchOptions::default()
+            };
+            assert_eq!(
+                matches(best_matches(&thumbs, &bk_tree, &options, &())),
+                matches(best_matches(&thumbs, &brute_force, &options, &()))
+            );
+            assert_eq!(
+                matches(best_matches(&thumbs, &bk_tree, &options, &EvenHooks)),
+                matches(best_matches(&thumbs, &brute_force, &options, &EvenHooks))
+            );
+        }
+    }
+
+    #[test]
+    fn bk_tree_of_no_images_finds_nothing() {
+        let thumbs = random_phashes(3, "thumbnail", 19);
+        let search = FullsizeSearch::new(&[], Matcher::BkTree, DEFAULT_INDEX_CHUNKS);
+        let matches = best_matches(&thumbs, &search, &SearchOptions::default(), &());
+        assert!(matches
+            .iter()
+            .all(|matches| matches.as_ref().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn closest_candidates_are_those_of_a_linear_scan() {
+        let fullsize = random_phashes(2000, "fullsize", 23);
+        let mut random = Random(24);
+        // Near copies of some of the fullsize images, and others far from
+        // all of them.
+        let mut thumbs: Vec<PathPhash> = fullsize
+            .iter()
+            .step_by(40)
+            .enumerate()
+            .map(|(index, image)| {
+                let flipped = flip_bits(image.phash.as_bytes(), index % 6, &mut random);
+                path_phash(format!("thumbnail/{}.png", index), hash_of(&flipped))
+            })
+            .collect();
+        thumbs.extend(random_phashes(10, "thumbnail", 25));
+        // Popcount of the differing bits, compared a byte at a time.
+        let reference = |thumb: &PathPhash| -> Vec<(u32, PathBuf)> {
+            let mut distances: Vec<(u32, PathBuf)> = fullsize
+                .iter()
+                .map(|image| {
+                    let distance = thumb
+                        .phash
+                        .as_bytes()
+                        .iter()
+                        .zip(image.phash.as_bytes())
+                        .map(|(a, b)| (a ^ b).count_ones())
+                        .sum();
+                    (distance, image.path.clone())
+                })
+                .collect();
+            distances.sort();
+            distances.truncate(3);
+            distances
+        };
+        for matcher in [Matcher::BruteForce, Matcher::BkTree, Matcher::MultiIndex].iter() {
+            let search = FullsizeSearch::new(&fullsize, *matcher, DEFAULT_INDEX_CHUNKS);
+            for thumb in thumbs.iter() {
+                let mut compared = 0;
+                let mut found: Vec<(u32, PathBuf)> = search
+                    .candidates(thumb, 3, None, |_| true, &mut |_, _| compared += 1)
+                    .into_iter()
+                    .map(|candidate| (candidate.distance, candidate.fullsize))
+                    .collect();
+                let mut expected = reference(thumb);
+                match matcher {
+                    Matcher::BruteForce => assert_eq!(compared, fullsize.len()),
+                    // Only images sharing a chunk are compared, as every
+                    // image closer than the number of chunks does.
+                    Matcher::MultiIndex => {
+                        let shared = |(distance, _): &(u32, PathBuf)| {
+                            (*distance as usize) < DEFAULT_INDEX_CHUNKS
+                        };
+                        expected.retain(shared);
+                        found.retain(shared);
+                    }
+                    Matcher::BkTree => {}
+                }
+                assert_eq!(found, expected, "{:?}", matcher);
+            }
+        }
+    }
+
+    #[test]
+    fn ties_are_broken_by_fullsize_path() {
+        let hash = random_phashes(1, "thumbnail", 20).remove(0).phash;
+        let thumbs = vec![path_phash("thumbnail/0.png".to_owned(), hash.clone())];
+        // Equal hashes, loaded in either order.
+        for names in [["b.png", "a.png"], ["a.png", "b.png"]].iter() {
+            let fullsize: Vec<PathPhash> = names
+                .iter()
+                .map(|name| path_phash(format!("fullsize/{}", name), hash.clone()))
+                .collect();
+            for matcher in [Matcher::BruteForce, Matcher::BkTree, Matcher::MultiIndex].iter() {
+                let search = FullsizeSearch::new(&fullsize, *matcher, DEFAULT_INDEX_CHUNKS);
+                let matches = best_matches(&thumbs, &search, &SearchOptions::default(), &());
+                let best = &matches[0].as_ref().unwrap()[0];
+                assert_eq!(best.fullsize, PathBuf::from("fullsize/a.png"));
+                assert_eq!(best.distance, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn assignments_break_ties_by_index() {
+        let distances = vec![vec![1, 1, 1], vec![1, 1, 1]];
+        assert_eq!(assign_greedy(&distances), vec![Some(0), Some(1)]);
+        assert_eq!(assign_optimal(&distances), vec![Some(0), Some(1)]);
+        let transposed = vec![vec![1, 1], vec![1, 1], vec![1, 1]];
+        assert_eq!(assign_greedy(&transposed), vec![Some(0), Some(1), None]);
+        assert_eq!(assign_optimal(&transposed), vec![Some(0), Some(1), None]);
+        // Ties at a lower distance are taken first, whatever their position.
+        let distances = vec![vec![2, 0, 0], vec![0, 2, 2]];
+        assert_eq!(assign_greedy(&distances), vec![Some(1), Some(0)]);
+        assert_eq!(assign_optimal(&distances), vec![Some(1), Some(0)]);
+    }
+}
@@ -0,0 +1,1564 @@
+//! Perceptual hashing of images, and the caches their hashes are kept in so
+//! that each image is only hashed once.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, read_to_string, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+use img_hash::{FilterType, HashAlg, Hasher, HasherConfig, ImageHash};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::borders::{border_crop, content_fraction, BorderOptions};
+use crate::matching::{PathPhash, TransformedPhash};
+use crate::{Error, Result};
+
+/// Default size images are downsized to fit before hashing.
+pub const THUMBNAIL_LIMIT: u32 = 255;
+/// Default side length of the hash, matching the `img_hash` default.
+pub const HASH_SIZE: u32 = 8;
+/// Largest supported side length of the hash.
+pub const MAX_HASH_SIZE: u32 = 64;
+/// Side length of the coarse hash used to prefilter candidates.
+pub const COARSE_HASH_SIZE: u32 = 4;
+/// Bins per channel of the color histograms kept with hashes.
+pub const HISTOGRAM_BINS: u32 = 4;
+/// Smallest side length left by center cropping. Images that would be
+/// cropped smaller are hashed uncropped.
+const MIN_CENTER_CROP_SIZE: u32 = 8;
+/// Version of the cache entry format. Entries with a different version are
+/// recomputed.
+const CACHE_VERSION: u32 = 8;
+/// Name of the index of cached hashes in each cache directory.
+const CACHE_INDEX_NAME: &str = "index.json";
+
+/// Transform applied to a thumbnail before hashing, so that it can match a
+/// fullsize image in a different orientation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transform {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    /// Flip horizontally, then rotate by 90 degrees.
+    Transpose,
+    /// Flip horizontally, then rotate by 270 degrees.
+    Transverse,
+}
+
+/// Returns the transforms to hash each thumbnail with, in addition to the
+/// original. Rotations and flips together give all eight orientations.
+pub fn thumbnail_transforms(rotations: bool, flips: bool) -> Vec<Transform> {
+    let mut transforms = Vec::new();
+    if rotations {
+        transforms.extend([
+            Transform::Rotate90,
+            Transform::Rotate180,
+            Transform::Rotate270,
+        ]);
+    }
+    if flips {
+        transforms.extend([Transform::FlipHorizontal, Transform::FlipVertical]);
+    }
+    if rotations && flips {
+        transforms.extend([Transform::Transpose, Transform::Transverse]);
+    }
+    transforms
+}
+
+impl Transform {
+    pub fn apply(self, image: &DynamicImage) -> DynamicImage {
+        match self {
+            Transform::Identity => image.clone(),
+            Transform::Rotate90 => image.rotate90(),
+            Transform::Rotate180 => image.rotate180(),
+            Transform::Rotate270 => image.rotate270(),
+            Transform::FlipHorizontal => image.fliph(),
+            Transform::FlipVertical => image.flipv(),
+            Transform::Transpose => image.fliph().rotate90(),
+            Transform::Transverse => image.fliph().rotate270(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transform::Identity => "none",
+            Transform::Rotate90 => "rotate90",
+            Transform::Rotate180 => "rotate180",
+            Transform::Rotate270 => "rotate270",
+            Transform::FlipHorizontal => "fliph",
+            Transform::FlipVertical => "flipv",
+            Transform::Transpose => "transpose",
+            Transform::Transverse => "transverse",
+        }
+    }
+
+    /// Suffix of the cache entry holding the hash of the transformed image.
+    /// It starts with `/`, which no file name contains, so that the entries of
+    /// `scan.png` never collide with those of an image named `scan.png.fliph`.
+    pub fn cache_suffix(self) -> String {
+        match self {
+            Transform::Identity => String::new(),
+            _ => format!("/{}", self.name()),
+        }
+    }
+
+    /// Suffix of the cache files of earlier releases, which did collide.
+    fn legacy_cache_suffix(self) -> String {
+        match self {
+            Transform::Identity => String::new(),
+            _ => format!(".{}", self.name()),
+        }
+    }
+}
+
+/// Perceptual hashing algorithm used for every image in a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    Mean,
+    #[default]
+    Gradient,
+    DoubleGradient,
+    /// Mean hash of the DCT of the image, as in the original pHash.
+    Dct,
+    Blockhash,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Mean => "mean",
+            HashAlgorithm::Gradient => "gradient",
+            HashAlgorithm::DoubleGradient => "double-gradient",
+            HashAlgorithm::Dct => "dct",
+            HashAlgorithm::Blockhash => "blockhash",
+        }
+    }
+
+    fn configure(self, config: HasherConfig) -> HasherConfig {
+        match self {
+            HashAlgorithm::Mean => config.hash_alg(HashAlg::Mean),
+            HashAlgorithm::Gradient => config.hash_alg(HashAlg::Gradient),
+            HashAlgorithm::DoubleGradient => config.hash_alg(HashAlg::DoubleGradient),
+            HashAlgorithm::Dct => config.hash_alg(HashAlg::Mean).preproc_dct(),
+            HashAlgorithm::Blockhash => config.hash_alg(HashAlg::Blockhash),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mean" => Ok(HashAlgorithm::Mean),
+            "gradient" => Ok(HashAlgorithm::Gradient),
+            "double-gradient" => Ok(HashAlgorithm::DoubleGradient),
+            "dct" => Ok(HashAlgorithm::Dct),
+            "blockhash" => Ok(HashAlgorithm::Blockhash),
+            _ => Err(Error::Unknown {
+                kind: "hash algorithm",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Filter used to downsize images while hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    /// The `img_hash` default.
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub fn name(self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Triangle => "triangle",
+            ResizeFilter::CatmullRom => "catmull-rom",
+            ResizeFilter::Gaussian => "gaussian",
+            ResizeFilter::Lanczos3 => "lanczos3",
+        }
+    }
+
+    fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for ResizeFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "triangle" => Ok(ResizeFilter::Triangle),
+            "catmull-rom" => Ok(ResizeFilter::CatmullRom),
+            "gaussian" => Ok(ResizeFilter::Gaussian),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            _ => Err(Error::Unknown {
+                kind: "resize filter",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Normalization steps applied to every image after cleanup and before
+/// hashing. Steps run in a fixed order, whatever order they were given in:
+/// grayscale, then autocontrast, then equalize.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Preprocess {
+    pub grayscale: bool,
+    pub autocontrast: bool,
+    pub equalize: bool,
+}
+
+impl Preprocess {
+    /// Names of the enabled steps, in the order they are applied.
+    pub fn steps(&self) -> Vec<&'static str> {
+        [
+            (self.grayscale, "grayscale"),
+            (self.autocontrast, "autocontrast"),
+            (self.equalize, "equalize"),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+
+    /// Suffix of the cache subdirectory for images preprocessed with these
+    /// steps, empty if there are none.
+    pub fn cache_suffix(&self) -> String {
+        self.steps()
+            .iter()
+            .map(|step| format!("-{}", step))
+            .collect()
+    }
+
+    pub fn apply(&self, image: DynamicImage) -> DynamicImage {
+        if *self == Preprocess::default() {
+            return image;
+        }
+        let mut image = if self.grayscale {
+            DynamicImage::ImageLuma8(image.to_luma()).to_rgb()
+        } else {
+            image.to_rgb()
+        };
+        if self.autocontrast {
+            autocontrast(&mut image);
+        }
+        if self.equalize {
+            equalize(&mut image);
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+}
+
+impl FromStr for Preprocess {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut preprocess = Preprocess::default();
+        for step in s.split(',').map(str::trim).filter(|step| !step.is_empty()) {
+            match step {
+                "grayscale" => preprocess.grayscale = true,
+                "autocontrast" => preprocess.autocontrast = true,
+                "equalize" => preprocess.equalize = true,
+                _ => {
+                    return Err(Error::Unknown {
+                        kind: "preprocessing step",
+                        value: step.to_owned(),
+                    })
+                }
+            }
+        }
+        Ok(preprocess)
+    }
+}
+
+/// Stretches each channel so that its darkest value becomes 0 and its
+/// brightest 255.
+fn autocontrast(image: &mut RgbImage) {
+    let mut low = [u8::MAX; 3];
+    let mut high = [u8::MIN; 3];
+    for pixel in image.pixels() {
+        for channel in 0..3 {
+            low[channel] = low[channel].min(pixel.0[channel]);
+            high[channel] = high[channel].max(pixel.0[channel]);
+        }
+    }
+    for pixel in image.pixels_mut() {
+        for channel in 0..3 {
+            let range = u32::from(high[channel] - low[channel]);
+            let value = u32::from(pixel.0[channel] - low[channel]);
+            // A flat channel is left as it is.
+            if let Some(stretched) = (value * 255).checked_div(range) {
+                pixel.0[channel] = stretched as u8;
+            }
+        }
+    }
+}
+
+/// Equalizes the histogram of each channel, spreading its values evenly over
+/// 0 to 255.
+fn equalize(image: &mut RgbImage) {
+    let total = u64::from(image.width()) * u64::from(image.height());
+    if total == 0 {
+        return;
+    }
+    let mut histograms = [[0u64; 256]; 3];
+    for pixel in image.pixels() {
+        for channel in 0..3 {
+            histograms[channel][usize::from(pixel.0[channel])] += 1;
+        }
+    }
+    let mut lookups = [[0u8; 256]; 3];
+    for (histogram, lookup) in histograms.iter().zip(lookups.iter_mut()) {
+        let mut cumulative = 0;
+        for (count, value) in histogram.iter().zip(lookup.iter_mut()) {
+            cumulative += count;
+            *value = (cumulative * 255 / total) as u8;
+        }
+    }
+    for pixel in image.pixels_mut() {
+        for channel in 0..3 {
+            pixel.0[channel] = lookups[channel][usize::from(pixel.0[channel])];
+        }
+    }
+}
+
+/// How an image is cleaned up once decoded, before it is hashed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PrepareOptions {
+    /// Remove borders before hashing.
+    pub cleanup: bool,
+    /// How borders are found, both to remove them and to tell blank images.
+    pub border: BorderOptions,
+    /// Percentage of each dimension to keep, cropping around the center.
+    pub center_crop: Option<u32>,
+    /// Sigma of a Gaussian blur applied before downsizing.
+    pub preblur: Option<f32>,
+    pub preprocess: Preprocess,
+    /// Fraction of content at or below which images are refused as blank.
+    pub skip_blank: Option<f64>,
+    /// Size images larger than it are downsized to fit, or 0 to keep them
+    /// as they are.
+    pub prescale: u32,
+}
+
+/// An image decoded and cleaned up ready for hashing.
+pub struct PreparedImage {
+    pub image: DynamicImage,
+    /// Dimensions of the image as decoded and oriented.
+    pub original: (u32, u32),
+    /// Bounds the border was cropped to, as `(x, y, width, height)`, if any.
+    pub crop: Option<(u32, u32, u32, u32)>,
+    /// Dimensions of the image after cleanup, before prescaling.
+    pub dimensions: (u32, u32),
+}
+
+/// Decodes the image at `path` upright and cleans it up ready for hashing.
+/// Images larger than the prescale size are downsized to fit it, and smaller
+/// ones are left as they are.
+pub fn prepare_image(path: &Path, options: &PrepareOptions) -> Result<PreparedImage> {
+    let mut img = open_upright(path)?;
+    if let Some(threshold) = options.skip_blank {
+        let fraction = content_fraction(&img, &options.border);
+        if fraction <= threshold {
+            return Err(Error::Blank(fraction));
+        }
+    }
+    let original = img.dimensions();
+    let crop = if options.cleanup {
+        border_crop(&img, &options.border, path)
+    } else {
+        None
+    };
+    if let Some((x, y, width, height)) = crop {
+        img = img.crop_imm(x, y, width, height);
+    }
+    if let Some(percent) = options.center_crop {
+        img = center_crop(img, percent, path);
+    }
+    if let Some(sigma) = options.preblur {
+        img = img.blur(sigma);
+    }
+    img = options.preprocess.apply(img);
+    let dimensions = img.dimensions();
+    let prescale = options.prescale;
+    if prescale > 0 && (dimensions.0 > prescale || dimensions.1 > prescale) {
+        img = img.thumbnail(prescale, prescale);
+    }
+    Ok(PreparedImage {
+        image: img,
+        original,
+        crop,
+        dimensions,
+    })
+}
+
+/// Decodes the image at `path`, rotated and flipped upright as its EXIF
+/// orientation says.
+pub fn open_upright(path: &Path) -> Result<DynamicImage> {
+    Ok(apply_orientation(
+        image::open(path)?,
+        exif_orientation(path),
+    ))
+}
+
+/// Crops an image to the central `percent` of each dimension. Images that
+/// would be cropped too small are returned as they are.
+fn center_crop(image: DynamicImage, percent: u32, path: &Path) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let cropped_width = width * percent / 100;
+    let cropped_height = height * percent / 100;
+    if cropped_width < MIN_CENTER_CROP_SIZE || cropped_height < MIN_CENTER_CROP_SIZE {
+        warn!(
+            "Not cropping {}, as it would only be {}x{}",
+            path.to_string_lossy(),
+            cropped_width,
+            cropped_height
+        );
+        return image;
+    }
+    image.crop_imm(
+        (width - cropped_width) / 2,
+        (height - cropped_height) / 2,
+        cropped_width,
+        cropped_height,
+    )
+}
+
+/// Reads the EXIF orientation of the image at `path`, from 1 to 8. Images
+/// without one are upright.
+fn exif_orientation(path: &Path) -> u32 {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return 1,
+    };
+    let mut reader = BufReader::new(file);
+    match exif::Reader::new().read_from_container(&mut reader) {
+        Ok(exif) => exif
+            .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .unwrap_or(1),
+        Err(_) => 1,
+    }
+}
+
+/// Rotates and flips an image stored with the given EXIF orientation upright.
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Returns a normalised joint RGB histogram of the image, with
+/// `HISTOGRAM_BINS` bins per channel.
+pub fn color_histogram(image: &DynamicImage) -> Vec<f32> {
+    let bins = HISTOGRAM_BINS as usize;
+    let bin = |value: u8| value as usize * bins / 256;
+    let mut histogram = vec![0.0; bins * bins * bins];
+    let rgb = image.to_rgb();
+    for pixel in rgb.pixels() {
+        let [r, g, b] = pixel.0;
+        histogram[(bin(r) * bins + bin(g)) * bins + bin(b)] += 1.0;
+    }
+    let total = std::cmp::max(rgb.width() * rgb.height(), 1) as f32;
+    for count in histogram.iter_mut() {
+        *count /= total;
+    }
+    histogram
+}
+
+/// Returns the intersection of two normalised histograms, from 0 (disjoint)
+/// to 1 (identical).
+pub fn histogram_similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| a.min(*b)).sum()
+}
+
+/// What cached hashes are keyed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheKey {
+    /// The file name, within a cache for each source directory.
+    #[default]
+    Name,
+    /// A digest of the file contents, within a cache shared by every source
+    /// directory, so that renamed and moved images keep their hashes.
+    Content,
+}
+
+impl FromStr for CacheKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "name" => Ok(CacheKey::Name),
+            "content" => Ok(CacheKey::Content),
+            _ => Err(Error::Unknown {
+                kind: "cache key",
+                value: s.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Algorithm, size and resize filter of the fine hash used for a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashConfig {
+    pub algorithm: HashAlgorithm,
+    pub width: u32,
+    pub height: u32,
+    pub filter: ResizeFilter,
+    /// Size images are downsized to fit before hashing, or 0 to hash them at
+    /// full size.
+    pub prescale: u32,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        HashConfig {
+            algorithm: HashAlgorithm::default(),
+            width: HASH_SIZE,
+            height: HASH_SIZE,
+            filter: ResizeFilter::default(),
+            prescale: THUMBNAIL_LIMIT,
+        }
+    }
+}
+
+impl HashConfig {
+    /// Builds a config from a width and optional height, which defaults to the
+    /// width.
+    pub fn new(
+        algorithm: HashAlgorithm,
+        size: &[u32],
+        filter: ResizeFilter,
+        prescale: u32,
+    ) -> Result<Self> {
+        let (width, height) = match *size {
+            [width] => (width, width),
+            [width, height] => (width, height),
+            _ => return Err(Error::HashSides),
+        };
+        for side in [width, height].iter() {
+            if *side < 2 || *side > MAX_HASH_SIZE {
+                return Err(Error::HashSize(*side));
+            }
+        }
+        Ok(HashConfig {
+            algorithm,
+            width,
+            height,
+            filter,
+            prescale,
+        })
+    }
+
+    /// Name of the cache subdirectory for hashes with this config. Default
+    /// settings are left out, keeping caches from before they were
+    /// configurable.
+    pub fn cache_name(&self) -> String {
+        let mut name = format!("{}-{}x{}", self.algorithm.name(), self.width, self.height);
+        if self.filter != ResizeFilter::default() {
+            name.push('-');
+            name.push_str(self.filter.name());
+        }
+        if self.prescale != THUMBNAIL_LIMIT {
+            name.push_str(&format!("-prescale{}", self.prescale));
+        }
+        name
+    }
+
+    pub fn hasher(&self, width: u32, height: u32) -> Hasher {
+        let config = HasherConfig::new()
+            .hash_size(width, height)
+            .resize_filter(self.filter.filter_type());
+        self.algorithm.configure(config).to_hasher()
+    }
+
+    /// Number of bits in each hash, and so the largest possible distance. Some
+    /// algorithms round the size, or produce fewer bits than it implies.
+    pub fn bits(&self) -> usize {
+        let hasher = self.hasher(self.width, self.height);
+        let blank = DynamicImage::new_luma8(THUMBNAIL_LIMIT, THUMBNAIL_LIMIT);
+        hasher.hash_image(&blank).as_bytes().len() * 8
+    }
+
+    /// Describes this config in `hash.json` and reports.
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "algorithm": self.algorithm.name(),
+            "width": self.width,
+            "height": self.height,
+            "filter": self.filter.name(),
+            "prescale": self.prescale,
+            "bits": self.bits(),
+        })
+    }
+}
+
+/// Hashers for the fine and coarse hashes.
+pub struct Hashers {
+    pub fine: Hasher,
+    pub coarse: Hasher,
+}
+
+impl Hashers {
+    pub fn new(config: HashConfig) -> Self {
+        Hashers {
+            fine: config.hasher(config.width, config.height),
+            coarse: config.hasher(COARSE_HASH_SIZE, COARSE_HASH_SIZE),
+        }
+    }
+}
+
+/// On disk format of a cached hash. Entries written before versioning are a
+/// bare base64 hash, and are read with unknown dimensions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub version: u32,
+    pub hash: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Vec<f32>>,
+    /// Size of the source image in bytes, unknown for entries imported from
+    /// earlier releases.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Modification time of the source image, in nanoseconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+}
+
+impl CacheEntry {
+    pub fn new(cached: &CachedHash, provenance: Provenance) -> Self {
+        CacheEntry {
+            version: CACHE_VERSION,
+            hash: cached.phash.to_base64(),
+            width: cached.dimensions.map(|(width, _)| width),
+            height: cached.dimensions.map(|(_, height)| height),
+            histogram: cached.histogram.clone(),
+            size: Some(provenance.size),
+            modified: provenance.modified,
+        }
+    }
+
+    /// Reads an entry written to its own file by earlier releases.
+    fn read_legacy(cache_file: &Path) -> Result<Self> {
+        let contents = read_to_string(cache_file)?;
+        if contents.starts_with('{') {
+            return Ok(serde_json::from_str(&contents)?);
+        }
+        Ok(CacheEntry {
+            version: CACHE_VERSION,
+            hash: contents.trim().to_owned(),
+            width: None,
+            height: None,
+            histogram: None,
+            size: None,
+            modified: None,
+        })
+    }
+
+    /// Returns whether the source image may have changed since the entry was
+    /// written. Entries of unknown provenance, imported from earlier
+    /// releases, are stale so that they are recomputed once.
+    pub fn is_stale(&self, provenance: Provenance) -> bool {
+        self.size.is_none_or(|size| size != provenance.size)
+            || self
+                .modified
+                .zip(provenance.modified)
+                .is_some_and(|(cached, current)| cached != current)
+    }
+
+    pub fn to_cached_hash(&self) -> Result<CachedHash> {
+        Ok(CachedHash {
+            phash: ImageHash::from_base64(&self.hash)
+                .map_err(|error| Error::InvalidHash(error.to_string()))?,
+            dimensions: self.width.zip(self.height),
+            histogram: self.histogram.clone(),
+        })
+    }
+}
+
+/// A hash read from a cache, or computed to be written to one.
+#[derive(Debug)]
+pub struct CachedHash {
+    pub phash: ImageHash,
+    pub dimensions: Option<(u32, u32)>,
+    pub histogram: Option<Vec<f32>>,
+}
+
+/// Size and modification time of a source image, recorded with its cached
+/// hashes to notice when it changes.
+#[derive(Debug, Clone, Copy)]
+pub struct Provenance {
+    pub size: u64,
+    pub modified: Option<u64>,
+}
+
+impl Provenance {
+    pub fn read(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_nanos() as u64);
+        Ok(Provenance {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// Everything that affects the hashes in a cache directory, recorded in its
+/// index so that hashes computed differently are never mixed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    pub algorithm: String,
+    pub width: u32,
+    pub height: u32,
+    pub filter: String,
+    pub prescale: u32,
+    /// How borders are found, if they are removed.
+    pub border: Option<String>,
+    pub center_crop: Option<u32>,
+    pub preblur: Option<f32>,
+    pub preprocess: Vec<String>,
+}
+
+impl CacheConfig {
+    /// Describes hashes computed with `hash`, or the coarse hash alongside it,
+    /// of images cleaned up with `border` if their borders are removed.
+    pub fn new(
+        hash: HashConfig,
+        coarse: bool,
+        border: Option<&BorderOptions>,
+        center_crop: Option<u32>,
+        preblur: Option<f32>,
+        preprocess: Preprocess,
+    ) -> Self {
+        let (width, height) = if coarse {
+            (COARSE_HASH_SIZE, COARSE_HASH_SIZE)
+        } else {
+            (hash.width, hash.height)
+        };
+        CacheConfig {
+            algorithm: hash.algorithm.name().to_owned(),
+            width,
+            height,
+            filter: hash.filter.name().to_owned(),
+            prescale: hash.prescale,
+            border: border.map(BorderOptions::config_name),
+            center_crop,
+            preblur,
+            preprocess: preprocess
+                .steps()
+                .iter()
+                .map(|step| (*step).to_owned())
+                .collect(),
+        }
+    }
+}
+
+/// Returns `file_name` as a cache index key, which must be a string. Names
+/// that are not valid UTF-8 are followed by their raw bytes in hex, so that
+/// names differing only in invalid bytes have distinct keys.
+pub fn encode_file_name(file_name: &OsStr) -> String {
+    match file_name.to_str() {
+        Some(name) => name.to_owned(),
+        None => format!("{}#{}", file_name.to_string_lossy(), raw_hex(file_name)),
+    }
+}
+
+/// Returns the raw bytes of `value` in hex.
+#[cfg(unix)]
+pub fn raw_hex(value: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    value
+        .as_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns the raw bytes of `value` in hex.
+#[cfg(not(unix))]
+pub fn raw_hex(value: &OsStr) -> String {
+    value
+        .to_string_lossy()
+        .bytes()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns the index key of a per-file cache entry from an earlier release.
+fn legacy_cache_name(file_name: &str) -> String {
+    thumbnail_transforms(true, true)
+        .iter()
+        .find_map(|transform| {
+            file_name
+                .strip_suffix(&transform.legacy_cache_suffix())
+                .map(|source| format!("{}{}", source, transform.cache_suffix()))
+        })
+        .unwrap_or_else(|| file_name.to_owned())
+}
+
+/// On disk format of a cache index.
+#[derive(Debug, Deserialize)]
+struct IndexFile {
+    config: CacheConfig,
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+/// What `HashCache::hash_file` reads or computes for an image.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HashRequest<'a> {
+    /// What the cache entries of the image are keyed by.
+    pub key: CacheKey,
+    /// Transforms to hash, in addition to the original.
+    pub transforms: &'a [Transform],
+    /// Whether the dimensions of the image must be known, rehashing it if
+    /// they were not cached.
+    pub dimensions: bool,
+    /// Whether its color histogram must be known, rehashing it if it was not
+    /// cached.
+    pub histogram: bool,
+    /// Ignore cached entries, rehashing the image.
+    pub rebuild: bool,
+}
+
+/// Entries computed by `HashCache::hash_file` for one image, to be written
+/// to the caches.
+#[derive(Debug, Default)]
+pub struct CacheUpdates {
+    /// Entries of the fine hashes, for the cache the image was hashed with.
+    pub fine: Vec<(String, CacheEntry)>,
+    /// Entries of the coarse hashes, for the coarse cache.
+    pub coarse: Vec<(String, CacheEntry)>,
+    /// Number of entries read from the caches instead.
+    pub reused: usize,
+    /// Whether the image was decoded.
+    pub decoded: bool,
+    /// Bytes of the image read from disk.
+    pub bytes_read: u64,
+}
+
+/// Cached hashes of one cache directory, stored together in a single index
+/// file and keyed by source file name and transform.
+#[derive(Debug)]
+pub struct HashCache {
+    directory: PathBuf,
+    config: CacheConfig,
+    entries: BTreeMap<String, CacheEntry>,
+    /// Per-file entries from earlier releases, removed once the index has
+    /// been written.
+    legacy_files: Vec<PathBuf>,
+    /// Whether the index must be written back even without new entries.
+    changed: bool,
+}
+
+impl HashCache {
+    /// An index without entries, which is never written.
+    pub fn empty(config: CacheConfig) -> Self {
+        HashCache {
+            directory: PathBuf::new(),
+            config,
+            entries: BTreeMap::new(),
+            legacy_files: Vec::new(),
+            changed: false,
+        }
+    }
+
+    /// Reads the index of `directory`, importing any per-file entries.
+    /// Entries with a different version are dropped, to be recomputed, as are
+    /// all entries if they were hashed with a different configuration, unless
+    /// `strict` is set, in which case that is an error.
+    pub fn read(directory: &Path, config: CacheConfig, strict: bool) -> Result<Self> {
+        let index_file = directory.join(CACHE_INDEX_NAME);
+        let mut changed = false;
+        let mut entries = BTreeMap::new();
+        if index_file.exists() {
+            let index: IndexFile =
+                serde_json::from_reader(BufReader::new(File::open(&index_file)?))?;
+            if index.config == config {
+                entries = index.entries;
+            } else if strict {
+                return Err(Error::CacheConfig {
+                    directory: directory.to_owned(),
+                    found: Box::new(index.config),
+                    expected: Box::new(config),
+                });
+            } else {
+                warn!(
+                    "Cache {} was hashed with a different configuration, rehashing",
+                    directory.to_string_lossy()
+                );
+                changed = true;
+            }
+        }
+
+        let mut legacy_files = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let cache_file = entry?.path();
+            let name = match cache_file.file_name().and_then(OsStr::to_str) {
+                Some(name) if !name.starts_with(CACHE_INDEX_NAME) => legacy_cache_name(name),
+                _ => continue,
+            };
+            match CacheEntry::read_legacy(&cache_file) {
+                Ok(legacy) => {
+                    entries.entry(name).or_insert(legacy);
+                    legacy_files.push(cache_file);
+                }
+                Err(error) => warn!(
+                    "Ignoring cache file {}: {}",
+                    cache_file.to_string_lossy(),
+                    error
+                ),
+            }
+        }
+        if !legacy_files.is_empty() {
+            info!(
+                "Importing {} cache files into {}",
+                legacy_files.len(),
+                index_file.to_string_lossy()
+            );
+            changed = true;
+        }
+
+        entries.retain(|_, entry| entry.version == CACHE_VERSION);
+        Ok(HashCache {
+            directory: directory.to_owned(),
+            config,
+            entries,
+            legacy_files,
+            changed,
+        })
+    }
+
+    /// Returns the entry for `name`, unless `provenance` is given and the
+    /// source image has changed since.
+    pub fn get(&self, name: &str, provenance: Option<Provenance>) -> Option<&CacheEntry> {
+        self.entries
+            .get(name)
+            .filter(|entry| provenance.is_none_or(|provenance| !entry.is_stale(provenance)))
+    }
+
+    /// Returns the hashes of the image at `path` that `request` asks for,
+    /// with the coarse ones from `coarse_cache` if it is given. Hashes are
+    /// read from the cache unless the image has changed since, or the
+    /// request rebuilds them. Otherwise the image is prepared with `prepare`,
+    /// at most once, and the computed entries are returned rather than kept,
+    /// so that images can be hashed in parallel and the entries written to
+    /// each cache once with `write`.
+    pub fn hash_file(
+        &self,
+        path: &Path,
+        request: &HashRequest,
+        hashers: &Hashers,
+        coarse_cache: Option<&HashCache>,
+        prepare: impl Fn(&Path) -> Result<PreparedImage>,
+    ) -> Result<(PathPhash, CacheUpdates)> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| Error::NoFileName(path.to_owned()))?;
+        let provenance = Provenance::read(path)?;
+        // Content keyed entries are never stale, as any change to the image
+        // changes its key.
+        let (key, check_provenance) = match request.key {
+            CacheKey::Name => (encode_file_name(file_name), Some(provenance)),
+            CacheKey::Content => (format!("{:032x}", xxh3_128(&fs::read(path)?)), None),
+        };
+        let mut updates = CacheUpdates::default();
+        if request.key == CacheKey::Content {
+            updates.bytes_read = provenance.size;
+        }
+
+        // The image is only decoded if some hash is missing from the cache,
+        // and then at most once.
+        let mut prepared = None;
+        let mut reused = 0;
+        let mut load = |cache: &HashCache,
+                        updates: &mut Vec<(String, CacheEntry)>,
+                        hasher: &Hasher,
+                        transform: Transform,
+                        need_extra: bool|
+         -> Result<CachedHash> {
+            let mut cache_name = key.clone();
+            cache_name.push_str(&transform.cache_suffix());
+            if let Some(entry) = cache
+                .get(&cache_name, check_provenance)
+                .filter(|_| !request.rebuild)
+            {
+                let cached = entry.to_cached_hash()?;
+                let missing_extra = need_extra
+                    && ((request.dimensions && cached.dimensions.is_none())
+                        || (request.histogram && cached.histogram.is_none()));
+                if !missing_extra {
+                    reused += 1;
+                    return Ok(cached);
+                }
+            }
+
+            if prepared.is_none() {
+                prepared = Some(prepare(path)?);
+            }
+            let prepared = prepared.as_ref().expect("Image was not prepared.");
+            let computed = CachedHash {
+                phash: hasher.hash_image(&transform.apply(&prepared.image)),
+                dimensions: Some(prepared.dimensions),
+                histogram: if need_extra {
+                    Some(color_histogram(&prepared.image))
+                } else {
+                    None
+                },
+            };
+            updates.push((cache_name, CacheEntry::new(&computed, provenance)));
+            Ok(computed)
+        };
+
+        let cached = load(
+            self,
+            &mut updates.fine,
+            &hashers.fine,
+            Transform::Identity,
+            true,
+        )?;
+        let mut transformed = Vec::with_capacity(request.transforms.len());
+        for transform in request.transforms.iter() {
+            transformed.push(TransformedPhash {
+                transform: *transform,
+                phash: load(self, &mut updates.fine, &hashers.fine, *transform, false)?.phash,
+                coarse: None,
+            });
+        }
+
+        let mut coarse = None;
+        if let Some(coarse_cache) = coarse_cache {
+            coarse = Some(
+                load(
+                    coarse_cache,
+                    &mut updates.coarse,
+                    &hashers.coarse,
+                    Transform::Identity,
+                    false,
+                )?
+                .phash,
+            );
+            for transformed in transformed.iter_mut() {
+                transformed.coarse = Some(
+                    load(
+                        coarse_cache,
+                        &mut updates.coarse,
+                        &hashers.coarse,
+                        transformed.transform,
+                        false,
+                    )?
+                    .phash,
+                );
+            }
+        }
+
+        updates.reused = reused;
+        updates.decoded = prepared.is_some();
+        if updates.decoded {
+            updates.bytes_read += provenance.size;
+        }
+        let phash = PathPhash {
+            file_name: file_name.to_owned(),
+            relative: PathBuf::from(file_name),
+            path: path.to_owned(),
+            phash: cached.phash,
+            coarse,
+            dimensions: cached.dimensions,
+            transformed,
+            histogram: cached.histogram,
+            provenance: Some(provenance),
+        };
+        Ok((phash, updates))
+    }
+
+    /// Removes the entries of the source image encoded as `source`, returning
+    /// each with the rest of its name after `source`, such as the suffix of
+    /// a transform.
+    pub fn remove_source(&mut self, source: &str) -> Vec<(String, CacheEntry)> {
+        let names: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|name| entry_source(name) == source)
+            .cloned()
+            .collect();
+        let mut removed = Vec::with_capacity(names.len());
+        for name in names {
+            if let Some(entry) = self.entries.remove(&name) {
+                removed.push((name[source.len()..].to_owned(), entry));
+                self.changed = true;
+            }
+        }
+        removed
+    }
+
+    /// Removes entries whose source image is no longer in `source_directory`,
+    /// returning how many there were. With `dry_run`, they are only listed.
+    pub fn prune(&mut self, source_directory: &Path, dry_run: bool) -> Result<usize> {
+        let mut sources = HashSet::new();
+        for entry in fs::read_dir(source_directory)? {
+            sources.insert(encode_file_name(&entry?.file_name()));
+        }
+        let missing: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|name| !sources.contains(entry_source(name)))
+            .cloned()
+            .collect();
+        for name in missing.iter() {
+            if dry_run {
+                info!(
+                    "Would prune {} from {}",
+                    name,
+                    self.directory.to_string_lossy()
+                );
+            } else {
+                debug!("Pruning {} from {}", name, self.directory.to_string_lossy());
+                self.entries.remove(name);
+                self.changed = true;
+            }
+        }
+        Ok(missing.len())
+    }
+
+    /// Merges `updates` into the index and writes it back, through a
+    /// temporary file so that an interrupted run leaves the old index intact.
+    pub fn write(&mut self, updates: Vec<(String, CacheEntry)>) -> Result<()> {
+        if updates.is_empty() && !self.changed {
+            return Ok(());
+        }
+        self.entries.extend(updates);
+
+        let index_file = self.directory.join(CACHE_INDEX_NAME);
+        let temporary_file = self.directory.join(format!("{}.tmp", CACHE_INDEX_NAME));
+        let mut writer = BufWriter::new(File::create(&temporary_file)?);
+        serde_json::to_writer(
+            &mut writer,
+            &serde_json::json!({
+                "config": &self.config,
+                "entries": &self.entries,
+            }),
+        )?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temporary_file, &index_file)?;
+
+        for legacy_file in self.legacy_files.drain(..) {
+            fs::remove_file(&legacy_file)?;
+        }
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Returns the encoded file name of the source image of the cache entry
+/// `name`, which may have the suffix of a transform.
+fn entry_source(name: &str) -> &str {
+    thumbnail_transforms(true, true)
+        .iter()
+        .find_map(|transform| name.strip_suffix(&transform.cache_suffix()))
+        .unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    /// Writes a white image with a dark square of `side` pixels in the
+    /// middle to `name` in `directory`, returning its path.
+    fn write_image(directory: &Path, name: &str, side: u32) -> PathBuf {
+        let path = directory.join(name);
+        RgbImage::from_fn(100, 100, |x, y| {
+            let inside =
+                |coordinate: u32| (50 - side / 2..50 - side / 2 + side).contains(&coordinate);
+            if inside(x) && inside(y) {
+                Rgb([20, 20, 20])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        })
+        .save(&path)
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn prepare_image_refuses_blank_images_when_skipping_them() {
+        let directory = tempfile::tempdir().unwrap();
+        let blank = write_image(directory.path(), "blank.png", 0);
+        let speck = write_image(directory.path(), "speck.png", 2);
+        let photo = write_image(directory.path(), "photo.png", 80);
+        let options = PrepareOptions {
+            cleanup: true,
+            skip_blank: Some(0.01),
+            ..PrepareOptions::default()
+        };
+        for path in [&blank, &speck].iter() {
+            match prepare_image(path, &options) {
+                Err(Error::Blank(fraction)) => assert!(fraction <= 0.01),
+                other => panic!("{:?}", other.map(|prepared| prepared.dimensions)),
+            }
+        }
+        let prepared = prepare_image(&photo, &options).unwrap();
+        assert_eq!(prepared.crop, Some((10, 10, 80, 80)));
+        assert_eq!(prepared.dimensions, (80, 80));
+
+        // Without skipping, blank images are hashed uncropped.
+        let options = PrepareOptions {
+            cleanup: true,
+            ..PrepareOptions::default()
+        };
+        let prepared = prepare_image(&blank, &options).unwrap();
+        assert_eq!(prepared.crop, None);
+        assert_eq!(prepared.dimensions, (100, 100));
+    }
+
+    /// Packs `bits` into bytes from the lowest bit up, as `img_hash` does.
+    fn pack(bits: impl Iterator<Item = bool>) -> Vec<u8> {
+        let bits: Vec<bool> = bits.collect();
+        bits.chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .enumerate()
+                    .fold(0, |packed, (bit, set)| packed | (u8::from(*set) << bit))
+            })
+            .collect()
+    }
+
+    /// Returns the gradient hash of `image` at `width` by `height`: whether
+    /// each pixel of it downsized with `filter` is brighter than the one on
+    /// its left.
+    fn reference_gradient(
+        image: &DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> Vec<u8> {
+        let small =
+            image::imageops::resize(&image.to_luma(), width + 1, height, filter.filter_type());
+        pack((0..height).flat_map(|y| {
+            let small = &small;
+            (0..width).map(move |x| small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0])
+        }))
+    }
+
+    /// Returns the mean hash of `image` at `width` by `height`: whether each
+    /// pixel of it downsized with `filter` is at least its mean brightness.
+    fn reference_mean(
+        image: &DynamicImage,
+        width: u32,
+        height: u32,
+        filter: ResizeFilter,
+    ) -> Vec<u8> {
+        let small = image::imageops::resize(&image.to_luma(), width, height, filter.filter_type());
+        let total: u32 = small.pixels().map(|pixel| u32::from(pixel.0[0])).sum();
+        let mean = (total / (width * height)) as u8;
+        pack(small.pixels().map(|pixel| pixel.0[0] >= mean))
+    }
+
+    /// A scan of `width` by `height` pixels of gradients with noise, as the
+    /// benchmarks hash.
+    fn gradient_scan(width: u32, height: u32, seed: u64) -> DynamicImage {
+        let mut state = seed;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let noise = (state >> 56) as u8 / 8;
+            Rgb([
+                (x * 255 / width) as u8 / 2 + noise,
+                (y * 255 / height) as u8 / 2 + noise,
+                ((x + y) * 127 / (width + height)) as u8 + noise,
+            ])
+        }))
+    }
+
+    #[test]
+    fn hashers_match_reference_gradient_and_mean_hashes() {
+        let filters = [
+            ResizeFilter::Nearest,
+            ResizeFilter::Triangle,
+            ResizeFilter::CatmullRom,
+            ResizeFilter::Gaussian,
+            ResizeFilter::Lanczos3,
+        ];
+        for (seed, (width, height)) in [(128, 128), (300, 200), (97, 311)].iter().enumerate() {
+            let image = gradient_scan(*width, *height, seed as u64 + 1);
+            for filter in filters.iter() {
+                for sides in [[8, 8], [16, 8], [5, 12]].iter() {
+                    for (algorithm, reference) in [
+                        (
+                            HashAlgorithm::Gradient,
+                            reference_gradient as fn(&_, _, _, _) -> _,
+                        ),
+                        (HashAlgorithm::Mean, reference_mean),
+                    ]
+                    .iter()
+                    {
+                        let config = HashConfig::new(*algorithm, sides, *filter, 0).unwrap();
+                        let hashers = Hashers::new(config);
+                        assert_eq!(
+                            hashers.fine.hash_image(&image).as_bytes(),
+                            &reference(&image, sides[0], sides[1], *filter)[..],
+                            "{} at {:?} with {} of {}x{}",
+                            algorithm.name(),
+                            sides,
+                            filter.name(),
+                            width,
+                            height
+                        );
+                        assert_eq!(
+                            hashers.coarse.hash_image(&image).as_bytes(),
+                            &reference(&image, COARSE_HASH_SIZE, COARSE_HASH_SIZE, *filter)[..]
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hashes the image at `path` through `cache` with the default options,
+    /// returning it with the entries computed, and writing them.
+    fn hash_cached(cache: &mut HashCache, path: &Path) -> (PathPhash, CacheUpdates) {
+        let hashers = Hashers::new(HashConfig::default());
+        let (phash, updates) = cache
+            .hash_file(path, &HashRequest::default(), &hashers, None, |path| {
+                prepare_image(path, &PrepareOptions::default())
+            })
+            .unwrap();
+        let computed = updates
+            .fine
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        cache.write(computed).unwrap();
+        (phash, updates)
+    }
+
+    fn cache_config() -> CacheConfig {
+        CacheConfig::new(
+            HashConfig::default(),
+            false,
+            None,
+            None,
+            None,
+            Preprocess::default(),
+        )
+    }
+
+    #[test]
+    fn hash_cache_reuses_hashes_until_the_image_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache_directory = directory.path().join("cache");
+        fs::create_dir(&cache_directory).unwrap();
+        let path = write_image(directory.path(), "photo.png", 40);
+        let read = || HashCache::read(&cache_directory, cache_config(), true).unwrap();
+
+        // Cold: the image is decoded and its hash written.
+        let (cold, updates) = hash_cached(&mut read(), &path);
+        assert!(updates.decoded);
+        assert_eq!((updates.fine.len(), updates.reused), (1, 0));
+        assert!(cache_directory.join(CACHE_INDEX_NAME).is_file());
+
+        // Warm: the hash is read back without decoding the image.
+        let (warm, updates) = hash_cached(&mut read(), &path);
+        assert!(!updates.decoded);
+        assert_eq!((updates.fine.len(), updates.reused), (0, 1));
+        assert_eq!(warm.phash, cold.phash);
+        assert_eq!(warm.dimensions, Some((100, 100)));
+
+        // Stale by modification time, with the same size.
+        let modified = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&path, modified).unwrap();
+        let (_, updates) = hash_cached(&mut read(), &path);
+        assert!(updates.decoded);
+        let (_, updates) = hash_cached(&mut read(), &path);
+        assert!(!updates.decoded);
+
+        // Stale by size, rehashing the changed image.
+        write_image(directory.path(), "photo.png", 80);
+        filetime::set_file_mtime(&path, modified).unwrap();
+        let (changed, updates) = hash_cached(&mut read(), &path);
+        assert!(updates.decoded);
+        assert_ne!(changed.phash, cold.phash);
+        let (_, updates) = hash_cached(&mut read(), &path);
+        assert!(!updates.decoded);
+    }
+
+    #[test]
+    fn hash_cache_drops_entries_of_other_versions() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache_directory = directory.path().join("cache");
+        fs::create_dir(&cache_directory).unwrap();
+        let path = write_image(directory.path(), "photo.png", 40);
+        let mut cache = HashCache::read(&cache_directory, cache_config(), false).unwrap();
+        hash_cached(&mut cache, &path);
+
+        let index_file = cache_directory.join(CACHE_INDEX_NAME);
+        let mut index: serde_json::Value =
+            serde_json::from_str(&read_to_string(&index_file).unwrap()).unwrap();
+        index["entries"]["photo.png"]["version"] = serde_json::json!(CACHE_VERSION - 1);
+        fs::write(&index_file, index.to_string()).unwrap();
+
+        let cache = HashCache::read(&cache_directory, cache_config(), false).unwrap();
+        assert!(cache.get("photo.png", None).is_none());
+        let (_, updates) = hash_cached(
+            &mut HashCache::read(&cache_directory, cache_config(), false).unwrap(),
+            &path,
+        );
+        assert!(updates.decoded);
+    }
+
+    #[test]
+    fn hash_cache_of_another_configuration_is_rehashed_unless_strict() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache_directory = directory.path().join("cache");
+        fs::create_dir(&cache_directory).unwrap();
+        let path = write_image(directory.path(), "photo.png", 40);
+        hash_cached(
+            &mut HashCache::read(&cache_directory, cache_config(), false).unwrap(),
+            &path,
+        );
+
+        let mut other = cache_config();
+        other.preblur = Some(1.5);
+        match HashCache::read(&cache_directory, other.clone(), true) {
+            Err(Error::CacheConfig { .. }) => (),
+            other => panic!("{:?}", other),
+        }
+        let cache = HashCache::read(&cache_directory, other, false).unwrap();
+        assert!(cache.get("photo.png", None).is_none());
+    }
+
+    #[test]
+    fn cache_keys_tell_apart_names_sharing_a_stem_or_lossy_name() {
+        let keys: Vec<String> = ["scan.png", "scan.jpg", "scan.png.fliph"]
+            .iter()
+            .map(|name| encode_file_name(OsStr::new(name)))
+            .collect();
+        assert_eq!(keys, ["scan.png", "scan.jpg", "scan.png.fliph"]);
+        // Transformed entries can't collide with an image's own name.
+        let flipped = format!("{}{}", keys[0], Transform::FlipHorizontal.cache_suffix());
+        assert_eq!(flipped, "scan.png/fliph");
+        assert!(!keys.contains(&flipped));
+        assert_eq!(legacy_cache_name("scan.png.fliph"), flipped);
+        assert_eq!(legacy_cache_name("scan.png"), "scan.png");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let latin1 = encode_file_name(OsStr::from_bytes(b"caf\xe9.png"));
+            let cp1252 = encode_file_name(OsStr::from_bytes(b"caf\xe8.png"));
+            assert_eq!(latin1, "caf\u{fffd}.png#636166e92e706e67");
+            assert_ne!(latin1, cp1252);
+        }
+    }
+
+    /// A dull gradient, with each channel in a narrow band of its own.
+    fn dull_gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(32, 16, |x, y| {
+            Rgb([100 + x as u8, 60 + y as u8 * 2, 180 - x as u8])
+        }))
+    }
+
+    #[test]
+    fn each_preprocessing_step_changes_the_image() {
+        let image = dull_gradient();
+        let step = |steps: &str| {
+            steps
+                .parse::<Preprocess>()
+                .unwrap()
+                .apply(image.clone())
+                .to_rgb()
+        };
+        assert_eq!(step(""), image.to_rgb());
+
+        let gray = step("grayscale");
+        assert_ne!(gray, image.to_rgb());
+        assert!(gray
+            .pixels()
+            .all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2]));
+
+        let stretched = step("autocontrast");
+        for channel in 0..3 {
+            let values: Vec<u8> = stretched.pixels().map(|pixel| pixel[channel]).collect();
+            assert_eq!(values.iter().min(), Some(&0));
+            assert_eq!(values.iter().max(), Some(&255));
+        }
+
+        let equalized = step("equalize");
+        assert_ne!(equalized, image.to_rgb());
+        assert_ne!(equalized, stretched);
+        // Each of the 32 values of the red channel is equally common, so they
+        // are spread evenly up to 255.
+        let mut reds: Vec<u8> = equalized.pixels().map(|pixel| pixel[0]).collect();
+        reds.sort_unstable();
+        reds.dedup();
+        assert_eq!(reds.len(), 32);
+        assert_eq!(reds.last(), Some(&255));
+    }
+
+    #[test]
+    fn preprocessing_steps_apply_in_one_order_however_given() {
+        let image = dull_gradient();
+        let given = [
+            "equalize,grayscale,autocontrast",
+            "grayscale, autocontrast,equalize,",
+        ];
+        let preprocesses: Vec<Preprocess> =
+            given.iter().map(|steps| steps.parse().unwrap()).collect();
+        assert_eq!(preprocesses[0], preprocesses[1]);
+        assert_eq!(
+            preprocesses[0].steps(),
+            ["grayscale", "autocontrast", "equalize"]
+        );
+        assert_eq!(
+            preprocesses[0].cache_suffix(),
+            "-grayscale-autocontrast-equalize"
+        );
+        let applied = preprocesses[0].apply(image.clone()).to_rgb();
+        assert_eq!(applied, preprocesses[1].apply(image).to_rgb());
+        assert!("grayscale,sharpen".parse::<Preprocess>().is_err());
+        assert_eq!(Preprocess::default().cache_suffix(), "");
+    }
+}
@@ -0,0 +1,228 @@
+//! Runs each command of `fromthumb`, and the shims for the binaries it
+//! replaced, over generated images.
+
+mod common;
+
+use common::{arg, file_names, run, Fixture};
+
+const FROMTHUMB: &str = env!("CARGO_BIN_EXE_fromthumb");
+const FIND: &str = env!("CARGO_BIN_EXE_find");
+
+fn args(command: &str, rest: &[String]) -> Vec<String> {
+    std::iter::once(command.to_owned())
+        .chain(rest.iter().cloned())
+        .collect()
+}
+
+fn stdout(output: &std::process::Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+/// The matches recorded in `matches.json`, as thumbnail and fullsize file
+/// names with their distance.
+fn recorded_matches(fixture: &Fixture) -> Vec<(String, String, u64)> {
+    let text = std::fs::read_to_string(fixture.output().join("matches.json")).unwrap();
+    let record: serde_json::Value = serde_json::from_str(&text).unwrap();
+    let name = |path: &serde_json::Value| {
+        std::path::Path::new(path.as_str().unwrap())
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    };
+    record["matches"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| {
+            (
+                name(&m["thumb"]),
+                name(&m["fullsize"]),
+                m["distance"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn find_copies_the_fullsize_image_of_each_thumbnail() {
+    let fixture = Fixture::new(4);
+    stdout(&run(
+        FROMTHUMB,
+        fixture.dir.path(),
+        &args("find", &fixture.find_args()),
+    ));
+    let names = file_names(&fixture.output());
+    for n in 0..4 {
+        assert!(names.contains(&format!("full-{}.png", n)), "{:?}", names);
+    }
+    let matches = recorded_matches(&fixture);
+    assert_eq!(matches.len(), 4);
+    for (thumb, fullsize, distance) in matches {
+        assert_eq!(thumb.replace("thumb", "full"), fullsize);
+        assert!(distance <= 2, "{} to {} at {}", thumb, fullsize, distance);
+    }
+}
+
+#[test]
+fn legacy_find_matches_as_fromthumb_find() {
+    let fixture = Fixture::new(4);
+    stdout(&run(
+        FROMTHUMB,
+        fixture.dir.path(),
+        &args("find", &fixture.find_args()),
+    ));
+    let expected = (file_names(&fixture.output()), recorded_matches(&fixture));
+    std::fs::remove_dir_all(fixture.output()).unwrap();
+    std::fs::remove_dir_all(fixture.cache()).unwrap();
+    stdout(&run(FIND, fixture.dir.path(), &fixture.find_args()));
+    assert_eq!(
+        (file_names(&fixture.output()), recorded_matches(&fixture)),
+        expected
+    );
+}
+
+#[test]
+fn hash_prints_the_same_hash_for_a_thumbnail_as_its_fullsize_image() {
+    let fixture = Fixture::new(1);
+    let paths = vec![
+        arg(&fixture.fullsize().join("full-0.png")),
+        arg(&fixture.thumbnails().join("thumb-0.png")),
+    ];
+    let output = stdout(&run(FROMTHUMB, fixture.dir.path(), &args("hash", &paths)));
+    let hashes: Vec<&str> = output
+        .lines()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(hashes.len(), 2, "{}", output);
+    assert_eq!(hashes[0], hashes[1]);
+    // The shim takes the same arguments after `hash`.
+    assert_eq!(
+        stdout(&run(FIND, fixture.dir.path(), &args("hash", &paths))),
+        output
+    );
+}
+
+#[test]
+fn dist_prints_the_distance_between_two_images() {
+    let fixture = Fixture::new(2);
+    let paths = vec![
+        arg(&fixture.thumbnails().join("thumb-0.png")),
+        arg(&fixture.fullsize().join("full-1.png")),
+    ];
+    let output = stdout(&run(FROMTHUMB, fixture.dir.path(), &args("dist", &paths)));
+    let distance: u32 = output
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("distance "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(distance > 10, "{}", output);
+    assert_eq!(
+        stdout(&run(FIND, fixture.dir.path(), &args("dist", &paths))),
+        output
+    );
+}
+
+#[test]
+fn verify_fails_on_a_changed_copy() {
+    let fixture = Fixture::new(2);
+    stdout(&run(
+        FROMTHUMB,
+        fixture.dir.path(),
+        &args("find", &fixture.find_args()),
+    ));
+    let verify = args("verify", &[arg(&fixture.output())]);
+    let output = stdout(&run(FROMTHUMB, fixture.dir.path(), &verify));
+    assert!(output.contains("2 copies passed, 0 failed"), "{}", output);
+
+    std::fs::write(fixture.output().join("full-1.png"), b"changed").unwrap();
+    let output = run(FROMTHUMB, fixture.dir.path(), &verify);
+    assert!(!output.status.success());
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert!(output.contains("fail "), "{}", output);
+    assert!(output.contains("1 copies passed, 1 failed"), "{}", output);
+}
+
+#[test]
+fn config_file_in_the_working_directory_sets_options() {
+    let fixture = Fixture::new(2);
+    std::fs::write(fixture.path("fromthumb.toml"), "[find]\nmax-distance = 3\n").unwrap();
+    let mut find = args("find", &fixture.find_args());
+    find.push("--print-config".to_owned());
+    let output = stdout(&run(FROMTHUMB, fixture.dir.path(), &find));
+    assert!(
+        output.starts_with("# config: fromthumb.toml\n[find]\n"),
+        "{}",
+        output
+    );
+    assert!(output.contains("\nmax-distance = 3\n"), "{}", output);
+}
+
+#[test]
+fn top_level_config_options_only_apply_to_the_commands_taking_them() {
+    let fixture = Fixture::new(2);
+    let config = fixture.path("fromthumb.toml");
+    std::fs::write(&config, "max-distance = 4\nthreads = 1\n").unwrap();
+    let hash = args(
+        "hash",
+        &[
+            arg(&fixture.thumbnails().join("thumb-0.png")),
+            arg(&fixture.thumbnails().join("thumb-1.png")),
+        ],
+    );
+    let output = stdout(&run(FROMTHUMB, fixture.dir.path(), &hash));
+    assert_eq!(output.lines().count(), 2, "{}", output);
+    let mut find = args("find", &fixture.find_args());
+    find.push("--print-config".to_owned());
+    let output = stdout(&run(FROMTHUMB, fixture.dir.path(), &find));
+    assert!(output.contains("\nmax-distance = 4\n"), "{}", output);
+
+    std::fs::write(&config, "max-distanse = 4\n").unwrap();
+    let output = run(FROMTHUMB, fixture.dir.path(), &hash);
+    assert!(!output.status.success());
+    let error = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        error.contains("Unknown option in config fromthumb.toml: max-distanse"),
+        "{}",
+        error
+    );
+}
+
+#[cfg(feature = "decollage")]
+#[test]
+fn decollage_writes_each_subimage_of_a_page() {
+    use image::{GenericImage, Rgb, RgbImage};
+
+    const DECOLLAGE: &str = env!("CARGO_BIN_EXE_decollage");
+
+    let fixture = Fixture::new(0);
+    let input = fixture.path("pages");
+    std::fs::create_dir(&input).unwrap();
+    let mut page = RgbImage::from_pixel(800, 600, Rgb([255, 255, 255]));
+    page.copy_from(&common::pattern(0, 240, 180), 60, 60)
+        .unwrap();
+    page.copy_from(&common::pattern(1, 260, 200), 420, 320)
+        .unwrap();
+    page.save(input.join("page.png")).unwrap();
+
+    let outputs = [fixture.path("fromthumb"), fixture.path("legacy")];
+    for (binary, output) in [FROMTHUMB, DECOLLAGE].iter().zip(outputs.iter()) {
+        std::fs::create_dir(output).unwrap();
+        let mut rest = vec![arg(&input), arg(output)];
+        if *binary == FROMTHUMB {
+            rest.insert(0, "decollage".to_owned());
+        }
+        stdout(&run(binary, fixture.dir.path(), &rest));
+    }
+    let names = file_names(&outputs[0]);
+    let images: Vec<&String> = names.iter().filter(|name| name.ends_with(".png")).collect();
+    assert_eq!(images, ["page-00.png", "page-01.png"]);
+    assert_eq!(file_names(&outputs[1]), names);
+}
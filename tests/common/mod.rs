@@ -0,0 +1,182 @@
+//! Fixtures shared by the integration tests: directories of generated
+//! fullsize images and the thumbnails made from them.
+
+// Each test binary uses its own share of the fixtures.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use image::{imageops, Rgb, RgbImage};
+use tempfile::TempDir;
+
+/// Side of the generated fullsize images, in pixels.
+pub const FULLSIZE_SIDE: u32 = 256;
+/// Side of the thumbnails made from them.
+pub const THUMBNAIL_SIDE: u32 = 64;
+
+/// Number generator for fixtures, seeded so each run generates the same
+/// images.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn below(&mut self, n: u32) -> u32 {
+        (self.next_u64() % u64::from(n)) as u32
+    }
+}
+
+/// Returns an image of a grid of randomly colored cells, which no other seed
+/// hashes close to.
+pub fn pattern(seed: u64, width: u32, height: u32) -> RgbImage {
+    let mut rng = Rng::new(seed);
+    let cells: Vec<Rgb<u8>> = (0..64)
+        .map(|_| {
+            Rgb([
+                rng.below(200) as u8,
+                rng.below(200) as u8,
+                rng.below(200) as u8,
+            ])
+        })
+        .collect();
+    RgbImage::from_fn(width, height, |x, y| {
+        cells[(y * 8 / height * 8 + x * 8 / width) as usize]
+    })
+}
+
+/// Returns the thumbnail of `image`.
+pub fn thumbnail(image: &RgbImage) -> RgbImage {
+    imageops::resize(
+        image,
+        THUMBNAIL_SIDE,
+        THUMBNAIL_SIDE * image.height() / image.width(),
+        imageops::FilterType::Triangle,
+    )
+}
+
+/// A temporary directory of fullsize images and thumbnails, with the
+/// directories `find` writes to.
+pub struct Fixture {
+    pub dir: TempDir,
+}
+
+impl Fixture {
+    /// Generates `count` fullsize images, `full-<n>.png`, and a thumbnail of
+    /// each, `thumb-<n>.png`.
+    pub fn new(count: u64) -> Self {
+        let fixture = Fixture {
+            dir: tempfile::tempdir().unwrap(),
+        };
+        std::fs::create_dir(fixture.fullsize()).unwrap();
+        std::fs::create_dir(fixture.thumbnails()).unwrap();
+        for seed in 0..count {
+            let image = fixture.add_fullsize(seed);
+            fixture.add_thumbnail(seed, &thumbnail(&image));
+        }
+        fixture
+    }
+
+    /// Writes the fullsize image `full-<seed>.png`, returning it.
+    pub fn add_fullsize(&self, seed: u64) -> RgbImage {
+        let image = pattern(seed, FULLSIZE_SIDE, FULLSIZE_SIDE);
+        image
+            .save(self.fullsize().join(format!("full-{}.png", seed)))
+            .unwrap();
+        image
+    }
+
+    /// Writes `image` as the thumbnail `thumb-<seed>.png`.
+    pub fn add_thumbnail(&self, seed: u64, image: &RgbImage) {
+        image
+            .save(self.thumbnails().join(format!("thumb-{}.png", seed)))
+            .unwrap();
+    }
+
+    /// Returns `text` with the fixture's directory replaced by `<dir>`, so
+    /// that reports can be compared between runs.
+    pub fn relative(&self, text: &str) -> String {
+        text.replace(&arg(self.dir.path()), "<dir>")
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    pub fn fullsize(&self) -> PathBuf {
+        self.path("fullsize")
+    }
+
+    pub fn thumbnails(&self) -> PathBuf {
+        self.path("thumbnails")
+    }
+
+    pub fn output(&self) -> PathBuf {
+        self.path("output")
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.path("cache")
+    }
+
+    /// Arguments of `find` over the fixture's directories.
+    pub fn find_args(&self) -> Vec<String> {
+        vec![
+            "--thumbnail".to_owned(),
+            arg(&self.thumbnails()),
+            "--fullsize".to_owned(),
+            arg(&self.fullsize()),
+            "--output".to_owned(),
+            arg(&self.output()),
+            "--cache".to_owned(),
+            arg(&self.cache()),
+            "--no-progress".to_owned(),
+        ]
+    }
+}
+
+pub fn arg(path: &Path) -> String {
+    path.to_str().unwrap().to_owned()
+}
+
+/// Runs `binary` with `args` in `dir`, so no configuration file is found.
+pub fn run(binary: &str, dir: &Path, args: &[String]) -> Output {
+    Command::new(binary)
+        .args(args)
+        .current_dir(dir)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap()
+}
+
+/// Returns `image` inside a white border `width` pixels wide, as if scanned
+/// on a mount.
+pub fn bordered(image: &RgbImage, width: u32) -> RgbImage {
+    let mut mounted = RgbImage::from_pixel(
+        image.width() + 2 * width,
+        image.height() + 2 * width,
+        Rgb([255, 255, 255]),
+    );
+    imageops::overlay(&mut mounted, image, width, width);
+    mounted
+}
+
+/// Names of the files in `dir`, sorted.
+pub fn file_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
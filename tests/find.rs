@@ -0,0 +1,1235 @@
+//! Runs `fromthumb find` over generated fixtures, checking its reports.
+
+mod common;
+
+use image::imageops;
+
+use common::{arg, bordered, file_names, run, thumbnail, Fixture};
+
+const FROMTHUMB: &str = env!("CARGO_BIN_EXE_fromthumb");
+
+/// Fullsize images `full-0.png` to `full-6.png`, with a thumbnail of each
+/// but `full-4.png`. The thumbnail of `full-5.png` has a white border, and
+/// that of `full-6.png` is mirrored.
+fn fixture() -> Fixture {
+    let fixture = Fixture::new(4);
+    fixture.add_fullsize(4);
+    let image = fixture.add_fullsize(5);
+    fixture.add_thumbnail(5, &bordered(&thumbnail(&image), 12));
+    let image = fixture.add_fullsize(6);
+    fixture.add_thumbnail(6, &imageops::flip_horizontal(&thumbnail(&image)));
+    fixture
+}
+
+/// Runs `find` over `fixture` with `options` into a fresh output directory.
+fn run_find(fixture: &Fixture, options: &[&str]) -> std::process::Output {
+    if fixture.output().exists() {
+        std::fs::remove_dir_all(fixture.output()).unwrap();
+    }
+    let args: Vec<String> = std::iter::once("find".to_owned())
+        .chain(fixture.find_args())
+        .chain(options.iter().map(|option| (*option).to_owned()))
+        .collect();
+    run(FROMTHUMB, fixture.dir.path(), &args)
+}
+
+/// Runs `find` over `fixture` with `options`, returning whether it succeeded
+/// and its log with the fixture's directory replaced.
+fn find_log(fixture: &Fixture, options: &[&str]) -> (bool, String) {
+    let output = run_find(fixture, options);
+    (
+        output.status.success(),
+        fixture.relative(&String::from_utf8(output.stderr).unwrap()),
+    )
+}
+
+/// Runs `find` over `fixture` with `options` into a fresh output directory,
+/// returning the report `name` with the fixture's directory replaced.
+fn find(fixture: &Fixture, options: &[&str], name: &str) -> String {
+    let output = run_find(fixture, options);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    fixture.relative(&std::fs::read_to_string(fixture.output().join(name)).unwrap())
+}
+
+/// Matches of the fixture without `--try-flips`, which leave the mirrored
+/// thumbnail to the closest other image for review.
+const MATCHES: &str = "\
+thumb,fullsize,distance,needs_review,output,sidecars
+<dir>/thumbnails/thumb-0.png,<dir>/fullsize/full-0.png,0,false,<dir>/output/full-0.png,
+<dir>/thumbnails/thumb-1.png,<dir>/fullsize/full-1.png,1,false,<dir>/output/full-1.png,
+<dir>/thumbnails/thumb-2.png,<dir>/fullsize/full-2.png,1,false,<dir>/output/full-2.png,
+<dir>/thumbnails/thumb-3.png,<dir>/fullsize/full-3.png,0,false,<dir>/output/full-3.png,
+<dir>/thumbnails/thumb-5.png,<dir>/fullsize/full-5.png,2,false,<dir>/output/full-5.png,
+<dir>/thumbnails/thumb-6.png,<dir>/fullsize/full-5.png,31,true,<dir>/output/full-5.png,
+";
+
+/// Matches of the fixture with `--try-flips`.
+const FLIPPED_MATCHES: &str = "\
+thumb,fullsize,distance,needs_review,output,sidecars
+<dir>/thumbnails/thumb-0.png,<dir>/fullsize/full-0.png,0,false,<dir>/output/full-0.png,
+<dir>/thumbnails/thumb-1.png,<dir>/fullsize/full-1.png,1,false,<dir>/output/full-1.png,
+<dir>/thumbnails/thumb-2.png,<dir>/fullsize/full-2.png,1,false,<dir>/output/full-2.png,
+<dir>/thumbnails/thumb-3.png,<dir>/fullsize/full-3.png,0,false,<dir>/output/full-3.png,
+<dir>/thumbnails/thumb-5.png,<dir>/fullsize/full-5.png,2,false,<dir>/output/full-5.png,
+<dir>/thumbnails/thumb-6.png,<dir>/fullsize/full-6.png,1,false,<dir>/output/full-6.png,
+";
+
+#[test]
+fn find_reports_are_unchanged() {
+    let fixture = fixture();
+    assert_eq!(find(&fixture, &[], "matches.csv"), MATCHES);
+    assert_eq!(
+        find(&fixture, &[], "unclaimed_fullsize.txt"),
+        "<dir>/fullsize/full-4.png\n<dir>/fullsize/full-6.png\n"
+    );
+    assert_eq!(
+        find(&fixture, &["--matcher", "bktree"], "matches.csv"),
+        MATCHES
+    );
+    assert_eq!(
+        find(&fixture, &["--try-flips"], "matches.csv"),
+        FLIPPED_MATCHES
+    );
+    assert_eq!(
+        find(
+            &fixture,
+            &[
+                "--try-rotations",
+                "--try-flips",
+                "--matcher",
+                "multi-index",
+                "--top",
+                "2"
+            ],
+            "candidates.csv"
+        ),
+        "\
+thumb,rank,fullsize,distance,transform,group
+<dir>/thumbnails/thumb-0.png,1,<dir>/fullsize/full-0.png,0,none,
+<dir>/thumbnails/thumb-1.png,1,<dir>/fullsize/full-1.png,1,none,
+<dir>/thumbnails/thumb-2.png,1,<dir>/fullsize/full-2.png,1,none,
+<dir>/thumbnails/thumb-3.png,1,<dir>/fullsize/full-3.png,0,none,
+<dir>/thumbnails/thumb-5.png,1,<dir>/fullsize/full-5.png,2,none,
+<dir>/thumbnails/thumb-6.png,1,<dir>/fullsize/full-6.png,1,fliph,
+"
+    );
+    // Given the closest fullsize image, the mirrored thumbnail takes the
+    // unclaimed one instead.
+    assert_eq!(
+        find(&fixture, &["--unique"], "matches.csv"),
+        MATCHES.replace(
+            "full-5.png,31,true,<dir>/output/full-5.png",
+            "full-6.png,32,true,<dir>/output/full-6.png"
+        )
+    );
+}
+
+#[test]
+fn mirrored_thumbnails_only_match_with_try_flips() {
+    // Fullsize images `full-0.png` to `full-4.png`, with thumbnails of the
+    // first two and an upside down thumbnail of the last.
+    let fixture = Fixture::new(2);
+    for seed in 2..4 {
+        fixture.add_fullsize(seed);
+    }
+    let image = fixture.add_fullsize(4);
+    fixture.add_thumbnail(4, &imageops::flip_vertical(&thumbnail(&image)));
+    let mirrored = |options: &[&str], name: &str| {
+        find(&fixture, options, name)
+            .lines()
+            .find(|line| line.starts_with("<dir>/thumbnails/thumb-4.png,"))
+            .unwrap()
+            .to_owned()
+    };
+    let unmatched = "<dir>/thumbnails/thumb-4.png,<dir>/fullsize/full-1.png,31,true,\
+                     <dir>/output/full-1.png,";
+    assert_eq!(mirrored(&[], "matches.csv"), unmatched);
+    assert_eq!(
+        mirrored(&["--try-flips", "--top", "1"], "candidates.csv"),
+        "<dir>/thumbnails/thumb-4.png,1,<dir>/fullsize/full-4.png,0,flipv,"
+    );
+    assert_eq!(
+        mirrored(&["--try-flips"], "matches.csv"),
+        "<dir>/thumbnails/thumb-4.png,<dir>/fullsize/full-4.png,0,false,\
+         <dir>/output/full-4.png,"
+    );
+    // The flipped hashes cached since aren't taken for unflipped ones.
+    assert_eq!(mirrored(&[], "matches.csv"), unmatched);
+}
+
+#[test]
+fn find_reports_are_the_same_on_one_thread_or_many() {
+    let fixture = fixture();
+    for options in [vec![], vec!["--unique"], vec!["--try-flips", "--top", "3"]].iter() {
+        let reports = |threads: &str| {
+            let mut threaded = vec!["--threads", threads];
+            threaded.extend(options);
+            let matches = find(&fixture, &threaded, "matches.csv");
+            let candidates = fixture.output().join("candidates.csv");
+            let candidates = if candidates.exists() {
+                std::fs::read_to_string(candidates).unwrap()
+            } else {
+                String::new()
+            };
+            (matches, fixture.relative(&candidates))
+        };
+        assert_eq!(reports("1"), reports("4"), "{:?}", options);
+    }
+}
+
+#[test]
+fn find_skips_and_quarantines_corrupt_images() {
+    let fixture = Fixture::new(2);
+    for name in [
+        "fullsize/full-8.png",
+        "fullsize/full-9.png",
+        "thumbnails/thumb-7.png",
+    ]
+    .iter()
+    {
+        std::fs::write(fixture.path(name), b"\x89PNG truncated").unwrap();
+    }
+    let log = |options: &[&str]| find_log(&fixture, options);
+    let (success, stderr) = log(&["--error-policy", "abort"]);
+    assert!(!success, "{}", stderr);
+    let (success, stderr) = log(&[]);
+    assert!(success, "{}", stderr);
+    let failed = stderr.split("Failed files:").nth(1).unwrap();
+    for name in ["full-8.png", "full-9.png", "thumb-7.png"].iter() {
+        assert!(failed.contains(name), "{}", stderr);
+    }
+    assert_eq!(
+        fixture.relative(&std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap()),
+        "\
+thumb,fullsize,distance,needs_review,output,sidecars
+<dir>/thumbnails/thumb-0.png,<dir>/fullsize/full-0.png,0,false,<dir>/output/full-0.png,
+<dir>/thumbnails/thumb-1.png,<dir>/fullsize/full-1.png,1,false,<dir>/output/full-1.png,
+"
+    );
+
+    // An image that can't be set aside is warned of, and the others still are.
+    let quarantine = fixture.path("quarantine");
+    std::fs::create_dir_all(quarantine.join("fullsize/full-9.png")).unwrap();
+    let (success, stderr) = log(&["--quarantine", &arg(&quarantine), "--quarantine-move"]);
+    assert!(success, "{}", stderr);
+    assert!(
+        stderr.contains("Failed to quarantine <dir>/fullsize/full-9.png"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        file_names(&fixture.fullsize()),
+        ["full-0.png", "full-1.png", "full-9.png"]
+    );
+    assert_eq!(
+        file_names(&quarantine.join("fullsize")),
+        ["full-8.png", "full-9.png", "quarantine.log"]
+    );
+    assert!(quarantine.join("fullsize/full-9.png").is_dir());
+    assert_eq!(
+        file_names(&quarantine.join("thumbnail")),
+        ["quarantine.log", "thumb-7.png"]
+    );
+    assert_eq!(
+        file_names(&fixture.thumbnails()),
+        ["thumb-0.png", "thumb-1.png"]
+    );
+    let quarantined = std::fs::read_to_string(quarantine.join("fullsize/quarantine.log")).unwrap();
+    assert!(quarantined.starts_with(&format!("{}\t", arg(&fixture.path("fullsize/full-8.png")))));
+    assert_eq!(quarantined.lines().count(), 1);
+}
+
+#[test]
+fn find_only_loads_the_images_of_a_mixed_directory() {
+    let fixture = Fixture::new(3);
+    for name in ["Thumbs.db", ".DS_Store", "notes.txt", "full-3.png.bak"].iter() {
+        std::fs::write(fixture.fullsize().join(name), b"not an image").unwrap();
+    }
+    let hidden = fixture.add_fullsize(3);
+    std::fs::rename(
+        fixture.fullsize().join("full-3.png"),
+        fixture.fullsize().join(".full-3.png"),
+    )
+    .unwrap();
+    fixture.add_thumbnail(3, &thumbnail(&hidden));
+    std::fs::create_dir(fixture.fullsize().join("nested")).unwrap();
+    fixture.add_fullsize(4);
+    std::fs::rename(
+        fixture.fullsize().join("full-4.png"),
+        fixture.fullsize().join("nested/full-4.png"),
+    )
+    .unwrap();
+    // Extensions are compared ignoring case.
+    fixture.add_fullsize(5);
+    std::fs::rename(
+        fixture.fullsize().join("full-5.png"),
+        fixture.fullsize().join("FULL-5.PNG"),
+    )
+    .unwrap();
+
+    let (success, stderr) = find_log(&fixture, &["--verbose"]);
+    assert!(success, "{}", stderr);
+    assert!(
+        stderr.contains("Ignored 6 entries of <dir>/fullsize that are not images"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("Failed to hash"), "{}", stderr);
+    let matched = |report: &str, thumb: &str| {
+        report
+            .lines()
+            .find(|line| line.starts_with(&format!("<dir>/thumbnails/{},", thumb)))
+            .unwrap()
+            .split(',')
+            .nth(1)
+            .unwrap()
+            .to_owned()
+    };
+    let report =
+        fixture.relative(&std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap());
+    for seed in 0..3 {
+        assert_eq!(
+            matched(&report, &format!("thumb-{}.png", seed)),
+            format!("<dir>/fullsize/full-{}.png", seed)
+        );
+    }
+    assert_ne!(
+        matched(&report, "thumb-3.png"),
+        "<dir>/fullsize/.full-3.png"
+    );
+    assert_eq!(
+        find(&fixture, &[], "unclaimed_fullsize.txt"),
+        "<dir>/fullsize/FULL-5.PNG\n"
+    );
+
+    let report = find(&fixture, &["--include-hidden"], "matches.csv");
+    assert_eq!(
+        matched(&report, "thumb-3.png"),
+        "<dir>/fullsize/.full-3.png"
+    );
+    // Which fails to decode, once loaded.
+    let (success, stderr) = find_log(&fixture, &["--extensions", "png,bak"]);
+    assert!(success, "{}", stderr);
+    let failed = stderr.split("Failed files:").nth(1).unwrap();
+    assert!(
+        failed.contains("<dir>/fullsize/full-3.png.bak"),
+        "{}",
+        stderr
+    );
+    let (success, stderr) = find_log(&fixture, &["--extensions", "jpg"]);
+    assert!(!success);
+    assert!(stderr.contains("has no images"), "{}", stderr);
+}
+
+#[test]
+fn find_counts_the_images_left_out_by_include_and_exclude_globs() {
+    let fixture = Fixture::new(4);
+    let summary = |options: &[&str]| {
+        let (success, stderr) = find_log(&fixture, options);
+        assert!(success, "{}", stderr);
+        let matched = stderr
+            .lines()
+            .find(|line| line.contains("Summary: "))
+            .unwrap()
+            .split(", ")
+            .nth(1)
+            .unwrap()
+            .to_owned();
+        let skipped = stderr
+            .lines()
+            .find_map(|line| line.split("Skipped ").nth(1))
+            .map(|skipped| format!("Skipped {}", skipped));
+        (matched, skipped)
+    };
+    assert_eq!(summary(&[]), ("4 matched".to_owned(), None));
+    assert_eq!(
+        summary(&["--include", "*-[01].png"]),
+        (
+            "2 matched".to_owned(),
+            Some("Skipped 4 images not included, and 0 excluded".to_owned())
+        )
+    );
+    assert_eq!(
+        summary(&["--exclude", "*-3.png"]),
+        (
+            "3 matched".to_owned(),
+            Some("Skipped 0 images not included, and 2 excluded".to_owned())
+        )
+    );
+    assert_eq!(
+        summary(&["--include", "*-[012].png", "--exclude", "*-2.png"]),
+        (
+            "2 matched".to_owned(),
+            Some("Skipped 2 images not included, and 2 excluded".to_owned())
+        )
+    );
+    // Globs of one side only leave out images of that side.
+    assert_eq!(
+        summary(&[
+            "--thumbnail-include",
+            "thumb-[012].png",
+            "--fullsize-exclude",
+            "full-3.png"
+        ]),
+        (
+            "3 matched".to_owned(),
+            Some("Skipped 1 images not included, and 1 excluded".to_owned())
+        )
+    );
+}
+
+#[test]
+fn find_fails_on_missing_or_empty_source_directories() {
+    let fixture = Fixture::new(2);
+    let mistyped = arg(&fixture.path("fullsze"));
+    let (success, stderr) = find_log(&fixture, &["--fullsize", &mistyped]);
+    assert!(!success);
+    assert!(
+        stderr.contains("The fullsize directory <dir>/fullsze does not exist"),
+        "{}",
+        stderr
+    );
+    assert!(!fixture.path("fullsze").exists());
+    let (success, stderr) = find_log(&fixture, &["--thumbnail", &mistyped, "--allow-empty"]);
+    assert!(!success);
+    assert!(
+        stderr.contains("The thumbnail directory <dir>/fullsze does not exist"),
+        "{}",
+        stderr
+    );
+
+    // Each of the directories given for a side must have images.
+    let empty = fixture.path("empty");
+    std::fs::create_dir(&empty).unwrap();
+    for (side, option) in [("fullsize", "--fullsize"), ("thumbnail", "--thumbnail")].iter() {
+        let (success, stderr) = find_log(&fixture, &[option, &arg(&empty)]);
+        assert!(!success);
+        assert!(
+            stderr.contains(&format!(
+                "The {} directory <dir>/empty has no images, use --allow-empty to continue anyway",
+                side
+            )),
+            "{}",
+            stderr
+        );
+    }
+    // Still creating the cache and output directories.
+    for name in file_names(&fixture.fullsize()).iter() {
+        std::fs::remove_file(fixture.fullsize().join(name)).unwrap();
+    }
+    std::fs::remove_dir_all(fixture.cache()).unwrap();
+    let (success, stderr) = find_log(&fixture, &[]);
+    assert!(!success);
+    assert!(stderr.contains("has no images"), "{}", stderr);
+    let (success, stderr) = find_log(&fixture, &["--allow-empty"]);
+    assert!(success, "{}", stderr);
+    assert!(
+        stderr.contains("Summary: 2 thumbnails, 0 matched, 0 need review, 0 failed, 2 unmatched"),
+        "{}",
+        stderr
+    );
+    assert!(fixture.output().join("matches.csv").is_file());
+    assert!(fixture.cache().join("thumbnail").is_dir());
+    assert!(fixture.cache().is_dir());
+}
+
+#[test]
+fn find_recursive_keeps_the_layout_of_nested_directories() {
+    // `full-0.png` in `2019/05`, `full-1.png` in `2020` and `full-2.png` at
+    // the top, with a link from `2020` back to the top.
+    let fixture = Fixture::new(3);
+    for (seed, directory) in [(0, "2019/05"), (1, "2020")].iter() {
+        let nested = fixture.fullsize().join(directory);
+        std::fs::create_dir_all(&nested).unwrap();
+        let name = format!("full-{}.png", seed);
+        std::fs::rename(fixture.fullsize().join(&name), nested.join(&name)).unwrap();
+    }
+    std::os::unix::fs::symlink("..", fixture.fullsize().join("2020/back")).unwrap();
+    let fullsize = |report: &str| -> Vec<String> {
+        report
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(1).unwrap().to_owned())
+            .collect()
+    };
+    assert_eq!(
+        fullsize(&find(&fixture, &[], "matches.csv")),
+        ["<dir>/fullsize/full-2.png"; 3]
+    );
+
+    let report = find(&fixture, &["--recursive"], "matches.csv");
+    assert_eq!(
+        report,
+        "\
+thumb,fullsize,distance,needs_review,output,sidecars
+<dir>/thumbnails/thumb-0.png,<dir>/fullsize/2019/05/full-0.png,0,false,<dir>/output/2019/05/full-0.png,
+<dir>/thumbnails/thumb-1.png,<dir>/fullsize/2020/full-1.png,1,false,<dir>/output/2020/full-1.png,
+<dir>/thumbnails/thumb-2.png,<dir>/fullsize/full-2.png,1,false,<dir>/output/full-2.png,
+"
+    );
+    assert_eq!(
+        file_names(&fixture.output().join("2019/05")),
+        ["full-0.png"]
+    );
+    assert!(fixture.output().join("2020/full-1.png").is_file());
+    // Each directory of the tree is cached apart, under its own name, and
+    // read back on the next run.
+    let cached: Vec<String> = file_names(&fixture.cache().join("fullsize/gradient-8x8"))
+        .iter()
+        .map(|name| name.split('-').next().unwrap().to_owned())
+        .collect();
+    assert_eq!(cached, ["05", "2020", "fullsize"]);
+    let (success, stderr) = find_log(&fixture, &["--recursive"]);
+    assert!(success, "{}", stderr);
+    assert!(
+        stderr.contains("(0 hashes computed, 6 cached)"),
+        "{}",
+        stderr
+    );
+
+    let report = find(&fixture, &["--recursive", "--flatten"], "matches.csv");
+    assert_eq!(
+        report
+            .lines()
+            .map(|line| line.split(',').nth(4).unwrap())
+            .collect::<Vec<_>>(),
+        [
+            "output",
+            "<dir>/output/full-0.png",
+            "<dir>/output/full-1.png",
+            "<dir>/output/full-2.png"
+        ]
+    );
+
+    // Following the link back up is skipped rather than looping.
+    let (success, stderr) = find_log(&fixture, &["--recursive", "--follow-symlinks"]);
+    assert!(success, "{}", stderr);
+    assert!(
+        stderr
+            .contains("Not following <dir>/fullsize/2020/back, which links back to <dir>/fullsize"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        fullsize(&std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap())
+            .iter()
+            .map(|path| fixture.relative(path))
+            .collect::<Vec<_>>(),
+        [
+            "<dir>/fullsize/2019/05/full-0.png",
+            "<dir>/fullsize/2020/full-1.png",
+            "<dir>/fullsize/full-2.png"
+        ]
+    );
+}
+
+#[test]
+fn find_reports_parse_back_with_commas_quotes_and_newlines_in_paths() {
+    let fixture = Fixture::new(0);
+    let names = [
+        ("full, \"0\".png", "thumb, \"0\".png"),
+        ("full\n1.png", "thumb\n1.png"),
+        ("\"full\"2.png", "thumb 2,.png"),
+    ];
+    for (seed, (fullsize, thumb)) in names.iter().enumerate() {
+        let image = common::pattern(seed as u64, common::FULLSIZE_SIDE, common::FULLSIZE_SIDE);
+        image.save(fixture.fullsize().join(fullsize)).unwrap();
+        thumbnail(&image)
+            .save(fixture.thumbnails().join(thumb))
+            .unwrap();
+    }
+    let report = fixture.output().join("report.csv");
+    let (success, stderr) = find_log(&fixture, &["--report", &arg(&report)]);
+    assert!(success, "{}", stderr);
+    let mut reader = csv::Reader::from_path(&report).unwrap();
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec![
+            "thumb",
+            "fullsize",
+            "distance",
+            "needs_review",
+            "output",
+            "sidecars"
+        ]
+    );
+    let rows: Vec<csv::StringRecord> = reader.records().map(|row| row.unwrap()).collect();
+    // Sorted by thumbnail.
+    let mut expected: Vec<(&str, &str)> = names
+        .iter()
+        .map(|(fullsize, thumb)| (*thumb, *fullsize))
+        .collect();
+    expected.sort();
+    assert_eq!(rows.len(), expected.len());
+    for (row, (thumb, fullsize)) in rows.iter().zip(expected.iter()) {
+        assert_eq!(&row[0], arg(&fixture.thumbnails().join(thumb)));
+        assert_eq!(&row[1], arg(&fixture.fullsize().join(fullsize)));
+        assert_eq!(&row[3], "false");
+        assert_eq!(&row[4], arg(&fixture.output().join(fullsize)));
+        assert!(std::path::Path::new(&row[4]).is_file(), "{:?}", row);
+    }
+    assert!(!fixture.output().join("matches.csv").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn find_json_reports_parse_back_with_the_raw_bytes_of_paths_that_are_not_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let fixture = Fixture::new(0);
+    let names: [(&[u8], &[u8]); 2] = [
+        (b"caf\xe9, \"0\".png", b"thumb \xff0.png"),
+        (b"full\n1.png", b"thumb 1.png"),
+    ];
+    for (seed, (fullsize, thumb)) in names.iter().enumerate() {
+        let image = common::pattern(seed as u64, common::FULLSIZE_SIDE, common::FULLSIZE_SIDE);
+        image
+            .save(fixture.fullsize().join(OsStr::from_bytes(fullsize)))
+            .unwrap();
+        thumbnail(&image)
+            .save(fixture.thumbnails().join(OsStr::from_bytes(thumb)))
+            .unwrap();
+    }
+    let hex = |path: &std::path::Path| -> String {
+        path.as_os_str()
+            .as_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    };
+    // Checks `entry` has the lossy path `key` and, if it isn't UTF-8, its raw
+    // bytes, giving `path`.
+    let check = |entry: &serde_json::Value, key: &str, path: &std::path::Path| {
+        assert_eq!(entry[key], path.to_string_lossy().as_ref(), "{}", entry);
+        let hex_key = format!("{}_hex", key);
+        match path.to_str() {
+            Some(_) => assert!(entry.get(&hex_key).is_none(), "{}", entry),
+            None => assert_eq!(entry[&hex_key], hex(path), "{}", entry),
+        }
+    };
+
+    let report = fixture.path("report.json");
+    for format in ["json", "jsonl"].iter() {
+        let (success, stderr) =
+            find_log(&fixture, &["--report", &arg(&report), "--format", format]);
+        assert!(success, "{}", stderr);
+        let text = std::fs::read_to_string(&report).unwrap();
+        let mut entries: Vec<serde_json::Value> = if *format == "json" {
+            let document: serde_json::Value = serde_json::from_str(&text).unwrap();
+            document["matches"].as_array().unwrap().clone()
+        } else {
+            text.lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        entries.sort_by_key(|entry| entry["thumb"].as_str().unwrap().to_owned());
+        assert_eq!(entries.len(), names.len(), "{}", text);
+        for (entry, (fullsize, thumb)) in entries.iter().zip(names.iter().rev()) {
+            let fullsize = OsStr::from_bytes(fullsize);
+            check(
+                entry,
+                "thumb",
+                &fixture.thumbnails().join(OsStr::from_bytes(thumb)),
+            );
+            check(entry, "fullsize", &fixture.fullsize().join(fullsize));
+            check(entry, "output", &fixture.output().join(fullsize));
+            assert_eq!(entry["needs_review"], false, "{}", entry);
+        }
+        let thumb_hex = entries[1]["thumb_hex"].as_str().unwrap();
+        assert!(thumb_hex.ends_with("ff302e706e67"), "{}", thumb_hex);
+    }
+}
+
+#[test]
+fn find_reuses_cached_hashes() {
+    let fixture = fixture();
+    let args: Vec<String> = std::iter::once("find".to_owned())
+        .chain(fixture.find_args())
+        .chain(std::iter::once("--try-flips".to_owned()))
+        .collect();
+    let summaries: Vec<String> = (0..2)
+        .map(|_| {
+            let output = run(FROMTHUMB, fixture.dir.path(), &args);
+            assert!(output.status.success());
+            let log = String::from_utf8(output.stderr).unwrap();
+            log.lines()
+                .find(|line| line.contains("Summary: "))
+                .unwrap()
+                .to_owned()
+        })
+        .collect();
+    // Each image is hashed as is and flipped both ways on the first run.
+    assert!(
+        summaries[0].contains("(25 hashes computed, 0 cached)"),
+        "{}",
+        summaries[0]
+    );
+    assert!(
+        summaries[1].contains("(0 hashes computed, 25 cached)"),
+        "{}",
+        summaries[1]
+    );
+    assert_eq!(
+        fixture.relative(&std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap()),
+        FLIPPED_MATCHES
+    );
+}
+
+#[test]
+fn find_records_the_verbatim_copy_of_an_image_that_fails_to_convert() {
+    let fixture = Fixture::new(2);
+    find(&fixture, &[], "matches.csv");
+    // Its cached hash is still used, as its size and modification time are
+    // kept, but it can no longer be decoded to convert.
+    let path = fixture.fullsize().join("full-0.png");
+    let metadata = std::fs::metadata(&path).unwrap();
+    let modified = filetime::FileTime::from_last_modification_time(&metadata);
+    std::fs::write(&path, vec![0; metadata.len() as usize]).unwrap();
+    filetime::set_file_mtime(&path, modified).unwrap();
+
+    let matches = find(&fixture, &["--convert", "jpeg"], "matches.csv");
+    assert!(
+        matches.contains(",false,<dir>/output/full-0.png,\n"),
+        "{}",
+        matches
+    );
+    assert!(
+        matches.contains(",false,<dir>/output/full-1.jpg,\n"),
+        "{}",
+        matches
+    );
+    assert_eq!(
+        file_names(&fixture.output())[..2],
+        ["full-0.png", "full-1.jpg"]
+    );
+    let state = std::fs::read_to_string(fixture.output().join("matches.json")).unwrap();
+    assert!(
+        state.contains(&format!(
+            "\"output\": {:?}",
+            arg(&fixture.output().join("full-0.png"))
+        )),
+        "{}",
+        state
+    );
+}
+
+#[test]
+fn find_matches_against_exported_hashes_as_against_the_images() {
+    let fixture = fixture();
+    let manifest = fixture.path("hashes.json");
+    let matches = find(
+        &fixture,
+        &["--export-hashes", &arg(&manifest)],
+        "matches.csv",
+    );
+    assert_eq!(matches, MATCHES);
+    // The images themselves are not needed to match against the manifest.
+    std::fs::rename(fixture.fullsize(), fixture.path("elsewhere")).unwrap();
+    let report = fixture.path("imported.csv");
+    let args: Vec<String> = vec![
+        "find",
+        "--thumbnail",
+        &arg(&fixture.thumbnails()),
+        "--import-hashes",
+        &arg(&manifest),
+        "--output",
+        &arg(&fixture.output()),
+        "--cache",
+        &arg(&fixture.cache()),
+        "--report",
+        &arg(&report),
+        "--no-progress",
+    ]
+    .into_iter()
+    .map(str::to_owned)
+    .collect();
+    let output = run(FROMTHUMB, fixture.dir.path(), &args);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // Imported images can't be copied, so nothing is reported as output.
+    let columns = |report: &str| -> Vec<String> {
+        report
+            .lines()
+            .map(|line| line.splitn(5, ',').take(4).collect::<Vec<_>>().join(","))
+            .collect()
+    };
+    let imported = fixture.relative(&std::fs::read_to_string(&report).unwrap());
+    assert_eq!(columns(&imported), columns(MATCHES));
+    assert!(
+        imported.lines().skip(1).all(|line| line.ends_with(",,")),
+        "{}",
+        imported
+    );
+}
+
+#[test]
+fn find_resume_skips_the_thumbnails_matched_before_unless_forced() {
+    let fixture = Fixture::new(3);
+    let (success, stderr) = find_log(&fixture, &[]);
+    assert!(success, "{}", stderr);
+    // Runs into the same output directory, returning the log line of the
+    // thumbnails resumed and the thumbnails recorded in `matches.json`.
+    let resume = |options: &[&str]| {
+        let args: Vec<String> = std::iter::once("find".to_owned())
+            .chain(fixture.find_args())
+            .chain(std::iter::once("--resume".to_owned()))
+            .chain(options.iter().map(|option| (*option).to_owned()))
+            .collect();
+        let output = run(FROMTHUMB, fixture.dir.path(), &args);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(output.status.success(), "{}", stderr);
+        let resumed = stderr
+            .lines()
+            .find_map(|line| line.split("Resuming: ").nth(1))
+            .unwrap()
+            .to_owned();
+        let state: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(fixture.output().join("matches.json")).unwrap(),
+        )
+        .unwrap();
+        let mut thumbs: Vec<String> = state["matches"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| {
+                let thumb = record["thumb"].as_str().unwrap();
+                thumb.rsplit('/').next().unwrap().to_owned()
+            })
+            .collect();
+        thumbs.sort();
+        (resumed, thumbs)
+    };
+    let thumbs = |count: usize| -> Vec<String> {
+        (0..count)
+            .map(|seed| format!("thumb-{}.png", seed))
+            .collect()
+    };
+
+    // New thumbnails are matched and recorded alongside those resumed.
+    let image = fixture.add_fullsize(3);
+    fixture.add_thumbnail(3, &thumbnail(&image));
+    assert_eq!(
+        resume(&[]),
+        ("skipping 3 thumbnails matched before".to_owned(), thumbs(4))
+    );
+    assert_eq!(
+        resume(&[]),
+        ("skipping 4 thumbnails matched before".to_owned(), thumbs(4))
+    );
+    // Thumbnails whose copy is gone are matched again, replacing their record.
+    std::fs::remove_file(fixture.output().join("full-0.png")).unwrap();
+    assert_eq!(
+        resume(&[]),
+        ("skipping 3 thumbnails matched before".to_owned(), thumbs(4))
+    );
+    assert!(fixture.output().join("full-0.png").is_file());
+    assert_eq!(
+        resume(&["--force", "thumb-[12].png"]),
+        ("skipping 2 thumbnails matched before".to_owned(), thumbs(4))
+    );
+    // Without --resume, the record is started afresh.
+    let (success, stderr) = find_log(&fixture, &["--thumbnail-include", "thumb-0.png"]);
+    assert!(success, "{}", stderr);
+    assert_eq!(
+        resume(&["--thumbnail-include", "thumb-0.png"]),
+        ("skipping 1 thumbnails matched before".to_owned(), thumbs(1))
+    );
+}
+
+#[test]
+fn find_dry_run_leaves_the_output_directories_untouched() {
+    let fixture = fixture();
+    std::fs::write(fixture.fullsize().join("full-9.png"), b"not a png").unwrap();
+    let review = fixture.path("review");
+    let conflicts = fixture.path("conflicts");
+    let quarantine = fixture.path("quarantine");
+    let output = run_find(
+        &fixture,
+        &[
+            "--dry-run",
+            "--review-dir",
+            &arg(&review),
+            "--conflicts",
+            &arg(&conflicts),
+            "--quarantine",
+            &arg(&quarantine),
+        ],
+    );
+    let stderr = fixture.relative(&String::from_utf8(output.stderr).unwrap());
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        !fixture.output().exists() || file_names(&fixture.output()).is_empty(),
+        "{:?}",
+        file_names(&fixture.output())
+    );
+    for directory in [&review, &conflicts, &quarantine].iter() {
+        assert!(!directory.exists(), "{}", directory.display());
+    }
+    // The copies are listed, and the report written to stdout, instead.
+    assert!(
+        stderr.contains("Would copy <dir>/fullsize/full-0.png to <dir>/output/full-0.png"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Would quarantine 1 images that failed to decode in <dir>/quarantine"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("Dry run: "), "{}", stderr);
+    let report = fixture.relative(&String::from_utf8(output.stdout).unwrap());
+    assert!(
+        report.starts_with(MATCHES.lines().next().unwrap()),
+        "{}",
+        report
+    );
+    assert!(
+        report.contains("<dir>/thumbnails/thumb-0.png,<dir>/fullsize/full-0.png,0,false,"),
+        "{}",
+        report
+    );
+}
+
+/// Runs `find` over `fixture` with `options`, returning the hashes computed
+/// and cached as given in its summary.
+fn hashes_computed_and_cached(fixture: &Fixture, options: &[&str]) -> String {
+    let (success, stderr) = find_log(fixture, options);
+    assert!(success, "{}", stderr);
+    let summary = stderr
+        .lines()
+        .find(|line| line.contains("Summary: "))
+        .unwrap();
+    let start = summary.find('(').unwrap();
+    let end = summary.find(')').unwrap();
+    summary[start + 1..end].to_owned()
+}
+
+#[test]
+fn find_content_cache_key_survives_renames_but_not_changes() {
+    let fixture = Fixture::new(3);
+    let content = ["--cache-key", "content"];
+    assert_eq!(
+        hashes_computed_and_cached(&fixture, &content),
+        "6 hashes computed, 0 cached"
+    );
+    std::fs::rename(
+        fixture.fullsize().join("full-0.png"),
+        fixture.fullsize().join("renamed.png"),
+    )
+    .unwrap();
+    assert_eq!(
+        hashes_computed_and_cached(&fixture, &content),
+        "0 hashes computed, 6 cached"
+    );
+    let report =
+        fixture.relative(&std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap());
+    assert!(
+        report.contains("<dir>/thumbnails/thumb-0.png,<dir>/fullsize/renamed.png,0,"),
+        "{}",
+        report
+    );
+    // Keyed by name, the renamed image is hashed again.
+    assert_eq!(
+        hashes_computed_and_cached(&fixture, &[]),
+        "6 hashes computed, 0 cached"
+    );
+    std::fs::rename(
+        fixture.fullsize().join("renamed.png"),
+        fixture.fullsize().join("full-0.png"),
+    )
+    .unwrap();
+    assert_eq!(
+        hashes_computed_and_cached(&fixture, &[]),
+        "1 hashes computed, 5 cached"
+    );
+
+    // A changed image is hashed again, however it is keyed.
+    common::pattern(7, common::FULLSIZE_SIDE, common::FULLSIZE_SIDE)
+        .save(fixture.fullsize().join("full-1.png"))
+        .unwrap();
+    assert_eq!(
+        hashes_computed_and_cached(&fixture, &content),
+        "1 hashes computed, 5 cached"
+    );
+}
+
+#[test]
+fn find_caches_images_sharing_a_stem_or_file_name_apart() {
+    let fixture = Fixture::new(0);
+    let more = fixture.path("more");
+    std::fs::create_dir(&more).unwrap();
+    let fullsize = [
+        fixture.fullsize().join("scan.png"),
+        fixture.fullsize().join("scan.bmp"),
+        more.join("scan.png"),
+    ];
+    for (seed, path) in fullsize.iter().enumerate() {
+        let image = common::pattern(seed as u64, common::FULLSIZE_SIDE, common::FULLSIZE_SIDE);
+        image.save(path).unwrap();
+        fixture.add_thumbnail(seed as u64, &thumbnail(&image));
+    }
+    let options = ["--fullsize", &arg(&more)];
+    for computed in ["6 hashes computed, 0 cached", "0 hashes computed, 6 cached"].iter() {
+        assert_eq!(hashes_computed_and_cached(&fixture, &options), *computed);
+        let report = std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap();
+        for (seed, path) in fullsize.iter().enumerate() {
+            let row = format!(
+                "{},{},",
+                arg(&fixture.thumbnails().join(format!("thumb-{}.png", seed))),
+                arg(path)
+            );
+            assert!(report.contains(&row), "{}\n{}", row, report);
+        }
+    }
+}
+
+/// Runs `find` over `fixture` with each of `variants` of options twice,
+/// checking that each is cached apart from the others and reused by its second
+/// run with the same matches. Returns the names of the fullsize caches.
+fn cached_apart(fixture: &Fixture, variants: &[&[&str]]) -> Vec<String> {
+    let hashes = 2 * file_names(&fixture.fullsize()).len();
+    let mut reports = Vec::new();
+    for (index, options) in variants.iter().enumerate() {
+        assert_eq!(
+            hashes_computed_and_cached(fixture, options),
+            format!("{} hashes computed, 0 cached", hashes),
+            "{:?}",
+            options
+        );
+        assert_eq!(
+            file_names(&fixture.cache().join("fullsize")).len(),
+            index + 1
+        );
+        reports.push(std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap());
+    }
+    for (options, report) in variants.iter().zip(reports.iter()) {
+        assert_eq!(
+            hashes_computed_and_cached(fixture, options),
+            format!("0 hashes computed, {} cached", hashes),
+            "{:?}",
+            options
+        );
+        assert_eq!(
+            &std::fs::read_to_string(fixture.output().join("matches.csv")).unwrap(),
+            report
+        );
+    }
+    file_names(&fixture.cache().join("fullsize"))
+}
+
+#[test]
+fn find_caches_the_hashes_of_each_resize_filter_apart() {
+    let fixture = Fixture::new(2);
+    let names = cached_apart(
+        &fixture,
+        &[
+            &[],
+            &["--hash-filter", "triangle"],
+            &["--hash-filter", "nearest"],
+        ],
+    );
+    assert_eq!(names.len(), 3);
+    assert_eq!(
+        names
+            .iter()
+            .filter(|name| name.contains("triangle"))
+            .count(),
+        1,
+        "{:?}",
+        names
+    );
+}
+
+#[test]
+fn find_caches_blurred_hashes_apart() {
+    let fixture = Fixture::new(2);
+    let names = cached_apart(&fixture, &[&[], &["--preblur", "1.5"]]);
+    assert_eq!(names, ["gradient-8x8", "gradient-8x8-blur1.5"]);
+    // No blur is the same as none given.
+    assert_eq!(
+        hashes_computed_and_cached(&fixture, &["--preblur", "0"]),
+        "0 hashes computed, 4 cached"
+    );
+}
+
+#[test]
+fn find_cleanup_of_fullsize_images_matches_their_unbordered_thumbnails() {
+    let fixture = Fixture::new(3);
+    let image = common::pattern(3, common::FULLSIZE_SIDE, common::FULLSIZE_SIDE);
+    bordered(&image, 40)
+        .save(fixture.fullsize().join("full-3.png"))
+        .unwrap();
+    fixture.add_thumbnail(3, &thumbnail(&image));
+    let matched = |cleanup: &str| {
+        let report = find(
+            &fixture,
+            &["--cleanup", cleanup, "--max-distance", "4"],
+            "matches.csv",
+        );
+        report
+            .lines()
+            .find(|line| line.starts_with("<dir>/thumbnails/thumb-3.png,"))
+            .map(|line| line.split(',').nth(1).unwrap().to_owned())
+    };
+    assert_eq!(matched("thumbs"), None);
+    assert_eq!(matched("none"), None);
+    for cleanup in ["fullsize", "both"].iter() {
+        assert_eq!(
+            matched(cleanup).as_deref(),
+            Some("<dir>/fullsize/full-3.png"),
+            "{}",
+            cleanup
+        );
+    }
+    assert_eq!(
+        find(
+            &fixture,
+            &["--cleanup", "thumbs", "--max-distance", "4"],
+            "unmatched.txt"
+        ),
+        "<dir>/thumbnails/thumb-3.png\n"
+    );
+}
+
+#[test]
+fn find_strict_exits_with_the_code_of_what_needs_attention() {
+    let fixture = fixture();
+    let code = |options: &[&str]| run_find(&fixture, options).status.code();
+    // The mirrored thumbnail needs review unless flips are tried.
+    assert_eq!(code(&[]), Some(0));
+    assert_eq!(code(&["--strict", "--try-flips"]), Some(0));
+    assert_eq!(code(&["--strict"]), Some(2));
+    // An image that could not be hashed takes precedence over reviews.
+    std::fs::write(fixture.fullsize().join("full-9.png"), b"not a png").unwrap();
+    assert_eq!(code(&["--strict", "--try-flips"]), Some(3));
+    assert_eq!(code(&["--strict"]), Some(3));
+    assert_eq!(code(&[]), Some(0));
+}
+
+#[test]
+fn find_watch_needs_a_report_it_can_add_to() {
+    let fixture = Fixture::new(1);
+    let (success, stderr) = find_log(&fixture, &["--watch"]);
+    assert!(!success);
+    assert!(
+        stderr.contains("--watch needs --format jsonl or --no-report"),
+        "{}",
+        stderr
+    );
+    let (success, stderr) = find_log(&fixture, &["--watch", "--format", "json"]);
+    assert!(!success);
+    assert!(
+        stderr.contains("--watch needs --format jsonl or --no-report"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn find_copies_matches_needing_review_for_their_colors_to_the_review_directory() {
+    let fixture = fixture();
+    let review = fixture.path("review");
+    let options = [
+        "--try-flips",
+        "--verify-color",
+        "--color-threshold",
+        "1",
+        "--review-dir",
+        &arg(&review),
+    ];
+    let (success, stderr) = find_log(&fixture, &options);
+    assert!(success, "{}", stderr);
+    assert!(stderr.contains("color similarity"), "{}", stderr);
+    let listed = std::fs::read_to_string(review.join("review.csv")).unwrap();
+    assert_eq!(listed.lines().count(), 7, "{}", listed);
+    assert_eq!(
+        file_names(&review),
+        [
+            "full-0.png",
+            "full-1.png",
+            "full-2.png",
+            "full-3.png",
+            "full-5.png",
+            "full-6.png",
+            "review.csv"
+        ]
+    );
+    assert!(!file_names(&fixture.output()).contains(&"full-0.png".to_owned()));
+}
+
+#[test]
+fn find_warns_that_the_prefilter_only_applies_to_brute_force() {
+    let fixture = fixture();
+    let log = |options: &[&str]| {
+        let args: Vec<String> = std::iter::once("find".to_owned())
+            .chain(fixture.find_args())
+            .chain(options.iter().map(|option| (*option).to_owned()))
+            .collect();
+        let output = run(FROMTHUMB, fixture.dir.path(), &args);
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let warning = "--prefilter has no effect with --matcher";
+    assert!(log(&["--prefilter", "2"]).contains(warning));
+    assert!(log(&["--prefilter", "2", "--matcher", "multi-index"]).contains(warning));
+    assert!(!log(&["--prefilter", "2", "--matcher", "bruteforce"]).contains(warning));
+    assert!(!log(&[]).contains(warning));
+    // Prefiltering to two of the seven fullsize images still finds each match.
+    assert_eq!(
+        find(
+            &fixture,
+            &["--prefilter", "2", "--matcher", "bruteforce", "--try-flips"],
+            "matches.csv"
+        ),
+        FLIPPED_MATCHES
+    );
+}
+
+#[test]
+fn hash_output_is_unchanged() {
+    let fixture = fixture();
+    let hash = |side: &[&str], directory: &std::path::Path| {
+        let args: Vec<String> = std::iter::once("hash".to_owned())
+            .chain(side.iter().map(|option| (*option).to_owned()))
+            .chain(
+                file_names(directory)
+                    .iter()
+                    .map(|name| arg(&directory.join(name))),
+            )
+            .collect();
+        let output = run(FROMTHUMB, fixture.dir.path(), &args);
+        assert!(output.status.success());
+        fixture.relative(&String::from_utf8(output.stdout).unwrap())
+    };
+    assert_eq!(
+        hash(&[], &fixture.thumbnails()),
+        "\
+tA22PdJkZ20= <dir>/thumbnails/thumb-0.png
+Zjk9JmFHmk4= <dir>/thumbnails/thumb-1.png
+jDtkZRnbybk= <dir>/thumbnails/thumb-2.png
+jcllY5qY41s= <dir>/thumbnails/thumb-3.png
+se1ksqUZmMY= <dir>/thumbnails/thumb-5.png
+nozOmAxOGBs= <dir>/thumbnails/thumb-6.png
+"
+    );
+    assert_eq!(
+        hash(&["--fullsize"], &fixture.fullsize()),
+        "\
+tA22PdJkZ20= <dir>/fullsize/full-0.png
+Zzk9JmFHmk4= <dir>/fullsize/full-1.png
+nDtkZRnbybk= <dir>/fullsize/full-2.png
+jcllY5qY41s= <dir>/fullsize/full-3.png
+jGHBQ1pYw2M= <dir>/fullsize/full-4.png
+se1ksqUZmsI= <dir>/fullsize/full-5.png
+hs6M5s+N5yc= <dir>/fullsize/full-6.png
+"
+    );
+}